@@ -75,6 +75,19 @@ pub fn core_state(apic_id: u32) -> ApicState {
     APICS[apic_id as usize].load(Ordering::SeqCst).into()
 }
 
+/// Returns `true` if no core other than the running core is launched or
+/// online
+pub fn other_cores_parked() -> bool {
+    let our_apic_id = core!().apic_id();
+
+    (0..MAX_CORES as u32).all(|apic_id| {
+        Some(apic_id) == our_apic_id || match core_state(apic_id) {
+            ApicState::Online | ApicState::Launched => false,
+            _ => true,
+        }
+    })
+}
+
 /// Check in that the current core has booted
 pub fn core_checkin() {
     /// Number of cores which have checked in
@@ -112,6 +125,65 @@ pub fn num_cores() -> u32 {
     count
 }
 
+/// A PCIe enhanced configuration space region described by the MCFG
+#[derive(Clone, Copy, Debug)]
+pub struct McfgEntry {
+    /// Physical base address of the enhanced configuration space. This is
+    /// the address of bus 0, even if `start_bus` is non-zero
+    pub base: PhysAddr,
+
+    /// PCI segment group number
+    pub segment: u16,
+
+    /// First bus number decoded by this region
+    pub start_bus: u8,
+
+    /// Last bus number (inclusive) decoded by this region
+    pub end_bus: u8,
+}
+
+/// An I/O APIC described by the MADT
+#[derive(Clone, Copy, Debug)]
+pub struct IoApicEntry {
+    /// I/O APIC ID
+    pub id: u8,
+
+    /// Physical address of the registers of the I/O APIC
+    pub addr: PhysAddr,
+
+    /// Global system interrupt of the first input of the I/O APIC
+    pub gsi_base: u32,
+}
+
+/// An interrupt source override described by the MADT, which maps an ISA
+/// IRQ to a different global system interrupt
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptOverride {
+    /// ISA IRQ being overridden
+    pub source: u8,
+
+    /// Global system interrupt the IRQ is connected to
+    pub gsi: u32,
+
+    /// MPS INTI flags, the polarity in bits 1:0 and the trigger mode in bits
+    /// 3:2
+    pub flags: u16,
+}
+
+/// A DMA remapping hardware unit (IOMMU) described by the DMAR
+#[derive(Clone, Copy, Debug)]
+pub struct DmarUnit {
+    /// Physical address of the remapping unit's register set
+    pub base: PhysAddr,
+
+    /// PCI segment group number of the devices behind this unit
+    pub segment: u16,
+
+    /// If set, this unit handles all devices in the segment which are not
+    /// claimed by another unit
+    pub include_all: bool,
+}
+
 /// In-memory representation of an RSDP ACPI structure
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
@@ -173,9 +245,8 @@ unsafe fn parse_header(addr: PhysAddr) -> (Header, PhysAddr, usize) {
     (head, PhysAddr(addr.0 + size_of::<Header>() as u64), payload_len as usize)
 }
 
-/// Initialize the ACPI subsystem. Mainly looking for APICs and memory maps.
-/// Brings up all cores on the system
-pub unsafe fn init() {
+/// Locate and validate the RSDP
+unsafe fn find_rsdp() -> Rsdp {
     // Specification says we have to scan the first 1 KiB of the EBDA and the
     // range from 0xe0000 to 0xfffff
 
@@ -245,7 +316,13 @@ pub unsafe fn init() {
     }
 
     // Get access to the RSDP
-    let rsdp = rsdp.expect("Failed to find RSDP for ACPI");
+    rsdp.expect("Failed to find RSDP for ACPI")
+}
+
+/// Get the physical addresses of all of the tables described by the RSDT
+unsafe fn rsdt_tables() -> Vec<PhysAddr> {
+    // Get access to the RSDP
+    let rsdp = find_rsdp();
 
     // Parse out the RSDT
     let (rsdt, rsdt_payload, rsdt_size) =
@@ -257,32 +334,40 @@ pub unsafe fn init() {
         "Invalid table size for RSDT");
     let rsdt_entries = rsdt_size / size_of::<u32>();
 
-    // Set up the structures we're interested as parsing out as `None` as some
-    // of them may or may not be present.
-    let mut apics          = None;
-    let mut apic_domains   = None;
-    let mut memory_domains = None;
-
     // Go through each table described by the RSDT
-    for entry in 0..rsdt_entries {
+    (0..rsdt_entries).map(|entry| {
         // Get the physical address of the RSDP table entry
         let entry_paddr = rsdt_payload.0 as usize + entry * size_of::<u32>();
 
         // Get the pointer to the table
         let table_ptr: u32 = mm::read_phys(PhysAddr(entry_paddr as u64));
+        PhysAddr(table_ptr as u64)
+    }).collect()
+}
+
+/// Initialize the ACPI subsystem. Mainly looking for APICs and memory maps.
+/// Brings up all cores on the system
+pub unsafe fn init() {
+    // Set up the structures we're interested as parsing out as `None` as some
+    // of them may or may not be present.
+    let mut apics          = None;
+    let mut apic_domains   = None;
+    let mut memory_domains = None;
 
+    // Go through each table described by the RSDT
+    for table_ptr in rsdt_tables() {
         // Get the signature for the table
-        let signature: [u8; 4] = mm::read_phys(PhysAddr(table_ptr as u64));
+        let signature: [u8; 4] = mm::read_phys(table_ptr);
 
         if &signature == b"APIC" {
             // Parse the MADT
             assert!(apics.is_none(), "Multiple MADT ACPI table entries");
-            apics = Some(parse_madt(PhysAddr(table_ptr as u64)));
+            apics = Some(parse_madt(table_ptr));
         } else if &signature == b"SRAT" {
             // Parse the SRAT
             assert!(apic_domains.is_none() && memory_domains.is_none(),
                 "Multiple SRAT ACPI table entries");
-            let (ad, md) = parse_srat(PhysAddr(table_ptr as u64));
+            let (ad, md) = parse_srat(table_ptr);
             apic_domains   = Some(ad);
             memory_domains = Some(md);
         }
@@ -342,6 +427,19 @@ pub unsafe fn init() {
     }
 }
 
+/// Get the number of usable cores described by the MADT. Unlike `num_cores`
+/// this can be used before `init`, as it parses the tables directly.
+///
+/// If there is no MADT, the system is assumed to have a single core.
+pub unsafe fn madt_core_count() -> u32 {
+    // Find the MADT
+    let ptr = rsdt_tables().into_iter().find(|&table_ptr| {
+        &mm::read_phys::<[u8; 4]>(table_ptr) == b"APIC"
+    });
+
+    ptr.map(|ptr| parse_madt(ptr).len() as u32).unwrap_or(1)
+}
+
 /// Parse the MADT out of the ACPI tables
 /// Returns a vector of all usable APIC IDs
 unsafe fn parse_madt(ptr: PhysAddr) -> Vec<u32> {
@@ -418,6 +516,76 @@ unsafe fn parse_madt(ptr: PhysAddr) -> Vec<u32> {
     apics
 }
 
+/// Locate the MADT and parse the I/O APICs and interrupt source overrides
+/// out of it. Returns empty vectors if there is no MADT.
+pub unsafe fn parse_ioapics() -> (Vec<IoApicEntry>, Vec<InterruptOverride>) {
+    let mut ioapics   = Vec::new();
+    let mut overrides = Vec::new();
+
+    // Find the MADT
+    let ptr = rsdt_tables().into_iter().find(|&table_ptr| {
+        &mm::read_phys::<[u8; 4]>(table_ptr) == b"APIC"
+    });
+    let ptr = if let Some(ptr) = ptr {
+        ptr
+    } else {
+        return (ioapics, overrides);
+    };
+
+    // Parse the MADT header
+    let (_header, payload, size) = parse_header(ptr);
+
+    // Skip the local interrupt controller address and the flags to get the
+    // physical address of the ICS
+    let mut ics = PhysAddr(payload.0 + 4 + 4);
+    let end = payload.0 + size as u64;
+
+    loop {
+        // Make sure there's room for the type and the length
+        if ics.0 + 2 > end { break; }
+
+        // Parse out the type and the length of the ICS entry
+        let typ: u8 = mm::read_phys(PhysAddr(ics.0 + 0));
+        let len: u8 = mm::read_phys(PhysAddr(ics.0 + 1));
+
+        // Make sure there's room for this structure
+        if ics.0 + len as u64 > end { break; }
+        assert!(len >= 2, "Bad length for MADT ICS entry");
+
+        match typ {
+            1 => {
+                // I/O APIC entry
+                assert!(len == 12, "Invalid I/O APIC ICS entry");
+
+                ioapics.push(IoApicEntry {
+                    id:       mm::read_phys(PhysAddr(ics.0 + 2)),
+                    addr:     PhysAddr(
+                        mm::read_phys::<u32>(PhysAddr(ics.0 + 4)) as u64),
+                    gsi_base: mm::read_phys(PhysAddr(ics.0 + 8)),
+                });
+            }
+            2 => {
+                // Interrupt source override entry
+                assert!(len == 10, "Invalid interrupt source override entry");
+
+                overrides.push(InterruptOverride {
+                    source: mm::read_phys(PhysAddr(ics.0 + 3)),
+                    gsi:    mm::read_phys(PhysAddr(ics.0 + 4)),
+                    flags:  mm::read_phys(PhysAddr(ics.0 + 8)),
+                });
+            }
+            _ => {
+                // Not interrupt routing
+            }
+        }
+
+        // Go to the next ICS entry
+        ics = PhysAddr(ics.0 + len as u64);
+    }
+
+    (ioapics, overrides)
+}
+
 /// Parse the SRAT out of the ACPI tables
 /// Returns a tuple of (apic -> domain, memory domain -> phys_ranges)
 unsafe fn parse_srat(ptr: PhysAddr) ->
@@ -526,3 +694,90 @@ unsafe fn parse_srat(ptr: PhysAddr) ->
     (apic_affinities, memory_affinities)
 }
 
+/// Locate and parse the MCFG out of the ACPI tables
+/// Returns all of the enhanced configuration space regions on the system, or
+/// an empty vector if there is no MCFG
+pub unsafe fn parse_mcfg() -> Vec<McfgEntry> {
+    // Find the MCFG
+    let ptr = rsdt_tables().into_iter().find(|&table_ptr| {
+        &mm::read_phys::<[u8; 4]>(table_ptr) == b"MCFG"
+    });
+
+    // Create a new structure to hold the ECAM regions
+    let mut regions = Vec::new();
+
+    // Check if we have an MCFG at all
+    let ptr = if let Some(ptr) = ptr { ptr } else { return regions; };
+
+    // Parse the MCFG header
+    let (_header, payload, size) = parse_header(ptr);
+
+    // Skip the 8 reserved bytes to get to the allocation structures
+    let mut entry = PhysAddr(payload.0 + 8);
+    let end = payload.0 + size as u64;
+
+    // Each configuration space base address allocation structure is 16 bytes
+    while entry.0 + 16 <= end {
+        regions.push(McfgEntry {
+            base:      mm::read_phys(PhysAddr(entry.0 +  0)),
+            segment:   mm::read_phys(PhysAddr(entry.0 +  8)),
+            start_bus: mm::read_phys(PhysAddr(entry.0 + 10)),
+            end_bus:   mm::read_phys(PhysAddr(entry.0 + 11)),
+        });
+
+        // Go to the next allocation structure
+        entry = PhysAddr(entry.0 + 16);
+    }
+
+    regions
+}
+
+/// Locate and parse the DMAR out of the ACPI tables
+/// Returns all of the DMA remapping hardware units on the system, or an empty
+/// vector if there is no DMAR
+pub unsafe fn parse_dmar() -> Vec<DmarUnit> {
+    // Find the DMAR
+    let ptr = rsdt_tables().into_iter().find(|&table_ptr| {
+        &mm::read_phys::<[u8; 4]>(table_ptr) == b"DMAR"
+    });
+
+    // Create a new structure to hold the remapping units
+    let mut units = Vec::new();
+
+    // Check if we have a DMAR at all
+    let ptr = if let Some(ptr) = ptr { ptr } else { return units; };
+
+    // Parse the DMAR header
+    let (_header, payload, size) = parse_header(ptr);
+
+    // Skip the host address width, flags, and reserved bytes to get to the
+    // remapping structures
+    let mut entry = PhysAddr(payload.0 + 12);
+    let end = payload.0 + size as u64;
+
+    // Each remapping structure starts with a 2-byte type and 2-byte length
+    while entry.0 + 4 <= end {
+        let typ: u16 = mm::read_phys(PhysAddr(entry.0 + 0));
+        let len: u16 = mm::read_phys(PhysAddr(entry.0 + 2));
+
+        // Don't loop forever on a malformed table
+        if len < 4 {
+            break;
+        }
+
+        // Type 0 is a DMA remapping hardware unit definition
+        if typ == 0 && len >= 16 && entry.0 + 16 <= end {
+            let flags: u8 = mm::read_phys(PhysAddr(entry.0 + 4));
+            units.push(DmarUnit {
+                base:        PhysAddr(mm::read_phys(PhysAddr(entry.0 + 8))),
+                segment:     mm::read_phys(PhysAddr(entry.0 + 6)),
+                include_all: (flags & 1) != 0,
+            });
+        }
+
+        // Go to the next remapping structure
+        entry = PhysAddr(entry.0 + len as u64);
+    }
+
+    units
+}
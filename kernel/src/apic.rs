@@ -321,7 +321,7 @@ impl Apic {
         // Get access to the APIC without needing the lock. This is safe in
         // all situations as we issue one EOI wrmsr which is "atomic" WRT other
         // interrupts.
-        let apic = &mut *core!().apic().shatter();
+        let apic = &mut *core!().apic().shatter_local();
 
         if let Some(apic) = apic {
             apic.write_apic(Register::EndOfInterrupt, 0);
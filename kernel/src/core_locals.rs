@@ -85,6 +85,10 @@ impl lockcell::InterruptState for LockInterrupts {
     fn exit_lock() {
         unsafe { core!().enable_interrupts(); }
     }
+
+    fn other_cores_parked() -> bool {
+        crate::acpi::other_cores_parked()
+    }
 }
 
 /// A core-exclusive data structure which can be accessed via the `core!()`
@@ -21,6 +21,12 @@ static EOI_REQUIRED: [AtomicBool; 256] = [AtomicBool::new(false); 256];
 /// no handler will be invoked.
 pub static DRAINING_EOIS: AtomicBool = AtomicBool::new(false);
 
+/// Set whether interrupt `num` requires an EOI when it is handled, for
+/// interrupts which are not dispatched through `Interrupts::add_handler`
+pub fn set_eoi_required(num: u8, required: bool) {
+    EOI_REQUIRED[num as usize].store(required, Ordering::SeqCst);
+}
+
 /// Type for an interrupt gate for 64-bit mode
 const X64_INTERRUPT_GATE: u32 = 0xe;
 
@@ -360,7 +366,7 @@ pub unsafe extern fn interrupt_handler(
             panic!("Panic occured on another core");
         } else {
             // VMXOFF if we're in VMX root operation
-            let vmxon_lock = core!().vmxon_region().shatter();
+            let vmxon_lock = core!().vmxon_region().shatter_local();
             if (*vmxon_lock).is_some() {
                 // Disable VMX root operation
                 llvm_asm!("vmxoff" :::: "intel", "volatile");
@@ -402,6 +408,11 @@ pub unsafe extern fn interrupt_handler(
                 handled = true;
             }
         }
+
+        // Otherwise, see if this is a device interrupt
+        if !handled && number >= 32 {
+            handled = crate::pci::irq::dispatch(number);
+        }
     }
     
     // EOI the APIC if this vector warrants EOIs
@@ -37,6 +37,10 @@ pub mod winboot;
 
 use page_table::PhysAddr;
 
+/// If `true` PCI devices are enumerated and initialized by all cores once
+/// they have come online, rather than by the BSP alone during early boot
+const PARALLEL_PCI_INIT: bool = false;
+
 /// Release the early boot stack such that other cores can use it by marking
 /// it as available
 fn release_early_stack() {
@@ -64,8 +68,23 @@ pub extern fn entry(boot_args: PhysAddr, core_id: u32) -> ! {
     if core!().id == 0 {
         // One-time initialization for the whole kernel
 
+        // Register the drivers with the PCI subsystem
+        net::register_drivers();
+
         // Initialize PCI devices
-        unsafe { pci::init() }
+        if !PARALLEL_PCI_INIT {
+            // A machine we can't enumerate still boots, just without any
+            // PCI devices
+            match unsafe { pci::init() } {
+                Ok(claimed) => {
+                    print!("PCI: {} devices claimed by drivers\n", claimed);
+                }
+                Err(err) => {
+                    print!("PCI: initialization failed, continuing without \
+                            PCI devices: {:?}\n", err);
+                }
+            }
+        }
 
         // Bring up all APICs on the system and also initialize NUMA
         // information with the memory manager through the use of the ACPI
@@ -92,6 +111,11 @@ pub extern fn entry(boot_args: PhysAddr, core_id: u32) -> ! {
     // NMIs and soft reboots work.
     acpi::core_checkin();
 
+    // Initialize PCI devices using all cores
+    if PARALLEL_PCI_INIT {
+        unsafe { pci::init_parallel() }
+    }
+
     // ====================================================================
     // Put your whatever code here, typically I just branch to a module
     // which has a "main" or something and call `mod::main()`
@@ -100,6 +124,11 @@ pub extern fn entry(boot_args: PhysAddr, core_id: u32) -> ! {
     //test_fuzzer::fuzz();
     winboot::main();
 
-    cpu::halt();
+    // Idle, servicing debug requests from the serial port
+    loop {
+        pci::service_dump_request();
+        unsafe { pci::service_removals(); }
+        cpu::single_halt();
+    }
 }
 
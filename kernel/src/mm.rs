@@ -11,10 +11,11 @@ use alloc::collections::BTreeMap;
 
 use crate::acpi::MAX_CORES;
 
-use rangeset::RangeSet;
+use rangeset::{Range, RangeSet};
 use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
 use boot_args::KERNEL_VMEM_BASE;
 use page_table::{PhysMem, PhysAddr, PageType, VirtAddr};
+use page_table::{PAGE_NX, PAGE_WRITE, PAGE_PRESENT, PAGE_CACHE_DISABLE};
 
 /// Table which is indexed by an APIC identifier to map to a physical range
 /// which is local to it its NUMA node
@@ -99,6 +100,134 @@ pub fn alloc_virt_addr_4k(size: u64) -> VirtAddr {
     ret
 }
 
+/// Reasons a range of memory-mapped I/O could not be mapped in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmioError {
+    /// The range is empty
+    Empty,
+
+    /// The range wraps the address space or lies beyond the physical
+    /// addresses the CPU supports
+    OutOfRange,
+
+    /// The range overlaps memory which is available to the kernel, thus
+    /// can't be device memory
+    OverlapsMemory,
+
+    /// The page tables could not be updated to hold the mapping
+    MapFailed,
+}
+
+/// Get the number of physical address bits the CPU supports
+fn phys_addr_bits() -> u32 {
+    unsafe {
+        // Without the leaf the CPU supports 36 bits of physical address
+        if cpu::cpuid(0x8000_0000, 0).0 < 0x8000_0008 {
+            36
+        } else {
+            cpu::cpuid(0x8000_0008, 0).0 & 0xff
+        }
+    }
+}
+
+/// Map `size` bytes of memory-mapped I/O at `paddr` into uncacheable virtual
+/// memory and return the virtual address which corresponds to `paddr`
+///
+/// `paddr` does not need to be 4 KiB aligned, the mapping is expanded to
+/// cover all pages which `paddr..paddr + size` touches. Panics if the range
+/// can't be mapped, see `try_map_mmio`.
+pub unsafe fn map_mmio(paddr: PhysAddr, size: u64) -> VirtAddr {
+    try_map_mmio(paddr, size).expect("Failed to map in MMIO")
+}
+
+/// Map `size` bytes of memory-mapped I/O at `paddr` like `map_mmio`, but
+/// check that the range is device memory the CPU can reach before mapping it
+/// in
+pub unsafe fn try_map_mmio(paddr: PhysAddr, size: u64)
+        -> Result<VirtAddr, MmioError> {
+    if size == 0 {
+        return Err(MmioError::Empty);
+    }
+
+    // Make sure the range is physically addressable
+    let end = paddr.0.checked_add(size - 1).ok_or(MmioError::OutOfRange)?;
+    if (end >> phys_addr_bits()) != 0 {
+        return Err(MmioError::OutOfRange);
+    }
+
+    // Make sure we're not about to map RAM as uncacheable
+    {
+        let free_memory = core!().boot_args.free_memory_ref().lock();
+        if let Some(free_memory) = free_memory.as_ref() {
+            if free_memory.entries().iter()
+                    .any(|x| x.start <= end && paddr.0 <= x.end) {
+                return Err(MmioError::OverlapsMemory);
+            }
+        }
+    }
+
+    // Compute the page aligned physical range we need to map
+    let page_offset = paddr.0 & 0xfff;
+    let base        = paddr.0 - page_offset;
+    let map_size    = page_offset.checked_add(size)
+        .and_then(|x| x.checked_add(0xfff))
+        .ok_or(MmioError::OutOfRange)? & !0xfff;
+
+    // Get a virtual address capable of holding the mapping
+    let vaddr = alloc_virt_addr_4k(map_size);
+
+    // Get access to physical memory allocations
+    let mut pmem = PhysicalMemory;
+
+    // Get access to the current page table
+    let mut page_table = core!().boot_args.page_table.lock();
+    let page_table = page_table.as_mut().unwrap();
+
+    // Map in the MMIO space as uncacheable memory
+    for offset in (0..map_size).step_by(4096) {
+        page_table.map_raw(&mut pmem, VirtAddr(vaddr.0 + offset),
+                           PageType::Page4K,
+                           (base + offset) | PAGE_NX | PAGE_WRITE |
+                           PAGE_CACHE_DISABLE | PAGE_PRESENT)
+            .ok_or(MmioError::MapFailed)?;
+    }
+
+    Ok(VirtAddr(vaddr.0 + page_offset))
+}
+
+/// Allocate `size` bytes of physically contiguous memory aligned to `align`
+/// which lies entirely below the physical address `limit`
+///
+/// This is intended for DMA buffers of devices which cannot address all of
+/// physical memory. The memory is never freed.
+pub fn alloc_phys_below(size: u64, align: u64, limit: u64)
+        -> Option<PhysAddr> {
+    // Get access to physical memory
+    let mut phys_mem = unsafe {
+        core!().boot_args.free_memory_ref().lock()
+    };
+    let phys_mem = phys_mem.as_mut()?;
+
+    // Prefer memory below the limit
+    let mut below = RangeSet::new();
+    below.insert(Range { start: 0, end: limit.checked_sub(1)? });
+
+    let alc = phys_mem.allocate_prefer(size, align, Some(&below))? as u64;
+
+    // If the allocation could not be satisfied from below the limit, give
+    // the memory back
+    if alc.checked_add(size)? > limit {
+        phys_mem.insert(Range { start: alc, end: alc + size - 1 });
+        return None;
+    }
+
+    // Update stats
+    GLOBAL_ALLOCATOR.free_physical
+        .store(phys_mem.sum().unwrap(), Ordering::Relaxed);
+
+    Some(PhysAddr(alc))
+}
+
 /// Gets access to a slice of physical memory
 #[allow(dead_code)]
 #[inline]
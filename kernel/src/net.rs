@@ -5,18 +5,21 @@ pub mod tcp;
 pub mod arp;
 pub mod dhcp;
 pub mod intel_nic;
+pub mod rtl8139;
+pub mod virtio_net;
 pub mod netmapping;
 
 use core::fmt::{self, Formatter, Debug};
 use core::convert::TryInto;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
 
-use crate::pci::Device;
+use crate::pci::{Device, DeviceHealth, PurgeError, PciAddress};
 use crate::mm::PhysContig;
 use crate::net::tcp::TcpConnectionInt;
 use crate::net::dhcp::Lease;
@@ -29,6 +32,14 @@ use page_table::PhysAddr;
 static NET_DEVICES: LockCell<Vec<Arc<NetDevice>>, LockInterrupts> =
     LockCell::new(Vec::new());
 
+/// Register all of the network drivers with the PCI subsystem. This must be
+/// done before `pci::init`.
+pub fn register_drivers() {
+    intel_nic::register();
+    virtio_net::register();
+    rtl8139::register();
+}
+
 /// IPv4 ethernet frame type
 const ETHTYPE_IPV4: u16 = 0x0800;
 
@@ -38,6 +49,15 @@ const IPPROTO_UDP: u8 = 0x11;
 /// TCP protocol for the IP header
 const IPPROTO_TCP: u8 = 0x6;
 
+/// Size of an ethernet header
+pub const ETH_HEADER_SIZE: usize = 14;
+
+/// MTU of network devices unless it is changed with `NetDevice::set_mtu`
+pub const DEFAULT_MTU: u16 = 1500;
+
+/// Largest ethernet frame with the default MTU, not including the FCS
+pub const MAX_FRAME_SIZE: usize = DEFAULT_MTU as usize + ETH_HEADER_SIZE;
+
 /// UDP/TCP address
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct NetAddress {
@@ -106,13 +126,169 @@ impl Debug for Ipv4Addr {
     }
 }
 
+/// Negotiated speed of a network link
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkSpeed {
+    Mbps10,
+    Mbps100,
+    Mbps1000,
+    Mbps10000,
+}
+
+/// State of the link of a network device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkStatus {
+    /// Set if the link is up
+    pub up: bool,
+
+    /// Negotiated speed of the link, `None` if the link is down or the speed
+    /// is not known
+    pub speed: Option<LinkSpeed>,
+}
+
+/// Reasons a frame could not be queued for sending
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxError {
+    /// The transmit ring is full, retry once sent frames have been reclaimed
+    Full,
+
+    /// The frame is larger than the MTU of the device allows
+    TooLarge,
+}
+
+/// Reasons a network device failed its self test
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// The driver can't loop frames back on this device
+    Unsupported,
+
+    /// The test frame could not be queued for sending
+    TxFailed(TxError),
+
+    /// The test frame was not received back in time
+    Timeout,
+
+    /// The test frame was received back with different contents
+    Corrupted,
+}
+
+/// Work done by one `NetDevice::poll` of the rings of a network device
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PollResult {
+    /// Number of received frames waiting to be picked up with `recv`
+    pub rx: usize,
+
+    /// Number of sent frames whose descriptors were reclaimed
+    pub tx: usize,
+}
+
+/// Checksums a network device computes and validates in hardware
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumOffload {
+    /// The device validates the IPv4, TCP and UDP checksums of received
+    /// frames and drops those with a bad checksum
+    pub rx: bool,
+
+    /// The device computes the TCP and UDP checksums of sent IPv4 frames,
+    /// see `NetDriver::set_checksum_offload`
+    pub tx: bool,
+}
+
+/// Packet and byte counters for a network device
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NetStats {
+    /// Number of frames received
+    pub rx_packets: u64,
+
+    /// Number of frames sent
+    pub tx_packets: u64,
+
+    /// Number of bytes received, not including the FCS
+    pub rx_bytes: u64,
+
+    /// Number of bytes sent, not including the FCS
+    pub tx_bytes: u64,
+
+    /// Number of frames which failed to be received
+    pub rx_errors: u64,
+
+    /// Number of frames which failed to be sent
+    pub tx_errors: u64,
+
+    /// Number of times the device was found to have no receive descriptors
+    /// left to receive into, dropping frames until some are given back
+    pub rx_starved: u64,
+}
+
+/// Callback invoked by a network device when the number of receive
+/// descriptors it has left to receive into on a queue falls below the
+/// threshold of `NetDevice::set_low_rx_callback`. It is passed the queue and
+/// the number of descriptors left.
+pub type LowRxCallback = fn(usize, usize);
+
+/// Counters which drivers can update from any core to implement
+/// `NetDriver::stats`
+#[derive(Default)]
+pub struct NetCounters {
+    rx_packets: AtomicU64,
+    tx_packets: AtomicU64,
+    rx_bytes:   AtomicU64,
+    tx_bytes:   AtomicU64,
+    rx_errors:  AtomicU64,
+    tx_errors:  AtomicU64,
+    rx_starved: AtomicU64,
+}
+
+impl NetCounters {
+    /// Record a received frame of `bytes` bytes
+    pub fn rx(&self, bytes: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record a sent frame of `bytes` bytes
+    pub fn tx(&self, bytes: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record a frame which failed to be received
+    pub fn rx_error(&self) {
+        self.rx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a frame which failed to be sent
+    pub fn tx_error(&self) {
+        self.tx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the device ran out of receive descriptors
+    pub fn rx_starved(&self) {
+        self.rx_starved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the current values of the counters
+    pub fn snapshot(&self) -> NetStats {
+        NetStats {
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            rx_bytes:   self.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes:   self.tx_bytes.load(Ordering::Relaxed),
+            rx_errors:  self.rx_errors.load(Ordering::Relaxed),
+            tx_errors:  self.tx_errors.load(Ordering::Relaxed),
+            rx_starved: self.rx_starved.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// An implementation for a network device. This holds packet queues and has
 /// an underlying driver to send and recv from.
 pub struct NetDevice {
     /// Driver that provides raw RX and TX to the network
     driver: Box<dyn NetDriver>,
 
-    /// MAC address for the network card
+    /// MAC address for the network card, cached from the driver when the
+    /// device was created
     mac: [u8; 6],
 
     /// The DHCP lease that was obtained during `init`. May be `None` if no
@@ -249,6 +425,16 @@ impl NetDevice {
     pub fn recv(&self) -> Option<PacketLease> {
         self.driver.recv()
     }
+
+    /// Receive a raw packet from the network, handing it to `func` without
+    /// copying it out of the receive buffer of the driver. Returns `true` if
+    /// a packet was delivered.
+    ///
+    /// The driver may hold its receive state while `func` runs, thus `func`
+    /// must not receive from this device.
+    pub fn recv_with<F: FnMut(&[u8])>(&self, mut func: F) -> bool {
+        self.driver.recv_with(&mut func)
+    }
  
     /// Send a raw frame over the network containing the bytes `packet`. This
     /// `packet` does not include the FCS, that must be computed or inserted
@@ -257,6 +443,33 @@ impl NetDevice {
         self.driver.send(packet, flush);
     }
 
+    /// Queue a copy of the raw frame `frame` for sending without waiting for
+    /// room in the transmit ring, see `NetDriver::try_send`
+    pub fn try_send(&self, frame: &[u8]) -> Result<(), TxError> {
+        self.driver.try_send(frame)
+    }
+
+    /// Get the number of receive and transmit queues of this device
+    pub fn num_queues(&self) -> usize {
+        self.driver.num_queues()
+    }
+
+    /// Receive a raw packet from the receive queue `queue`
+    pub fn recv_queue(&self, queue: usize) -> Option<PacketLease> {
+        self.driver.recv_queue(queue)
+    }
+
+    /// Send a raw frame on the transmit queue `queue`, see `send`
+    pub fn send_queue(&self, packet: Packet, queue: usize, flush: bool) {
+        self.driver.send_queue(packet, queue, flush);
+    }
+
+    /// Process the rings of this device without relying on its interrupts,
+    /// see `NetDriver::poll`
+    pub fn poll(&self) -> PollResult {
+        self.driver.poll()
+    }
+
     /// Allocate a new packet for use
     pub fn allocate_packet(&self) -> Packet {
         self.driver.allocate_packet()
@@ -266,11 +479,141 @@ impl NetDevice {
     pub fn mac(&self) -> [u8; 6] {
         self.mac
     }
+
+    /// Get the packet and byte counters for this network device
+    pub fn stats(&self) -> NetStats {
+        self.driver.stats()
+    }
+
+    /// Get the link state of this network device, `None` if the driver
+    /// can't tell
+    pub fn link_status(&self) -> Option<LinkStatus> {
+        self.driver.link_status()
+    }
+
+    /// Enable or disable promiscuous mode, where every frame on the wire is
+    /// received rather than only those addressed to us or broadcast
+    pub fn set_promiscuous(&self, enable: bool) {
+        self.driver.set_promiscuous(enable);
+    }
+
+    /// Get the MTU of this network device
+    pub fn mtu(&self) -> u16 {
+        self.driver.mtu()
+    }
+
+    /// Set the MTU of this network device, `Err` if the device can't support
+    /// `mtu`
+    pub fn set_mtu(&self, mtu: u16) -> Result<(), ()> {
+        self.driver.set_mtu(mtu)
+    }
+
+    /// Limit the interrupt rate of this network device to one interrupt
+    /// every `usecs` microseconds, 0 disables throttling. `Err` if the device
+    /// can't throttle its interrupts by `usecs`.
+    pub fn set_interrupt_throttle(&self, usecs: u16) -> Result<(), ()> {
+        self.driver.set_interrupt_throttle(usecs)
+    }
+
+    /// Enable or disable checksum offload for received and sent frames, see
+    /// `NetDriver::set_checksum_offload`
+    pub fn set_checksum_offload(&self, rx: bool, tx: bool)
+            -> Result<(), ()> {
+        self.driver.set_checksum_offload(rx, tx)
+    }
+
+    /// Invoke `callback` whenever a receive queue of this network device has
+    /// fewer than `threshold` descriptors left to receive into, `None`
+    /// removes the callback. See `NetDriver::set_low_rx_callback`.
+    pub fn set_low_rx_callback(&self, threshold: usize,
+                               callback: Option<LowRxCallback>) {
+        self.driver.set_low_rx_callback(threshold, callback);
+    }
+
+    /// Get the checksums this network device currently offloads
+    pub fn checksum_offload(&self) -> ChecksumOffload {
+        self.driver.checksum_offload()
+    }
+
+    /// Get the checksums this network device is able to offload
+    pub fn checksum_offload_support(&self) -> ChecksumOffload {
+        self.driver.checksum_offload_support()
+    }
+
+    /// Wait up to `timeout` microseconds for the link to come up. Returns the
+    /// link state if the link came up, or if the driver can't tell the link
+    /// state.
+    pub fn wait_for_link(&self, timeout: u64) -> Option<LinkStatus> {
+        let timeout = crate::time::future(timeout);
+        loop {
+            let status = self.link_status();
+            if status.map(|x| x.up).unwrap_or(true) {
+                return status;
+            }
+
+            if cpu::rdtsc() >= timeout {
+                return None;
+            }
+        }
+    }
+
+    /// Loop a frame back through the device to check that it can send and
+    /// receive, see `NetDriver::self_test`
+    pub fn self_test(&self) -> Result<(), SelfTestError> {
+        self.driver.self_test()
+    }
 }
 
 impl Device for NetDevice {
-    unsafe fn purge(&self) {
-        self.driver.reset();
+    fn name(&self) -> &str {
+        self.driver.name()
+    }
+
+    fn bdf(&self) -> Option<PciAddress> {
+        self.driver.bdf()
+    }
+
+    fn dma_mask(&self) -> u64 {
+        self.driver.dma_mask()
+    }
+
+    fn as_net(&self) -> Option<&NetDevice> {
+        Some(self)
+    }
+
+    unsafe fn purge(&self) -> Result<(), PurgeError> {
+        self.driver.reset()
+    }
+
+    unsafe fn suspend(&self) {
+        self.driver.suspend();
+    }
+
+    unsafe fn resume(&self) {
+        self.driver.resume();
+    }
+
+    unsafe fn handle_interrupt(&self, _vector: u8) -> bool {
+        self.driver.handle_interrupt()
+    }
+
+    fn health(&self) -> DeviceHealth {
+        self.driver.health()
+    }
+
+    fn describe(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let mac = self.driver.mac();
+        write!(out, "{} | {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+               self.driver.name(), mac[0], mac[1], mac[2], mac[3], mac[4],
+               mac[5])
+    }
+
+    unsafe fn unplug(&self) {
+        self.driver.suspend();
+
+        // Stop handing out this device
+        NET_DEVICES.lock()
+            .retain(|x| &**x as *const NetDevice != self as *const NetDevice);
     }
 }
 
@@ -278,11 +621,138 @@ impl Device for NetDevice {
 pub trait NetDriver: Send + Sync {
     /// Forceably reset the NIC, this is to disable it fully before we soft
     /// reboot
-    unsafe fn reset(&self);
+    unsafe fn reset(&self) -> Result<(), PurgeError>;
+
+    /// Gracefully stop the NIC from performing DMA, see `Device::suspend`
+    unsafe fn suspend(&self) {}
+
+    /// Restart a NIC which was stopped with `suspend`
+    unsafe fn resume(&self) {}
+
+    /// Service an interrupt from the NIC, acknowledging it. Returns `true` if
+    /// the NIC was the source of the interrupt.
+    unsafe fn handle_interrupt(&self) -> bool {
+        false
+    }
+
+    /// Gets the human-readable name of the NIC
+    fn name(&self) -> &str;
+
+    /// Gets the location of the NIC on the PCI bus, see `Device::bdf`
+    fn bdf(&self) -> Option<PciAddress> {
+        None
+    }
+
+    /// Gets the physical addresses the NIC can DMA to, see `Device::dma_mask`
+    fn dma_mask(&self) -> u64 {
+        !0
+    }
 
     /// Gets the MAC address of the hardware
+    ///
+    /// This must not change for the lifetime of the device, drivers should
+    /// read it once during probe and cache it. `NetDevice` caches the value
+    /// returned when the device is created.
     fn mac(&self) -> [u8; 6];
 
+    /// Gets the link state of the NIC, `None` if the driver can't tell
+    fn link_status(&self) -> Option<LinkStatus> {
+        None
+    }
+
+    /// Check whether the NIC is working, see `Device::health`
+    fn health(&self) -> DeviceHealth {
+        DeviceHealth::Unknown
+    }
+
+    /// Enable or disable reception of all unicast and multicast frames
+    fn set_promiscuous(&self, _enable: bool) {
+        // By default, the driver doesn't support promiscuous mode and stays
+        // in its normal filtering mode
+    }
+
+    /// Gets the largest payload of an ethernet frame the NIC sends and
+    /// receives
+    fn mtu(&self) -> u16 {
+        DEFAULT_MTU
+    }
+
+    /// Reconfigure the NIC to send and receive frames with payloads up to
+    /// `mtu` bytes. Returns `Err` if the NIC can't support `mtu`.
+    fn set_mtu(&self, mtu: u16) -> Result<(), ()> {
+        // By default, the driver only supports the default MTU
+        if mtu == DEFAULT_MTU { Ok(()) } else { Err(()) }
+    }
+
+    /// Throttle the interrupts of the NIC to at most one every `usecs`
+    /// microseconds, trading latency for less interrupt overhead under load.
+    /// An interval of 0 disables throttling. Returns `Err` if the NIC can't
+    /// throttle its interrupts by `usecs`.
+    fn set_interrupt_throttle(&self, _usecs: u16) -> Result<(), ()> {
+        // By default, the driver can't throttle its interrupts
+        Err(())
+    }
+
+    /// Register `callback` to be invoked when a receive queue of the NIC has
+    /// fewer than `threshold` descriptors left to receive into, such that
+    /// the caller can pick up frames before the NIC starts dropping them.
+    /// `None` removes the callback.
+    ///
+    /// The callback may be invoked with the receive state of the queue held,
+    /// thus it must not receive from the NIC itself.
+    fn set_low_rx_callback(&self, _threshold: usize,
+                           _callback: Option<LowRxCallback>) {
+        // By default, the driver doesn't track its receive descriptors
+    }
+
+    /// Gets the checksums the NIC is able to offload
+    fn checksum_offload_support(&self) -> ChecksumOffload {
+        // By default, the driver computes no checksums in hardware
+        ChecksumOffload::default()
+    }
+
+    /// Gets the checksums the NIC currently offloads
+    fn checksum_offload(&self) -> ChecksumOffload {
+        ChecksumOffload::default()
+    }
+
+    /// Enable or disable validating the checksums of received frames (`rx`)
+    /// and computing the checksums of sent frames (`tx`) in hardware.
+    /// Returns `Err` if the NIC can't offload what was asked for.
+    ///
+    /// With transmit offload enabled, the TCP or UDP checksum field of sent
+    /// IPv4 frames must hold the checksum of the pseudo header, the NIC adds
+    /// in the rest of the segment.
+    fn set_checksum_offload(&self, rx: bool, tx: bool) -> Result<(), ()> {
+        // By default, only disabling offload is supported
+        if rx || tx { Err(()) } else { Ok(()) }
+    }
+
+    /// Put the NIC in loopback, send a known frame and check that it is
+    /// received back unchanged, then return the NIC to normal operation
+    ///
+    /// Nothing else may use the NIC during the test, frames received while
+    /// it runs are dropped.
+    fn self_test(&self) -> Result<(), SelfTestError> {
+        // By default, the driver can't loop back frames
+        Err(SelfTestError::Unsupported)
+    }
+
+    /// Gets the packet and byte counters of the NIC
+    fn stats(&self) -> NetStats {
+        // By default, the driver doesn't keep track of anything
+        NetStats::default()
+    }
+
+    /// Walk the rings of the NIC, reclaiming the descriptors of sent frames
+    /// and counting the received frames ready for `recv`. This is the same
+    /// work the interrupt path does, such that a core can spin on it with the
+    /// interrupts of the NIC ignored.
+    fn poll(&self) -> PollResult {
+        // By default, the driver has nothing to process outside of `recv`
+        PollResult::default()
+    }
+
     /// Recv a raw frame from the network and return ownership of the raw
     /// physical buffer that was used for the DMA of the packet
     ///
@@ -290,10 +760,54 @@ pub trait NetDriver: Send + Sync {
     /// should be validated by the driver
     fn recv<'a, 'b: 'a>(&'b self) -> Option<PacketLease<'a>>;
 
+    /// Recv a raw frame from the network and pass its contents to `func`
+    /// from the buffer it was received into, which is only recycled once
+    /// `func` returns. Returns `true` if a frame was delivered, see `recv`.
+    fn recv_with(&self, func: &mut dyn FnMut(&[u8])) -> bool {
+        // By default, receive a lease to the frame
+        self.recv().map(|packet| func(packet.raw())).is_some()
+    }
+
     /// Send a raw frame over the network containing the bytes `packet`. This
     /// `packet` does not include the FCS, that must be computed or inserted
     /// by the driver.
     fn send(&self, packet: Packet, flush: bool);
+
+    /// Queue a copy of the raw frame `frame` for sending, which does not
+    /// include the FCS. Unlike `send` this never waits for room in the
+    /// transmit ring, `TxError::Full` is returned instead.
+    fn try_send(&self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.len() > self.mtu() as usize + ETH_HEADER_SIZE {
+            return Err(TxError::TooLarge);
+        }
+
+        // By default we can't tell whether `send` would wait, thus we never
+        // have room
+        Err(TxError::Full)
+    }
+
+    /// Gets the number of receive and transmit queues of the NIC. Queues are
+    /// indexed from 0 to `num_queues() - 1`.
+    ///
+    /// `recv` must return frames from any of the queues, and `send` may pick
+    /// whichever queue is best for the current core.
+    fn num_queues(&self) -> usize {
+        // By default, the driver only has a single queue
+        1
+    }
+
+    /// Recv a raw frame from the receive queue `queue`, see `recv`
+    fn recv_queue<'a, 'b: 'a>(&'b self, queue: usize)
+            -> Option<PacketLease<'a>> {
+        assert!(queue == 0, "Invalid receive queue");
+        self.recv()
+    }
+
+    /// Send a raw frame on the transmit queue `queue`, see `send`
+    fn send_queue(&self, packet: Packet, queue: usize, flush: bool) {
+        assert!(queue == 0, "Invalid transmit queue");
+        self.send(packet, flush);
+    }
     
     /// Get a packet from the NIC's packet free list. This allows us to give
     /// ownership of a packet during the `send` process, which the NIC can then
@@ -501,7 +1015,7 @@ impl Packet {
     /// Set the length of the internally held bytes
     #[inline]
     pub fn set_len(&mut self, len: usize) {
-        assert!(len <= 1514 && len <= self.raw.len(),
+        assert!(len <= self.raw.len(),
             "set_len() on packet OOB");
         self.length = len;
     }
@@ -1,25 +1,98 @@
 //! Intel network card driver(s) for both 1gbit and 10gbit
 
-use core::mem::size_of;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize};
+use core::sync::atomic::Ordering;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::boxed::Box;
 
 use lockcell::LockCell;
-use page_table::{PAGE_NX, PAGE_CACHE_DISABLE};
-use page_table::{PhysAddr, VirtAddr, PageType, PAGE_PRESENT, PAGE_WRITE};
 
-use crate::mm::{alloc_virt_addr_4k, PhysContig};
-use crate::net::{NetDriver, NetDevice, Packet, PacketLease};
-use crate::pci::{PciDevice, BarType};
+use crate::net::{NetDriver, NetDevice, NetStats, NetCounters, Packet};
+use crate::net::{PacketLease, LinkStatus, LinkSpeed, TxError, SelfTestError};
+use crate::net::{DEFAULT_MTU, ETH_HEADER_SIZE, MAX_FRAME_SIZE, PollResult};
+use crate::net::{ChecksumOffload, LowRxCallback};
+use crate::pci::{self, Device, DeviceHealth, PurgeError, ProbedDevice};
+use crate::pci::PciAddress;
+use crate::pci::intx;
+use crate::pci::mmio::RegisterBlock;
+use crate::pci::dma::DmaRegion;
+use crate::acpi;
 use crate::core_locals::LockInterrupts;
 
-/// Number of receive descriptors to allocate per device (max is 256)
-const NUM_RX_DESCS: usize = 256;
+/// If `true`, NICs which can signal transmit completions on their legacy
+/// INTx line have it hooked through the I/O APIC, such that sent frames are
+/// reclaimed from the interrupt. Otherwise, or if hooking the line fails, the
+/// NIC is only polled.
+const LEGACY_INTERRUPTS: bool = false;
 
-/// Number of transmit descriptors to allocate per device (max is 256)
-const NUM_TX_DESCS: usize = 256;
+/// Default number of receive descriptors to allocate per queue
+const DEFAULT_RX_DESCS: usize = 256;
+
+/// Default number of transmit descriptors to allocate per queue
+const DEFAULT_TX_DESCS: usize = 256;
+
+/// Smallest number of descriptors in a ring. The length of a ring must be a
+/// multiple of the 128-byte cache lines of the NIC, which hold 8 descriptors.
+const MIN_RING_DESCS: usize = 8;
+
+/// Largest number of descriptors in a ring we allow, which keeps a ring to
+/// 64 KiB
+const MAX_RING_DESCS: usize = 4096;
+
+/// Number of receive descriptors per queue of NICs probed from now on
+static RX_RING_DESCS: AtomicUsize = AtomicUsize::new(DEFAULT_RX_DESCS);
+
+/// Number of transmit descriptors per queue of NICs probed from now on
+static TX_RING_DESCS: AtomicUsize = AtomicUsize::new(DEFAULT_TX_DESCS);
+
+/// Set the number of receive and transmit descriptors per queue of the NICs
+/// probed from now on. Larger rings absorb bursts of traffic, smaller rings
+/// use less memory and queue less latency.
+///
+/// Ring sizes must be powers of two from 8 to 4096, otherwise `Err` is
+/// returned and the sizes are left unchanged.
+pub fn set_ring_sizes(rx_descs: usize, tx_descs: usize) -> Result<(), ()> {
+    let valid = |descs: usize| {
+        descs.is_power_of_two() &&
+            descs >= MIN_RING_DESCS && descs <= MAX_RING_DESCS
+    };
+    if !valid(rx_descs) || !valid(tx_descs) {
+        return Err(());
+    }
+
+    RX_RING_DESCS.store(rx_descs, Ordering::SeqCst);
+    TX_RING_DESCS.store(tx_descs, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The NIC uses 64-bit addresses for its descriptor rings and buffers, thus
+/// it can DMA anywhere
+const DMA_MASK: u64 = !0;
+
+/// Smallest MTU we allow to be configured, the minimum for IPv4
+const MIN_MTU: u16 = 68;
+
+/// Ethernet frame type of the frames looped back by the self test, this is
+/// the type reserved for local experiments
+const SELF_TEST_ETHTYPE: u16 = 0x88b5;
+
+/// Layout of the interrupt throttling registers of a NIC
+#[derive(Clone, Copy)]
+struct ThrottleRegister {
+    /// Register of queue 0, the registers of the other queues follow it
+    reg: usize,
+
+    /// Granularity of the interval, in nanoseconds
+    unit: u32,
+
+    /// Bit position of the interval field in the register
+    shift: u32,
+
+    /// Width of the interval field, in bits
+    width: u32,
+}
 
 /// Network register offsets
 ///
@@ -33,6 +106,12 @@ struct NicRegisters {
     /// Interrupt mask clear
     imc: usize,
 
+    /// Interrupt mask set, `None` if we don't take interrupts from this NIC
+    ims: Option<usize>,
+
+    /// Interrupt cause read, reading this acknowledges the interrupts
+    icr: usize,
+
     /// Receive descriptor base low
     rdbal: usize,
     
@@ -95,140 +174,280 @@ struct NicRegisters {
 
     /// Extended control
     ctrl_ext: Option<usize>,
+
+    /// Device status, holding the link state on gigabit parts
+    status: Option<usize>,
+
+    /// Link status (x540)
+    links: Option<usize>,
+
+    /// Distance in bytes between the per-queue registers of consecutive
+    /// queues. The per-queue registers above are those of queue 0.
+    queue_stride: usize,
+
+    /// Maximum number of receive and transmit queue pairs we will use
+    max_queues: usize,
+
+    /// Multiple receive queues command, and the value which enables RSS
+    mrqc: Option<(usize, u32)>,
+
+    /// RSS redirection table, 32 registers of 4 entries each
+    reta: Option<usize>,
+
+    /// RSS random key, 10 registers
+    rssrk: Option<usize>,
+
+    /// Receive long packet maximum length
+    rlpml: Option<usize>,
+
+    /// Largest frame, not including the FCS, which the NIC can be configured
+    /// to receive into a single one of our packets
+    max_frame: usize,
+
+    /// Interrupt throttling
+    throttle: Option<ThrottleRegister>,
+
+    /// Receive checksum control, `None` if we can't offload receive
+    /// checksums on this NIC
+    rxcsum: Option<usize>,
+}
+
+/// PCI driver ID of this driver, this must never change as it is persisted
+/// across soft reboots
+const DRIVER_ID: u16 = 1;
+
+/// Register this driver with the PCI subsystem
+pub fn register() {
+    pci::register_driver(DRIVER_ID, probe);
 }
 
 /// Checks to see if the PCI device being probed is a device that we can handle
 /// with our driver
-pub fn probe(device: &PciDevice) -> Option<Arc<NetDevice>> {
+pub fn probe(probed: &ProbedDevice) -> Option<Arc<dyn Device>> {
     const E1000_REGS: NicRegisters = NicRegisters {
-        ctrl:     0x0000,
-        imc:      0x00d8,
-        rdbal:    0x2800,
-        rdbah:    0x2804,
-        rdlen:    0x2808,
-        rdh:      0x2810,
-        rdt:      0x2818,
-        tdbal:    0x3800,
-        tdbah:    0x3804,
-        tdlen:    0x3808,
-        tdh:      0x3810,
-        tdt:      0x3818,
-        ral0:     0x5400,
-        rah0:     0x5404,
-        rctl:     Some(0x0100),
-        tctl:     Some(0x0400),
-        rxdctl:   None,
-        txdctl:   None,
-        srrctl:   None,
-        dmatxctl: None,
-        fctrl:    None,
-        rxctrl:   None,
-        ctrl_ext: None,
+        ctrl:         0x0000,
+        imc:          0x00d8,
+        ims:          Some(0x00d0),
+        icr:          0x00c0,
+        rdbal:        0x2800,
+        rdbah:        0x2804,
+        rdlen:        0x2808,
+        rdh:          0x2810,
+        rdt:          0x2818,
+        tdbal:        0x3800,
+        tdbah:        0x3804,
+        tdlen:        0x3808,
+        tdh:          0x3810,
+        tdt:          0x3818,
+        ral0:         0x5400,
+        rah0:         0x5404,
+        rctl:         Some(0x0100),
+        tctl:         Some(0x0400),
+        rxdctl:       None,
+        txdctl:       None,
+        srrctl:       None,
+        dmatxctl:     None,
+        fctrl:        None,
+        rxctrl:       None,
+        ctrl_ext:     None,
+        status:       Some(0x0008),
+        links:        None,
+        queue_stride: 0x100,
+        max_queues:   1,
+        mrqc:         None,
+        reta:         None,
+        rssrk:        None,
+        rlpml:        None,
+        max_frame:    4096,
+        rxcsum:       Some(0x5000),
+        throttle:     Some(ThrottleRegister {
+            reg: 0x00c4, unit: 256, shift: 0, width: 16,
+        }),
     };
     
-    /// The different (vendor, device IDs) we support
-    const HANDLED_DEVICES: &[(u16, u16, NicRegisters)] = &[
+    /// The different (vendor ID, device ID, name) we support
+    const HANDLED_DEVICES: &[(u16, u16, &str, NicRegisters)] = &[
         // 82540EM Gigabit Ethernet Controller "e1000"
-        (0x8086, 0x100e, E1000_REGS),
+        (0x8086, 0x100e,
+            "Intel 82540EM Gigabit Ethernet Controller", E1000_REGS),
 
         // 82574L Gigabit Network Connection "e1000e"
-        (0x8086, 0x10d3, E1000_REGS),
+        (0x8086, 0x10d3,
+            "Intel 82574L Gigabit Network Connection", E1000_REGS),
 
         // I210 Gigabit Network Connection
-        (0x8086, 0x1533, NicRegisters {
-            ctrl:     0x0000,
-            imc:      0x00d8,
-            rdbal:    0x2800,
-            rdbah:    0x2804,
-            rdlen:    0x2808,
-            rdh:      0x2810,
-            rdt:      0x2818,
-            tdbal:    0x3800,
-            tdbah:    0x3804,
-            tdlen:    0x3808,
-            tdh:      0x3810,
-            tdt:      0x3818,
-            ral0:     0x5400,
-            rah0:     0x5404,
-            rctl:     Some(0x0100),
-            tctl:     Some(0x0400),
-            rxdctl:   Some(0x2828),
-            txdctl:   Some(0x3828),
-            srrctl:   None,
-            dmatxctl: None,
-            fctrl:    None,
-            rxctrl:   None,
-            ctrl_ext: Some(0x0018),
+        (0x8086, 0x1533,
+            "Intel I210 Gigabit Network Connection", NicRegisters {
+            ctrl:         0x0000,
+            imc:          0x00d8,
+            ims:          Some(0x00d0),
+            icr:          0x00c0,
+            rdbal:        0x2800,
+            rdbah:        0x2804,
+            rdlen:        0x2808,
+            rdh:          0x2810,
+            rdt:          0x2818,
+            tdbal:        0x3800,
+            tdbah:        0x3804,
+            tdlen:        0x3808,
+            tdh:          0x3810,
+            tdt:          0x3818,
+            ral0:         0x5400,
+            rah0:         0x5404,
+            rctl:         Some(0x0100),
+            tctl:         Some(0x0400),
+            rxdctl:       Some(0x2828),
+            txdctl:       Some(0x3828),
+            srrctl:       None,
+            dmatxctl:     None,
+            fctrl:        None,
+            rxctrl:       None,
+            ctrl_ext:     Some(0x0018),
+            status:       Some(0x0008),
+            links:        None,
+            queue_stride: 0x100,
+            max_queues:   4,
+            mrqc:         Some((0x5818, 2)),
+            reta:         Some(0x5c00),
+            rssrk:        Some(0x5c80),
+            rlpml:        Some(0x5004),
+            max_frame:    2048,
+            rxcsum:       Some(0x5000),
+            throttle:     Some(ThrottleRegister {
+                reg: 0x1680, unit: 1000, shift: 2, width: 13,
+            }),
         }),
         
         // I350 Gigabit Network Connection
-        (0x8086, 0x1521, NicRegisters {
-            ctrl:     0x0000,
-            imc:      0x00d8,
-            rdbal:    0x2800,
-            rdbah:    0x2804,
-            rdlen:    0x2808,
-            rdh:      0x2810,
-            rdt:      0x2818,
-            tdbal:    0x3800,
-            tdbah:    0x3804,
-            tdlen:    0x3808,
-            tdh:      0x3810,
-            tdt:      0x3818,
-            ral0:     0x5400,
-            rah0:     0x5404,
-            rctl:     Some(0x0100),
-            tctl:     Some(0x0400),
-            rxdctl:   Some(0x2828),
-            txdctl:   Some(0x3828),
-            srrctl:   Some(0xc00c),
-            dmatxctl: None,
-            fctrl:    None,
-            rxctrl:   None,
-            ctrl_ext: Some(0x0018),
+        (0x8086, 0x1521,
+            "Intel I350 Gigabit Network Connection", NicRegisters {
+            ctrl:         0x0000,
+            imc:          0x00d8,
+            ims:          Some(0x00d0),
+            icr:          0x00c0,
+            rdbal:        0x2800,
+            rdbah:        0x2804,
+            rdlen:        0x2808,
+            rdh:          0x2810,
+            rdt:          0x2818,
+            tdbal:        0x3800,
+            tdbah:        0x3804,
+            tdlen:        0x3808,
+            tdh:          0x3810,
+            tdt:          0x3818,
+            ral0:         0x5400,
+            rah0:         0x5404,
+            rctl:         Some(0x0100),
+            tctl:         Some(0x0400),
+            rxdctl:       Some(0x2828),
+            txdctl:       Some(0x3828),
+            srrctl:       Some(0x280c),
+            dmatxctl:     None,
+            fctrl:        None,
+            rxctrl:       None,
+            ctrl_ext:     Some(0x0018),
+            status:       Some(0x0008),
+            links:        None,
+            queue_stride: 0x100,
+            max_queues:   4,
+            mrqc:         Some((0x5818, 2)),
+            reta:         Some(0x5c00),
+            rssrk:        Some(0x5c80),
+            rlpml:        Some(0x5004),
+            max_frame:    4096,
+            rxcsum:       Some(0x5000),
+            throttle:     Some(ThrottleRegister {
+                reg: 0x1680, unit: 1000, shift: 2, width: 13,
+            }),
         }),
 
         // Ethernet Converged Network Adapter X540-T1
-        (0x8086, 0x1528, NicRegisters {
-            ctrl:     0x0000,
-            imc:      0x0888, // Technically the EIMC
-            rdbal:    0x1000,
-            rdbah:    0x1004,
-            rdlen:    0x1008,
-            rdh:      0x1010,
-            rdt:      0x1018,
-            tdbal:    0x6000,
-            tdbah:    0x6004,
-            tdlen:    0x6008,
-            tdh:      0x6010,
-            tdt:      0x6018,
-            ral0:     0xa200,
-            rah0:     0xa204,
-            rctl:     None,
-            tctl:     None,
-            rxdctl:   Some(0x1028),
-            txdctl:   Some(0x6028),
-            srrctl:   Some(0x1014),
-            dmatxctl: Some(0x4a80),
-            fctrl:    Some(0x5080),
-            rxctrl:   Some(0x3000),
-            ctrl_ext: Some(0x0018),
+        (0x8086, 0x1528,
+            "Intel Ethernet Converged Network Adapter X540-T1", NicRegisters {
+            ctrl:         0x0000,
+            imc:          0x0888, // Technically the EIMC
+            ims:          None,
+            icr:          0x0800, // Technically the EICR
+            rdbal:        0x1000,
+            rdbah:        0x1004,
+            rdlen:        0x1008,
+            rdh:          0x1010,
+            rdt:          0x1018,
+            tdbal:        0x6000,
+            tdbah:        0x6004,
+            tdlen:        0x6008,
+            tdh:          0x6010,
+            tdt:          0x6018,
+            ral0:         0xa200,
+            rah0:         0xa204,
+            rctl:         None,
+            tctl:         None,
+            rxdctl:       Some(0x1028),
+            txdctl:       Some(0x6028),
+            srrctl:       Some(0x1014),
+            dmatxctl:     Some(0x4a80),
+            fctrl:        Some(0x5080),
+            rxctrl:       Some(0x3000),
+            ctrl_ext:     Some(0x0018),
+            status:       None,
+            links:        Some(0x42a4),
+            queue_stride: 0x40,
+            max_queues:   16,
+            mrqc:         Some((0xec80, 1)),
+            reta:         Some(0xeb00),
+            rssrk:        Some(0xeb80),
+            rlpml:        None,
+            max_frame:    MAX_FRAME_SIZE,
+            rxcsum:       None,
+            throttle:     Some(ThrottleRegister {
+                reg: 0x0820, unit: 2000, shift: 3, width: 9,
+            }),
         })
     ];
 
+    // Get the configuration space of the device
+    let device = &probed.config;
+
     // Check if we can handle this device
-    for &(vid, did, regs) in HANDLED_DEVICES {
+    for &(vid, did, name, regs) in HANDLED_DEVICES {
         // Check if the VID:DID match what we support
-        if device.header.vendor_id == vid && device.header.device_id == did {
+        if device.matches(vid, did, None, None) {
             if device.bar0 == 2864185344 || device.bar0 == 2863136768 ||
                     device.bar0 == 2862612480 {
                 continue;
             }
 
+            // Map in the MMIO space of the NIC from BAR0
+            let mmio = unsafe { probed.map_bar(0) }
+                .expect("Failed to map in Intel NIC BAR0");
+
+            // Make sure the MMIO space is decoded and let the NIC DMA its
+            // descriptor rings and packets. We poll the NIC, so mask its
+            // legacy interrupt.
+            unsafe {
+                probed.enable().ok()?;
+                probed.set_intx_mask(true);
+            }
+
             // Create the new device
-            return Some(
-                NetDevice::new(Box::new(IntelGbit::new(*device, regs)))
-            );
+            let rx_descs = RX_RING_DESCS.load(Ordering::SeqCst);
+            let tx_descs = TX_RING_DESCS.load(Ordering::SeqCst);
+            let nic = NetDevice::new(Box::new(
+                IntelGbit::new(probed, mmio, name, regs, rx_descs, tx_descs)));
+
+            // Hook the legacy interrupt if we want it, it stays masked if
+            // that fails
+            if LEGACY_INTERRUPTS && regs.ims.is_some() {
+                match unsafe { intx::register_handler(probed, nic.clone()) } {
+                    Ok(_) => unsafe { probed.set_intx_mask(false) },
+                    Err(err) => {
+                        print!("{} | legacy interrupt unavailable: {:?}\n",
+                               name, err);
+                    }
+                }
+            }
+
+            return Some(nic);
         }
     }
 
@@ -236,6 +455,42 @@ pub fn probe(device: &PciDevice) -> Option<Arc<NetDevice>> {
     None
 }
 
+/// Get the offsets, from the start of `frame`, at which the NIC has to start
+/// summing the TCP or UDP checksum and insert it, as used by the `css` and
+/// `cso` fields of a legacy transmit descriptor. Returns `None` if `frame`
+/// is not an unfragmented IPv4 TCP or UDP frame.
+fn tx_checksum_offsets(frame: &[u8]) -> Option<(u8, u8)> {
+    // Only IPv4 has a checksum in the IP header we are not offloading
+    if frame.get(12..14)? != [0x08, 0x00] {
+        return None;
+    }
+
+    // Get the IP header length, and make sure this is the first and only
+    // fragment
+    let ihl = (*frame.get(ETH_HEADER_SIZE)? & 0xf) as usize * 4;
+    let fragment = u16::from_be_bytes(
+        [*frame.get(ETH_HEADER_SIZE + 6)?, *frame.get(ETH_HEADER_SIZE + 7)?]);
+    if ihl < 20 || (fragment & 0x3fff) != 0 {
+        return None;
+    }
+
+    // Get the offset of the checksum field in the transport header
+    let field = match *frame.get(ETH_HEADER_SIZE + 9)? {
+        6  => 16,
+        17 => 6,
+        _  => return None,
+    };
+
+    // Make sure the checksum field is within the frame
+    let css = ETH_HEADER_SIZE + ihl;
+    let cso = css + field;
+    if cso + 2 > frame.len() {
+        return None;
+    }
+
+    Some((css as u8, cso as u8))
+}
+
 /// Legacy receive descriptor
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
@@ -264,7 +519,7 @@ struct LegacyTxDesc {
 /// Transmit logic state
 struct TxState {
     /// Virtually mapped TX descriptors
-    descriptors: PhysContig<[LegacyTxDesc; NUM_TX_DESCS]>,
+    descriptors: DmaRegion<[LegacyTxDesc]>,
     
     /// Packets held by the transmit descriptors. When the descriptor is free
     /// these will be `None`
@@ -280,7 +535,7 @@ struct TxState {
 /// Receive logic state
 struct RxState {
     /// Virtually mapped RX descriptors
-    descriptors: PhysContig<[LegacyRxDesc; NUM_RX_DESCS]>,
+    descriptors: DmaRegion<[LegacyRxDesc]>,
 
     /// Receive buffers corresponding to their descriptors
     buffers: Vec<Packet>,
@@ -290,104 +545,24 @@ struct RxState {
     head: usize,
 }
 
-/// Intel gigabit network driver
-struct IntelGbit {
-    /// Per-NIC registers for the different registers we use
-    regs: NicRegisters,
-
-    /// Memory mapped I/O for this device
-    /// These devices map 128 KiB of memory
-    mmio: &'static mut [u32; 32 * 1024],
-
+/// Receive and transmit rings of one queue of the NIC
+struct Queue {
     /// Receive logic state
     rx_state: LockCell<RxState, LockInterrupts>,
 
-    /// Transmit logic state
+    /// Transmit logic state. Sent packets are also reclaimed from interrupt
+    /// context, thus this must disable interrupts while held.
     tx_state: LockCell<TxState, LockInterrupts>,
-
-    /// Free list of packets
-    packets: LockCell<Vec<Packet>, LockInterrupts>,
-
-    /// Mac address of this device
-    mac: [u8; 6],
 }
 
-impl<'a> IntelGbit {
-    fn new(device: PciDevice, regs: NicRegisters) -> Self {
-        // The BAR0 should be a memory bar
-        assert!((device.bar0 & 1) == 0,
-            "Intel NIC BAR0 was not a memory BAR");
-
-        // Get the type of memory bar
-        let bar_type = BarType::from((device.bar0 >> 1) & 3);
-
-        // Get the physical address of the memory for this NIC
-        let bar = match bar_type {
-            BarType::Bits32 => {
-                // 32-bit BAR is just in-place, mask off the BAR type bits
-                PhysAddr((device.bar0 & 0xffff_fff0) as u64)
-            },
-            BarType::Bits64 => {
-                // Compute the 64-bit BAR by grabing both BAR0 and BAR1
-                let low_bits = (device.bar0 & 0xffff_fff0) as u64;
-                PhysAddr(((device.bar1 as u64) << 32) | low_bits)
-            },
-        };
-
-        // Not sure if this can ever happen, but make sure the physical address
-        // is 4 KiB aligned
-        assert!((bar.0 & 0xfff) == 0, "Non-4 KiB aligned Intel NIC?!");
-
-        // Map in the physical MMIO space into uncacheable virtual memory
-        let mmio = {
-            // Get a virtual address capable of holding a 128 KiB mapping
-            let vaddr = alloc_virt_addr_4k(128 * 1024);
-            
-            // Get access to physical memory allocations
-            let mut pmem = crate::mm::PhysicalMemory;
-
-            // Get access to the current page table
-            let mut page_table = core!().boot_args.page_table.lock();
-            let page_table = page_table.as_mut().unwrap();
-
-            // Map in the 128 KiB of MMIO space into virtual memory based on
-            // the `vaddr` we allocated above
-            for paddr in (bar.0..bar.0.checked_add(128 * 1024).unwrap())
-                    .step_by(4096) {
-                // Compute the offset into MMIO space
-                let offset = paddr - bar.0;
-
-                unsafe {
-                    page_table.map_raw(&mut pmem, VirtAddr(vaddr.0 + offset),
-                                       PageType::Page4K,
-                                       paddr | PAGE_NX | PAGE_WRITE | 
-                                       PAGE_CACHE_DISABLE | PAGE_PRESENT)
-                        .expect("Failed to map in Intel NIC MMIO to \
-                                 virtual memory");
-                }
-            }
-
-            // Box up the MMIO space
-            unsafe {
-                &mut *(vaddr.0 as *mut [u32; 32 * 1024])
-            }
-        };
-
-        // Make sure that the descriptor tables fit on a single page. They're
-        // 16-byte entries thus we make sure that we never use more than 256
-        // entries per table.
-        // RX and TX descriptor tables must also be divisible by 8 for their
-        // number of entries as a require of the 128-byte cache line sizes
-        // implemented in the NIC.
-        assert!(NUM_RX_DESCS <= 256 && (NUM_RX_DESCS % 8) == 0 &&
-                NUM_RX_DESCS > 0 &&
-                NUM_TX_DESCS <= 256 && (NUM_TX_DESCS % 8) == 0 &&
-                NUM_TX_DESCS > 0,
-            "Invalid Intel NIC constant configuration");
-
+impl Queue {
+    /// Allocate the descriptor rings and receive buffers for a queue of
+    /// `device`, with `rx_descs` receive and `tx_descs` transmit descriptors
+    fn new(device: &ProbedDevice, rx_descs: usize, tx_descs: usize) -> Self {
         // Create the RX descriptors
-        let mut rx_descriptors =
-            PhysContig::new([LegacyRxDesc::default(); NUM_RX_DESCS]);
+        let mut rx_descriptors = device
+            .alloc_dma_slice(DMA_MASK, LegacyRxDesc::default(), rx_descs)
+            .expect("Failed to allocate RX descriptors");
 
         // Create the RX buffers
         let mut rx_buffers = Vec::new();
@@ -403,27 +578,110 @@ impl<'a> IntelGbit {
         }
         
         // Create the TX descriptors
-        let tx_descriptors =
-            PhysContig::new([LegacyTxDesc::default(); NUM_TX_DESCS]);
-        
-        // Create the NIC
-        let mut nic = IntelGbit {
-            regs,
-            mmio,
+        let tx_descriptors = device
+            .alloc_dma_slice(DMA_MASK, LegacyTxDesc::default(), tx_descs)
+            .expect("Failed to allocate TX descriptors");
+
+        Queue {
             rx_state: LockCell::new(RxState {
                 descriptors: rx_descriptors,
                 buffers:     rx_buffers,
                 head:        0,
             }),
-            tx_state: LockCell::new(TxState {
+            tx_state: LockCell::new_no_preempt(TxState {
                 descriptors: tx_descriptors,
                 head:        0,
                 tail:        0, 
-                buffers:     (0..NUM_TX_DESCS).map(|_| None).collect(),
+                buffers:     (0..tx_descs).map(|_| None).collect(),
             }),
-            packets: LockCell::new(
-                         Vec::with_capacity(NUM_TX_DESCS + NUM_RX_DESCS)),
-            mac: [0u8; 6],
+        }
+    }
+}
+
+/// Intel gigabit network driver
+struct IntelGbit {
+    /// Human-readable name of the model of this NIC
+    name: &'static str,
+
+    /// Location of the NIC on the PCI bus
+    addr: PciAddress,
+
+    /// Per-NIC registers for the different registers we use
+    regs: NicRegisters,
+
+    /// Memory mapped I/O for this device
+    mmio: RegisterBlock,
+
+    /// Receive and transmit queues. Received frames are spread across the
+    /// queues by RSS, and each core sends on its own queue.
+    queues: Vec<Queue>,
+
+    /// Free list of packets
+    packets: LockCell<Vec<Packet>, LockInterrupts>,
+
+    /// Mac address of this device
+    mac: [u8; 6],
+
+    /// Packet and byte counters
+    stats: NetCounters,
+
+    /// Current MTU of the NIC
+    mtu: AtomicU16,
+
+    /// Set if the NIC validates the checksums of received frames
+    rx_csum: AtomicBool,
+
+    /// Set if the NIC computes the TCP and UDP checksums of sent frames
+    tx_csum: AtomicBool,
+
+    /// Callback invoked when a receive queue runs low on descriptors, along
+    /// with the number of descriptors it is invoked below
+    low_rx: LockCell<Option<(usize, LowRxCallback)>, LockInterrupts>,
+
+    /// Sum of the error and RX starvation counters the last time the health
+    /// of the NIC was checked
+    health_errors: AtomicU64,
+}
+
+impl<'a> IntelGbit {
+    fn new(device: &ProbedDevice, mmio: RegisterBlock, name: &'static str,
+           regs: NicRegisters, rx_descs: usize, tx_descs: usize) -> Self {
+        // RX and TX descriptor tables must be divisible by 8 for their
+        // number of entries as a require of the 128-byte cache line sizes
+        // implemented in the NIC, see `set_ring_sizes`
+        assert!(rx_descs >= MIN_RING_DESCS && rx_descs <= MAX_RING_DESCS &&
+                rx_descs.is_power_of_two() &&
+                tx_descs >= MIN_RING_DESCS && tx_descs <= MAX_RING_DESCS &&
+                tx_descs.is_power_of_two(),
+            "Invalid Intel NIC ring configuration");
+
+        // Use a queue per core, up to the number of queues the NIC supports.
+        // ACPI has not been initialized yet, thus we have to parse the MADT
+        // ourselves to get the number of cores.
+        let num_queues = core::cmp::min(regs.max_queues,
+            unsafe { acpi::madt_core_count() as usize }).max(1);
+
+        // Create the queues
+        let queues: Vec<Queue> =
+            (0..num_queues).map(|_| Queue::new(device, rx_descs, tx_descs))
+            .collect();
+        
+        // Create the NIC
+        let mut nic = IntelGbit {
+            name,
+            addr: device.addr,
+            regs,
+            mmio,
+            queues,
+            packets: LockCell::new(Vec::with_capacity(
+                num_queues * (tx_descs + rx_descs))),
+            mac:     [0u8; 6],
+            stats:   NetCounters::default(),
+            mtu:     AtomicU16::new(DEFAULT_MTU),
+            rx_csum: AtomicBool::new(false),
+            tx_csum: AtomicBool::new(false),
+            low_rx:  LockCell::new(None),
+            health_errors: AtomicU64::new(0),
         };
 
         unsafe {
@@ -450,83 +708,100 @@ impl<'a> IntelGbit {
                 nic.write(ctrl_ext, 1 << 16);
             }
 
-            // Initialize the NIC for receive
-            {
-                let rx_state = nic.rx_state.lock();
+            if let Some(fctrl) = nic.regs.fctrl {
+                // Accept broadcast packets
+                nic.write(fctrl, 1 << 10);
+            }
+
+            // Initialize each queue for receive
+            for queue in 0..nic.queues.len() {
+                let rx_state = nic.queues[queue].rx_state.lock();
 
                 // Program the receive descriptor base
-                nic.write(nic.regs.rdbah,
+                nic.write(nic.queue_reg(nic.regs.rdbah, queue),
                     (rx_state.descriptors.phys_addr().0 >> 32) as u32); // high
-                nic.write(nic.regs.rdbal,
+                nic.write(nic.queue_reg(nic.regs.rdbal, queue),
                     (rx_state.descriptors.phys_addr().0 >>  0) as u32); // low
 
                 // Write in the size of the RX descriptor queue
                 let queue_size = core::mem::size_of_val(
                     &rx_state.descriptors[..]);
-                nic.write(nic.regs.rdlen, queue_size as u32);
+                nic.write(nic.queue_reg(nic.regs.rdlen, queue),
+                          queue_size as u32);
                 
-                if let Some(fctrl) = nic.regs.fctrl {
-                    // Accept broadcast packets
-                    nic.write(fctrl, 1 << 10);
-                }
-
                 if let Some(srrctl) = nic.regs.srrctl {
                     // Program the receive control
                     // Drop enable, legacy descriptors, 2 KiB packets
-                    nic.write(srrctl, (1 << 28) | (4 << 8) | (2 << 0));
+                    nic.write(nic.queue_reg(srrctl, queue),
+                              (1 << 28) | (4 << 8) | (2 << 0));
                 }
                 
                 if let Some(rxdctl) = nic.regs.rxdctl {
                     // Enable the RX queue
+                    let rxdctl = nic.queue_reg(rxdctl, queue);
                     nic.write(rxdctl, (1 << 25) | nic.read(rxdctl));
                     while (nic.read(rxdctl) & (1 << 25)) == 0 {}
                 }
                 
                 // Set the RX head and tail
-                nic.write(nic.regs.rdh, 0);
-                nic.write(nic.regs.rdt, rx_state.descriptors.len() as u32 - 1);
-            
-                if let Some(rxctrl) = nic.regs.rxctrl {
-                    // Enable receives by setting RXCTRL.RXEN
-                    nic.write(rxctrl, 1);
-                }
+                nic.write(nic.queue_reg(nic.regs.rdh, queue), 0);
+                nic.write(nic.queue_reg(nic.regs.rdt, queue),
+                          rx_state.descriptors.len() as u32 - 1);
+            }
 
-                if let Some(rctl) = nic.regs.rctl {
-                    // Strip ethernet CRC, 2 KiB RX buffers,
-                    // and accept broadcast packets, and enable RX
-                    nic.write(rctl, (1 << 26) | (1 << 15) | (1 << 1));
-                }
+            if nic.queues.len() > 1 {
+                // Spread received frames across the queues
+                nic.enable_rss();
             }
 
-            // Initialize the NIC for transmit
-            {
-                let tx_state = nic.tx_state.lock();
+            if let Some(rxctrl) = nic.regs.rxctrl {
+                // Enable receives by setting RXCTRL.RXEN
+                nic.write(rxctrl, 1);
+            }
+
+            if let Some(rctl) = nic.regs.rctl {
+                // Strip ethernet CRC, 2 KiB RX buffers,
+                // and accept broadcast packets, and enable RX
+                nic.write(rctl, (1 << 26) | (1 << 15) | (1 << 1));
+            }
+
+            // Initialize each queue for transmit
+            for queue in 0..nic.queues.len() {
+                let tx_state = nic.queues[queue].tx_state.lock();
 
                 // Program the transmit descriptor base
-                nic.write(nic.regs.tdbah,
+                nic.write(nic.queue_reg(nic.regs.tdbah, queue),
                     (tx_state.descriptors.phys_addr().0 >> 32) as u32); // high
-                nic.write(nic.regs.tdbal,
+                nic.write(nic.queue_reg(nic.regs.tdbal, queue),
                     (tx_state.descriptors.phys_addr().0 >>  0) as u32); // low
 
                 // Write in the size of the TX descriptor queue
                 let queue_size = core::mem::size_of_val(
                     &tx_state.descriptors[..]);
-                nic.write(nic.regs.tdlen, queue_size as u32);
+                nic.write(nic.queue_reg(nic.regs.tdlen, queue),
+                          queue_size as u32);
                 
                 if let Some(txdctl) = nic.regs.txdctl {
                     // Enable the TX queue
+                    let txdctl = nic.queue_reg(txdctl, queue);
                     nic.write(txdctl, (1 << 25) | nic.read(txdctl));
                     while (nic.read(txdctl) & (1 << 25)) == 0 {}
                 }
  
                 // Set the TX head and tail
-                nic.write(nic.regs.tdh, 0);
-                nic.write(nic.regs.tdt, 0);
+                nic.write(nic.queue_reg(nic.regs.tdh, queue), 0);
+                nic.write(nic.queue_reg(nic.regs.tdt, queue), 0);
+            }
 
-                if let Some(tctl) = nic.regs.tctl {
-                    // Enable TX
-                    nic.write(tctl, 1 << 1);
-                }
+            if let Some(tctl) = nic.regs.tctl {
+                // Enable TX
+                nic.write(tctl, 1 << 1);
+            }
+
+            // Interrupt on transmit descriptor write backs. The interrupt
+            // only gets anywhere once `probe` unmasks the INTx of the NIC.
+            if let (true, Some(ims)) = (LEGACY_INTERRUPTS, nic.regs.ims) {
+                nic.write(ims, 1 << 0);
             }
 
             // Read the receive address high and low for the first entry
@@ -544,49 +819,549 @@ impl<'a> IntelGbit {
         nic
     }
 
+    /// Program RSS to distribute received frames across all of our queues
+    /// based on a hash of their IPv4/IPv6 addresses and TCP ports
+    unsafe fn enable_rss(&self) {
+        /// The default Toeplitz hash key from the Microsoft RSS
+        /// specification
+        const RSS_KEY: [u32; 10] = [
+            0xda565a6d, 0xc20e5b25, 0x3d256741, 0xb08fa343, 0xcb2bcad0,
+            0xb4307bae, 0xa32dcb77, 0x0cf23080, 0x3bb7426a, 0xfa01acbe,
+        ];
+
+        let (mrqc, enable) = self.regs.mrqc.unwrap();
+        let reta  = self.regs.reta.unwrap();
+        let rssrk = self.regs.rssrk.unwrap();
+
+        // Program the hash key
+        for (ii, &key) in RSS_KEY.iter().enumerate() {
+            self.write(rssrk + ii * 4, key);
+        }
+
+        // Fill the 128-entry redirection table round-robin with our queues
+        for ii in 0..32 {
+            let mut entries = 0u32;
+            for jj in 0..4 {
+                let queue = (ii * 4 + jj) % self.queues.len();
+                entries |= (queue as u32) << (jj * 8);
+            }
+            self.write(reta + ii * 4, entries);
+        }
+
+        // Enable RSS hashing on IPv4, IPv4 TCP, IPv6 and IPv6 TCP
+        self.write(mrqc, enable |
+                   (1 << 16) | (1 << 17) | (1 << 20) | (1 << 21));
+    }
+
+    /// Advance the head of `tx_state` past every packet the NIC has finished
+    /// sending, accounting for them in the stats. Returns the number of
+    /// packets reclaimed.
+    fn reclaim_tx(&self, tx_state: &mut TxState) -> usize {
+        let start = tx_state.head;
+        while tx_state.head != tx_state.tail {
+            // Get the status for the queued packet at the head
+            let head_idx = tx_state.head % tx_state.descriptors.len();
+            let desc = unsafe {
+                read_volatile(&tx_state.descriptors[head_idx])
+            };
+
+            // Check if the packet at the head has been sent by the NIC
+            if (desc.status & 1) == 0 {
+                break;
+            }
+
+            // Excess collisions, late collision, or underrun
+            if (desc.status & 0xe) != 0 {
+                self.stats.tx_error();
+            } else {
+                self.stats.tx(desc.len as usize);
+            }
+
+            tx_state.head += 1;
+        }
+
+        tx_state.head - start
+    }
+
+    /// Count the received frames waiting at the head of `rx_state`, which
+    /// belongs to `queue`. Frames received with errors at the head are
+    /// dropped.
+    unsafe fn rx_pending(&self, rx_state: &mut RxState, queue: usize)
+            -> usize {
+        // Drop errored frames until the head holds a good frame, or nothing
+        loop {
+            let status = read_volatile(&rx_state.descriptors[rx_state.head]
+                                       .status);
+            if (status & 1) == 0 {
+                return 0;
+            }
+
+            if self.rx_ready(rx_state, queue).is_some() {
+                break;
+            }
+        }
+
+        // Count the descriptors the NIC is done with, starting at the head
+        let len = rx_state.descriptors.len();
+        (0..len).take_while(|ii| {
+            let desc = &rx_state.descriptors[(rx_state.head + ii) % len];
+            (read_volatile(&desc.status) & 1) != 0
+        }).count()
+    }
+
+    /// Check if the descriptor at the head of `rx_state`, which belongs to
+    /// `queue`, holds a received frame. Frames received with errors are
+    /// dropped.
+    ///
+    /// Returns the length of the frame, which is left at the head.
+    unsafe fn rx_ready(&self, rx_state: &mut RxState, queue: usize)
+            -> Option<usize> {
+        let head = rx_state.head;
+
+        // Check if there is a packet that is ready to read
+        let status = read_volatile(&rx_state.descriptors[head].status);
+        if (status & 1) == 0 {
+            // No packet present
+            return None;
+        }
+
+        // Check for RX errors
+        let errors = read_volatile(&rx_state.descriptors[head].errors);
+        if errors != 0 {
+            // Drop the frame, giving the descriptor with the same buffer back
+            // to the NIC
+            let buffer = rx_state.buffers[head].phys_addr().0;
+            self.rx_recycle(rx_state, queue, buffer);
+
+            self.stats.rx_error();
+            return None;
+        }
+
+        // Get the length of the rxed buffer
+        Some(read_volatile(&rx_state.descriptors[head].len) as usize)
+    }
+
+    /// Give the descriptor at the head of `rx_state`, which belongs to
+    /// `queue`, back to the NIC to receive into `buffer`, and advance the head
+    unsafe fn rx_recycle(&self, rx_state: &mut RxState, queue: usize,
+                         buffer: u64) {
+        // Clear the status to put this descriptor back up for use
+        let head = rx_state.head;
+        write_volatile(&mut rx_state.descriptors[head],
+           LegacyRxDesc {
+               buffer,
+               ..Default::default()
+           });
+
+        // Let the NIC know this buffer is available for use again
+        self.write(self.queue_reg(self.regs.rdt, queue), head as u32);
+
+        // Bump the RX head
+        rx_state.head = (head + 1) % rx_state.descriptors.len();
+    }
+
+    /// Reclaim sent packets from `tx_state` and return `true` if there is room
+    /// to queue another packet
+    fn tx_has_room(&self, tx_state: &mut TxState) -> bool {
+        self.reclaim_tx(tx_state);
+
+        // Determine number of queued packets
+        let queued = tx_state.tail - tx_state.head;
+        queued < (tx_state.descriptors.len() - 1)
+    }
+
+    /// Queue `packet` for sending on `tx_state`, which belongs to `queue`.
+    /// There must be room for the packet in the ring.
+    ///
+    /// The NIC is notified of the new packet if `flush` is set or the ring is
+    /// now full.
+    fn enqueue_tx(&self, tx_state: &mut TxState, mut packet: Packet,
+                  queue: usize, flush: bool) {
+        // Set the packet length to 64 bytes minimum
+        if packet.len() < 64 {
+            // Zero out padding bytes
+            let pl = packet.len();
+            packet.raw[pl..64].iter_mut().for_each(|x| *x = 0);
+
+            // Set the length 64 bytes
+            packet.set_len(64);
+        }
+
+        // Get the index for the tail
+        let tail_idx = tx_state.tail % tx_state.descriptors.len();
+
+        // Have the NIC insert the TCP or UDP checksum, if we can
+        let (css, cso, ic) = if self.tx_csum.load(Ordering::Relaxed) {
+            tx_checksum_offsets(packet.raw())
+                .map(|(css, cso)| (css, cso, 1 << 2)).unwrap_or((0, 0, 0))
+        } else {
+            (0, 0, 0)
+        };
+
+        // Fill in the TX descriptor
+        tx_state.descriptors[tail_idx] =
+            LegacyTxDesc {
+                buffer: packet.phys_addr().0,
+                cmd: (1 << 3) | ic | (1 << 1) | (1 << 0),
+                len: packet.len() as u16,
+                css,
+                cso,
+                ..Default::default()
+            };
+ 
+        // Swap the new packet into the TX buffer list
+        let mut packet = Some(packet);
+        core::mem::swap(&mut packet, &mut tx_state.buffers[tail_idx]);
+
+        // Free the old packet, if we replaced an existing packet
+        if let Some(old_packet) = packet {
+            // Put the packet onto our free list
+            self.release_packet(old_packet);
+        }
+
+        // Increment the tail
+        tx_state.tail = tx_state.tail.wrapping_add(1);
+
+        if flush || (tx_state.tail - tx_state.head) ==
+                    (tx_state.descriptors.len() - 1) {
+            unsafe {
+                self.write(self.queue_reg(self.regs.tdt, queue),
+                    (tx_state.tail % tx_state.descriptors.len()) as u32);
+            }
+        }
+    }
+
+    /// Get the offset of the per-queue register `reg` for `queue`, where
+    /// `reg` is the offset of the register for queue 0
+    fn queue_reg(&self, reg: usize, queue: usize) -> usize {
+        reg + queue * self.regs.queue_stride
+    }
+
     /// Read from the MMIO Intel register at `reg_offset`. This is the offset
     /// into MMIO space in bytes, not the register ID
     unsafe fn read(&self, reg_offset: usize) -> u32 {
-        let reg = reg_offset / size_of::<u32>();
-        core::ptr::read_volatile(&self.mmio[reg])
+        self.mmio.read32(reg_offset)
     }
 
     /// Write `val` to the MMIO Intel register at `reg_offset`. This is the
     /// offset into MMIO space in bytes, not the register ID
     unsafe fn write(&self, reg_offset: usize, val: u32) {
-        let reg = reg_offset / size_of::<u32>();
-        core::ptr::write_volatile(
-            &self.mmio[reg] as *const u32 as *mut u32, val);
+        self.mmio.write32(reg_offset, val);
+    }
+}
+
+impl Drop for IntelGbit {
+    fn drop(&mut self) {
+        unsafe {
+            // Stop all DMA before the descriptor rings and packet buffers are
+            // freed out from under the NIC
+            NetDriver::suspend(self);
+            self.write(self.regs.imc, !0);
+        }
+
+        pci::driver_dropped(self.addr);
     }
 }
 
 impl NetDriver for IntelGbit {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn bdf(&self) -> Option<PciAddress> {
+        Some(self.addr)
+    }
+
+    fn dma_mask(&self) -> u64 {
+        DMA_MASK
+    }
+
     fn mac(&self) -> [u8; 6] {
         self.mac
     }
+
+    fn link_status(&self) -> Option<LinkStatus> {
+        unsafe {
+            if let Some(status) = self.regs.status {
+                // Gigabit parts report link up in bit 1 and the speed in
+                // bits 7:6 of the status register
+                let status = self.read(status);
+                let up     = (status & (1 << 1)) != 0;
+                let speed  = match (status >> 6) & 3 {
+                    0 => LinkSpeed::Mbps10,
+                    1 => LinkSpeed::Mbps100,
+                    _ => LinkSpeed::Mbps1000,
+                };
+
+                Some(LinkStatus {
+                    up,
+                    speed: if up { Some(speed) } else { None },
+                })
+            } else if let Some(links) = self.regs.links {
+                // 10 gigabit parts report link up in bit 30 and the speed in
+                // bits 29:28 of the link status register
+                let links = self.read(links);
+                let up    = (links & (1 << 30)) != 0;
+                let speed = match (links >> 28) & 3 {
+                    1 => Some(LinkSpeed::Mbps100),
+                    2 => Some(LinkSpeed::Mbps1000),
+                    3 => Some(LinkSpeed::Mbps10000),
+                    _ => None,
+                };
+
+                Some(LinkStatus { up, speed: speed.filter(|_| up) })
+            } else {
+                None
+            }
+        }
+    }
+
+    fn set_promiscuous(&self, enable: bool) {
+        // Hold the receive rings such that this doesn't race with `suspend`
+        // and `resume` which also modify the receive control register
+        let _rx_states: Vec<_> = self.queues.iter()
+            .map(|x| x.rx_state.lock()).collect();
+
+        unsafe {
+            // Unicast and multicast promiscuous enables are bits 3 and 4 of
+            // RCTL on gigabit parts and bits 9 and 8 of FCTRL on the x540
+            let (reg, bits) = if let Some(rctl) = self.regs.rctl {
+                (rctl, (1 << 3) | (1 << 4))
+            } else if let Some(fctrl) = self.regs.fctrl {
+                (fctrl, (1 << 9) | (1 << 8))
+            } else {
+                return;
+            };
+
+            if enable {
+                self.write(reg, self.read(reg) | bits);
+            } else {
+                self.write(reg, self.read(reg) & !bits);
+            }
+        }
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu.load(Ordering::SeqCst)
+    }
+
+    fn set_interrupt_throttle(&self, usecs: u16) -> Result<(), ()> {
+        let throttle = self.regs.throttle.ok_or(())?;
+
+        // Convert the interval to the units of the register, making sure it
+        // fits
+        let interval = usecs as u32 * 1000 / throttle.unit;
+        if interval >= (1 << throttle.width) {
+            return Err(());
+        }
+
+        // Program the interval of every queue we use
+        let mask = ((1 << throttle.width) - 1) << throttle.shift;
+        for queue in 0..self.queues.len() {
+            let reg = throttle.reg + queue * 4;
+            unsafe {
+                self.write(reg, (self.read(reg) & !mask) |
+                                (interval << throttle.shift));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_low_rx_callback(&self, threshold: usize,
+                           callback: Option<LowRxCallback>) {
+        *self.low_rx.lock() = callback.map(|callback| (threshold, callback));
+    }
+
+    fn checksum_offload_support(&self) -> ChecksumOffload {
+        ChecksumOffload {
+            rx: self.regs.rxcsum.is_some(),
+            tx: true,
+        }
+    }
+
+    fn checksum_offload(&self) -> ChecksumOffload {
+        ChecksumOffload {
+            rx: self.rx_csum.load(Ordering::SeqCst),
+            tx: self.tx_csum.load(Ordering::SeqCst),
+        }
+    }
+
+    fn set_checksum_offload(&self, rx: bool, tx: bool) -> Result<(), ()> {
+        if rx && self.regs.rxcsum.is_none() {
+            return Err(());
+        }
+
+        if let Some(rxcsum) = self.regs.rxcsum {
+            // IP and TCP/UDP checksum offload. Frames failing the checks
+            // have their error bits set and are dropped by `rx_ready`.
+            let offload = (1 << 8) | (1 << 9);
+            unsafe {
+                let old = self.read(rxcsum);
+                self.write(rxcsum,
+                           if rx { old | offload } else { old & !offload });
+            }
+        }
+
+        self.rx_csum.store(rx, Ordering::SeqCst);
+        self.tx_csum.store(tx, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_mtu(&self, mtu: u16) -> Result<(), ()> {
+        // Frames must fit in a single receive buffer
+        let frame = mtu as usize + ETH_HEADER_SIZE;
+        if mtu < MIN_MTU || frame > self.regs.max_frame {
+            return Err(());
+        }
+
+        // Hold the receive rings such that this doesn't race with other
+        // modifications of the receive control registers
+        let _rx_states: Vec<_> = self.queues.iter()
+            .map(|x| x.rx_state.lock()).collect();
+
+        // Frames over the standard size have to be enabled on gigabit parts,
+        // on the x540 we only support the standard size
+        if let Some(rctl) = self.regs.rctl {
+            let long   = frame > MAX_FRAME_SIZE;
+            let buf_4k = frame > 2048;
+
+            unsafe {
+                // Long packet enable, and clear the buffer size and buffer
+                // size extension back to 2 KiB buffers
+                let mut val = self.read(rctl) &
+                    !((1 << 5) | (3 << 16) | (1 << 25));
+                if long {
+                    val |= 1 << 5;
+                }
+
+                if let Some(srrctl) = self.regs.srrctl {
+                    // The buffer size is in 1 KiB units in SRRCTL
+                    // Drop enable, legacy descriptors
+                    for queue in 0..self.queues.len() {
+                        self.write(self.queue_reg(srrctl, queue),
+                            (1 << 28) | (4 << 8) | if buf_4k { 4 } else { 2 });
+                    }
+                } else if buf_4k {
+                    // 4 KiB buffers with the buffer size extension
+                    val |= (1 << 25) | (3 << 16);
+                }
+
+                self.write(rctl, val);
+
+                // The long packet limit includes the FCS
+                if let Some(rlpml) = self.regs.rlpml {
+                    self.write(rlpml, frame as u32 + 4);
+                }
+            }
+        }
+
+        self.mtu.store(mtu, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn self_test(&self) -> Result<(), SelfTestError> {
+        // Loopback is configured in RCTL, which the x540 doesn't have
+        let rctl = self.regs.rctl.ok_or(SelfTestError::Unsupported)?;
+
+        // Build a frame addressed from and to us, with the local
+        // experimental ethertype and a counting payload
+        let mut frame = [0u8; 64];
+        frame[0..6].copy_from_slice(&self.mac);
+        frame[6..12].copy_from_slice(&self.mac);
+        frame[12..14].copy_from_slice(&SELF_TEST_ETHTYPE.to_be_bytes());
+        for (ii, byte) in frame[14..].iter_mut().enumerate() {
+            *byte = ii as u8;
+        }
+
+        // Switch to MAC loopback, loopback mode is bits 7:6 of RCTL
+        let orig = unsafe { self.read(rctl) };
+        unsafe { self.write(rctl, (orig & !(3 << 6)) | (1 << 6)); }
+
+        let result = (|| {
+            self.try_send(&frame).map_err(SelfTestError::TxFailed)?;
+
+            // Wait for the frame to come back
+            let timeout = crate::time::future(100_000);
+            loop {
+                let mut result = None;
+                self.recv_with(&mut |rxed| {
+                    if rxed == &frame[..] {
+                        result = Some(Ok(()));
+                    } else if rxed.get(12..14) == Some(&frame[12..14]) {
+                        result = Some(Err(SelfTestError::Corrupted));
+                    }
+                });
+
+                if let Some(result) = result {
+                    return result;
+                }
+                if cpu::rdtsc() >= timeout {
+                    return Err(SelfTestError::Timeout);
+                }
+            }
+        })();
+
+        // Back to normal operation
+        unsafe { self.write(rctl, orig); }
+
+        result
+    }
+
+    fn stats(&self) -> NetStats {
+        self.stats.snapshot()
+    }
+
+    fn health(&self) -> DeviceHealth {
+        // A NIC which fell off the bus reads back all ones
+        if unsafe { self.read(self.regs.ctrl) } == !0 {
+            return DeviceHealth::Failed("not responding");
+        }
+
+        // Check for errors since the last time we looked, always updating
+        // the count so a one-off error only degrades us for one check
+        let stats  = self.stats.snapshot();
+        let errors = stats.rx_errors + stats.tx_errors + stats.rx_starved;
+        let new_errors =
+            self.health_errors.swap(errors, Ordering::Relaxed) != errors;
+
+        match self.link_status() {
+            Some(LinkStatus { up: false, .. }) => {
+                DeviceHealth::Degraded("link down")
+            }
+            _ if new_errors => {
+                DeviceHealth::Degraded("errors since last check")
+            }
+            _ => DeviceHealth::Healthy,
+        }
+    }
     
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
     fn recv<'a, 'b: 'a>(&'b self) -> Option<PacketLease<'a>> {
+        // RSS may have placed the frame on any queue, poll all of them
+        // starting with the one owned by this core
+        let start = core!().id as usize;
+        for ii in 0..self.queues.len() {
+            let queue = (start + ii) % self.queues.len();
+            if let Some(packet) = self.recv_queue(queue) {
+                return Some(packet);
+            }
+        }
+
+        None
+    }
+
+    fn recv_queue<'a, 'b: 'a>(&'b self, queue: usize)
+            -> Option<PacketLease<'a>> {
         // Get access to the RX state
-        let mut rx_state = self.rx_state.lock();
+        let mut rx_state = self.queues[queue].rx_state.lock();
 
         unsafe {
             // Check if there is a packet that is ready to read
-            let status = read_volatile(
-                &rx_state.descriptors[rx_state.head].status);
-            if (status & 1) == 0 {
-                // No packet present
-                return None;
-            }
-            
-            // Make sure there were no RX errors
-            let errors = read_volatile(
-                &rx_state.descriptors[rx_state.head].errors);
-            assert!(errors == 0);
-
-            // Get the length of the rxed buffer and copy into the caller
-            // supplied buffer
-            let rxed = read_volatile(
-                &rx_state.descriptors[rx_state.head].len) as usize;
+            let rxed = self.rx_ready(&mut rx_state, queue)?;
 
             // Allocate a new packet for this descriptor
             let mut packet = self.allocate_packet();
@@ -600,99 +1375,123 @@ impl NetDriver for IntelGbit {
             core::mem::swap(&mut packet,
                             &mut rx_state.buffers[head]);
 
-            // Clear the status to put this descriptor back up for use
-            write_volatile(&mut rx_state.descriptors[head],
-               LegacyRxDesc {
-                   buffer: new_packet_phys.0,
-                   ..Default::default()
-               });
-            
-            // Let the NIC know this buffer is available for use again
-            self.write(self.regs.rdt, rx_state.head as u32);
-
-            // Bump the RX head
-            rx_state.head = (rx_state.head + 1) % rx_state.descriptors.len();
-            
+            // Put this descriptor back up for use with the new packet
+            self.rx_recycle(&mut rx_state, queue, new_packet_phys.0);
+
             // Set the length of the packet
             packet.set_len(rxed);
+            self.stats.rx(rxed);
 
             // Return out a lease to this packet
             Some(PacketLease::new(self, packet))
         }
     }
-    
-    fn send(&self, mut packet: Packet, flush: bool) {
-        // Get access to the transmit state
-        let mut tx_state = self.tx_state.lock();
 
-        // Set the packet length to 64 bytes minimum
-        if packet.len() < 64 {
-            // Zero out padding bytes
-            let pl = packet.len();
-            packet.raw[pl..64].iter_mut().for_each(|x| *x = 0);
-
-            // Set the length 64 bytes
-            packet.set_len(64);
-        }
+    fn recv_with(&self, func: &mut dyn FnMut(&[u8])) -> bool {
+        // RSS may have placed the frame on any queue, poll all of them
+        // starting with the one owned by this core
+        let start = core!().id as usize;
+        for ii in 0..self.queues.len() {
+            let queue = (start + ii) % self.queues.len();
 
-        // Check for sent packets by the NIC
-        loop {
-            // Determine number of queued packets
-            let queued = tx_state.tail - tx_state.head;
-            if queued < (tx_state.descriptors.len() - 1) {
-                // Queue has room for our packet
-                break;
-            }
+            // Get access to the RX state
+            let mut rx_state = self.queues[queue].rx_state.lock();
 
-            // No room for the packet in the queue, update the head for each
-            // packet which was sent by the NIC previously.
-            for end in (tx_state.head..tx_state.tail).rev() {
-                // Get the status for the queued packet at the head
-                let head_idx = end % tx_state.descriptors.len();
-                let status = unsafe {
-                    read_volatile(&tx_state.descriptors[head_idx].status)
+            unsafe {
+                // Check if there is a packet that is ready to read
+                let rxed = if let Some(rxed) =
+                        self.rx_ready(&mut rx_state, queue) {
+                    rxed
+                } else {
+                    continue;
                 };
 
-                // Check if the packet at the head has been sent by the NIC
-                if (status & 1) != 0 {
-                    tx_state.head = end + 1;
-                    break;
-                }
+                // Hand the frame to the caller straight out of the DMA buffer
+                let head = rx_state.head;
+                rx_state.buffers[head].set_len(rxed);
+                func(rx_state.buffers[head].raw());
+                self.stats.rx(rxed);
+
+                // Give the same buffer back to the NIC
+                let buffer = rx_state.buffers[head].phys_addr().0;
+                self.rx_recycle(&mut rx_state, queue, buffer);
             }
+
+            return true;
         }
 
-        // Get the index for the tail
-        let tail_idx = tx_state.tail % tx_state.descriptors.len();
-        
-        // Fill in the TX descriptor
-        tx_state.descriptors[tail_idx] =
-            LegacyTxDesc {
-                buffer: packet.phys_addr().0,
-                cmd: (1 << 3) | (1 << 1) | (1 << 0),
-                len: packet.len() as u16,
-                ..Default::default()
-            };
- 
-        // Swap the new packet into the TX buffer list
-        let mut packet = Some(packet);
-        core::mem::swap(&mut packet, &mut tx_state.buffers[tail_idx]);
+        false
+    }
+    
+    fn send(&self, packet: Packet, flush: bool) {
+        // Send on the queue owned by this core
+        let queue = core!().id as usize % self.queues.len();
+        self.send_queue(packet, queue, flush);
+    }
 
-        // Free the old packet, if we replaced an existing packet
-        if let Some(old_packet) = packet {
-            // Put the packet onto our free list
-            self.release_packet(old_packet);
+    fn send_queue(&self, packet: Packet, queue: usize, flush: bool) {
+        // Get access to the transmit state
+        let mut tx_state = self.queues[queue].tx_state.lock();
+
+        // Check for sent packets by the NIC until there's room for ours
+        while !self.tx_has_room(&mut tx_state) {}
+
+        self.enqueue_tx(&mut tx_state, packet, queue, flush);
+    }
+
+    fn try_send(&self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.len() > self.mtu() as usize + ETH_HEADER_SIZE {
+            return Err(TxError::TooLarge);
         }
 
-        // Increment the tail
-        tx_state.tail = tx_state.tail.wrapping_add(1);
+        // Send on the queue owned by this core
+        let queue = core!().id as usize % self.queues.len();
+        let mut tx_state = self.queues[queue].tx_state.lock();
 
-        if flush || (tx_state.tail - tx_state.head) ==
-                    (tx_state.descriptors.len() - 1) {
-            unsafe {
-                self.write(self.regs.tdt,
-                    (tx_state.tail % tx_state.descriptors.len()) as u32);
+        // Check for sent packets by the NIC, and give up if that still
+        // didn't make room for this one
+        if !self.tx_has_room(&mut tx_state) {
+            return Err(TxError::Full);
+        }
+
+        // Copy the frame into a packet we can DMA from
+        let mut packet = self.allocate_packet();
+        packet.set_len(frame.len());
+        packet.raw_mut().copy_from_slice(frame);
+
+        self.enqueue_tx(&mut tx_state, packet, queue, true);
+        Ok(())
+    }
+
+    fn poll(&self) -> PollResult {
+        let mut result = PollResult::default();
+
+        for (ii, queue) in self.queues.iter().enumerate() {
+            // Reclaim sent packets
+            result.tx += self.reclaim_tx(&mut queue.tx_state.lock());
+
+            // Count frames ready to be received. Every descriptor holding
+            // a frame is one the NIC can't receive into, and it never gets
+            // the last one.
+            let mut rx_state = queue.rx_state.lock();
+            let pending = unsafe { self.rx_pending(&mut rx_state, ii) };
+            let free = (rx_state.descriptors.len() - 1).saturating_sub(pending);
+            result.rx += pending;
+
+            if free == 0 {
+                self.stats.rx_starved();
+            }
+
+            // Let the owner of the callback know we're running low
+            let low_rx = *self.low_rx.lock();
+            if let Some((threshold, callback)) = low_rx {
+                if free < threshold {
+                    callback(ii, free);
+                }
             }
         }
+
+        result
     }
 
     fn allocate_packet(&self) -> Packet {
@@ -711,19 +1510,95 @@ impl NetDriver for IntelGbit {
         }
     }
     
-    unsafe fn reset(&self) {
+    unsafe fn reset(&self) -> Result<(), PurgeError> {
         // Write all `f`s to the IMC to disable all interrupts
         self.write(self.regs.imc, !0);
 
         // Reset the NIC
         self.write(self.regs.ctrl, self.read(self.regs.ctrl) | (1 << 26));
 
-        // Wait for the reset to clear
-        while (self.read(self.regs.ctrl) & (1 << 26)) != 0 {}
+        // Wait for the reset to clear, unless the purge running us is overdue
+        let timeout = crate::time::future(100_000);
+        while (self.read(self.regs.ctrl) & (1 << 26)) != 0 {
+            if cpu::rdtsc() >= timeout || pci::purge_overdue() {
+                return Err(PurgeError::ResetTimeout);
+            }
+        }
         crate::time::sleep(20000);
 
         // Write all `f`s to the IMC to disable all interrupts
         self.write(self.regs.imc, !0);
+
+        Ok(())
+    }
+
+    unsafe fn handle_interrupt(&self) -> bool {
+        // Reading the cause acknowledges the interrupt, if there's no cause
+        // the interrupt wasn't from us
+        if self.read(self.regs.icr) == 0 {
+            return false;
+        }
+
+        // Account for completed transmits. Received frames stay in the rings
+        // until they are polled with `recv`. If a queue is busy, whoever
+        // holds it will reclaim its descriptors.
+        for queue in &self.queues {
+            if let Some(mut tx_state) = queue.tx_state.try_lock() {
+                self.reclaim_tx(&mut tx_state);
+            }
+        }
+
+        true
+    }
+
+    unsafe fn suspend(&self) {
+        // Hold all rings such that no one can queue or reap packets while
+        // the NIC is stopped
+        let _states: Vec<_> = self.queues.iter()
+            .map(|x| (x.rx_state.lock(), x.tx_state.lock())).collect();
+
+        // Give the NIC a chance to finish transmitting queued packets
+        let timeout = crate::time::future(100_000);
+        for queue in 0..self.queues.len() {
+            let tdh = self.queue_reg(self.regs.tdh, queue);
+            let tdt = self.queue_reg(self.regs.tdt, queue);
+            while self.read(tdh) != self.read(tdt) &&
+                    cpu::rdtsc() < timeout {}
+        }
+
+        // Stop the transmit and receive DMA engines. The descriptor rings
+        // and their head and tail pointers are left intact for `resume`.
+        if let Some(tctl) = self.regs.tctl {
+            self.write(tctl, self.read(tctl) & !(1 << 1));
+        }
+        if let Some(dmatxctl) = self.regs.dmatxctl {
+            self.write(dmatxctl, self.read(dmatxctl) & !1);
+        }
+        if let Some(rctl) = self.regs.rctl {
+            self.write(rctl, self.read(rctl) & !(1 << 1));
+        }
+        if let Some(rxctrl) = self.regs.rxctrl {
+            self.write(rxctrl, self.read(rxctrl) & !1);
+        }
+    }
+
+    unsafe fn resume(&self) {
+        let _states: Vec<_> = self.queues.iter()
+            .map(|x| (x.rx_state.lock(), x.tx_state.lock())).collect();
+
+        // Restart the receive and transmit DMA engines
+        if let Some(rxctrl) = self.regs.rxctrl {
+            self.write(rxctrl, self.read(rxctrl) | 1);
+        }
+        if let Some(rctl) = self.regs.rctl {
+            self.write(rctl, self.read(rctl) | (1 << 1));
+        }
+        if let Some(dmatxctl) = self.regs.dmatxctl {
+            self.write(dmatxctl, self.read(dmatxctl) | 1);
+        }
+        if let Some(tctl) = self.regs.tctl {
+            self.write(tctl, self.read(tctl) | (1 << 1));
+        }
     }
 }
 
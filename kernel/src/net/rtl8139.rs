@@ -0,0 +1,580 @@
+//! Driver for the Realtek RTL8139 Fast Ethernet controller
+
+use core::ptr::read_volatile;
+use core::sync::atomic::{fence, Ordering};
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::boxed::Box;
+
+use lockcell::LockCell;
+use page_table::PhysAddr;
+
+use crate::mm;
+use crate::net::{NetDriver, NetDevice, NetStats, NetCounters, Packet};
+use crate::net::{PacketLease, LinkStatus, LinkSpeed, TxError};
+use crate::pci::{self, Device, PurgeError, ProbedDevice, PciAddress};
+use crate::pci::pio::IoPort;
+use crate::pci::dma::DmaRegion;
+use crate::core_locals::LockInterrupts;
+
+/// Size of the receive ring, not including the 16 bytes of slack the NIC
+/// requires after it
+const RX_RING_SIZE: usize = 8 * 1024;
+
+/// Number of bytes to allocate for the receive ring. With `RCR.WRAP` set the
+/// NIC writes a packet which crosses the end of the ring past the end rather
+/// than wrapping it, thus we need room for one more full frame.
+const RX_ALLOC_SIZE: usize = RX_RING_SIZE + 16 + 2048;
+
+/// Number of transmit descriptors, this is fixed by the hardware
+const NUM_TX_DESCS: usize = 4;
+
+/// Size of each transmit buffer
+const TX_BUFFER_SIZE: usize = 2048;
+
+/// Largest frame we send or receive, not including the FCS
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// The NIC can only DMA to and from the low 4 GiB of physical memory
+const DMA_LIMIT: u64 = 1 << 32;
+
+/// ID registers, holding the MAC address
+const IDR0: u16 = 0x00;
+
+/// Multicast filter registers, 8 bytes of hash filter bits
+const MAR0: u16 = 0x08;
+
+/// Transmit status of descriptor 0, the others follow at 4 byte strides
+const TSD0: u16 = 0x10;
+
+/// Transmit start address of descriptor 0, the others follow at 4 byte
+/// strides
+const TSAD0: u16 = 0x20;
+
+/// Receive buffer start address
+const RBSTART: u16 = 0x30;
+
+/// Command register
+const CR: u16 = 0x37;
+
+/// Current address of packet read
+const CAPR: u16 = 0x38;
+
+/// Interrupt mask register
+const IMR: u16 = 0x3c;
+
+/// Interrupt status register
+const ISR: u16 = 0x3e;
+
+/// Transmit configuration register
+const TCR: u16 = 0x40;
+
+/// Receive configuration register
+const RCR: u16 = 0x44;
+
+/// Receive configuration bit to accept all physical address packets
+const RCR_AAP: u32 = 1 << 0;
+
+/// Receive configuration bit to accept multicast packets
+const RCR_AM: u32 = 1 << 2;
+
+/// Configuration register 1
+const CONFIG1: u16 = 0x52;
+
+/// Media status register
+const MSR: u16 = 0x58;
+
+/// Command register bit, receive buffer is empty
+const CR_BUFE: u8 = 1 << 0;
+
+/// Command register bit, transmitter enable
+const CR_TE: u8 = 1 << 2;
+
+/// Command register bit, receiver enable
+const CR_RE: u8 = 1 << 3;
+
+/// Command register bit, software reset
+const CR_RST: u8 = 1 << 4;
+
+/// Transmit status bit, the NIC is done with the buffer
+const TSD_OWN: u32 = 1 << 13;
+
+/// Transmit status bit, the packet was sent successfully
+const TSD_TOK: u32 = 1 << 15;
+
+/// Receive packet header status bit, packet received OK
+const RX_ROK: u16 = 1 << 0;
+
+/// PCI driver ID of this driver, this must never change as it is persisted
+/// across soft reboots
+const DRIVER_ID: u16 = 3;
+
+/// Register this driver with the PCI subsystem
+pub fn register() {
+    pci::register_id_driver(DRIVER_ID, &[(0x10ec, 0x8139)], probe);
+}
+
+/// Checks to see if the PCI device being probed is a device that we can handle
+/// with our driver
+pub fn probe(probed: &ProbedDevice) -> Option<Arc<dyn Device>> {
+    if !probed.config.matches(0x10ec, 0x8139, None, None) {
+        return None;
+    }
+
+    unsafe {
+        // The registers are accessed through the I/O BAR
+        let io = probed.io_bar(0)?;
+
+        // Make sure the I/O BAR is decoded and let the NIC DMA. We poll the
+        // NIC, so mask its legacy interrupt.
+        probed.set_io_space(true);
+        probed.set_bus_master(true).ok()?;
+        probed.set_intx_mask(true);
+
+        // Create the new device
+        let nic = Rtl8139::new(io, probed)?;
+        Some(NetDevice::new(Box::new(nic)))
+    }
+}
+
+/// Receive logic state
+struct RxState {
+    /// Offset into the receive ring of the next packet to read
+    offset: usize,
+}
+
+/// Transmit logic state
+struct TxState {
+    /// Index of the next transmit descriptor to use
+    next: usize,
+
+    /// Tracks which descriptors have been given to the NIC and may still be
+    /// in use
+    in_flight: [bool; NUM_TX_DESCS],
+}
+
+/// A Realtek RTL8139 NIC
+struct Rtl8139 {
+    /// I/O ports of the NIC registers
+    io: IoPort,
+
+    /// Location of the NIC on the PCI bus
+    addr: PciAddress,
+
+    /// Receive ring
+    rx_ring: DmaRegion<[u8]>,
+
+    /// Transmit buffers, `TX_BUFFER_SIZE` bytes for each descriptor. The NIC
+    /// can only address the low 4 GiB, thus packets are copied into these
+    /// before they are sent.
+    tx_buffers: DmaRegion<[u8]>,
+
+    /// Receive logic state
+    rx_state: LockCell<RxState, LockInterrupts>,
+
+    /// Transmit logic state
+    tx_state: LockCell<TxState, LockInterrupts>,
+
+    /// Free list of packets
+    packets: LockCell<Vec<Packet>, LockInterrupts>,
+
+    /// Mac address of this device
+    mac: [u8; 6],
+
+    /// Packet and byte counters
+    stats: NetCounters,
+}
+
+impl Rtl8139 {
+    /// Initialize the RTL8139 `probed` with registers at the I/O ports `io`
+    unsafe fn new(io: IoPort, probed: &ProbedDevice) -> Option<Self> {
+        // Allocate the DMA buffers
+        let rx_ring = probed.alloc_dma_slice(DMA_LIMIT - 1, 0u8,
+                                             RX_ALLOC_SIZE)?;
+        let tx_buffers = probed.alloc_dma_slice(
+            DMA_LIMIT - 1, 0u8, NUM_TX_DESCS * TX_BUFFER_SIZE)?;
+
+        let mut nic = Rtl8139 {
+            io,
+            addr: probed.addr,
+            rx_ring,
+            tx_buffers,
+            rx_state: LockCell::new(RxState { offset: 0 }),
+            tx_state: LockCell::new(TxState {
+                next:      0,
+                in_flight: [false; NUM_TX_DESCS],
+            }),
+            packets: LockCell::new(Vec::with_capacity(64)),
+            mac:     [0u8; 6],
+            stats:   NetCounters::default(),
+        };
+
+        // Power on the NIC
+        nic.write8(CONFIG1, 0);
+
+        // Reset the NIC and bring it up
+        nic.start().ok()?;
+
+        // Read the MAC address
+        let mut mac = [0u8; 6];
+        for (ii, byte) in mac.iter_mut().enumerate() {
+            *byte = nic.read8(IDR0 + ii as u16);
+        }
+
+        nic.mac = mac;
+
+        Some(nic)
+    }
+
+    /// Reset the NIC and set up the receiver and transmitter, the rings are
+    /// started over from their beginning
+    unsafe fn start(&self) -> Result<(), PurgeError> {
+        self.reset()?;
+
+        // Program the receive ring
+        self.write32(RBSTART, self.rx_ring.phys_addr().0 as u32);
+
+        // We poll the NIC, no interrupts please
+        self.write16(IMR, 0);
+
+        // Enable the receiver and transmitter
+        self.write8(CR, CR_RE | CR_TE);
+
+        // Standard inter-frame gap, unlimited DMA burst size
+        self.write32(TCR, (3 << 24) | (7 << 8));
+
+        // Accept broadcast and physical match packets, no wrapping in the
+        // receive ring, 8 KiB + 16 byte ring, unlimited DMA burst size
+        self.write32(RCR, (1 << 7) | (7 << 8) | (1 << 3) | (1 << 1));
+
+        Ok(())
+    }
+
+    /// Read the 8-bit register at `reg`
+    unsafe fn read8(&self, reg: u16) -> u8 {
+        self.io.read8(reg)
+    }
+
+    /// Read the 16-bit register at `reg`
+    unsafe fn read16(&self, reg: u16) -> u16 {
+        self.io.read16(reg)
+    }
+
+    /// Read the 32-bit register at `reg`
+    unsafe fn read32(&self, reg: u16) -> u32 {
+        self.io.read32(reg)
+    }
+
+    /// Write `val` to the 8-bit register at `reg`
+    unsafe fn write8(&self, reg: u16, val: u8) {
+        self.io.write8(reg, val);
+    }
+
+    /// Write `val` to the 16-bit register at `reg`
+    unsafe fn write16(&self, reg: u16, val: u16) {
+        self.io.write16(reg, val);
+    }
+
+    /// Write `val` to the 32-bit register at `reg`
+    unsafe fn write32(&self, reg: u16, val: u32) {
+        self.io.write32(reg, val);
+    }
+
+    /// Restart the receiver after it reported a bad packet, discarding
+    /// everything in the receive ring
+    unsafe fn restart_rx(&self, rx_state: &mut RxState) {
+        self.write8(CR, CR_TE);
+        self.write32(RBSTART, self.rx_ring.phys_addr().0 as u32);
+        self.write8(CR, CR_RE | CR_TE);
+        rx_state.offset = 0;
+    }
+
+    /// Get the physical address of the transmit buffer of descriptor `desc`
+    fn tx_buffer(&self, desc: usize) -> PhysAddr {
+        PhysAddr(self.tx_buffers.phys_addr().0 +
+                 (desc * TX_BUFFER_SIZE) as u64)
+    }
+
+    /// Check if the next descriptor of `tx_state` can be used, recording how
+    /// the previous transmit from it went if the NIC is done with it.
+    /// Returns `false` if the NIC is still sending from it.
+    fn tx_has_room(&self, tx_state: &mut TxState) -> bool {
+        let desc = tx_state.next;
+        if !tx_state.in_flight[desc] {
+            return true;
+        }
+
+        let status = unsafe { self.read32(TSD0 + desc as u16 * 4) };
+        if (status & TSD_OWN) == 0 {
+            return false;
+        }
+
+        if (status & TSD_TOK) != 0 {
+            self.stats.tx((status & 0x1fff) as usize);
+        } else {
+            self.stats.tx_error();
+        }
+
+        tx_state.in_flight[desc] = false;
+        true
+    }
+
+    /// Copy `packet` into the transmit buffer of the next descriptor of
+    /// `tx_state` and start sending it. The descriptor must have room, see
+    /// `tx_has_room`, and the packet must fit in a standard frame.
+    fn enqueue_tx(&self, tx_state: &mut TxState, mut packet: Packet) {
+        let desc = tx_state.next;
+
+        unsafe {
+            // Set the packet length to the minimum ethernet frame size
+            if packet.len() < 60 {
+                // Zero out padding bytes
+                let pl = packet.len();
+                packet.set_len(60);
+                packet.raw_mut()[pl..].iter_mut().for_each(|x| *x = 0);
+            }
+
+            // Copy the packet into the transmit buffer
+            let buffer = mm::slice_phys_mut(self.tx_buffer(desc),
+                                            TX_BUFFER_SIZE as u64);
+            buffer[..packet.len()].copy_from_slice(packet.raw());
+
+            // Make sure the packet is in memory before the NIC is told about
+            // it
+            fence(Ordering::SeqCst);
+
+            // Writing the size with `OWN` clear starts the transmit
+            let reg = desc as u16 * 4;
+            self.write32(TSAD0 + reg, self.tx_buffer(desc).0 as u32);
+            self.write32(TSD0  + reg, packet.len() as u32);
+        }
+
+        tx_state.in_flight[desc] = true;
+        tx_state.next = (desc + 1) % NUM_TX_DESCS;
+
+        // We're done with the packet as it was copied, put it back up for use
+        self.release_packet(packet);
+    }
+}
+
+impl Drop for Rtl8139 {
+    fn drop(&mut self) {
+        // Stop all DMA before the receive ring and transmit buffers are freed
+        // out from under the NIC
+        unsafe { let _ = NetDriver::reset(self); }
+
+        pci::driver_dropped(self.addr);
+    }
+}
+
+impl NetDriver for Rtl8139 {
+    fn name(&self) -> &str {
+        "Realtek RTL8139"
+    }
+
+    fn bdf(&self) -> Option<PciAddress> {
+        Some(self.addr)
+    }
+
+    fn dma_mask(&self) -> u64 {
+        DMA_LIMIT - 1
+    }
+
+    fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn link_status(&self) -> Option<LinkStatus> {
+        // The media status register reports link failure in bit 2 and 10
+        // Mbps operation in bit 3
+        let msr   = unsafe { self.read8(MSR) };
+        let up    = (msr & (1 << 2)) == 0;
+        let speed = if (msr & (1 << 3)) != 0 {
+            LinkSpeed::Mbps10
+        } else {
+            LinkSpeed::Mbps100
+        };
+
+        Some(LinkStatus { up, speed: if up { Some(speed) } else { None } })
+    }
+
+    fn set_promiscuous(&self, enable: bool) {
+        // Hold the receive ring such that the receiver isn't reconfigured
+        // while packets are being reaped
+        let _rx_state = self.rx_state.lock();
+
+        unsafe {
+            // Accept all physical address packets and all multicast packets
+            let bits = RCR_AAP | RCR_AM;
+            if enable {
+                // Let every multicast hash through the multicast filter
+                self.write32(MAR0,     !0);
+                self.write32(MAR0 + 4, !0);
+
+                self.write32(RCR, self.read32(RCR) | bits);
+            } else {
+                self.write32(RCR, self.read32(RCR) & !bits);
+            }
+        }
+    }
+
+    fn stats(&self) -> NetStats {
+        self.stats.snapshot()
+    }
+
+    fn recv<'a, 'b: 'a>(&'b self) -> Option<PacketLease<'a>> {
+        // Get access to the RX state
+        let mut rx_state = self.rx_state.lock();
+
+        unsafe {
+            // Acknowledge all events, this keeps the receiver running after
+            // an overflow
+            let isr = self.read16(ISR);
+            if isr != 0 {
+                self.write16(ISR, isr);
+            }
+
+            // Check if there is a packet that is ready to read
+            if (self.read8(CR) & CR_BUFE) != 0 {
+                return None;
+            }
+
+            // Don't read the ring before we've observed the packet
+            fence(Ordering::SeqCst);
+
+            // Get access to the receive ring
+            let ring = mm::slice_phys(self.rx_ring.phys_addr(),
+                                      RX_ALLOC_SIZE as u64);
+
+            // Parse the packet header, the length includes the FCS
+            let offset = rx_state.offset;
+            let status = read_volatile(
+                ring[offset..].as_ptr() as *const u16);
+            let length = read_volatile(
+                ring[offset + 2..].as_ptr() as *const u16) as usize;
+
+            // Make sure the packet is sane
+            if (status & RX_ROK) == 0 || length < 4 ||
+                    length - 4 > MAX_FRAME_SIZE {
+                self.restart_rx(&mut rx_state);
+                self.stats.rx_error();
+                return None;
+            }
+
+            // Copy the frame out of the ring, dropping the FCS
+            let mut packet = self.allocate_packet();
+            packet.set_len(length - 4);
+            packet.raw_mut().copy_from_slice(
+                &ring[offset + 4..offset + 4 + length - 4]);
+            self.stats.rx(packet.len());
+
+            // Advance to the next dword aligned packet in the ring
+            rx_state.offset = ((offset + length + 4 + 3) & !3) % RX_RING_SIZE;
+
+            // Let the NIC know we consumed the packet. The NIC expects the
+            // read pointer to be biased by 16 bytes.
+            self.write16(CAPR, (rx_state.offset as u16).wrapping_sub(16));
+
+            // Return out a lease to this packet
+            Some(PacketLease::new(self, packet))
+        }
+    }
+
+    fn send(&self, packet: Packet, _flush: bool) {
+        // We don't support jumbo frames, drop anything larger than a standard
+        // frame rather than overflowing the transmit buffer or truncating it
+        // into the size field of the descriptor
+        if packet.len() > MAX_FRAME_SIZE {
+            self.stats.tx_error();
+            self.release_packet(packet);
+            return;
+        }
+
+        // Get access to the transmit state
+        let mut tx_state = self.tx_state.lock();
+
+        // Wait for the NIC to be done with the next descriptor
+        while !self.tx_has_room(&mut tx_state) {}
+
+        self.enqueue_tx(&mut tx_state, packet);
+    }
+
+    fn try_send(&self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(TxError::TooLarge);
+        }
+
+        // Give up if the NIC is still sending from the next descriptor
+        let mut tx_state = self.tx_state.lock();
+        if !self.tx_has_room(&mut tx_state) {
+            return Err(TxError::Full);
+        }
+
+        // Copy the frame into a packet to queue
+        let mut packet = self.allocate_packet();
+        packet.set_len(frame.len());
+        packet.raw_mut().copy_from_slice(frame);
+
+        self.enqueue_tx(&mut tx_state, packet);
+        Ok(())
+    }
+
+    fn allocate_packet(&self) -> Packet {
+        self.packets.lock().pop().unwrap_or_else(|| Packet::new())
+    }
+
+    unsafe fn suspend(&self) {
+        // Hold the rings such that no one touches them while the NIC is
+        // stopped
+        let _rx_state = self.rx_state.lock();
+        let _tx_state = self.tx_state.lock();
+
+        // Resetting the NIC stops all DMA
+        let _ = self.reset();
+    }
+
+    unsafe fn resume(&self) {
+        let mut rx_state = self.rx_state.lock();
+        let mut tx_state = self.tx_state.lock();
+
+        // The reset in `suspend` cleared the setup and the ring positions of
+        // the NIC, start over from the beginning of the rings. The promiscuous
+        // setting is lost.
+        if self.start().is_err() {
+            print!("RTL8139 | failed to restart after suspend\n");
+        }
+        rx_state.offset    = 0;
+        tx_state.next      = 0;
+        tx_state.in_flight = [false; NUM_TX_DESCS];
+    }
+
+    fn release_packet(&self, packet: Packet) {
+        let mut packets = self.packets.lock();
+
+        // If we have room in our free list, push the packet into it.
+        // Otherwise, we'll just free the packet entirely, putting it back up
+        // for use for the whole system
+        if packets.len() < packets.capacity() {
+            // Put the packet back into the free list
+            packets.push(packet);
+        }
+    }
+
+    unsafe fn reset(&self) -> Result<(), PurgeError> {
+        // Disable all interrupts
+        self.write16(IMR, 0);
+
+        // Reset the NIC, this stops all DMA
+        self.write8(CR, CR_RST);
+
+        // Wait for the reset to clear, unless the purge running us is overdue
+        let timeout = crate::time::future(100_000);
+        while (self.read8(CR) & CR_RST) != 0 {
+            if cpu::rdtsc() >= timeout || pci::purge_overdue() {
+                return Err(PurgeError::ResetTimeout);
+            }
+        }
+
+        Ok(())
+    }
+}
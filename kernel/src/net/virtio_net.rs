@@ -0,0 +1,810 @@
+//! Driver for virtio network devices using the virtio 1.0 PCI transport
+
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::boxed::Box;
+
+use lockcell::LockCell;
+use page_table::{PhysAddr, VirtAddr};
+
+use crate::mm;
+use crate::net::{NetDriver, NetDevice, NetStats, NetCounters, Packet};
+use crate::net::{PacketLease, TxError};
+use crate::pci::{self, Bar, Device, DeviceHealth, PurgeError, ProbedDevice};
+use crate::pci::PciAddress;
+use crate::pci::capability::CAP_ID_VENDOR;
+use crate::pci::dma::DmaRegion;
+use crate::core_locals::LockInterrupts;
+
+/// Number of descriptors in each virtqueue
+///
+/// Every packet uses 2 descriptors, one for the virtio network header and one
+/// for the frame itself.
+const QUEUE_SIZE: usize = 256;
+
+/// Number of packets which can be in flight on each virtqueue
+const QUEUE_SLOTS: usize = QUEUE_SIZE / 2;
+
+/// Virtqueue index of the receive queue
+const RX_QUEUE: u16 = 0;
+
+/// Virtqueue index of the transmit queue
+const TX_QUEUE: u16 = 1;
+
+/// Largest frame we send or receive, not including the FCS
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// Capability configuration type for the common configuration
+const PCI_CAP_COMMON_CFG: u8 = 1;
+
+/// Capability configuration type for the queue notification area
+const PCI_CAP_NOTIFY_CFG: u8 = 2;
+
+/// Capability configuration type for the device specific configuration
+const PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// Device status bit, we have noticed the device
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+
+/// Device status bit, we know how to drive the device
+const STATUS_DRIVER: u8 = 1 << 1;
+
+/// Device status bit, we are ready to drive the device
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+
+/// Device status bit, feature negotiation is complete
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+
+/// Device status bit, we gave up on the device
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Feature bit, the device has a MAC address in its configuration
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// Feature bit, the device complies with virtio 1.0
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Descriptor flag, the descriptor continues via the `next` field
+const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
+
+/// Descriptor flag, the buffer is write-only for the device
+const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+
+/// Available ring flag, the device should not interrupt us
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+/// MSI-X vector value which disables interrupts for a queue
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// Offsets of registers in the common configuration structure
+mod common {
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    pub const DEVICE_FEATURE:        usize = 0x04;
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    pub const DRIVER_FEATURE:        usize = 0x0c;
+    pub const DEVICE_STATUS:         usize = 0x14;
+    pub const QUEUE_SELECT:          usize = 0x16;
+    pub const QUEUE_SIZE:            usize = 0x18;
+    pub const QUEUE_MSIX_VECTOR:     usize = 0x1a;
+    pub const QUEUE_ENABLE:          usize = 0x1c;
+    pub const QUEUE_NOTIFY_OFF:      usize = 0x1e;
+    pub const QUEUE_DESC:            usize = 0x20;
+    pub const QUEUE_DRIVER:          usize = 0x28;
+    pub const QUEUE_DEVICE:          usize = 0x30;
+}
+
+/// PCI driver ID of this driver, this must never change as it is persisted
+/// across soft reboots
+const DRIVER_ID: u16 = 2;
+
+/// The transitional and modern virtio network devices
+const HANDLED_DEVICES: &[(u16, u16)] = &[(0x1af4, 0x1000), (0x1af4, 0x1041)];
+
+/// Register this driver with the PCI subsystem
+pub fn register() {
+    pci::register_id_driver(DRIVER_ID, HANDLED_DEVICES, probe);
+}
+
+/// Checks to see if the PCI device being probed is a device that we can handle
+/// with our driver
+pub fn probe(probed: &ProbedDevice) -> Option<Arc<dyn Device>> {
+    let header = &probed.config.header;
+
+    // Check for the transitional or modern virtio network device
+    if !HANDLED_DEVICES.contains(&(header.vendor_id, header.device_id)) {
+        return None;
+    }
+
+    unsafe {
+        // Make sure the configuration structures are decoded and let the
+        // device DMA the virtqueues. We poll the device, so mask its legacy
+        // interrupt.
+        probed.enable().ok()?;
+        probed.set_intx_mask(true);
+
+        // Create the new device
+        let nic = VirtioNet::new(probed)?;
+        Some(NetDevice::new(Box::new(nic)))
+    }
+}
+
+/// A mapped virtio configuration structure
+#[derive(Clone, Copy)]
+struct Mmio(VirtAddr);
+
+impl Mmio {
+    /// Read the register at `offset` bytes into the structure
+    unsafe fn read<T>(&self, offset: usize) -> T {
+        read_volatile((self.0 .0 as usize + offset) as *const T)
+    }
+
+    /// Write `val` to the register at `offset` bytes into the structure
+    unsafe fn write<T>(&self, offset: usize, val: T) {
+        write_volatile((self.0 .0 as usize + offset) as *mut T, val);
+    }
+
+    /// Write a 64-bit register as two 32-bit halves, low half first
+    unsafe fn write_u64(&self, offset: usize, val: u64) {
+        self.write::<u32>(offset,     val as u32);
+        self.write::<u32>(offset + 4, (val >> 32) as u32);
+    }
+}
+
+/// Find the first virtio configuration structure of `cfg_type` and map it in.
+/// Returns the mapping and the configuration space offset of the capability
+/// which described it.
+unsafe fn map_cap(probed: &ProbedDevice, cfg_type: u8) -> Option<(Mmio, u8)> {
+    for (id, offset) in probed.capabilities() {
+        // virtio structures are described by vendor-specific capabilities
+        if id != CAP_ID_VENDOR {
+            continue;
+        }
+
+        // Check the configuration type
+        let cap = probed.read_config_u32(offset as u16);
+        if (cap >> 24) as u8 != cfg_type {
+            continue;
+        }
+
+        // Get the location of the structure
+        let bar    = probed.read_config_u32(offset as u16 + 4) as u8;
+        let start  = probed.read_config_u32(offset as u16 + 8) as u64;
+        let length = probed.read_config_u32(offset as u16 + 12) as u64;
+        if bar > 5 || length == 0 {
+            continue;
+        }
+
+        // The structure must be in a memory BAR
+        let base = match probed.config.bar(bar as usize) {
+            Some(Bar::Memory { addr, .. }) => addr,
+            _ => continue,
+        };
+
+        let vaddr = mm::map_mmio(PhysAddr(base.checked_add(start)?), length);
+        return Some((Mmio(vaddr), offset));
+    }
+
+    None
+}
+
+/// A virtqueue descriptor
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct VirtqDesc {
+    addr:  u64,
+    len:   u32,
+    flags: u16,
+    next:  u16,
+}
+
+/// The available ring of a virtqueue, written by us
+#[repr(C)]
+struct VirtqAvail {
+    flags:      u16,
+    idx:        u16,
+    ring:       [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+/// An entry in the used ring
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct VirtqUsedElem {
+    id:  u32,
+    len: u32,
+}
+
+/// The used ring of a virtqueue, written by the device
+#[repr(C)]
+struct VirtqUsed {
+    flags:       u16,
+    idx:         u16,
+    ring:        [VirtqUsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// Header which precedes every frame sent or received
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+struct VirtioNetHdr {
+    flags:       u8,
+    gso_type:    u8,
+    hdr_len:     u16,
+    gso_size:    u16,
+    csum_start:  u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+/// A split virtqueue
+///
+/// The queue is divided up into slots, where slot `n` uses descriptor `2n`
+/// for the network header and descriptor `2n + 1` for the frame.
+struct Virtqueue {
+    /// Index of this queue on the device
+    index: u16,
+
+    /// Address to write `index` to in order to notify the device of new
+    /// buffers
+    notify: VirtAddr,
+
+    /// Descriptor table
+    descs: DmaRegion<[VirtqDesc; QUEUE_SIZE]>,
+
+    /// Available ring
+    avail: DmaRegion<VirtqAvail>,
+
+    /// Used ring
+    used: DmaRegion<VirtqUsed>,
+
+    /// Network headers for each slot
+    headers: DmaRegion<[VirtioNetHdr; QUEUE_SLOTS]>,
+
+    /// Packets held by each slot. When the slot is free these will be `None`
+    buffers: Vec<Option<Packet>>,
+
+    /// Index into the used ring of the next entry we have not processed
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// Allocate a new virtqueue for queue `index` of `probed`. Returns `None`
+    /// if we're out of physical memory.
+    ///
+    /// The rings are allocated through the DMA interface such that they are
+    /// accounted to the device, and they are page aligned which satisfies
+    /// the alignment the split virtqueue layout requires.
+    fn new(probed: &ProbedDevice, index: u16, notify: VirtAddr)
+            -> Option<Self> {
+        Some(Virtqueue {
+            index,
+            notify,
+            descs: probed.alloc_dma([VirtqDesc::default(); QUEUE_SIZE])?,
+            avail: probed.alloc_dma(VirtqAvail {
+                flags:      VIRTQ_AVAIL_F_NO_INTERRUPT,
+                idx:        0,
+                ring:       [0; QUEUE_SIZE],
+                used_event: 0,
+            })?,
+            used: probed.alloc_dma(VirtqUsed {
+                flags:       0,
+                idx:         0,
+                ring:        [VirtqUsedElem::default(); QUEUE_SIZE],
+                avail_event: 0,
+            })?,
+            headers: probed.alloc_dma(
+                [VirtioNetHdr::default(); QUEUE_SLOTS])?,
+            buffers:   (0..QUEUE_SLOTS).map(|_| None).collect(),
+            last_used: 0,
+        })
+    }
+
+    /// Give `packet` to the device in `slot`. If `write` is set the device
+    /// fills the packet (receive), otherwise the device reads `len` bytes
+    /// from it (transmit).
+    ///
+    /// The packet must also be stored in `buffers` such it stays alive until
+    /// the device is done with it.
+    unsafe fn submit(&mut self, slot: usize, packet: &Packet, len: usize,
+                     write: bool) {
+        let flags = if write { VIRTQ_DESC_F_WRITE } else { 0 };
+
+        // Set up the descriptor chain for the header and the frame
+        let header = self.headers.phys_addr().0 +
+            (slot * size_of::<VirtioNetHdr>()) as u64;
+        self.descs[slot * 2] = VirtqDesc {
+            addr:  header,
+            len:   size_of::<VirtioNetHdr>() as u32,
+            flags: flags | VIRTQ_DESC_F_NEXT,
+            next:  (slot * 2 + 1) as u16,
+        };
+        self.descs[slot * 2 + 1] = VirtqDesc {
+            addr:  packet.phys_addr().0,
+            len:   len as u32,
+            flags: flags,
+            next:  0,
+        };
+
+        // Put the chain in the available ring
+        let idx = read_volatile(&self.avail.idx);
+        write_volatile(&mut self.avail.ring[idx as usize % QUEUE_SIZE],
+                       (slot * 2) as u16);
+
+        // Make sure the descriptors and ring entry are visible before the
+        // device can observe the new index
+        fence(Ordering::SeqCst);
+        write_volatile(&mut self.avail.idx, idx.wrapping_add(1));
+    }
+
+    /// Get the next slot the device is done with, along with the number of
+    /// bytes the device wrote to it
+    unsafe fn pop_used(&mut self) -> Option<(usize, usize)> {
+        // Check if the device has used any new buffers
+        if read_volatile(&self.used.idx) == self.last_used {
+            return None;
+        }
+
+        // Don't read the entry before we've observed the index
+        fence(Ordering::SeqCst);
+        let elem = read_volatile(
+            &self.used.ring[self.last_used as usize % QUEUE_SIZE]);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        Some(((elem.id / 2) as usize, elem.len as usize))
+    }
+
+    /// Clear the rings for the device to start over with an empty queue
+    /// after a reset. The packets in `buffers` are left in place.
+    unsafe fn clear(&mut self) {
+        *self.descs = [VirtqDesc::default(); QUEUE_SIZE];
+        write_volatile(&mut self.avail.idx, 0);
+        write_volatile(&mut self.used.idx, 0);
+        self.last_used = 0;
+    }
+
+    /// Notify the device that there are new available buffers
+    unsafe fn notify(&self) {
+        fence(Ordering::SeqCst);
+        write_volatile(self.notify.0 as *mut u16, self.index);
+    }
+}
+
+/// Transmit logic state
+struct TxState {
+    /// Transmit virtqueue
+    queue: Virtqueue,
+
+    /// Slots which are not in use by the device
+    free: Vec<usize>,
+}
+
+/// A virtio network device
+struct VirtioNet {
+    /// Location of the device on the PCI bus
+    addr: PciAddress,
+
+    /// Common configuration structure
+    common: Mmio,
+
+    /// Receive virtqueue
+    rx_state: LockCell<Virtqueue, LockInterrupts>,
+
+    /// Transmit logic state
+    tx_state: LockCell<TxState, LockInterrupts>,
+
+    /// Free list of packets
+    packets: LockCell<Vec<Packet>, LockInterrupts>,
+
+    /// Mac address of this device
+    mac: [u8; 6],
+
+    /// Packet and byte counters
+    stats: NetCounters,
+
+    /// Set while the virtqueues are set up on the device. Cleared by
+    /// `suspend` as it resets the device, until `resume` sets it up again.
+    running: AtomicBool,
+}
+
+impl VirtioNet {
+    /// Initialize the virtio network device `probed`. Returns `None` if the
+    /// device does not support the features we require.
+    unsafe fn new(probed: &ProbedDevice) -> Option<Self> {
+        // Map in the configuration structures
+        let (common, _) = map_cap(probed, PCI_CAP_COMMON_CFG)?;
+        let (device, _) = map_cap(probed, PCI_CAP_DEVICE_CFG)?;
+        let (notify, notify_cap) = map_cap(probed, PCI_CAP_NOTIFY_CFG)?;
+        let notify_mul = probed.read_config_u32(notify_cap as u16 + 16);
+
+        // Agree on the features we use
+        let mut status = Self::negotiate(common)?;
+
+        // Create the virtqueues
+        let queues =
+            Self::setup_queue(probed, common, notify, notify_mul, RX_QUEUE)
+            .and_then(|rx| {
+                Some((rx, Self::setup_queue(probed, common, notify,
+                                            notify_mul, TX_QUEUE)?))
+            });
+        let (mut rx_queue, tx_queue) = match queues {
+            Some(queues) => queues,
+            None => {
+                common.write::<u8>(common::DEVICE_STATUS, STATUS_FAILED);
+                return None;
+            }
+        };
+
+        // Fill the receive queue with buffers
+        for slot in 0..QUEUE_SLOTS {
+            let packet = Packet::new();
+            rx_queue.submit(slot, &packet, MAX_FRAME_SIZE, true);
+            rx_queue.buffers[slot] = Some(packet);
+        }
+
+        // Read the MAC address
+        let mut mac = [0u8; 6];
+        for (ii, byte) in mac.iter_mut().enumerate() {
+            *byte = device.read::<u8>(ii);
+        }
+
+        // The device is ready to go!
+        status |= STATUS_DRIVER_OK;
+        common.write::<u8>(common::DEVICE_STATUS, status);
+
+        // Let the device know about the receive buffers
+        rx_queue.notify();
+
+        Some(VirtioNet {
+            addr: probed.addr,
+            common,
+            rx_state: LockCell::new(rx_queue),
+            tx_state: LockCell::new(TxState {
+                queue: tx_queue,
+                free:  (0..QUEUE_SLOTS).collect(),
+            }),
+            packets: LockCell::new(
+                         Vec::with_capacity(QUEUE_SLOTS * 2)),
+            mac,
+            stats: NetCounters::default(),
+            running: AtomicBool::new(true),
+        })
+    }
+
+    /// Reset the device and negotiate the features we require. Returns the
+    /// device status to continue the initialization with, or `None` if the
+    /// device does not support our features, in which case it's marked as
+    /// failed.
+    unsafe fn negotiate(common: Mmio) -> Option<u8> {
+        // Reset the device
+        common.write::<u8>(common::DEVICE_STATUS, 0);
+        while common.read::<u8>(common::DEVICE_STATUS) != 0 {}
+
+        // Let the device know we found it and that we can drive it
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        common.write::<u8>(common::DEVICE_STATUS, status);
+
+        // Get the features the device offers
+        common.write::<u32>(common::DEVICE_FEATURE_SELECT, 0);
+        let mut features = common.read::<u32>(common::DEVICE_FEATURE) as u64;
+        common.write::<u32>(common::DEVICE_FEATURE_SELECT, 1);
+        features |= (common.read::<u32>(common::DEVICE_FEATURE) as u64) << 32;
+
+        // Give up if the device does not have the features we require
+        let required = VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC;
+        if (features & required) != required {
+            common.write::<u8>(common::DEVICE_STATUS, STATUS_FAILED);
+            return None;
+        }
+
+        // Accept only the features we require
+        common.write::<u32>(common::DRIVER_FEATURE_SELECT, 0);
+        common.write::<u32>(common::DRIVER_FEATURE, required as u32);
+        common.write::<u32>(common::DRIVER_FEATURE_SELECT, 1);
+        common.write::<u32>(common::DRIVER_FEATURE, (required >> 32) as u32);
+
+        // Make sure the device is happy with our features
+        status |= STATUS_FEATURES_OK;
+        common.write::<u8>(common::DEVICE_STATUS, status);
+        if (common.read::<u8>(common::DEVICE_STATUS) &
+                STATUS_FEATURES_OK) == 0 {
+            common.write::<u8>(common::DEVICE_STATUS, STATUS_FAILED);
+            return None;
+        }
+
+        Some(status)
+    }
+
+    /// Allocate and register virtqueue `index` with the device. Returns
+    /// `None` if the device doesn't support queues of `QUEUE_SIZE` entries,
+    /// or if the queue could not be allocated.
+    unsafe fn setup_queue(probed: &ProbedDevice, common: Mmio, notify: Mmio,
+                          notify_mul: u32, index: u16) -> Option<Virtqueue> {
+        common.write::<u16>(common::QUEUE_SELECT, index);
+
+        // Compute the notification address for this queue
+        let notify_off = common.read::<u16>(common::QUEUE_NOTIFY_OFF);
+        let notify = VirtAddr(notify.0 .0 +
+                              notify_off as u64 * notify_mul as u64);
+
+        // Allocate the queue and give it to the device
+        let queue = Virtqueue::new(probed, index, notify)?;
+        Self::enable_queue(common, &queue)?;
+
+        Some(queue)
+    }
+
+    /// Give the rings of `queue` to the device and enable it. Returns `None`
+    /// if the device doesn't support queues of `QUEUE_SIZE` entries.
+    unsafe fn enable_queue(common: Mmio, queue: &Virtqueue) -> Option<()> {
+        common.write::<u16>(common::QUEUE_SELECT, queue.index);
+
+        // Make sure the queue can be as large as we want
+        let max_size = common.read::<u16>(common::QUEUE_SIZE) as usize;
+        if max_size < QUEUE_SIZE {
+            return None;
+        }
+        common.write::<u16>(common::QUEUE_SIZE, QUEUE_SIZE as u16);
+
+        // We poll the queues, no interrupts please
+        common.write::<u16>(common::QUEUE_MSIX_VECTOR, VIRTIO_MSI_NO_VECTOR);
+
+        common.write_u64(common::QUEUE_DESC,   queue.descs.phys_addr().0);
+        common.write_u64(common::QUEUE_DRIVER, queue.avail.phys_addr().0);
+        common.write_u64(common::QUEUE_DEVICE, queue.used.phys_addr().0);
+        common.write::<u16>(common::QUEUE_ENABLE, 1);
+
+        Some(())
+    }
+
+    /// Reclaim all the transmit slots the device is done with. Returns `true`
+    /// if there is a free slot for a packet.
+    unsafe fn tx_has_room(&self, tx_state: &mut TxState) -> bool {
+        while let Some((slot, _)) = tx_state.queue.pop_used() {
+            if let Some(old_packet) = tx_state.queue.buffers[slot].take() {
+                self.stats.tx(old_packet.len());
+
+                // Put the packet onto our free list
+                self.release_packet(old_packet);
+            }
+            tx_state.free.push(slot);
+        }
+
+        !tx_state.free.is_empty()
+    }
+
+    /// Queue `packet` for sending in a free slot of `tx_state`, see
+    /// `tx_has_room`. The device is notified if `flush` is set or if this
+    /// used the last free slot.
+    unsafe fn enqueue_tx(&self, tx_state: &mut TxState, packet: Packet,
+                         flush: bool) {
+        // Queue the packet with an empty header, we don't use any offloads
+        let slot = tx_state.free.pop()
+            .expect("Queued a virtio transmit without a free slot");
+        tx_state.queue.headers[slot] = VirtioNetHdr::default();
+        tx_state.queue.submit(slot, &packet, packet.len(), false);
+        tx_state.queue.buffers[slot] = Some(packet);
+
+        if flush || tx_state.free.is_empty() {
+            tx_state.queue.notify();
+        }
+    }
+}
+
+impl Drop for VirtioNet {
+    fn drop(&mut self) {
+        // Stop all DMA before the virtqueues and the receive buffers are
+        // freed out from under the device
+        unsafe { let _ = NetDriver::reset(self); }
+
+        pci::driver_dropped(self.addr);
+    }
+}
+
+impl NetDriver for VirtioNet {
+    fn name(&self) -> &str {
+        "Virtio network device"
+    }
+
+    fn bdf(&self) -> Option<PciAddress> {
+        Some(self.addr)
+    }
+
+    fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn stats(&self) -> NetStats {
+        self.stats.snapshot()
+    }
+
+    fn recv<'a, 'b: 'a>(&'b self) -> Option<PacketLease<'a>> {
+        // Get access to the RX state
+        let mut rx_state = self.rx_state.lock();
+
+        // Nothing is received while the device is stopped
+        if !self.running.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        unsafe {
+            // Check if there is a packet that is ready to read
+            let (slot, len) = rx_state.pop_used()?;
+
+            // Allocate a new packet for this slot and swap it in place of the
+            // received packet
+            let new_packet = self.allocate_packet();
+            rx_state.submit(slot, &new_packet, MAX_FRAME_SIZE, true);
+            let mut packet = rx_state.buffers[slot].replace(new_packet)
+                .expect("Received into a virtio slot with no buffer");
+
+            // Let the device know this slot is available for use again
+            rx_state.notify();
+
+            // The length reported by the device includes the header
+            let rxed = len.saturating_sub(size_of::<VirtioNetHdr>());
+            packet.set_len(core::cmp::min(rxed, MAX_FRAME_SIZE));
+            self.stats.rx(packet.len());
+
+            // Return out a lease to this packet
+            Some(PacketLease::new(self, packet))
+        }
+    }
+
+    fn send(&self, packet: Packet, flush: bool) {
+        // Get access to the transmit state
+        let mut tx_state = self.tx_state.lock();
+
+        // The device never makes room while it's stopped, drop the packet
+        // rather than waiting forever
+        if !self.running.load(Ordering::SeqCst) {
+            self.stats.tx_error();
+            self.release_packet(packet);
+            return;
+        }
+
+        unsafe {
+            // Wait for room for our packet, making sure the device is working
+            // on the queue
+            while !self.tx_has_room(&mut tx_state) {
+                tx_state.queue.notify();
+            }
+
+            self.enqueue_tx(&mut tx_state, packet, flush);
+        }
+    }
+
+    fn try_send(&self, frame: &[u8]) -> Result<(), TxError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(TxError::TooLarge);
+        }
+
+        let mut tx_state = self.tx_state.lock();
+        if !self.running.load(Ordering::SeqCst) {
+            return Err(TxError::Full);
+        }
+
+        unsafe {
+            // Give up if the device still holds every slot, after making sure
+            // it's working on them
+            if !self.tx_has_room(&mut tx_state) {
+                tx_state.queue.notify();
+                return Err(TxError::Full);
+            }
+
+            // Copy the frame into a packet to queue
+            let mut packet = self.allocate_packet();
+            packet.set_len(frame.len());
+            packet.raw_mut().copy_from_slice(frame);
+
+            self.enqueue_tx(&mut tx_state, packet, true);
+        }
+
+        Ok(())
+    }
+
+    fn allocate_packet(&self) -> Packet {
+        self.packets.lock().pop().unwrap_or_else(|| Packet::new())
+    }
+
+    unsafe fn suspend(&self) {
+        // Hold the queues such that no one touches them while the device is
+        // stopped
+        let _rx_state = self.rx_state.lock();
+        let _tx_state = self.tx_state.lock();
+
+        // Virtio has no way of pausing a device, resetting it is the only way
+        // to stop its DMA. The reset drops the virtqueue setup, which
+        // `resume` has to redo.
+        self.running.store(false, Ordering::SeqCst);
+        let _ = self.reset();
+    }
+
+    unsafe fn resume(&self) {
+        let mut rx_state = self.rx_state.lock();
+        let mut tx_state = self.tx_state.lock();
+        if self.running.load(Ordering::SeqCst) {
+            return;
+        }
+
+        // Set the device up again with the virtqueues we already have,
+        // starting them over from empty. If it doesn't come back it stays
+        // stopped and reports as failed.
+        let mut status = if let Some(status) = Self::negotiate(self.common) {
+            status
+        } else {
+            return;
+        };
+        rx_state.clear();
+        tx_state.queue.clear();
+        if Self::enable_queue(self.common, &rx_state).is_none() ||
+                Self::enable_queue(self.common, &tx_state.queue).is_none() {
+            self.common.write::<u8>(common::DEVICE_STATUS, STATUS_FAILED);
+            return;
+        }
+
+        // Give the receive buffers back to the device
+        for slot in 0..QUEUE_SLOTS {
+            let packet = rx_state.buffers[slot].take()
+                .unwrap_or_else(|| self.allocate_packet());
+            rx_state.submit(slot, &packet, MAX_FRAME_SIZE, true);
+            rx_state.buffers[slot] = Some(packet);
+        }
+
+        // Frames which were queued for sending when we suspended are lost
+        for slot in 0..QUEUE_SLOTS {
+            if let Some(packet) = tx_state.queue.buffers[slot].take() {
+                self.stats.tx_error();
+                self.release_packet(packet);
+            }
+        }
+        tx_state.free = (0..QUEUE_SLOTS).collect();
+
+        // The device is ready to go again!
+        status |= STATUS_DRIVER_OK;
+        self.common.write::<u8>(common::DEVICE_STATUS, status);
+        rx_state.notify();
+
+        self.running.store(true, Ordering::SeqCst);
+    }
+
+    fn health(&self) -> DeviceHealth {
+        // We can only tell whether the device is stopped
+        if self.running.load(Ordering::SeqCst) {
+            DeviceHealth::Unknown
+        } else {
+            DeviceHealth::Failed("stopped")
+        }
+    }
+
+    fn release_packet(&self, packet: Packet) {
+        let mut packets = self.packets.lock();
+
+        // If we have room in our free list, push the packet into it.
+        // Otherwise, we'll just free the packet entirely, putting it back up
+        // for use for the whole system
+        if packets.len() < packets.capacity() {
+            // Put the packet back into the free list
+            packets.push(packet);
+        }
+    }
+
+    unsafe fn reset(&self) -> Result<(), PurgeError> {
+        // Writing a zero status resets the device, which stops all DMA to
+        // the virtqueues
+        self.common.write::<u8>(common::DEVICE_STATUS, 0);
+
+        // Wait for the reset to complete, unless the purge running us is
+        // overdue
+        let timeout = crate::time::future(100_000);
+        while self.common.read::<u8>(common::DEVICE_STATUS) != 0 {
+            if cpu::rdtsc() >= timeout || pci::purge_overdue() {
+                return Err(PurgeError::ResetTimeout);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -44,6 +44,12 @@ pub unsafe fn attempt_soft_reboot() {
         panic!("Halt requested from timer");
     }
 
+    // Check if we got a request to dump the PCI enumeration, we're in an
+    // interrupt thus it's done later by `pci::service_dump_request`
+    if let Some(b'P') = byte {
+        crate::pci::request_dump();
+    }
+
     // Check if we got a 'Z' from the serial port.
     if let Some(b'Z') = byte {
         // Request a soft reboot
@@ -110,7 +116,7 @@ pub unsafe fn soft_reboot(apic: &mut Apic) -> ! {
     apic.reset();
     
     // VMXOFF if we're in VMX root operation
-    let vmxon_lock = core!().vmxon_region().shatter();
+    let vmxon_lock = core!().vmxon_region().shatter_local();
     if (*vmxon_lock).is_some() {
         // Disable VMX root operation
         llvm_asm!("vmxoff" :::: "intel", "volatile");
@@ -192,7 +198,7 @@ pub fn panic(info: &PanicInfo) -> ! {
         let apic = unsafe {
             // Forcibly get access to the current APIC. This is likely safe in
             // almost every situation as the APIC is not very stateful.
-            let apic = &mut *core!().apic().shatter();
+            let apic = &mut *core!().apic().shatter_local();
             let apic = apic.as_mut().unwrap();
             
             // Disable all other cores, waiting for them to check-in notifying
@@ -221,7 +227,7 @@ pub fn panic(info: &PanicInfo) -> ! {
             unsafe {
                 // Forcibly get access to the current APIC. This is likely safe
                 // in almost every situation as the APIC is not very stateful.
-                let apic = &mut *core!().apic().shatter();
+                let apic = &mut *core!().apic().shatter_local();
                 let apic = apic.as_mut().unwrap();
 
                 // Notify the BSP that we paniced by sending it an NMI
@@ -1,21 +1,95 @@
 //! Handler for PCI-based devices
 
+pub mod ecam;
+pub mod mmio;
+pub mod pio;
+pub mod capability;
+pub mod config;
+pub mod sriov;
+pub mod irq;
+pub mod intx;
+pub mod iommu;
+pub mod dma;
+mod quirks;
+
 use core::any::Any;
+use core::convert::TryFrom;
+use core::fmt::Write;
 use core::mem::size_of;
+use core::ops::{Deref, RangeInclusive};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::{Ordering, spin_loop_hint};
 use alloc::vec::Vec;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use lockcell::LockCell;
+use page_table::PhysAddr;
+use boot_args::{PciClaim, PciClaims, PCI_NO_DRIVER};
+use boot_args::{PciConfig, PciConfigs, PCI_CONFIG_DWORDS};
 
+use crate::mm;
+use crate::acpi;
 use crate::net::NetDevice;
 use crate::core_locals::LockInterrupts;
+use crate::pci::capability::{Capability, CapabilityIter};
+use crate::pci::capability::{MsiCapability, CAP_ID_MSI};
+use crate::pci::capability::{MsiXCapability, CAP_ID_MSIX};
+use crate::pci::capability::{PmCapability, PowerState, CAP_ID_PM};
+use crate::pci::capability::{EaCapability, CAP_ID_EA};
+use crate::pci::capability::CAP_ID_PCIE;
+use crate::pci::capability::{ExtCapabilityIter, SriovCapability};
+use crate::pci::capability::{EXT_CAP_ID_SRIOV, EXT_CAP_ID_ARI};
+use crate::pci::capability::{AerStatus, EXT_CAP_ID_AER};
+use crate::pci::mmio::RegisterBlock;
+use crate::pci::pio::IoPort;
+use crate::pci::config::{ConfigAccess, HARDWARE};
+use crate::pci::config::{MockConfig, CONFIG_DWORDS};
+use crate::pci::dma::DmaRegion;
+
+/// If `true`, devices which no driver claims are put into D3hot during
+/// `init`. Bridges, memory controllers, system peripherals and display
+/// controllers are always left alone as firmware and the platform may still
+/// depend on them.
+const PARK_UNCLAIMED_DEVICES: bool = false;
+
+/// If `true`, the virtual functions of SR-IOV capable devices which a driver
+/// claimed are enabled and probed like any other device during `init`
+const ENABLE_SRIOV: bool = false;
+
+/// Largest power in milliwatts a device may report consuming in D0 for it to
+/// be handed to a driver, `u32::MAX` for no limit. See `set_power_budget`.
+static POWER_BUDGET: AtomicU32 = AtomicU32::new(!0);
 
-/// If `true` verbose PCI device enumeration will be displayed
+/// Refuse to bring up devices which report consuming more than `milliwatts`
+/// in D0 through their power management capability, `None` lifts the limit.
+/// Devices which don't report their power consumption are always allowed.
+pub fn set_power_budget(milliwatts: Option<u32>) {
+    POWER_BUDGET.store(milliwatts.unwrap_or(!0), Ordering::Relaxed);
+}
+
+/// Default for whether verbose PCI device enumeration will be displayed
 const DEBUG_PCI_DEVICES: bool = false;
 
+/// If `true` verbose PCI device enumeration will be displayed, this starts
+/// out as `DEBUG_PCI_DEVICES` and can be changed with `set_pci_debug`
+static PCI_DEBUG: AtomicBool = AtomicBool::new(DEBUG_PCI_DEVICES);
+
+/// Enable or disable verbose PCI device enumeration logging
+pub fn set_pci_debug(enable: bool) {
+    PCI_DEBUG.store(enable, Ordering::Relaxed);
+}
+
+/// Returns `true` if verbose PCI device enumeration logging is enabled
+fn pci_debug() -> bool {
+    PCI_DEBUG.load(Ordering::Relaxed)
+}
+
 /// Trait which allows for converting to an Any
 pub trait AsAny {
     fn as_any(&self) -> &(dyn Any + 'static);
     fn as_any_mut(&mut self) -> &mut (dyn Any + 'static);
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn Any + Send + Sync + 'static>;
 }
 
 /// Implement AsAny for any T that implements Plugin and 'static
@@ -29,11 +103,35 @@ impl<T: Device + 'static> AsAny for T {
     fn as_any_mut(&mut self) -> &mut (dyn Any + 'static) {
         self
     }
+
+    /// Convert an `Arc` of a Plugin to an `Arc` of an Any
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn Any + Send + Sync + 'static> {
+        self
+    }
 }
 
 /// An driver for a device. There are multiple instances of a driver for each
 /// device the driver handled during the probe process.
 pub trait Device: Send + Sync + AsAny {
+    /// Human-readable name of the device, used for debugging and logging
+    fn name(&self) -> &str;
+
+    /// Location of the device on the PCI bus, `None` if it's not a PCI device
+    fn bdf(&self) -> Option<PciAddress> {
+        None
+    }
+
+    /// Mask of the physical addresses the device can DMA to and from, memory
+    /// handed to the device must lie within it
+    fn dma_mask(&self) -> u64 {
+        !0
+    }
+
+    /// Get the network view of the device, `None` if it's not a NIC
+    fn as_net(&self) -> Option<&NetDevice> {
+        None
+    }
+
     /// Invoked on a device when we're doing a soft reboot. This may be called
     /// from an exceptionally hostile environment (eg. inside of a panic inside
     /// of an NMI exception). The goal of this function for a driver is to
@@ -49,11 +147,123 @@ pub trait Device: Send + Sync + AsAny {
     /// This will be invoked on the device regardless of locks, thus the
     /// device needs to be able to handle that a previous user of the device
     /// may have been interrupted mid-use.
-    unsafe fn purge(&self);
+    ///
+    /// If the device could not be brought into a safe state an error is
+    /// returned, such that the failure can at least be reported.
+    ///
+    /// Interrupts can't be relied on here, thus a purge which hangs can't be
+    /// stopped. Any loop waiting on the hardware must have a timeout, or
+    /// give up once `purge_overdue` returns `true`.
+    unsafe fn purge(&self) -> Result<(), PurgeError>;
+
+    /// Quiesce the device, stopping any DMA and interrupts such that it can
+    /// be safely left idle (eg. for a power transition). Unlike `purge`, this
+    /// is invoked from a sane environment and the driver may take its locks
+    /// and wait for in-flight work to complete.
+    unsafe fn suspend(&self) {}
+
+    /// Bring a device which was quiesced with `suspend` back into operation
+    unsafe fn resume(&self) {}
+
+    /// Invoked when an interrupt vector the device allocated with
+    /// `irq::allocate_vector` fires. Returns `true` if the device handled
+    /// the interrupt.
+    ///
+    /// This runs in interrupt context, thus only locks which disable
+    /// interrupts may be taken.
+    unsafe fn handle_interrupt(&self, _vector: u8) -> bool {
+        false
+    }
+
+    /// Invoked when the device has been removed from the system. The driver
+    /// must stop all DMA and drop any references to the device which it
+    /// handed out (eg. registrations with other subsystems), such that the
+    /// device can be dropped.
+    ///
+    /// The hardware may already be gone, thus register reads may return all
+    /// ones.
+    unsafe fn unplug(&self) {
+        self.suspend();
+    }
+
+    /// Order in which this device is purged during a soft reboot, relative to
+    /// other devices. Lower numbers are purged first, devices with the same
+    /// priority are purged in the order they were registered.
+    ///
+    /// For example, devices which sit in front of others in the DMA path
+    /// (eg. an IOMMU or a bridge) should have a higher number than the
+    /// devices behind them, such that they are torn down last, and devices
+    /// which DMA into buffers shared with others should have a lower number
+    /// such that their DMA is stopped first.
+    fn purge_priority(&self) -> u8 {
+        DEFAULT_PURGE_PRIORITY
+    }
+
+    /// Check whether the device is working, eg. for a monitoring loop going
+    /// over `with_devices` to flag degraded devices. This may be called
+    /// periodically from any core and should be cheap.
+    fn health(&self) -> DeviceHealth {
+        DeviceHealth::Unknown
+    }
+
+    /// Write a short description of the device to `out` to tell it apart
+    /// from other devices of the same driver in `dump_devices`, by default
+    /// its `name`. This may be called from a panic, thus it must not
+    /// allocate or take locks.
+    fn describe(&self, out: &mut dyn Write) -> core::fmt::Result {
+        out.write_str(self.name())
+    }
+}
+
+/// Health of a device, reported by `Device::health`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceHealth {
+    /// The driver doesn't check the health of the device
+    Unknown,
+
+    /// The device is working as expected
+    Healthy,
+
+    /// The device works, but something is wrong with it (eg. the link is
+    /// down or it reported errors)
+    Degraded(&'static str),
+
+    /// The device is no longer working
+    Failed(&'static str),
+}
+
+/// Purge priority for devices which don't care about their purge order
+pub const DEFAULT_PURGE_PRIORITY: u8 = 128;
+
+/// Reasons a device could not be purged
+///
+/// This is reported from `Device::purge`, which runs in a hostile
+/// environment, thus it must never require allocations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PurgeError {
+    /// The device did not complete a reset in time
+    ResetTimeout,
+
+    /// Driver-specific failure
+    Failed(&'static str),
+}
+
+/// Reasons PCI initialization failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciInitError {
+    /// The firmware described an ECAM region in the MCFG which we can't use
+    InvalidMcfg(&'static str),
+
+    /// There is no ECAM region for bus 0 and the legacy configuration ports
+    /// are missing
+    NoConfigMechanism,
+
+    /// Nothing responded on bus 0, not even a host bridge
+    NoHostBridge,
 }
 
 /// Different types for PCI BARs
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u32)]
 pub enum BarType {
     /// 32-bit BAR
@@ -63,25 +273,289 @@ pub enum BarType {
     Bits64 = 2,
 }
 
-impl From<u32> for BarType {
-    fn from(val: u32) -> Self {
+impl TryFrom<u32> for BarType {
+    /// The raw type field, if it's not a type we know about
+    type Error = u32;
+
+    fn try_from(val: u32) -> Result<Self, Self::Error> {
         match val {
-            0 => BarType::Bits32,
-            2 => BarType::Bits64,
-            _ => panic!("Invalid BAR type"),
+            0 => Ok(BarType::Bits32),
+            2 => Ok(BarType::Bits64),
+            _ => Err(val),
+        }
+    }
+}
+
+/// A decoded PCI BAR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    /// Memory-space BAR
+    Memory {
+        /// Physical base address of the region
+        addr: u64,
+
+        /// If set, reads from the region have no side effects and the region
+        /// may be mapped as prefetchable
+        prefetchable: bool,
+
+        /// Width of the BAR
+        ty: BarType,
+    },
+
+    /// I/O-space BAR
+    Io {
+        /// Base I/O port of the region
+        port: u16,
+    },
+}
+
+/// A decoded BAR of a device along with its size, see `ProbedDevice::bars`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizedBar {
+    /// Index of the BAR, the low half for 64-bit BARs
+    pub index: usize,
+
+    /// The decoded BAR
+    pub bar: Bar,
+
+    /// Size of the region decoded by the BAR, in bytes
+    pub size: u64,
+}
+
+/// Type used for PCI device probes to attempt to handle a device. Probes
+/// must access configuration space through `ProbedDevice::access`, such that
+/// they can be driven by a synthetic configuration space. If a probe returns
+/// `None` the command register is restored to what it was before the probe,
+/// thus probes may enable the device before deciding not to claim it.
+///
+/// Probes may return any kind of device. Code wanting a specific kind finds
+/// it through `find_device` and `for_each_as`, or accessors such as
+/// `Device::as_net`, rather than relying on what a probe returned.
+pub type ProbeFunction = fn(&ProbedDevice) -> Option<Arc<dyn Device>>;
+
+/// Type used for probes of drivers which expose several devices for one
+/// function, eg. a controller with multiple ports. The devices are all
+/// registered at the location of the function, returning no devices is
+/// treated like a `ProbeFunction` returning `None`.
+pub type MultiProbeFunction = fn(&ProbedDevice) -> Vec<Arc<dyn Device>>;
+
+/// Probe routine of a driver
+#[derive(Clone, Copy)]
+enum Probe {
+    /// Probe creating at most one device, see `ProbeFunction`
+    Single(ProbeFunction),
+
+    /// Probe creating any number of devices, see `MultiProbeFunction`
+    Multi(MultiProbeFunction),
+}
+
+impl Probe {
+    /// Run the probe against `device`, returning the devices it created
+    fn run(&self, device: &ProbedDevice) -> Vec<Arc<dyn Device>> {
+        match self {
+            Probe::Single(probe) => probe(device).into_iter().collect(),
+            Probe::Multi(probe)  => probe(device),
         }
     }
 }
 
-/// Type used for PCI device probes to attempt to handle a device
-type ProbeFunction = fn(&PciDevice) -> Option<Arc<NetDevice>>;
+/// A driver which can be bound to PCI devices
+#[derive(Clone, Copy)]
+struct Driver {
+    /// Unique ID of the driver. These are persisted across soft reboots to
+    /// remember which driver handled which device, thus IDs must never be
+    /// reused for a different driver, and must not be `PCI_NO_DRIVER`.
+    id: u16,
+
+    /// `(vendor ID, device ID)` pairs of the devices the driver handles, the
+    /// probe is only invoked for these. `None` probes every device.
+    ids: Option<&'static [(u16, u16)]>,
+
+    /// Probe routine for the driver
+    probe: Probe,
+}
+
+impl Driver {
+    /// Returns `true` if the probe of this driver wants to look at `device`
+    fn matches(&self, device: &ProbedDevice) -> bool {
+        let header = &device.config.header;
+        self.ids.map(|ids| ids.contains(&(header.vendor_id, header.device_id)))
+            .unwrap_or(true)
+    }
+}
+
+/// List of all driver probe routines on the system, in the order they were
+/// registered with `register_driver`. If they return `Some` then we
+/// successfully found a driver and thus we'll register it in the `DEVICES`
+/// database
+static DRIVERS: LockCell<Vec<Driver>, LockInterrupts> =
+    LockCell::new(Vec::new());
+
+/// A driver which is bound to PCI devices based on their class code rather
+/// than their vendor and device IDs, for devices which implement a standard
+/// interface
+#[derive(Clone, Copy)]
+struct ClassProbe {
+    /// Unique ID of the driver, these share the ID space of `Driver`
+    id: u16,
+
+    /// Class code of the devices handled by this driver
+    class: u8,
+
+    /// Subclass of the devices handled by this driver
+    subclass: u8,
+
+    /// Programming interface of the devices handled by this driver, `None`
+    /// to match any programming interface
+    prog_if: Option<u8>,
+
+    /// Probe routine for the driver
+    probe: ProbeFunction,
+}
+
+impl ClassProbe {
+    /// Returns `true` if `device` has a class code this driver is for
+    fn matches(&self, device: &ProbedDevice) -> bool {
+        device.config.class_code()
+            .matches(self.class, self.subclass, self.prog_if)
+    }
+}
+
+/// List of all drivers which are matched by class code, in the order they
+/// were registered with `register_class_driver`. These are only probed for a
+/// device if none of the drivers in `DRIVERS` claimed it.
+static CLASS_DRIVERS: LockCell<Vec<ClassProbe>, LockInterrupts> =
+    LockCell::new(Vec::new());
+
+/// Set once PCI initialization has started, after which the set of drivers
+/// can no longer change as its signature is persisted with the claims
+static DRIVERS_SEALED: AtomicBool = AtomicBool::new(false);
+
+/// Check that a new driver with `id` can be registered
+fn check_new_driver(id: u16) {
+    assert!(!DRIVERS_SEALED.load(Ordering::SeqCst),
+        "PCI driver registered after PCI initialization");
+    assert!(id != PCI_NO_DRIVER, "PCI driver uses the reserved ID");
+    assert!(!DRIVERS.lock().iter().any(|x| x.id == id) &&
+            !CLASS_DRIVERS.lock().iter().any(|x| x.id == id),
+        "Duplicate PCI driver ID");
+}
+
+/// Register a driver which matches devices by their vendor and device IDs.
+/// `probe` is invoked for every device, checks the IDs itself and returns
+/// `Some` if it claims the device. Drivers with a fixed list of IDs should
+/// use `register_id_driver` instead.
+///
+/// `id` is persisted across soft reboots to remember which driver handled
+/// which device, thus IDs must never be reused for a different driver, and
+/// must not be `PCI_NO_DRIVER`. Drivers must be registered before `init`.
+pub fn register_driver(id: u16, probe: ProbeFunction) {
+    check_new_driver(id);
+    DRIVERS.lock().push(Driver { id, ids: None, probe: Probe::Single(probe) });
+}
+
+/// Register a driver which handles the devices with the `(vendor ID, device
+/// ID)` pairs in `ids`, see `register_driver`. `probe` is only invoked for
+/// those devices, rather than for every device.
+pub fn register_id_driver(id: u16, ids: &'static [(u16, u16)],
+                          probe: ProbeFunction) {
+    check_new_driver(id);
+    DRIVERS.lock().push(Driver {
+        id,
+        ids:   Some(ids),
+        probe: Probe::Single(probe),
+    });
+}
+
+/// Register a driver which matches devices by their vendor and device IDs
+/// and may create several devices for one function, see `register_driver`.
+/// `probe` returns an empty list if it doesn't claim the device.
+pub fn register_multi_driver(id: u16, probe: MultiProbeFunction) {
+    check_new_driver(id);
+    DRIVERS.lock().push(Driver { id, ids: None, probe: Probe::Multi(probe) });
+}
+
+/// Register a driver which matches devices by their class code. `probe` is
+/// only invoked for devices with a matching class code, and only if none of
+/// the drivers registered with `register_driver` claimed the device.
+///
+/// IDs share the ID space of `register_driver`, `prog_if` of `None` matches
+/// any programming interface.
+pub fn register_class_driver(id: u16, class: u8, subclass: u8,
+                             prog_if: Option<u8>, probe: ProbeFunction) {
+    check_new_driver(id);
+    CLASS_DRIVERS.lock().push(ClassProbe {
+        id, class, subclass, prog_if, probe,
+    });
+}
+
+/// Compute a signature of the IDs of all the drivers in `DRIVERS` and
+/// `CLASS_DRIVERS`. Persisted driver claims are only valid if the set of
+/// drivers has not changed, as a new driver may be able to handle a device no
+/// one claimed before.
+fn drivers_signature() -> u64 {
+    // FNV-1a over the driver IDs
+    DRIVERS.lock().iter().map(|x| x.id)
+        .chain(CLASS_DRIVERS.lock().iter().map(|x| x.id))
+        .fold(0xcbf2_9ce4_8422_2325, |hash, id| {
+            (hash ^ id as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+/// Get a human-readable name for the PCI class code `class` and `subclass`
+///
+/// Unknown subclasses of known classes get the name of the class, and
+/// unknown classes are reported as "Unknown device".
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _   ) => "Unclassified device",
+
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x04) => "RAID bus controller",
+        (0x01, 0x05) => "ATA controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x07) => "Serial attached SCSI controller",
+        (0x01, 0x08) => "Non-volatile memory controller",
+        (0x01, _   ) => "Mass storage controller",
+
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _   ) => "Network controller",
+
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, 0x02) => "3D controller",
+        (0x03, _   ) => "Display controller",
+
+        (0x04, 0x03) => "Audio device",
+        (0x04, _   ) => "Multimedia controller",
+
+        (0x05, _   ) => "Memory controller",
+
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _   ) => "Bridge",
+
+        (0x07, 0x00) => "Serial controller",
+        (0x07, _   ) => "Communication controller",
+
+        (0x08, 0x00) => "PIC",
+        (0x08, 0x05) => "SD host controller",
+        (0x08, 0x06) => "IOMMU",
+        (0x08, _   ) => "System peripheral",
 
-/// List of all driver probe routines on the system. If they return `Some` then
-/// we successfully found a driver and thus we'll register it in the
-/// `DEVICES` database
-const DRIVERS: &[ProbeFunction] = &[
-    crate::net::intel_nic::probe,
-];
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _   ) => "Serial bus controller",
+
+        (0x0d, _   ) => "Wireless controller",
+        (0x10, _   ) => "Encryption controller",
+        (0x11, _   ) => "Signal processing controller",
+        (0x12, _   ) => "Processing accelerator",
+
+        _ => "Unknown device",
+    }
+}
 
 /// I/O port for the PCI configuration space window address
 const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
@@ -92,12 +566,347 @@ const PCI_CONFIG_DATA: u16 = 0xcfc;
 /// Enable bit for accessing the `0xcf8` I/O port
 const PCI_ADDRESS_ENABLE: u32 = 1 << 31;
 
-/// List of all devices which have been handled by a driver
+/// Value read from configuration space of functions which are not present.
+/// Reads which nothing responds to complete with all ones, both for the
+/// legacy configuration ports and ECAM.
+const PCI_INVALID: u32 = 0xffff_ffff;
+
+/// Returns `true` if the vendor and device ID dword `vid_did`, read at offset
+/// 0 of configuration space, belongs to a function which is present
 ///
-/// This is a list of all of the driver structures returned by the successful
-/// `probe` routines from the `DRIVERS` list.
-static DEVICES: LockCell<Vec<Arc<dyn Device>>, LockInterrupts> =
-    LockCell::new(Vec::new());
+/// The vendor ID alone decides, `0xffff` is never assigned to a vendor. Some
+/// multifunction devices have gaps in their functions which read back a
+/// vendor ID of all ones but something else in the device ID.
+fn device_present(vid_did: u32) -> bool {
+    vid_did != PCI_INVALID && (vid_did & 0xffff) != 0xffff
+}
+
+/// All devices which have been handled by a driver, along with where they
+/// were found
+struct Registry {
+    /// The driver structures returned by the successful `probe` routines of
+    /// the drivers
+    devices: Vec<Arc<dyn Device>>,
+
+    /// Location of each device in `devices`, at the same index
+    addrs: Vec<PciAddress>,
+
+    /// Locations of the devices currently held by a `DeviceHandle`
+    exclusive: Vec<PciAddress>,
+}
+
+impl Registry {
+    /// Sort the devices by their location, such that the order of the
+    /// devices does not depend on the order they were probed in
+    fn sort(&mut self) {
+        let mut entries: Vec<(PciAddress, Arc<dyn Device>)> =
+            self.addrs.drain(..).zip(self.devices.drain(..)).collect();
+        entries.sort_by_key(|&(addr, _)| addr);
+
+        for (addr, device) in entries {
+            self.addrs.push(addr);
+            self.devices.push(device);
+        }
+
+        self.publish();
+    }
+
+    /// Add `device` found at `addr`
+    fn push(&mut self, addr: PciAddress, device: Arc<dyn Device>) {
+        self.addrs.push(addr);
+        self.devices.push(device);
+        self.publish();
+    }
+
+    /// Get the device at `addr`. If the driver created several devices for
+    /// the function, this is the first of them.
+    fn get(&self, addr: PciAddress) -> Option<Arc<dyn Device>> {
+        let idx = self.addrs.iter().position(|&x| x == addr)?;
+        Some(self.devices[idx].clone())
+    }
+
+    /// Take all devices at `addr` out of the registry
+    ///
+    /// Once this returns no lock-free reader can find the devices anymore,
+    /// thus the only references left are the ones returned and those handed
+    /// out before.
+    fn remove(&mut self, addr: PciAddress) -> Vec<Arc<dyn Device>> {
+        let mut removed = Vec::new();
+        while let Some(idx) = self.addrs.iter().position(|&x| x == addr) {
+            self.addrs.remove(idx);
+            removed.push(self.devices.remove(idx));
+        }
+
+        if !removed.is_empty() {
+            self.publish();
+        }
+        removed
+    }
+
+    /// Take `device` itself out of the registry, leaving other devices the
+    /// driver created for the same function in place. Returns the location
+    /// `device` was registered at, `None` if it wasn't registered.
+    fn remove_one(&mut self, device: &Arc<dyn Device>)
+            -> Option<(PciAddress, Arc<dyn Device>)> {
+        let ptr = &**device as *const dyn Device as *const u8;
+        let idx = self.devices.iter()
+            .position(|x| &**x as *const dyn Device as *const u8 == ptr)?;
+
+        let addr    = self.addrs.remove(idx);
+        let removed = self.devices.remove(idx);
+        self.publish();
+        Some((addr, removed))
+    }
+
+    /// Publish a copy of the device list for `read_devices`, and free the
+    /// previous copy once no reader can be using it anymore
+    fn publish(&self) {
+        let new = Box::into_raw(Box::new(self.devices.clone()));
+        let old = PUBLISHED.swap(new, Ordering::SeqCst);
+
+        // Send new readers to the other slot, then wait for the readers
+        // which may have seen `old` to be done with it
+        let epoch = PUBLISH_EPOCH.fetch_add(1, Ordering::SeqCst);
+        while PUBLISH_READERS[epoch % 2].load(Ordering::SeqCst) != 0 {
+            spin_loop_hint();
+        }
+
+        if !old.is_null() {
+            unsafe { drop(Box::from_raw(old)); }
+        }
+    }
+}
+
+/// Copy of `Registry::devices` which can be read without taking the
+/// `DEVICES` lock, see `read_devices`. Null until a device was registered.
+static PUBLISHED: AtomicPtr<Vec<Arc<dyn Device>>> =
+    AtomicPtr::new(core::ptr::null_mut());
+
+/// Number of times a device list has been published. Readers account for
+/// themselves in the slot of `PUBLISH_READERS` of the epoch they saw.
+static PUBLISH_EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of readers currently using `PUBLISHED`, by the parity of the
+/// epoch they entered in
+static PUBLISH_READERS: [AtomicUsize; 2] =
+    [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Number of `read_devices` calls each core is currently nested in, indexed
+/// by core ID. `unregister_device` defers removals on cores which are
+/// reading, as republishing the list would wait for themselves.
+static READ_DEPTH: [AtomicU32; acpi::MAX_CORES] =
+    [AtomicU32::new(0); acpi::MAX_CORES];
+
+/// Returns `true` if the current core is inside of a `read_devices`
+fn reading_devices() -> bool {
+    READ_DEPTH.get(core!().id as usize)
+        .map(|x| x.load(Ordering::SeqCst) != 0).unwrap_or(false)
+}
+
+/// Invoke `func` with the published list of registered devices, without
+/// taking the `DEVICES` lock
+///
+/// Updates to the registry wait for every `func` which may be using the
+/// list they replace, thus `func` must be short and must not register or
+/// remove devices itself. Removals with `unregister_device` from within
+/// `func` are deferred until the next `service_removals`, we never run them
+/// here as the caller may hold locks the drivers being removed need.
+fn read_devices<R, F: FnOnce(&[Arc<dyn Device>]) -> R>(func: F) -> R {
+    let depth = READ_DEPTH.get(core!().id as usize);
+    if let Some(depth) = depth {
+        depth.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Account for ourselves in the slot of the current epoch. If a writer
+    // moved on to the next epoch in the meantime it may not have seen us,
+    // thus try again.
+    let slot = loop {
+        let epoch = PUBLISH_EPOCH.load(Ordering::SeqCst);
+        let slot  = &PUBLISH_READERS[epoch % 2];
+        slot.fetch_add(1, Ordering::SeqCst);
+
+        if PUBLISH_EPOCH.load(Ordering::SeqCst) == epoch {
+            break slot;
+        }
+        slot.fetch_sub(1, Ordering::SeqCst);
+    };
+
+    // Any writer replacing this list from now on waits for us
+    let devices = PUBLISHED.load(Ordering::SeqCst);
+    let ret = if devices.is_null() {
+        func(&[])
+    } else {
+        func(unsafe { &*devices })
+    };
+
+    slot.fetch_sub(1, Ordering::SeqCst);
+    if let Some(depth) = depth {
+        depth.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    ret
+}
+
+/// List of all devices which have been handled by a driver, sorted by their
+/// location once probing is done
+static DEVICES: LockCell<Registry, LockInterrupts> =
+    LockCell::new(Registry {
+        devices:   Vec::new(),
+        addrs:     Vec::new(),
+        exclusive: Vec::new(),
+    });
+
+/// Lock held while using the `0xcf8`/`0xcfc` configuration mechanism, as
+/// selecting the address and accessing the data are separate port accesses
+static CONFIG_LOCK: LockCell<(), LockInterrupts> =
+    LockCell::new_no_preempt(());
+
+/// Check that configuration space of the root bus is reachable
+///
+/// ECAM is always preferred when the MCFG covers a bus, so this only has to
+/// worry about systems where we'd fall back to the legacy `0xcf8`/`0xcfc`
+/// ports. On systems without them every read returns all ones, which would
+/// otherwise look like a machine with no PCI devices at all.
+unsafe fn check_config_access() -> Result<(), PciInitError> {
+    if ecam::available(0) {
+        return Ok(());
+    }
+
+    // The legacy address register latches what is written to it, if it
+    // doesn't read back the ports don't exist
+    let present = {
+        let _lock = CONFIG_LOCK.lock();
+        let old = cpu::in32(PCI_CONFIG_ADDRESS);
+        cpu::out32(PCI_CONFIG_ADDRESS, PCI_ADDRESS_ENABLE);
+        let present = cpu::in32(PCI_CONFIG_ADDRESS) == PCI_ADDRESS_ENABLE;
+        cpu::out32(PCI_CONFIG_ADDRESS, old);
+        present
+    };
+
+    if !present {
+        return Err(PciInitError::NoConfigMechanism);
+    }
+
+    // There's always a host bridge on bus 0, if nothing responds something
+    // is wrong with the configuration mechanism
+    if !(0..PCI_MAX_DEV as u8)
+            .any(|dev| device_present(read_config_u32(0, dev, 0, 0))) {
+        return Err(PciInitError::NoHostBridge);
+    }
+
+    Ok(())
+}
+
+/// Mechanism used to reach the configuration space of a bus
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciAccess {
+    /// The legacy `0xcf8`/`0xcfc` I/O ports, which only reach the first 256
+    /// bytes of configuration space
+    PortIo,
+
+    /// The memory-mapped ECAM region described by the MCFG, which is mapped
+    /// in a bus at a time as it's used
+    Ecam {
+        /// Physical address of the configuration space of bus 0
+        base: PhysAddr,
+
+        /// First bus decoded by the region
+        start_bus: u8,
+
+        /// Last bus (inclusive) decoded by the region
+        end_bus: u8,
+    },
+}
+
+/// Get the mechanism which `read_config_u32` and `write_config_u32` use for
+/// `bus`, ECAM if the MCFG covers the bus and the legacy ports otherwise
+pub fn config_access(bus: u8) -> PciAccess {
+    match ecam::region() {
+        Some((base, start_bus, end_bus))
+                if bus >= start_bus && bus <= end_bus => {
+            PciAccess::Ecam { base, start_bus, end_bus }
+        }
+        _ => PciAccess::PortIo,
+    }
+}
+
+/// Read the 32-bit configuration space register at `offset` for the PCI
+/// device at `bus:device.function`
+///
+/// This uses ECAM if it is available for this bus, otherwise the legacy
+/// `0xcf8`/`0xcfc` configuration mechanism is used. The extended
+/// configuration space (offsets `0x100` and above) is only accessible via
+/// ECAM. Configuration space is only addressed in dwords, thus `offset` must
+/// be 4-byte aligned.
+pub unsafe fn read_config_u32(bus: u8, device: u8, function: u8,
+                              offset: u16) -> u32 {
+    // Use ECAM if we can
+    if let PciAccess::Ecam { .. } = config_access(bus) {
+        return ecam::read_u32(bus, device, function, offset);
+    }
+
+    assert!(offset < 256, "Extended PCI config space requires ECAM");
+
+    // Select the address and read the register
+    let _lock = CONFIG_LOCK.lock();
+    cpu::out32(PCI_CONFIG_ADDRESS,
+               PciAddress { bus, device, function }
+                   .config_address(offset as u8));
+    cpu::in32(PCI_CONFIG_DATA)
+}
+
+/// Number of times `read_config_u32_present` retries a read which returned
+/// all ones
+const PRESENT_READ_RETRIES: usize = 3;
+
+/// Delay between the retries of `read_config_u32_present`, in microseconds
+const PRESENT_READ_DELAY: u64 = 1_000;
+
+/// Same as `read_config_u32`, but for a function which is known to be
+/// present, eg. one found by a previous enumeration
+///
+/// Some chipsets transiently return all ones for functions which were just
+/// reset, thus an all ones read is retried a few times with a short delay.
+/// If the function really is gone, all ones is returned.
+pub unsafe fn read_config_u32_present(bus: u8, device: u8, function: u8,
+                                      offset: u16) -> u32 {
+    let mut value = read_config_u32(bus, device, function, offset);
+    for _ in 0..PRESENT_READ_RETRIES {
+        if value != PCI_INVALID {
+            break;
+        }
+
+        crate::time::sleep(PRESENT_READ_DELAY);
+        value = read_config_u32(bus, device, function, offset);
+    }
+
+    value
+}
+
+/// Write `value` to the 32-bit configuration space register at `offset` for
+/// the PCI device at `bus:device.function`
+///
+/// This uses ECAM if it is available for this bus, otherwise the legacy
+/// `0xcf8`/`0xcfc` configuration mechanism is used. The extended
+/// configuration space (offsets `0x100` and above) is only accessible via
+/// ECAM. Configuration space is only addressed in dwords, thus `offset` must
+/// be 4-byte aligned.
+pub unsafe fn write_config_u32(bus: u8, device: u8, function: u8,
+                               offset: u16, value: u32) {
+    // Use ECAM if we can
+    if let PciAccess::Ecam { .. } = config_access(bus) {
+        return ecam::write_u32(bus, device, function, offset, value);
+    }
+
+    assert!(offset < 256, "Extended PCI config space requires ECAM");
+
+    // Select the address and write the register
+    let _lock = CONFIG_LOCK.lock();
+    cpu::out32(PCI_CONFIG_ADDRESS,
+               PciAddress { bus, device, function }
+                   .config_address(offset as u8));
+    cpu::out32(PCI_CONFIG_DATA, value);
+}
 
 /// Common PCI header for the PCI configuration space of any device or bridge
 #[derive(Clone, Copy, Debug)]
@@ -117,6 +926,43 @@ pub struct PciHeader {
     pub bist:            u8,
 }
 
+/// Class code of a PCI function, describing what kind of device it is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClassCode {
+    /// Base class, eg. `0x02` for network controllers
+    pub class: u8,
+
+    /// Subclass within the base class, eg. `0x00` for ethernet controllers
+    pub subclass: u8,
+
+    /// Register-level programming interface of the function
+    pub prog_if: u8,
+}
+
+impl ClassCode {
+    /// Get the class code from the configuration space header `header`
+    pub fn from_header(header: &PciHeader) -> Self {
+        ClassCode {
+            class:    header.class,
+            subclass: header.subclass,
+            prog_if:  header.prog_if,
+        }
+    }
+
+    /// Returns `true` if this is `class` and `subclass`, and `prog_if` when
+    /// it's `Some`
+    pub fn matches(&self, class: u8, subclass: u8, prog_if: Option<u8>)
+            -> bool {
+        self.class == class && self.subclass == subclass &&
+            prog_if.map(|x| x == self.prog_if).unwrap_or(true)
+    }
+
+    /// Get a human-readable name for the class code, see `class_name`
+    pub fn name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
 /// Configuration space for a PCI device
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
@@ -142,131 +988,3290 @@ pub struct PciDevice {
     pub max_latency:           u8,
 }
 
-/// Enumerate all PCI devices on the system and initialize drivers for any
-/// supported devices.
-pub unsafe fn init() {
-    // Get access to the persisted PCI device enumeration
-    let mut pci_devices = core!().persist_store().pci_devices.lock();
-
-    // Enumerate the PCI devices if we have not done so yet
-    if pci_devices.is_none() {
-        // Bitmap of present PCI devices
-        let mut pci_enum = [0u64; 256 * 32 * 8 / 64];
-
-        // For each possible bus ID
-        for bus in 0..256 {
-            // For each possible device ID
-            for device in 0..32 {
-                // For each possible function ID
-                for function in 0..8 {
-                    // Compute the address to select this BDF combination
-                    let pci_addr =
-                        (bus << 8) | (device << 3) | (function << 0);
-
-                    // Compute the PCI selection address
-                    let addr = PCI_ADDRESS_ENABLE | (pci_addr << 8);
-
-                    // Select the address and read the device and vendor ID
-                    cpu::out32(PCI_CONFIG_ADDRESS, addr);
-                    let did_vid = cpu::in32(PCI_CONFIG_DATA);
-
-                    if did_vid != 0xffff_ffff {
-                        // Set the device present in the PCI enumeration table
-                        let idx = pci_addr / 64;
-                        let bit = pci_addr % 64;
-                        pci_enum[idx as usize] |= 1 << bit;
-                    }
-                }
-            }
-        }
-
-        *pci_devices = Some(pci_enum);
-    }
+/// Location of a PCI device on the system
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    /// Bus number (0-255)
+    pub bus: u8,
 
-    // Get access to the PCI device enumeration bitmap
-    let pci_devices = pci_devices.as_ref().unwrap();
+    /// Device number (0-31)
+    pub device: u8,
 
-    for (idx, &pci_map) in pci_devices.iter().enumerate() {
-        // No devices here, go to the next `u64`
-        if pci_map == 0 {
-            continue;
-        }
+    /// Function number (0-7)
+    pub function: u8,
+}
 
-        // There is at least 1 device in this set
-        for bit in 0..64 {
-            // If the bit is not set, no device here, skip it
-            if (pci_map & (1u64 << bit)) == 0 { continue; }
+/// Number of PCI buses in a segment
+pub const PCI_MAX_BUS: usize = 256;
 
-            // Compute the PCI address for this bit
-            let pci_addr = (idx * 64) | bit;
-
-            // Compute the PCI selection address
-            let addr = PCI_ADDRESS_ENABLE | (pci_addr << 8) as u32;
-
-            // Read the PCI configuration header
-            let mut header =
-                [0u32; size_of::<PciHeader>() / size_of::<u32>()];
-            for (rid, register) in header.iter_mut().enumerate() { 
-                // Set the window to the register we want to read and read the
-                // value
-                cpu::out32(PCI_CONFIG_ADDRESS,
-                           addr | (rid * size_of::<u32>()) as u32);
-                *register = cpu::in32(PCI_CONFIG_DATA);
-            }
+/// Number of devices on a PCI bus
+pub const PCI_MAX_DEV: usize = 32;
 
-            // Convert the header to our `PciHeader` structure
-            let header: PciHeader = core::ptr::read_unaligned(
-                header.as_ptr() as *const PciHeader);
+/// Number of functions of a PCI device
+pub const PCI_MAX_FUNC: usize = 8;
 
-            // Skip non-device PCI entries (skips things like PCI bridges)
-            if (header.header_type & 0x7f) != 0 {
-                continue;
-            }
+/// Number of `u64`s in the presence bitmap of PCI functions
+const BITMAP_WORDS: usize = PciAddress::COUNT / 64;
 
-            // Read the PCI configuration
-            let mut device = [0u32; size_of::<PciDevice>() / size_of::<u32>()];
-            for (rid, register) in device.iter_mut().enumerate() { 
-                // Set the window to the register we want to read and read the
-                // value
-                cpu::out32(PCI_CONFIG_ADDRESS,
-                           addr | (rid * size_of::<u32>()) as u32);
-                *register = cpu::in32(PCI_CONFIG_DATA);
-            }
+/// Get the word in the presence bitmap holding `addr`, and the bit for
+/// `addr` within that word
+fn bitmap_index(addr: PciAddress) -> (usize, u64) {
+    let idx  = addr.index();
+    let word = idx / 64;
+    debug_assert!(word < BITMAP_WORDS, "PCI bitmap index out of range");
+    (word, 1u64 << (idx % 64))
+}
 
-            // Convert the device to our `PciDevice` structure
-            let device: PciDevice = core::ptr::read_unaligned(
-                device.as_ptr() as *const PciDevice);
+impl PciAddress {
+    /// Number of functions which can be addressed, this is the size of the
+    /// presence bitmap
+    pub const COUNT: usize = PCI_MAX_BUS * PCI_MAX_DEV * PCI_MAX_FUNC;
 
-            if DEBUG_PCI_DEVICES {
-                print!("PCI device | {:#06x}:{:#06x} | {:#06x}:{:#06x}\n",
-                       device.header.vendor_id,
-                       device.header.device_id,
-                       device.subsystem_vendor_id,
-                       device.subsystem_device_id);
-            }
+    /// Get the address of the function at `idx` in the presence bitmap. The
+    /// bitmap is indexed by bus, then device, then function.
+    pub fn from_index(idx: usize) -> Self {
+        assert!(idx < Self::COUNT, "Invalid PCI function index");
 
-            // Attempt to find a driver for this device
-            for probe in DRIVERS {
-                if let Some(driver) = probe(&device) {
-                    // Found a handler, go to the next function during the PCI
-                    // enumeration
-                    DEVICES.lock().push(driver);
-                }
-            }
+        PciAddress {
+            bus:      (idx >> 8) as u8,
+            device:   ((idx >> 3) & 0x1f) as u8,
+            function: ((idx >> 0) & 0x07) as u8,
         }
     }
-}
 
-/// Drop all devices in the `DEVICES` list, causing the devices to have their
-/// `Drop` handlers invoked
+    /// Get the index of this function in the presence bitmap, the inverse of
+    /// `from_index`
+    pub fn index(&self) -> usize {
+        assert!((self.device as usize) < PCI_MAX_DEV &&
+                (self.function as usize) < PCI_MAX_FUNC,
+            "Invalid PCI device or function");
+
+        ((self.bus as usize) << 8) | ((self.device as usize) << 3) |
+            ((self.function as usize) << 0)
+    }
+
+    /// Compute the `0xcf8` selection address for the 32-bit configuration
+    /// space register at `offset` of this function
+    ///
+    /// The legacy `0xcf8`/`0xcfc` configuration mechanism only addresses
+    /// dwords, thus `offset` must be 4-byte aligned.
+    pub fn config_address(&self, offset: u8) -> u32 {
+        assert!((offset & 3) == 0, "Unaligned PCI config space access");
+
+        PCI_ADDRESS_ENABLE | ((self.index() as u32) << 8) | offset as u32
+    }
+}
+
+/// Legacy INTx routing of a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LegacyInterrupt {
+    /// Interrupt pin the device uses, 1 through 4 for INTA# through INTD#
+    pub pin: u8,
+
+    /// Interrupt line firmware routed the pin to, `None` if unknown or not
+    /// connected
+    pub line: Option<u8>,
+}
+
+/// Decoded status register of a device, see `ProbedDevice::read_status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciStatus(pub u16);
+
+impl PciStatus {
+    /// We mastered a transaction which was terminated with a master abort,
+    /// eg. an access to an address nobody decodes
+    pub fn received_master_abort(&self) -> bool {
+        (self.0 & (1 << 13)) != 0
+    }
+
+    /// We mastered a transaction which the target terminated with a target
+    /// abort
+    pub fn received_target_abort(&self) -> bool {
+        (self.0 & (1 << 12)) != 0
+    }
+
+    /// We terminated a transaction targeting us with a target abort
+    pub fn signaled_target_abort(&self) -> bool {
+        (self.0 & (1 << 11)) != 0
+    }
+
+    /// We signaled a system error, eg. a fatal or non-fatal PCIe error
+    pub fn signaled_system_error(&self) -> bool {
+        (self.0 & (1 << 14)) != 0
+    }
+
+    /// We detected a parity error, or received a poisoned TLP
+    pub fn detected_parity_error(&self) -> bool {
+        (self.0 & (1 << 15)) != 0
+    }
+
+    /// Returns `true` if any of the error bits are latched
+    pub fn has_errors(&self) -> bool {
+        (self.0 & STATUS_ERRORS) != 0
+    }
+}
+
+/// Reasons the advanced error reporting registers can't be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AerError {
+    /// Only the legacy configuration space is reachable, which doesn't
+    /// include the extended capabilities
+    NoExtendedConfig,
+
+    /// The device doesn't implement AER
+    NotImplemented,
+}
+
+/// Maximum and negotiated PCIe link width and speed of a device. Speeds are
+/// the PCIe generation, eg. 1 for 2.5 GT/s and 3 for 8 GT/s.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkCaps {
+    /// Fastest speed the device supports
+    pub max_speed: u8,
+
+    /// Widest link the device supports, in lanes
+    pub max_width: u8,
+
+    /// Speed the link trained at
+    pub speed: u8,
+
+    /// Width the link trained at, in lanes
+    pub width: u8,
+}
+
+impl LinkCaps {
+    /// Returns `true` if the link trained slower or narrower than the device
+    /// supports, eg. due to a bad slot or a slower upstream port. Links
+    /// which are down (eg. the root port of an empty slot) report a width of
+    /// zero and are not considered downgraded.
+    pub fn downgraded(&self) -> bool {
+        self.width != 0 &&
+            (self.speed < self.max_speed || self.width < self.max_width)
+    }
+}
+
+/// A PCI device which was found during enumeration, along with the location
+/// it was found at. This is what is handed to driver probe routines such that
+/// they can perform further configuration space accesses on the device.
+#[derive(Clone, Copy, Debug)]
+pub struct ProbedDevice {
+    /// Location of the device
+    pub addr: PciAddress,
+
+    /// Configuration space of the device at the time of enumeration
+    pub config: PciDevice,
+
+    /// How configuration space of the device is accessed, this is the real
+    /// hardware except when decoding synthetic configuration spaces
+    pub access: &'static dyn ConfigAccess,
+
+    /// `(capability ID, configuration space offset)` pairs of the
+    /// capabilities of the device, in list order
+    capability_cache: [(u8, u8); CACHED_CAPABILITIES],
+
+    /// Number of valid entries in `capability_cache`, `None` if the device
+    /// has more capabilities than fit in the cache
+    cached_capabilities: Option<u8>,
+}
+
+/// Maximum number of capabilities cached in a `ProbedDevice`, lookups on
+/// devices with more capabilities walk the capability list
+const CACHED_CAPABILITIES: usize = 16;
+
+impl ProbedDevice {
+    /// Create a device found at `addr`, whose configuration space header
+    /// `config` was read through `access`
+    pub unsafe fn new(access: &'static dyn ConfigAccess, addr: PciAddress,
+                      config: PciDevice) -> Self {
+        let mut ret = ProbedDevice {
+            addr,
+            config,
+            access,
+            capability_cache:    [(0, 0); CACHED_CAPABILITIES],
+            cached_capabilities: None,
+        };
+        ret.cache_capabilities();
+        ret
+    }
+
+    /// Read the configuration space header of the device at `addr` through
+    /// `access`
+    pub unsafe fn from_access(access: &'static dyn ConfigAccess,
+                              addr: PciAddress) -> Self {
+        let mut config = [0u32; PCI_CONFIG_DWORDS];
+        for (ii, dword) in config.iter_mut().enumerate() {
+            *dword = access.read(addr, (ii * size_of::<u32>()) as u16);
+        }
+
+        Self::new(access, addr, PciDevice::from_raw(&config))
+    }
+
+    /// Walk the capability list once and remember where each capability is
+    /// for `capability_offset`. This must be called again if the capability
+    /// list or `config` is changed, eg. by a quirk hiding a capability.
+    pub unsafe fn cache_capabilities(&mut self) {
+        let mut count = 0;
+        for cap in self.capabilities() {
+            if count == CACHED_CAPABILITIES {
+                // Too many capabilities, always walk the list for this device
+                self.cached_capabilities = None;
+                return;
+            }
+
+            self.capability_cache[count] = cap;
+            count += 1;
+        }
+
+        self.cached_capabilities = Some(count as u8);
+    }
+
+    /// Read the 32-bit configuration space register at `offset` for this
+    /// device
+    pub unsafe fn read_config_u32(&self, offset: u16) -> u32 {
+        self.access.read(self.addr, offset)
+    }
+
+    /// Write `value` to the 32-bit configuration space register at `offset`
+    /// for this device
+    pub unsafe fn write_config_u32(&self, offset: u16, value: u32) {
+        self.access.write(self.addr, offset, value)
+    }
+
+    /// Read the live value of the 16-bit configuration space register at
+    /// `offset` for this device, which must be 2-byte aligned. Unlike the
+    /// fields of `config` this is never stale, and `config` is left as is.
+    pub unsafe fn reread_config_u16(&self, offset: u8) -> u16 {
+        assert!(offset & 1 == 0, "Unaligned 16-bit config space read");
+        (self.read_config_u32((offset & !3) as u16) >> ((offset & 2) * 8))
+            as u16
+    }
+
+    /// Read the entire 4 KiB configuration space of the device, for debugging
+    ///
+    /// If the extended configuration space can't be accessed (eg. ECAM is not
+    /// available) only the first 256 bytes are read and the rest is zero.
+    pub unsafe fn dump_extended_config(&self) -> [u32; config::CONFIG_DWORDS] {
+        let mut ret = [0u32; config::CONFIG_DWORDS];
+
+        // Figure out how much of the configuration space we can reach
+        let dwords = if self.access.has_extended(self.addr) {
+            config::CONFIG_DWORDS
+        } else {
+            256 / size_of::<u32>()
+        };
+
+        for (ii, dword) in ret[..dwords].iter_mut().enumerate() {
+            *dword = self.read_config_u32((ii * size_of::<u32>()) as u16);
+        }
+
+        ret
+    }
+
+    /// Walk the capability list of this device, yielding
+    /// `(capability ID, configuration space offset)` pairs
+    ///
+    /// Devices which do not report a capability list in their status register
+    /// yield nothing.
+    pub unsafe fn capabilities(&self) -> CapabilityIter {
+        // Bit 4 of the status register indicates the capability pointer is
+        // valid
+        let start = if (self.config.header.status & (1 << 4)) != 0 {
+            self.config.capabilities
+        } else {
+            0
+        };
+
+        CapabilityIter::new(self.access, self.addr, start)
+    }
+
+    /// Walk the capability list of this device like `capabilities`, parsing
+    /// the capabilities we know about
+    pub unsafe fn parsed_capabilities(&self) -> Vec<Capability> {
+        let (access, addr) = (self.access, self.addr);
+        self.capabilities().map(|(id, offset)| match id {
+            CAP_ID_PM => Capability::PowerManagement(
+                PmCapability::new(access, addr, offset)),
+            CAP_ID_MSI => Capability::Msi(
+                MsiCapability::new(access, addr, offset)),
+            CAP_ID_MSIX => Capability::MsiX(
+                MsiXCapability::new(access, addr, offset)),
+            CAP_ID_EA => Capability::EnhancedAllocation(
+                EaCapability::new(access, addr, offset)),
+            _ => Capability::Other { id, offset },
+        }).collect()
+    }
+
+    /// Find the configuration space offset of the first capability with ID
+    /// `id`, by walking the capability list. See `capability_offset` for
+    /// using the offsets cached when the device was probed.
+    pub unsafe fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities().find(|&(cap_id, _)| cap_id == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Get the configuration space offset of the first capability with ID
+    /// `id`, from the capabilities cached when the device was probed
+    pub unsafe fn capability_offset(&self, id: u8) -> Option<u8> {
+        match self.cached_capabilities {
+            Some(count) => self.capability_cache[..count as usize].iter()
+                .find(|&&(cap_id, _)| cap_id == id).map(|&(_, offset)| offset),
+            None => self.find_capability(id),
+        }
+    }
+
+    /// Walk the extended capability list of this device, yielding
+    /// `(extended capability ID, configuration space offset)` pairs
+    ///
+    /// Nothing is yielded if the extended configuration space can't be
+    /// accessed.
+    pub unsafe fn extended_capabilities(&self) -> ExtCapabilityIter {
+        let start = if self.access.has_extended(self.addr) { 0x100 } else { 0 };
+        ExtCapabilityIter::new(self.access, self.addr, start)
+    }
+
+    /// Find the configuration space offset of the first extended capability
+    /// with ID `id`
+    pub unsafe fn find_extended_capability(&self, id: u16) -> Option<u16> {
+        self.extended_capabilities().find(|&(cap_id, _)| cap_id == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Get the SR-IOV capability of this device, if it has one
+    pub unsafe fn sriov(&self) -> Option<SriovCapability> {
+        self.find_extended_capability(EXT_CAP_ID_SRIOV)
+            .map(|offset| SriovCapability::new(self.access, self.addr, offset))
+    }
+
+    /// Read the error status and mask registers of the advanced error
+    /// reporting capability of this device, eg. for a watchdog looking for
+    /// devices which latched a fatal error
+    pub unsafe fn aer_status(&self) -> Result<AerStatus, AerError> {
+        self.aer_offset()
+            .map(|offset| AerStatus::read(self.access, self.addr, offset))
+    }
+
+    /// Clear the errors logged in the advanced error reporting capability of
+    /// this device
+    pub unsafe fn clear_aer_status(&self) -> Result<(), AerError> {
+        self.aer_offset()
+            .map(|offset| AerStatus::clear(self.access, self.addr, offset))
+    }
+
+    /// Find the advanced error reporting capability of this device
+    unsafe fn aer_offset(&self) -> Result<u16, AerError> {
+        if !self.access.has_extended(self.addr) {
+            return Err(AerError::NoExtendedConfig);
+        }
+
+        self.find_extended_capability(EXT_CAP_ID_AER)
+            .ok_or(AerError::NotImplemented)
+    }
+
+    /// Get the enhanced allocation capability of this device, if it has one
+    pub unsafe fn enhanced_allocation(&self) -> Option<EaCapability> {
+        self.capability_offset(CAP_ID_EA)
+            .map(|offset| EaCapability::new(self.access, self.addr, offset))
+    }
+
+    /// Get the resource and its size which the enhanced allocation capability
+    /// gives the device in place of BAR `index`, if it's enabled
+    pub unsafe fn ea_bar(&self, index: usize) -> Option<(Bar, u64)> {
+        self.enhanced_allocation()?.entries()
+            .find(|entry| entry.enabled && entry.bei as usize == index)
+            .and_then(|entry| Some((entry.bar()?, entry.size)))
+    }
+
+    /// Get the MSI capability of this device, if it has one
+    pub unsafe fn msi(&self) -> Option<MsiCapability> {
+        self.capability_offset(CAP_ID_MSI)
+            .map(|offset| MsiCapability::new(self.access, self.addr, offset))
+    }
+
+    /// Get the MSI-X capability of this device, if it has one
+    pub unsafe fn msix(&self) -> Option<MsiXCapability> {
+        self.capability_offset(CAP_ID_MSIX)
+            .map(|offset| MsiXCapability::new(self.access, self.addr, offset))
+    }
+
+    /// Steer the interrupt `vector` of the device to the core with `apic_id`,
+    /// keeping the interrupt vector it delivers. `vector` is an entry in the
+    /// MSI-X table, or must be 0 for devices which only have MSI, as all MSI
+    /// messages of a device share an address.
+    ///
+    /// Returns `false` if the core is not online, or the device has no such
+    /// vector.
+    pub unsafe fn set_interrupt_affinity(&self, vector: usize,
+                                         apic_id: u32) -> bool {
+        // Make sure the target core exists
+        if apic_id as usize >= acpi::MAX_CORES {
+            return false;
+        }
+        match acpi::core_state(apic_id) {
+            acpi::ApicState::Online | acpi::ApicState::Launched => {}
+            _ => return false,
+        }
+        let address = irq::msi_address(apic_id);
+
+        if let Some(msix) = self.msix() {
+            let mut table = if let Some(table) = msix.map_table(&self.config) {
+                table
+            } else {
+                return false;
+            };
+            if vector >= table.len() {
+                return false;
+            }
+
+            // Reprogram the entry while it's masked, such that it never fires
+            // with a torn message
+            let masked = table.is_masked(vector);
+            let (_, data) = table.vector(vector);
+            table.set_masked(vector, true);
+            table.set_vector(vector, address, data);
+            table.set_masked(vector, masked);
+            true
+        } else if let Some(msi) = self.msi() {
+            if vector != 0 {
+                return false;
+            }
+
+            let (_, data) = msi.message();
+            msi.set_message(address, data);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the power management capability of this device, if it has one
+    pub unsafe fn power_management(&self) -> Option<PmCapability> {
+        self.capability_offset(CAP_ID_PM)
+            .map(|offset| PmCapability::new(self.access, self.addr, offset))
+    }
+
+    /// Move the device to power `state` through its power management
+    /// capability. Returns `Err` if the device has no power management
+    /// capability or does not support `state`.
+    ///
+    /// Putting a device into D3hot stops it from performing DMA, thus a
+    /// `Device::purge` which doesn't know how to reset a device can fall back
+    /// to this.
+    pub unsafe fn set_power_state(&self, state: PowerState) -> Result<(), ()> {
+        let pm = self.power_management().ok_or(())?;
+        if pm.set_power_state(state) { Ok(()) } else { Err(()) }
+    }
+
+    /// Read the command register of the device
+    pub unsafe fn command(&self) -> u16 {
+        self.read_config_u32(0x04) as u16
+    }
+
+    /// Read the live status register of the device, eg. to poll its
+    /// interrupt status bit. `config.header.status` is only a snapshot taken
+    /// during enumeration.
+    pub unsafe fn refresh_status(&self) -> u16 {
+        self.reread_config_u16(0x06)
+    }
+
+    /// Read the live status register of the device like `refresh_status`,
+    /// decoding its error bits
+    pub unsafe fn read_status(&self) -> PciStatus {
+        PciStatus(self.refresh_status())
+    }
+
+    /// Write the command register of the device
+    ///
+    /// The status register shares the dword with the command register, it's
+    /// written with zeros such that none of its write-1-to-clear bits are
+    /// cleared.
+    pub unsafe fn set_command(&self, command: u16) {
+        self.write_config_u32(0x04, command as u32);
+    }
+
+    /// Set or clear `bit` in the command register
+    unsafe fn set_command_bit(&self, bit: u16, enable: bool) {
+        let command = self.command();
+        if enable {
+            self.set_command(command | (1 << bit));
+        } else {
+            self.set_command(command & !(1 << bit));
+        }
+    }
+
+    /// Enable or disable the device responding to I/O space accesses
+    pub unsafe fn set_io_space(&self, enable: bool) {
+        self.set_command_bit(0, enable);
+    }
+
+    /// Enable or disable the device responding to memory space accesses
+    pub unsafe fn set_memory_space(&self, enable: bool) {
+        self.set_command_bit(1, enable);
+    }
+
+    /// Enable or disable the device issuing memory requests, this is required
+    /// for a device to perform DMA and to signal MSIs
+    ///
+    /// When enabling, the cache line size register is set to the line size
+    /// of the CPU if firmware left it zero, as some DMA engines misbehave
+    /// without it.
+    ///
+    /// Enabling is refused with `Err` if an IOMMU is translating DMA, as our
+    /// drivers use physical addresses directly. The first refusal is always
+    /// logged, the devices refused are only logged with `pci_debug`.
+    pub unsafe fn set_bus_master(&self, enable: bool) -> Result<(), ()> {
+        /// Set once a refusal was logged
+        static REFUSAL_LOGGED: AtomicBool = AtomicBool::new(false);
+
+        if enable && iommu::translation_enabled() {
+            if !REFUSAL_LOGGED.swap(true, Ordering::SeqCst) {
+                print!("PCI | DMA remapping enabled, not binding drivers \
+                        which use DMA\n");
+            }
+            if pci_debug() {
+                print!("PCI device | {:02x}:{:02x}.{} | not bound, DMA \
+                        remapping enabled\n",
+                       self.addr.bus, self.addr.device, self.addr.function);
+            }
+            return Err(());
+        }
+
+        if enable && self.cache_line_size() == 0 {
+            // The CLFLUSH line size is in units of 8 bytes, the register is
+            // in units of dwords
+            let line = ((cpu::cpuid(1, 0).1 >> 8) & 0xff) * 2;
+            self.set_cache_line_size(line as u8);
+        }
+
+        self.set_command_bit(2, enable);
+        Ok(())
+    }
+
+    /// Enable memory space decoding and bus mastering for a device which is
+    /// driven through memory BARs and DMA, see `set_memory_space` and
+    /// `set_bus_master`. The command register is read back to confirm both
+    /// took, `Err` if the device did not accept them or bus mastering was
+    /// refused as DMA remapping is enabled.
+    pub unsafe fn enable(&self) -> Result<(), ()> {
+        self.set_memory_space(true);
+        self.set_bus_master(true)?;
+
+        // Memory space enable is bit 1 and bus master enable is bit 2
+        if (self.command() & 0x6) == 0x6 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Allocate physically contiguous memory for this device to DMA to and
+    /// from, and move `val` into it. The driver must keep the region for as
+    /// long as the device may access it, typically by storing it in its
+    /// device structure. Returns `None` if out of physical memory.
+    ///
+    /// The memory may be anywhere in physical memory, devices which can only
+    /// address part of it must use `alloc_dma_masked`.
+    pub fn alloc_dma<T>(&self, val: T) -> Option<DmaRegion<T>> {
+        self.alloc_dma_masked(!0, val)
+    }
+
+    /// Same as `alloc_dma`, but the memory lies entirely within the physical
+    /// addresses covered by `dma_mask`, eg. `0xffff_ffff` for a device which
+    /// can only DMA below 4 GiB
+    pub fn alloc_dma_masked<T>(&self, dma_mask: u64, val: T)
+            -> Option<DmaRegion<T>> {
+        DmaRegion::new(self.addr, dma_mask, val)
+    }
+
+    /// Same as `alloc_dma_masked`, but holding `len` copies of `val`, eg.
+    /// for a descriptor ring whose size is only known at runtime
+    pub fn alloc_dma_slice<T: Copy>(&self, dma_mask: u64, val: T, len: usize)
+            -> Option<DmaRegion<[T]>> {
+        DmaRegion::new_slice(self.addr, dma_mask, val, len)
+    }
+
+    /// Same as `alloc_dma_slice`, but the memory is aligned to `align`
+    /// bytes, eg. for a ring the device requires to be naturally aligned.
+    /// `align` must be a power of two of at least 4 KiB.
+    pub fn alloc_dma_slice_aligned<T: Copy>(&self, dma_mask: u64, val: T,
+                                            len: usize, align: u64)
+            -> Option<DmaRegion<[T]>> {
+        DmaRegion::new_slice_aligned(self.addr, dma_mask, val, len, align)
+    }
+
+    /// Read the cache line size register, in units of dwords
+    pub unsafe fn cache_line_size(&self) -> u8 {
+        self.read_config_u32(0x0c) as u8
+    }
+
+    /// Read the latency timer register, in units of PCI bus clocks
+    pub unsafe fn latency_timer(&self) -> u8 {
+        (self.read_config_u32(0x0c) >> 8) as u8
+    }
+
+    /// Write the cache line size register, in units of dwords. Devices may
+    /// only implement some sizes and ignore others.
+    pub unsafe fn set_cache_line_size(&self, size: u8) {
+        self.set_header_byte(0, size);
+    }
+
+    /// Write the latency timer register, in units of PCI bus clocks. This is
+    /// read-only on PCIe devices.
+    pub unsafe fn set_latency_timer(&self, timer: u8) {
+        self.set_header_byte(1, timer);
+    }
+
+    /// Write `byte` of the dword at `0x0c` of configuration space to `value`
+    ///
+    /// The header type in byte 2 is read-only, and the BIST register in byte
+    /// 3 is written with zeros such that a self test is never started.
+    unsafe fn set_header_byte(&self, byte: usize, value: u8) {
+        let mut val = self.read_config_u32(0x0c) & 0x00ff_ffff;
+        val &= !(0xff << (byte * 8));
+        val |= (value as u32) << (byte * 8);
+        self.write_config_u32(0x0c, val);
+    }
+
+    /// Mask or unmask the device asserting legacy INTx interrupts. Devices
+    /// using MSI or MSI-X, or which are polled, should mask INTx.
+    pub unsafe fn set_intx_mask(&self, masked: bool) {
+        self.set_command_bit(10, masked);
+    }
+
+    /// Reset the function with a PCIe function level reset (FLR), waiting
+    /// for the device to come back. Returns `Err` if the device does not
+    /// support FLR, or did not come back in time.
+    ///
+    /// The whole configuration space of the function is reset, thus the
+    /// device must be set up again from scratch afterwards.
+    pub unsafe fn function_level_reset(&self) -> Result<(), ()> {
+        // Check that the device advertises FLR in its device capabilities
+        let cap = self.capability_offset(CAP_ID_PCIE).ok_or(())? as u16;
+        if (self.read_config_u32(cap + 0x4) & (1 << 28)) == 0 {
+            return Err(());
+        }
+
+        // Give outstanding transactions a chance to complete. The device
+        // status register is the upper half of the device control dword.
+        let timeout = crate::time::future(100_000);
+        while (self.read_config_u32(cap + 0x8) & (1 << (16 + 5))) != 0 &&
+                cpu::rdtsc() < timeout {}
+
+        // Initiate the reset. The status half is written with zeros such that
+        // none of its write-1-to-clear bits are cleared.
+        let control = self.read_config_u32(cap + 0x8) & 0xffff;
+        self.write_config_u32(cap + 0x8, control | (1 << 15));
+
+        // The device has 100 ms to complete the reset before we may touch it
+        crate::time::sleep(100_000);
+
+        // Wait for the device to respond to configuration requests again
+        let ids = self.config.header.vendor_id as u32 |
+            ((self.config.header.device_id as u32) << 16);
+        let timeout = crate::time::future(1_000_000);
+        while self.read_config_u32(0x00) != ids {
+            if cpu::rdtsc() >= timeout {
+                return Err(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the maximum and negotiated link width and speed of the device from
+    /// its PCIe capability, `None` for conventional PCI devices and devices
+    /// integrated in the root complex, which have no link
+    pub unsafe fn link_caps(&self) -> Option<LinkCaps> {
+        let cap = self.capability_offset(CAP_ID_PCIE)? as u16;
+
+        // Root complex integrated endpoints and event collectors don't
+        // implement the link registers
+        let port_type = (self.read_config_u32(cap) >> 20) & 0xf;
+        if port_type == 0x9 || port_type == 0xa {
+            return None;
+        }
+
+        // The link status register is the upper half of the link control
+        // dword
+        let caps   = self.read_config_u32(cap + 0x0c);
+        let status = self.read_config_u32(cap + 0x10) >> 16;
+
+        Some(LinkCaps {
+            max_speed: (caps & 0xf) as u8,
+            max_width: ((caps >> 4) & 0x3f) as u8,
+            speed:     (status & 0xf) as u8,
+            width:     ((status >> 4) & 0x3f) as u8,
+        })
+    }
+
+    /// Get the legacy INTx routing of the device, `None` if the device does
+    /// not use an interrupt pin
+    pub fn legacy_interrupt(&self) -> Option<LegacyInterrupt> {
+        let pin = self.config.interrupt_pin;
+        if pin == 0 || pin > 4 {
+            return None;
+        }
+
+        let line = self.config.interrupt_line;
+        Some(LegacyInterrupt {
+            pin,
+            line: if line == 0xff { None } else { Some(line) },
+        })
+    }
+
+    /// Determine the size (in bytes) of the region decoded by BAR `index`
+    /// by writing all ones to the BAR and reading back which bits stuck.
+    /// Returns zero if the BAR is not implemented or is of a reserved type.
+    ///
+    /// 64-bit memory BARs consume the adjacent BAR register as the high 32
+    /// bits, thus `index` must refer to the low half of the BAR. The original
+    /// BAR values are restored prior to returning.
+    ///
+    /// Memory and I/O decoding is disabled for the device while the BAR is
+    /// being sized, such that the device doesn't decode a bogus region.
+    pub unsafe fn bar_size(&self, index: usize) -> u64 {
+        assert!(index < 6, "Invalid BAR index");
+
+        // Compute the configuration space offset of the BAR
+        let offset = 0x10 + (index * size_of::<u32>()) as u16;
+
+        // Save the command register and disable memory and I/O decoding
+        let command = self.read_config_u32(0x04) & 0xffff;
+        self.write_config_u32(0x04, command & !0x3);
+
+        // Save the original BAR
+        let orig = self.read_config_u32(offset);
+
+        let size = if (orig & 1) != 0 {
+            // I/O BAR, the low 2 bits are not part of the address
+            self.write_config_u32(offset, !0);
+            let mask = self.read_config_u32(offset) & 0xffff_fffc;
+            self.write_config_u32(offset, orig);
+
+            // Devices are allowed to hardwire the upper 16 bits of I/O BARs
+            // to zero, thus only the low 16 bits are used for the size
+            if mask == 0 { 0 } else { (!mask & 0xffff) as u64 + 1 }
+        } else {
+            match BarType::try_from((orig >> 1) & 3) {
+                Ok(BarType::Bits32) => {
+                    // 32-bit memory BAR, the low 4 bits are not part of the
+                    // address
+                    self.write_config_u32(offset, !0);
+                    let mask = self.read_config_u32(offset) & 0xffff_fff0;
+                    self.write_config_u32(offset, orig);
+
+                    if mask == 0 { 0 } else { (!mask) as u64 + 1 }
+                }
+                // A 64-bit BAR can't be in the last slot, as there is no
+                // room for the high 32 bits
+                Ok(BarType::Bits64) if index >= 5 => 0,
+
+                Ok(BarType::Bits64) => {
+                    // 64-bit memory BAR, the high 32 bits are in the next
+                    // BAR register
+                    let offset_hi = offset + size_of::<u32>() as u16;
+                    let orig_hi   = self.read_config_u32(offset_hi);
+
+                    self.write_config_u32(offset,    !0);
+                    self.write_config_u32(offset_hi, !0);
+                    let mask_lo = self.read_config_u32(offset) & 0xffff_fff0;
+                    let mask_hi = self.read_config_u32(offset_hi);
+                    self.write_config_u32(offset,    orig);
+                    self.write_config_u32(offset_hi, orig_hi);
+
+                    let mask = ((mask_hi as u64) << 32) | mask_lo as u64;
+                    if mask == 0 { 0 } else { (!mask).wrapping_add(1) }
+                }
+
+                // Reserved BAR type, we don't know how to size it
+                Err(_) => 0,
+            }
+        };
+
+        // Restore the command register
+        self.write_config_u32(0x04, command);
+
+        size
+    }
+
+    /// Determine the size (in bytes) of the expansion ROM of the device.
+    /// Returns zero if the device does not implement an expansion ROM.
+    ///
+    /// Like `bar_size` decoding is disabled while the ROM BAR is being sized
+    /// and the original register values are restored prior to returning.
+    pub unsafe fn rom_size(&self) -> u64 {
+        // Save the command register and disable memory and I/O decoding
+        let command = self.read_config_u32(0x04) & 0xffff;
+        self.write_config_u32(0x04, command & !0x3);
+
+        // Bits 31:11 hold the address, write all ones to them with the ROM
+        // disabled and see which bits stuck
+        let orig = self.read_config_u32(0x30);
+        self.write_config_u32(0x30, 0xffff_f800);
+        let mask = self.read_config_u32(0x30) & 0xffff_f800;
+        self.write_config_u32(0x30, orig);
+
+        // Restore the command register
+        self.write_config_u32(0x04, command);
+
+        if mask == 0 { 0 } else { (!mask) as u64 + 1 }
+    }
+
+    /// Invoke `func` with the contents of the expansion ROM of the device
+    ///
+    /// The ROM and memory space decoding are enabled for the duration of
+    /// `func`, after which the ROM BAR and the command register are restored.
+    /// Some devices share an address decoder between the ROM and their other
+    /// BARs, thus the other BARs must not be accessed during `func`. Each call
+    /// maps the ROM into virtual memory again, the mappings are never freed.
+    ///
+    /// Returns `None` if the device has no expansion ROM or if the firmware
+    /// did not assign it an address.
+    pub unsafe fn with_rom<F, R>(&self, func: F) -> Option<R>
+            where F: FnOnce(&[u8]) -> R {
+        // Get the size of the ROM
+        let size = self.rom_size();
+        if size == 0 {
+            return None;
+        }
+
+        // Get the address the firmware assigned to the ROM
+        let orig = self.read_config_u32(0x30);
+        let addr = (orig & 0xffff_f800) as u64;
+        if addr == 0 {
+            return None;
+        }
+
+        // Map in the ROM
+        let vaddr = mm::try_map_mmio(PhysAddr(addr), size).ok()?;
+
+        // Enable the ROM and memory space decoding
+        let command = self.read_config_u32(0x04) & 0xffff;
+        self.write_config_u32(0x30, orig | 1);
+        self.write_config_u32(0x04, command | 0x2);
+
+        let ret = func(core::slice::from_raw_parts(
+            vaddr.0 as *const u8, size as usize));
+
+        // Restore the ROM BAR and the command register
+        self.write_config_u32(0x30, orig);
+        self.write_config_u32(0x04, command);
+
+        Some(ret)
+    }
+
+    /// Read the entire expansion ROM of the device into memory, see
+    /// `with_rom`. The ROM is disabled again once it's read.
+    ///
+    /// The contents are returned as-is, callers should check for the
+    /// `0x55 0xaa` signature of each image themselves.
+    pub unsafe fn read_option_rom(&self) -> Option<Vec<u8>> {
+        self.with_rom(|rom| rom.to_vec())
+    }
+
+    /// Read the images of the expansion ROM of the device which carry a
+    /// valid `0x55 0xaa` signature and a PCI data structure of our vendor,
+    /// see `with_rom`. The images are returned back to back, up to the image
+    /// flagged as the last one or the first invalid one.
+    ///
+    /// Returns `None` if the device has no ROM or its first image is not
+    /// valid.
+    pub unsafe fn read_rom_images(&self) -> Option<Vec<u8>> {
+        let vendor_id = self.config.header.vendor_id;
+
+        self.with_rom(|rom| {
+            let mut len = 0;
+            while let Some((size, last)) =
+                    rom_image(&rom[len..], vendor_id) {
+                len += size;
+                if last {
+                    break;
+                }
+            }
+
+            if len == 0 { None } else { Some(rom[..len].to_vec()) }
+        }).and_then(|x| x)
+    }
+
+    /// Decode BAR `index` of the device. Unlike `PciDevice::bar` this tells
+    /// apart BARs which are unimplemented from BARs which are implemented
+    /// but currently programmed to base 0, by sizing BARs which read as 0.
+    /// A BAR which reads back 0 after writing all ones is unimplemented and
+    /// gives `None`, a BAR which reports a size is returned even at base 0.
+    ///
+    /// See `bar_size` for the effects of sizing the BAR.
+    pub unsafe fn bar(&self, index: usize) -> Option<Bar> {
+        self.decode_bar(index).map(|(bar, _)| bar)
+    }
+
+    /// Implementation of `bar`, also returning the size of the BAR if it had
+    /// to be sized to tell whether it's implemented
+    unsafe fn decode_bar(&self, index: usize) -> Option<(Bar, Option<u64>)> {
+        let bar = self.config.decode_bar(index)?;
+        match bar {
+            Bar::Memory { addr: 0, .. } | Bar::Io { port: 0 } => {
+                match self.bar_size(index) {
+                    0    => None,
+                    size => Some((bar, Some(size))),
+                }
+            }
+            _ => Some((bar, None)),
+        }
+    }
+
+    /// Get BAR `index` and its size, falling back to the resource the
+    /// enhanced allocation capability gives in place of the BAR if the BAR
+    /// is not populated
+    unsafe fn bar_or_ea(&self, index: usize) -> Option<(Bar, u64)> {
+        match self.config.bar(index) {
+            Some(bar) => Some((bar, self.bar_size(index))),
+            None      => self.ea_bar(index),
+        }
+    }
+
+    /// Decode and size all implemented BARs of the device, in order. 64-bit
+    /// BARs are returned once, under the index of their low half.
+    ///
+    /// See `bar_size` for the effects of sizing the BARs.
+    pub unsafe fn bars(&self) -> Vec<SizedBar> {
+        let mut ret = Vec::new();
+
+        let mut index = 0;
+        while index < 6 {
+            let bar = self.decode_bar(index);
+
+            // Skip over the high half of 64-bit BARs
+            let next = match bar {
+                Some((Bar::Memory { ty: BarType::Bits64, .. }, _)) =>
+                    index + 2,
+                _ => index + 1,
+            };
+
+            // Only size the BAR if `decode_bar` didn't already have to
+            if let Some((bar, size)) = bar {
+                let size = size.unwrap_or_else(|| self.bar_size(index));
+                ret.push(SizedBar { index, bar, size });
+            }
+
+            index = next;
+        }
+
+        ret
+    }
+
+    /// Map in the memory BAR `index` as a block of registers. Returns `None`
+    /// if the BAR is not populated, is not a memory BAR, is not implemented,
+    /// or if its range can't be mapped in. The reason a populated BAR could
+    /// not be mapped is logged.
+    ///
+    /// Devices using enhanced allocation rather than BARs have the memory
+    /// resource standing in for BAR `index` mapped instead.
+    pub unsafe fn map_bar(&self, index: usize) -> Option<RegisterBlock> {
+        let (bar, size) = self.bar_or_ea(index)?;
+        RegisterBlock::new(bar, size).map_err(|err| {
+            print!("PCI device | {:02x}:{:02x}.{} | BAR{} can't be mapped: \
+                    {:?}\n",
+                   self.addr.bus, self.addr.device, self.addr.function,
+                   index, err);
+        }).ok()
+    }
+
+    /// Get bounds-checked access to the I/O BAR `index`. Returns `None` if
+    /// the BAR is not present or is not an I/O BAR. Like `map_bar` this
+    /// falls back to enhanced allocation.
+    pub unsafe fn io_bar(&self, index: usize) -> Option<IoPort> {
+        let (bar, size) = self.bar_or_ea(index)?;
+        IoPort::new(bar, size)
+    }
+}
+
+/// `PciDevice` must mirror exactly the raw configuration header decoded by
+/// `PciDevice::from_raw`, this fails to compile if a field change breaks that
+const _: [(); PCI_CONFIG_DWORDS * size_of::<u32>()] =
+    [(); size_of::<PciDevice>()];
+
+impl PciDevice {
+    /// Decode the raw configuration space header `config`, as read dword by
+    /// dword from the start of configuration space
+    ///
+    /// The fields are extracted from the register values rather than by
+    /// reinterpreting the memory of `config`, thus this does not depend on
+    /// the byte order of the host.
+    pub fn from_raw(config: &[u32; PCI_CONFIG_DWORDS]) -> Self {
+        let byte = |reg: usize, idx: usize| (config[reg] >> (idx * 8)) as u8;
+        let word = |reg: usize, idx: usize| (config[reg] >> (idx * 16)) as u16;
+
+        // The reserved bytes are the upper 3 bytes of the capability pointer
+        // register and the register after it
+        let mut reserved = [0u8; 7];
+        reserved[..3].copy_from_slice(&config[13].to_le_bytes()[1..]);
+        reserved[3..].copy_from_slice(&config[14].to_le_bytes());
+
+        PciDevice {
+            header: PciHeader {
+                vendor_id:       word(0, 0),
+                device_id:       word(0, 1),
+                command:         word(1, 0),
+                status:          word(1, 1),
+                revision:        byte(2, 0),
+                prog_if:         byte(2, 1),
+                subclass:        byte(2, 2),
+                class:           byte(2, 3),
+                cache_line_size: byte(3, 0),
+                latency_timer:   byte(3, 1),
+                header_type:     byte(3, 2),
+                bist:            byte(3, 3),
+            },
+            bar0:                  config[4],
+            bar1:                  config[5],
+            bar2:                  config[6],
+            bar3:                  config[7],
+            bar4:                  config[8],
+            bar5:                  config[9],
+            cardbus_cis_pointer:   config[10],
+            subsystem_vendor_id:   word(11, 0),
+            subsystem_device_id:   word(11, 1),
+            expansion_rom_address: config[12],
+            capabilities:          byte(13, 0),
+            reserved,
+            interrupt_line:        byte(15, 0),
+            interrupt_pin:         byte(15, 1),
+            min_grant:             byte(15, 2),
+            max_latency:           byte(15, 3),
+        }
+    }
+
+    /// Returns `true` if the device has vendor ID `vid` and device ID `did`.
+    /// Each of the subsystem vendor ID `svid` and subsystem device ID `sdid`
+    /// which is `Some` must match as well.
+    pub fn matches(&self, vid: u16, did: u16, svid: Option<u16>,
+                   sdid: Option<u16>) -> bool {
+        self.header.vendor_id == vid && self.header.device_id == did &&
+            svid.map(|x| x == self.subsystem_vendor_id).unwrap_or(true) &&
+            sdid.map(|x| x == self.subsystem_device_id).unwrap_or(true)
+    }
+
+    /// Class code of the device
+    pub fn class_code(&self) -> ClassCode {
+        ClassCode::from_header(&self.header)
+    }
+
+    /// Silicon revision of the device
+    pub fn revision(&self) -> u8 {
+        self.header.revision
+    }
+
+    /// Returns `true` if the device has vendor ID `vid` and device ID `did`
+    /// and its revision is within `revisions`, eg. `2..=u8::MAX` for
+    /// revision 2 and later
+    pub fn revision_matches(&self, vid: u16, did: u16,
+                            revisions: RangeInclusive<u8>) -> bool {
+        self.matches(vid, did, None, None) &&
+            revisions.contains(&self.revision())
+    }
+
+    /// Get the raw value of BAR `index`
+    fn raw_bar(&self, index: usize) -> u32 {
+        match index {
+            0 => self.bar0,
+            1 => self.bar1,
+            2 => self.bar2,
+            3 => self.bar3,
+            4 => self.bar4,
+            5 => self.bar5,
+            _ => panic!("Invalid BAR index"),
+        }
+    }
+
+    /// Decode BAR `index` from the configuration space. Returns `None` if the
+    /// BAR is unpopulated (zero), is a memory BAR of a reserved type, or if
+    /// `index` refers to the high half of a 64-bit BAR which was not present.
+    ///
+    /// A base of zero is ambiguous, the BAR may be unimplemented or may be
+    /// implemented but not assigned an address yet. Both give `None` here,
+    /// use `ProbedDevice::bar` to tell them apart.
+    ///
+    /// 64-bit memory BARs combine BAR `index` and BAR `index + 1`, thus
+    /// `index` must refer to the low half of the BAR.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        match self.decode_bar(index)? {
+            Bar::Memory { addr: 0, .. } | Bar::Io { port: 0 } => None,
+            bar => Some(bar),
+        }
+    }
+
+    /// Decode BAR `index` from the configuration space like `bar`, but
+    /// including BARs with a base of zero
+    fn decode_bar(&self, index: usize) -> Option<Bar> {
+        let bar = self.raw_bar(index);
+
+        if (bar & 1) != 0 {
+            // I/O-space BAR, the low 2 bits are not part of the address
+            Some(Bar::Io { port: (bar & 0xffff_fffc) as u16 })
+        } else {
+            // Memory-space BAR, reserved types are not supported
+            let ty           = BarType::try_from((bar >> 1) & 3).ok()?;
+            let prefetchable = (bar & (1 << 3)) != 0;
+
+            // Compute the address, the low 4 bits are not part of the address
+            let addr = match ty {
+                BarType::Bits32 => (bar & 0xffff_fff0) as u64,
+                BarType::Bits64 => {
+                    // The high 32 bits of the address are in the next BAR
+                    if index >= 5 {
+                        return None;
+                    }
+
+                    ((self.raw_bar(index + 1) as u64) << 32) |
+                        (bar & 0xffff_fff0) as u64
+                }
+            };
+
+            Some(Bar::Memory { addr, prefetchable, ty })
+        }
+    }
+}
+
+/// Driver claims and configuration spaces used and produced while binding
+/// drivers to the PCI functions
+struct ProbeState {
+    /// Driver claims from the previous boot, `None` if there were none or if
+    /// the set of drivers has changed
+    previous_claims: Option<PciClaims>,
+
+    /// Configuration spaces read during the previous boot
+    previous_configs: Option<PciConfigs>,
+
+    /// Claims made during this boot
+    claims: PciClaims,
+
+    /// Configuration spaces read during this boot
+    configs: PciConfigs,
+
+    /// Set if the state was taken out of the persist store, and thus must be
+    /// saved back to it. If the persist store was in use (eg. by a reentrant
+    /// call on another core) we probe without it rather than waiting on it
+    /// forever.
+    persist: bool,
+}
+
+impl ProbeState {
+    /// Take the claims and configuration spaces of the previous boot out of
+    /// the persist store
+    unsafe fn take() -> Self {
+        let (previous_claims, previous_configs, persist) =
+            match Self::try_take() {
+                Some((claims, configs)) => (claims, configs, true),
+                None => {
+                    print!("PCI: persisted claims are in use, probing \
+                            without them\n");
+                    (None, None, false)
+                }
+            };
+
+        ProbeState {
+            // Discard the driver claims from the previous boot if the set of
+            // drivers has changed
+            previous_claims: previous_claims
+                .filter(|x| x.drivers == drivers_signature()),
+            previous_configs,
+            claims:  PciClaims::new(drivers_signature()),
+            configs: PciConfigs::new(),
+            persist,
+        }
+    }
+
+    /// Take the claims and configuration spaces out of the persist store,
+    /// `None` if either of them is locked
+    unsafe fn try_take() -> Option<(Option<PciClaims>, Option<PciConfigs>)> {
+        let mut claims  = core!().persist_store().pci_claims.try_lock()?;
+        let mut configs = core!().persist_store().pci_configs.try_lock()?;
+        Some((claims.take(), configs.take()))
+    }
+
+    /// Take the claims and configuration spaces already saved during this
+    /// boot out of the persist store, such that more can be added to them
+    unsafe fn resume() -> Self {
+        let (claims, configs, persist) = match Self::try_take() {
+            Some((claims, configs)) => (claims, configs, true),
+            None => {
+                print!("PCI: persisted claims are in use, probing without \
+                        them\n");
+                (None, None, false)
+            }
+        };
+
+        ProbeState {
+            previous_claims:  None,
+            previous_configs: None,
+            claims: claims.filter(|x| x.drivers == drivers_signature())
+                .unwrap_or_else(|| PciClaims::new(drivers_signature())),
+            configs: configs.unwrap_or_else(PciConfigs::new),
+            persist,
+        }
+    }
+
+    /// Save the claims and configuration spaces of this boot to the persist
+    /// store for the next boot. Nothing is saved if the state wasn't taken
+    /// out of the persist store.
+    unsafe fn save(&self) {
+        if !self.persist {
+            return;
+        }
+
+        *core!().persist_store().pci_claims.lock()  = Some(self.claims);
+        *core!().persist_store().pci_configs.lock() = Some(self.configs);
+    }
+}
+
+/// Time spent in and devices found by the last PCI initialization, see
+/// `stats`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PciStats {
+    /// TSC cycles spent setting up configuration space access and finding
+    /// the present functions. This is short when the enumeration was
+    /// persisted by a previous boot.
+    pub scan_cycles: u64,
+
+    /// TSC cycles spent probing drivers for the present functions
+    pub bind_cycles: u64,
+
+    /// Number of functions found
+    pub present: usize,
+
+    /// Number of devices claimed by a driver
+    pub claimed: usize,
+}
+
+/// Statistics of the last PCI initialization
+static STATS: LockCell<PciStats, LockInterrupts> = LockCell::new(PciStats {
+    scan_cycles: 0,
+    bind_cycles: 0,
+    present:     0,
+    claimed:     0,
+});
+
+/// Number of devices claimed by drivers probed on each core, indexed by the
+/// core ID
+static CORE_CLAIMS: [AtomicU32; acpi::MAX_CORES] =
+    [AtomicU32::new(0); acpi::MAX_CORES];
+
+/// Get the time spent in and devices found by the last `enumerate`,
+/// `bind_drivers` or `init_parallel`
+pub fn stats() -> PciStats {
+    *STATS.lock()
+}
+
+/// Get the number of devices claimed by drivers probed on core `core`. Only
+/// `init_parallel` probes drivers on cores other than the BSP.
+pub fn core_claims(core: u32) -> u32 {
+    CORE_CLAIMS.get(core as usize)
+        .map(|x| x.load(Ordering::Relaxed)).unwrap_or(0)
+}
+
+/// Count the functions present in the presence bitmap `pci_enum`
+fn count_present(pci_enum: &[u64]) -> usize {
+    pci_enum.iter().map(|x| x.count_ones() as usize).sum()
+}
+
+/// Enumerate all PCI devices on the system and initialize drivers for any
+/// supported devices. This is `enumerate` followed by `bind_drivers`.
+///
+/// This must only be called on the BSP. See `init_parallel` for spreading the
+/// work across all cores.
+///
+/// Returns the number of devices which were claimed by a driver.
+pub unsafe fn init() -> Result<usize, PciInitError> {
+    let pci_devices = find_functions()?;
+    Ok(bind_functions(&pci_devices))
+}
+
+/// Set up access to configuration space and find all PCI functions on the
+/// system, without binding any drivers to them yet
+///
+/// The enumeration is persisted across soft reboots, thus the hardware is
+/// only walked on the first boot. This must only be called on the BSP.
+pub unsafe fn enumerate() -> Result<(), PciInitError> {
+    find_functions().map(|_| ())
+}
+
+/// Implementation of `enumerate`, returning the presence bitmap
+///
+/// If the persisted enumeration is locked (eg. by a reentrant call on
+/// another core) the hierarchy is walked without persisting the result,
+/// rather than waiting on the lock forever.
+unsafe fn find_functions() -> Result<[u64; BITMAP_WORDS], PciInitError> {
+    let start = cpu::rdtsc();
+
+    // Locate the memory-mapped configuration space, if there is one
+    ecam::init()?;
+
+    // Make sure we can actually reach configuration space
+    check_config_access()?;
+
+    // Get access to the persisted PCI device enumeration
+    let pci_devices =
+        if let Some(mut pci_devices) =
+                core!().persist_store().pci_devices.try_lock() {
+            // Enumerate the PCI devices if we have not done so yet
+            *pci_devices.get_or_insert_with(|| scan_hierarchy())
+        } else {
+            print!("PCI: persisted enumeration is in use, enumerating \
+                    without it\n");
+            scan_hierarchy()
+        };
+
+    let mut stats = STATS.lock();
+    stats.scan_cycles = cpu::rdtsc() - start;
+    stats.present     = count_present(&pci_devices);
+
+    Ok(pci_devices)
+}
+
+/// Probe drivers for every function found by `enumerate`, registering the
+/// devices they claim
+///
+/// This must only be called on the BSP, after `enumerate`. Drivers can no
+/// longer be registered once this has been called.
+///
+/// Returns the number of devices which were claimed by a driver.
+pub unsafe fn bind_drivers() -> usize {
+    // Get access to the PCI device enumeration bitmap
+    let pci_devices = core!().persist_store().pci_devices.lock()
+        .expect("PCI drivers bound before enumeration");
+
+    bind_functions(&pci_devices)
+}
+
+/// Implementation of `bind_drivers`, probing the functions in the presence
+/// bitmap `pci_devices`
+unsafe fn bind_functions(pci_devices: &[u64; BITMAP_WORDS]) -> usize {
+    let start = cpu::rdtsc();
+
+    // The set of drivers is final now
+    DRIVERS_SEALED.store(true, Ordering::SeqCst);
+
+    // Check if DMA is being remapped
+    iommu::init();
+
+    // Get the state from the previous boot
+    let state = LockCell::new(Some(ProbeState::take()));
+
+    for (idx, &pci_map) in pci_devices.iter().enumerate() {
+        // No devices here, go to the next `u64`
+        if pci_map == 0 {
+            continue;
+        }
+
+        // There is at least 1 device in this set
+        for bit in 0..64 {
+            // If the bit is not set, no device here, skip it
+            if (pci_map & (1u64 << bit)) == 0 { continue; }
+
+            // Bind a driver to the function
+            init_function(PciAddress::from_index((idx * 64) | bit), &state);
+        }
+    }
+
+    // Keep the devices in a stable order
+    let claimed = {
+        let mut registry = DEVICES.lock();
+        registry.sort();
+        registry.devices.len()
+    };
+
+    // Save the driver claims and configuration spaces for the next boot
+    state.lock().take().unwrap().save();
+
+    let mut stats = STATS.lock();
+    stats.bind_cycles = cpu::rdtsc() - start;
+    stats.claimed     = claimed;
+    drop(stats);
+
+    // Report the devices which will not be working
+    log_unclaimed_devices();
+
+    claimed
+}
+
+/// Walk the PCI hierarchy and return a bitmap of all present functions,
+/// indexed by `PciAddress::index`
+unsafe fn scan_hierarchy() -> [u64; BITMAP_WORDS] {
+    // Bitmap of present PCI devices
+    let mut pci_enum = [0u64; BITMAP_WORDS];
+
+    // Tracks which buses we have already scanned
+    let mut scanned = [false; PCI_MAX_BUS];
+
+    // Walk the hierarchy starting at the root bus, recursing into the buses
+    // behind bridges as we find them
+    enumerate_bus(0, &mut pci_enum, &mut scanned);
+
+    // If the host bridge is a multifunction device, each function is the host
+    // bridge for the root bus with the same number as the function
+    if (read_config_u32(0, 0, 0, 0xc) >> 16) & 0x80 != 0 {
+        for function in 1..8 {
+            if device_present(read_config_u32(0, 0, function, 0)) {
+                enumerate_bus(function, &mut pci_enum, &mut scanned);
+            }
+        }
+    }
+
+    // Cross check the walk against the exhaustive sweep when debugging
+    if pci_debug() {
+        verify_hierarchy_scan(&pci_enum);
+    }
+
+    pci_enum
+}
+
+/// Sweep every possible function with `function_exists` and log where the
+/// result differs from the presence bitmap `pci_enum` built by walking the
+/// hierarchy
+///
+/// The walk only visits buses behind bridges and skips the other functions
+/// of absent and single function devices, this catches it missing anything.
+/// Functions on buses not decoded by any bridge are expected to show up as
+/// only found by the sweep. This issues configuration reads for each of the
+/// 65536 functions, thus it's only done with `set_pci_debug`.
+unsafe fn verify_hierarchy_scan(pci_enum: &[u64; BITMAP_WORDS]) {
+    let mut mismatches = 0;
+
+    for idx in 0..PciAddress::COUNT {
+        let addr = PciAddress::from_index(idx);
+        let (word, bit) = bitmap_index(addr);
+
+        let walked = (pci_enum[word] & bit) != 0;
+        let swept  = function_exists(addr);
+        if walked != swept {
+            print!("PCI device | {:02x}:{:02x}.{} | {} by the hierarchy \
+                    walk, {} by the exhaustive sweep\n",
+                   addr.bus, addr.device, addr.function,
+                   if walked { "found" } else { "missed" },
+                   if swept { "found" } else { "missed" });
+            mismatches += 1;
+        }
+    }
+
+    print!("PCI: hierarchy walk and exhaustive sweep differ in {} \
+            functions\n", mismatches);
+}
+
+/// Re-scan the PCI hierarchy for functions which appeared since `init` (eg.
+/// devices hot-added to a VM) and bind drivers to them
+///
+/// Functions which were already known are left untouched, even if they have
+/// since disappeared. The persisted enumeration, claims, and configuration
+/// spaces are updated to include the new functions.
+///
+/// The persisted enumeration is only locked to copy it out and to merge the
+/// new functions back in, not while probing, as probes may look it up with
+/// `is_present`. If it's in use elsewhere nothing is rescanned, rather than
+/// probing every known function again.
+///
+/// Returns the number of new functions found.
+pub unsafe fn rescan() -> usize {
+    // Copy out the persisted PCI device enumeration
+    let known = match core!().persist_store().pci_devices.try_lock() {
+        Some(pci_devices) => pci_devices.unwrap_or([0u64; BITMAP_WORDS]),
+        None => {
+            print!("PCI: persisted enumeration is in use, not rescanning\n");
+            return 0;
+        }
+    };
+
+    // Walk the hierarchy again
+    let present = scan_hierarchy();
+
+    // Continue adding to the claims and configuration spaces of this boot
+    let state = LockCell::new(Some(ProbeState::resume()));
+
+    let mut found = 0;
+    for (idx, (&old, &new)) in known.iter().zip(&present).enumerate() {
+        // Only look at functions we have not seen before
+        let pci_map = new & !old;
+        if pci_map == 0 {
+            continue;
+        }
+
+        for bit in 0..64 {
+            if (pci_map & (1u64 << bit)) == 0 { continue; }
+
+            // Bind a driver to the function
+            init_function(PciAddress::from_index((idx * 64) | bit), &state);
+            found += 1;
+        }
+    }
+
+    // Slot the new devices in with the existing ones
+    DEVICES.lock().sort();
+
+    // Save the state with the new functions included
+    state.lock().take().unwrap().save();
+
+    // Merge the new functions into the enumeration, which may have gained
+    // functions from elsewhere (eg. `probe_one`) while we were probing
+    if let Some(mut pci_devices) =
+            core!().persist_store().pci_devices.try_lock() {
+        let merged = pci_devices.get_or_insert(known);
+        for (merged, &present) in merged.iter_mut().zip(&present) {
+            *merged |= present;
+        }
+    } else {
+        print!("PCI: persisted enumeration is in use, {} new functions not \
+                persisted\n", found);
+    }
+
+    found
+}
+
+/// Bind a driver to the single function at `addr`, without walking the rest
+/// of the hierarchy (eg. after a hotplug event for a known location)
+///
+/// If a driver already claimed the function its device is returned as-is.
+/// The function is added to the persisted enumeration, claims and
+/// configuration spaces like it would be by `rescan`.
+///
+/// Returns the device the driver created, `None` if the function is not
+/// present or no driver claimed it.
+pub unsafe fn probe_one(addr: PciAddress) -> Option<Arc<dyn Device>> {
+    // Nothing to do if someone already handles the function
+    if let Some(device) = DEVICES.lock().get(addr) {
+        return Some(device);
+    }
+
+    // Make sure the function is there, and isn't an alias of function 0
+    if !function_exists(addr) {
+        return None;
+    }
+
+    // Bind a driver, adding to the claims and configuration spaces of this
+    // boot
+    let state = LockCell::new(Some(ProbeState::resume()));
+    init_function(addr, &state);
+    state.lock().take().unwrap().save();
+
+    // Remember the function, if we have an enumeration to add it to
+    if let Some(pci_devices) =
+            core!().persist_store().pci_devices.lock().as_mut() {
+        let (word, bit) = bitmap_index(addr);
+        pci_devices[word] |= bit;
+    }
+
+    // Slot the new device in with the existing ones
+    let mut devices = DEVICES.lock();
+    devices.sort();
+    devices.get(addr)
+}
+
+/// Next bus to be scanned by `init_parallel`
+static PARALLEL_NEXT_BUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Next word of `PARALLEL_ENUM` to be probed by `init_parallel`
+static PARALLEL_NEXT_WORD: AtomicUsize = AtomicUsize::new(0);
+
+/// Presence bitmap shared by the cores during `init_parallel`, laid out the
+/// same way as the persisted `pci_devices` bitmap
+static PARALLEL_ENUM: [AtomicU64; BITMAP_WORDS] =
+    [AtomicU64::new(0); BITMAP_WORDS];
+
+/// Set if the presence bitmap was not persisted and `init_parallel` must scan
+/// the buses
+static PARALLEL_SCAN: AtomicBool = AtomicBool::new(false);
+
+/// Driver claims and configuration spaces shared by the cores during
+/// `init_parallel`
+static PARALLEL_STATE: LockCell<Option<ProbeState>, LockInterrupts> =
+    LockCell::new(None);
+
+/// Number of cores which have arrived at each of the barriers in
+/// `init_parallel`
+static PARALLEL_BARRIERS: [AtomicU32; 3] = [AtomicU32::new(0); 3];
+
+/// Wait until all cores have arrived at barrier `barrier`
+fn parallel_barrier(barrier: usize) {
+    PARALLEL_BARRIERS[barrier].fetch_add(1, Ordering::SeqCst);
+    while PARALLEL_BARRIERS[barrier].load(Ordering::SeqCst) !=
+            acpi::num_cores() {
+        spin_loop_hint();
+    }
+}
+
+/// Enumerate all PCI devices on the system and initialize drivers for any
+/// supported devices, with the work spread across all cores. This is an
+/// opt-in alternative to `init`.
+///
+/// This must be called exactly once on every core, once every core has come
+/// online. It returns on each core once all devices have been initialized.
+///
+/// Configuration space accesses are still serialized, thus this mostly speeds
+/// up enumeration of systems where the driver probes are slow. Unlike `init`,
+/// an unpersisted bus scan checks every bus rather than following bridges.
+pub unsafe fn init_parallel() {
+    let start = cpu::rdtsc();
+
+    if core!().id == 0 {
+        // The set of drivers is final now
+        DRIVERS_SEALED.store(true, Ordering::SeqCst);
+
+        // Locate the memory-mapped configuration space, if there is one,
+        // and make sure we can actually reach configuration space. All cores
+        // are waiting on us, so just report problems and carry on with
+        // whatever we can reach.
+        if let Err(err) = ecam::init().and_then(|_| check_config_access()) {
+            print!("PCI: initialization failed: {:?}\n", err);
+        }
+
+        // Check if DMA is being remapped
+        iommu::init();
+
+        // Get the persisted enumeration, if there is one
+        if let Some(pci_devices) =
+                core!().persist_store().pci_devices.lock().as_ref() {
+            for (word, &pci_map) in PARALLEL_ENUM.iter().zip(pci_devices) {
+                word.store(pci_map, Ordering::SeqCst);
+            }
+        } else {
+            PARALLEL_SCAN.store(true, Ordering::SeqCst);
+        }
+
+        // Get the state from the previous boot
+        *PARALLEL_STATE.lock() = Some(ProbeState::take());
+    }
+    parallel_barrier(0);
+
+    if PARALLEL_SCAN.load(Ordering::SeqCst) {
+        // Scan buses until there are none left
+        loop {
+            let bus = PARALLEL_NEXT_BUS.fetch_add(1, Ordering::SeqCst);
+            if bus > u8::MAX as usize {
+                break;
+            }
+
+            // Mark each function found on the bus as present
+            scan_bus(bus as u8, |device, function, _| {
+                let addr = PciAddress { bus: bus as u8, device, function };
+                let (word, bit) = bitmap_index(addr);
+                PARALLEL_ENUM[word].fetch_or(bit, Ordering::SeqCst);
+            });
+        }
+    }
+    parallel_barrier(1);
+
+    let bind_start = cpu::rdtsc();
+
+    // Bind drivers to the functions until there are none left
+    loop {
+        let idx = PARALLEL_NEXT_WORD.fetch_add(1, Ordering::SeqCst);
+        if idx >= PARALLEL_ENUM.len() {
+            break;
+        }
+
+        let pci_map = PARALLEL_ENUM[idx].load(Ordering::SeqCst);
+        for bit in 0..64 {
+            if (pci_map & (1u64 << bit)) != 0 {
+                init_function(PciAddress::from_index((idx * 64) | bit),
+                              &PARALLEL_STATE);
+            }
+        }
+    }
+    parallel_barrier(2);
+
+    if core!().id == 0 {
+        // Save the enumeration if we performed one
+        if PARALLEL_SCAN.load(Ordering::SeqCst) {
+            let mut pci_enum = [0u64; BITMAP_WORDS];
+            for (pci_map, word) in pci_enum.iter_mut().zip(&PARALLEL_ENUM) {
+                *pci_map = word.load(Ordering::SeqCst);
+            }
+            *core!().persist_store().pci_devices.lock() = Some(pci_enum);
+        }
+
+        // The devices were probed in whatever order the cores got to them,
+        // put them in a stable order
+        let claimed = {
+            let mut registry = DEVICES.lock();
+            registry.sort();
+            registry.devices.len()
+        };
+
+        // Save the driver claims and configuration spaces for the next boot
+        PARALLEL_STATE.lock().take().unwrap().save();
+
+        let present = PARALLEL_ENUM.iter()
+            .map(|x| x.load(Ordering::SeqCst).count_ones() as usize).sum();
+        *STATS.lock() = PciStats {
+            scan_cycles: bind_start - start,
+            bind_cycles: cpu::rdtsc() - bind_start,
+            present,
+            claimed,
+        };
+
+        // Report the devices which will not be working
+        log_unclaimed_devices();
+    }
+}
+
+/// Read the configuration space of the present function at `addr` and
+/// attempt to bind a driver to it, recording the results in `state`
+unsafe fn init_function(addr: PciAddress,
+                        state: &LockCell<Option<ProbeState>, LockInterrupts>) {
+    // Persisted state is keyed by the index in the presence bitmap
+    let bdf = addr.index() as u16;
+
+    // Look up what we know about this function from the previous boot. The
+    // state is not held while probing such that other cores can make
+    // progress.
+    let (cached, previous) = {
+        let state = state.lock();
+        let state = state.as_ref().unwrap();
+        (state.previous_configs.as_ref().and_then(|x| x.find(bdf)).copied(),
+         state.previous_claims.as_ref().and_then(|x| x.find(bdf)).copied())
+    };
+
+    // Read the PCI configuration, reusing the copy from the previous boot if
+    // we have one
+    let mut config = read_config_header(addr, cached.as_ref());
+
+    // If we park unclaimed devices a driver may now want a device we parked
+    // last boot, bring it back to D0. Leaving D3hot may reset the device, so
+    // the configuration must be read again.
+    if PARK_UNCLAIMED_DEVICES &&
+            previous.map(|x| x.driver_id) != Some(PCI_NO_DRIVER) &&
+            ((config[3] >> 16) & 0x7f) == 0 {
+        let device = ProbedDevice::new(&HARDWARE, addr,
+                                       PciDevice::from_raw(&config));
+
+        if wake_device(&device) {
+            config = read_config_header(addr, None);
+        }
+    }
+
+    // Cache the configuration for the next boot. If we run out of room the
+    // device will just be read again next boot.
+    state.lock().as_mut().unwrap().configs
+        .push(PciConfig { bdf, config });
+
+    // Convert the device to our `PciDevice` structure
+    let device = PciDevice::from_raw(&config);
+
+    // Only bind drivers to devices, the layout of the header is in the low 7
+    // bits of the header type
+    match device.header.header_type & 0x7f {
+        // Regular device
+        0 => {}
+
+        // PCI-to-PCI bridge, the buses behind it were already found during
+        // enumeration
+        1 => return,
+
+        // CardBus bridge, we don't support CardBus
+        2 => {
+            print!("PCI device | {:02x}:{:02x}.{} | CardBus bridge not \
+                    supported, skipping\n",
+                   addr.bus, addr.device, addr.function);
+            return;
+        }
+
+        // Nothing else is defined by the spec
+        other => {
+            print!("PCI device | {:02x}:{:02x}.{} | unknown header type \
+                    {:#x}, skipping\n",
+                   addr.bus, addr.device, addr.function, other);
+            return;
+        }
+    }
+
+    // Save the location along with the configuration space
+    let mut device = ProbedDevice::new(&HARDWARE, addr, device);
+
+    // Work around known problems with the device before drivers see it
+    quirks::apply(&mut device);
+
+    // Bind a driver to the device
+    let claimed = claim_device(&device, previous, state);
+
+    // Bring up the virtual functions of physical functions we're using
+    if ENABLE_SRIOV && claimed {
+        sriov::init_virtual_functions(&device, state);
+    }
+}
+
+/// Find a driver for `device`, given what the previous boot recorded for its
+/// location in `previous`, and record the resulting claim in `state`
+///
+/// Returns `true` if a driver claimed the device.
+///
+/// If a driver already claimed a device at this location (eg. `init` ran
+/// twice) the device is left alone, as probing it again would set it up a
+/// second time underneath the existing driver. This counts as claimed.
+unsafe fn claim_device(device: &ProbedDevice, previous: Option<PciClaim>,
+                       state: &LockCell<Option<ProbeState>, LockInterrupts>)
+        -> bool {
+    if DEVICES.lock().addrs.contains(&device.addr) {
+        print!("PCI device | {:02x}:{:02x}.{} | already claimed, not probing \
+                again\n",
+               device.addr.bus, device.addr.device, device.addr.function);
+
+        // Keep remembering who handled this device
+        if let Some(claim) = previous {
+            state.lock().as_mut().unwrap().claims.push(claim);
+        }
+        return true;
+    }
+
+    if pci_debug() {
+        print!("PCI device | {:#06x}:{:#06x} | {:#06x}:{:#06x} | {}\n",
+               device.config.header.vendor_id,
+               device.config.header.device_id,
+               device.config.subsystem_vendor_id,
+               device.config.subsystem_device_id,
+               device.config.class_code().name());
+        print_bars(device);
+    }
+
+    // Warn about links which didn't train at what the device supports, as
+    // that silently costs performance
+    if let Some(link) = device.link_caps().filter(|x| x.downgraded()) {
+        print!("PCI device | {:02x}:{:02x}.{} | link downgraded to Gen{} x{}, \
+                device supports Gen{} x{}\n",
+               device.addr.bus, device.addr.device, device.addr.function,
+               link.speed, link.width, link.max_speed, link.max_width);
+    }
+
+    // Don't let a driver bring up a device which would draw more power than
+    // we're allowed to give it. Nothing is recorded for it, so it's probed as
+    // usual next boot in case the budget changed.
+    if !within_power_budget(device) {
+        park_device(device);
+        return false;
+    }
+
+    // Check if we know which driver handled this device during the previous
+    // boot. Only trust the claim if the same device is still at this
+    // location.
+    let previous = previous.filter(|x| {
+        x.vendor_id == device.config.header.vendor_id &&
+            x.device_id == device.config.header.device_id
+    });
+
+    // Attempt to find a driver for this device
+    let driver_id = match previous {
+        // No driver handled this device last time, don't bother probing it
+        // again
+        Some(claim) if claim.driver_id == PCI_NO_DRIVER => None,
+
+        // Try the driver which handled this device last time first, falling
+        // back to probing all the drivers if it no longer wants the device
+        Some(claim) => {
+            bind_driver(device, Some(claim.driver_id))
+                .or_else(|| bind_driver(device, None))
+        }
+
+        // We don't know about this device, probe all drivers
+        None => bind_driver(device, None),
+    };
+
+    // Power down devices nobody wants
+    if PARK_UNCLAIMED_DEVICES && driver_id.is_none() {
+        park_device(device);
+    }
+
+    // Remember who handled this device. If we run out of room the device
+    // will just be probed by all drivers next boot.
+    state.lock().as_mut().unwrap().claims.push(PciClaim {
+        bdf:       device.addr.index() as u16,
+        vendor_id: device.config.header.vendor_id,
+        device_id: device.config.header.device_id,
+        driver_id: driver_id.unwrap_or(PCI_NO_DRIVER),
+    });
+
+    driver_id.is_some()
+}
+
+/// Print the decoded BARs of `device` along with their sizes, for debugging
+/// drivers. Sizing a BAR briefly disables decoding of the device.
+unsafe fn print_bars(device: &ProbedDevice) {
+    for SizedBar { index, bar, size } in device.bars() {
+        match bar {
+            Bar::Memory { addr, prefetchable, ty } => {
+                print!("PCI device | {:#06x}:{:#06x} | BAR{}: mem {:#x} size \
+                        {:#x} ({}{})\n",
+                       device.config.header.vendor_id,
+                       device.config.header.device_id,
+                       index, addr, size,
+                       if ty == BarType::Bits64 { "64-bit" } else { "32-bit" },
+                       if prefetchable { ", prefetchable" } else { "" });
+            }
+            Bar::Io { port } => {
+                print!("PCI device | {:#06x}:{:#06x} | BAR{}: io {:#x} size \
+                        {:#x}\n",
+                       device.config.header.vendor_id,
+                       device.config.header.device_id,
+                       index, port, size);
+            }
+        }
+    }
+}
+
+/// Returns `true` if `device` is allowed to be brought up under the power
+/// budget, logging the refusal otherwise
+unsafe fn within_power_budget(device: &ProbedDevice) -> bool {
+    let budget = POWER_BUDGET.load(Ordering::Relaxed);
+    if budget == !0 {
+        return true;
+    }
+
+    // Get how much power the device reports it needs when fully on
+    let consumed = match device.power_management()
+            .and_then(|pm| pm.power_consumed(PowerState::D0)) {
+        Some(consumed) => consumed,
+        None           => return true,
+    };
+
+    if consumed > budget {
+        print!("PCI device | {:02x}:{:02x}.{} | consumes {} mW in D0, over \
+                the {} mW power budget, not probing\n",
+               device.addr.bus, device.addr.device, device.addr.function,
+               consumed, budget);
+        return false;
+    }
+
+    true
+}
+
+/// Put an unclaimed `device` into D3hot, unless it's of a class the
+/// platform may still depend on
+unsafe fn park_device(device: &ProbedDevice) {
+    match device.config.header.class {
+        0x03 | 0x05 | 0x06 | 0x08 => return,
+        _ => {}
+    }
+
+    let _ = device.set_power_state(PowerState::D3Hot);
+}
+
+/// Bring `device` out of D3hot if we parked it. Returns `true` if the device
+/// changed power state.
+unsafe fn wake_device(device: &ProbedDevice) -> bool {
+    match device.power_management() {
+        Some(pm) if pm.power_state() == PowerState::D3Hot => {
+            pm.set_power_state(PowerState::D0)
+        }
+        _ => false,
+    }
+}
+
+/// Read the raw configuration space header of the function at `addr`
+///
+/// If `cached` holds the header from a previous boot and the same function is
+/// still present, the cached copy is used and only the IDs and the command
+/// and status registers are read from the device.
+///
+/// The cache only lives across soft reboots, which don't go back through the
+/// firmware, thus BARs are never reassigned underneath it. A cold boot
+/// starts without a cache and reads every header in full.
+unsafe fn read_config_header(addr: PciAddress, cached: Option<&PciConfig>)
+        -> [u32; PCI_CONFIG_DWORDS] {
+    let PciAddress { bus, device, function } = addr;
+
+    // The vendor and device IDs identify the function. The function was
+    // found by the enumeration, thus it's expected to be present.
+    let ids = read_config_u32_present(bus, device, function, 0);
+
+    if let Some(cached) = cached.filter(|x| x.config[0] == ids) {
+        let mut config = cached.config;
+
+        // Drivers modify the command register at runtime, thus it must
+        // always be read from the device
+        config[1] = read_config_u32(bus, device, function, 4);
+        return config;
+    }
+
+    // Not cached, or a different function is now at this location, read the
+    // whole header
+    let mut config = [0u32; PCI_CONFIG_DWORDS];
+    config[0] = ids;
+    for (rid, register) in config.iter_mut().enumerate().skip(1) {
+        *register = read_config_u32(bus, device, function,
+            (rid * size_of::<u32>()) as u16);
+    }
+
+    config
+}
+
+/// Attempt to find a driver for `device`, registering it in `DEVICES` if one
+/// claims it. If `only` is `Some`, only the driver with that ID is probed.
+///
+/// Returns the ID of the driver which claimed the device.
+fn bind_driver(device: &ProbedDevice, only: Option<u16>) -> Option<u16> {
+    let (id, handlers) = probe_drivers(device, only)?;
+
+    // Found a handler, go to the next function during the PCI enumeration
+    let mut devices = DEVICES.lock();
+    for handler in handlers {
+        devices.push(device.addr, handler);
+    }
+    drop(devices);
+
+    if let Some(claims) = CORE_CLAIMS.get(core!().id as usize) {
+        claims.fetch_add(1, Ordering::Relaxed);
+    }
+    Some(id)
+}
+
+/// Run the registered driver probes against the function at `addr`, with
+/// its configuration space accessed through `access` rather than the real
+/// hardware. The device is not registered in `DEVICES`.
+///
+/// This allows the probes to be driven by a synthetic (eg. fuzzer
+/// controlled) configuration space. Returns the ID of the driver which
+/// claimed the device and the devices it created.
+pub unsafe fn probe_with(access: &'static dyn ConfigAccess, addr: PciAddress)
+        -> Option<(u16, Vec<Arc<dyn Device>>)> {
+    let mut device = ProbedDevice::from_access(access, addr);
+    quirks::apply(&mut device);
+    probe_drivers(&device, None)
+}
+
+/// Location the configuration space of `fuzz_probe` is presented at
+const FUZZ_ADDR: PciAddress = PciAddress { bus: 0, device: 0, function: 0 };
+
+/// Synthetic configuration space driven by `fuzz_probe`
+static FUZZ_CONFIG: MockConfig = MockConfig::empty(FUZZ_ADDR);
+
+/// Lock held during `fuzz_probe`, as there is only one `FUZZ_CONFIG`
+static FUZZ_LOCK: LockCell<(), LockInterrupts> = LockCell::new(());
+
+/// Run every registered driver probe against the configuration space in
+/// `data`, for fuzzing the decoders and probes with arbitrary input
+///
+/// `data` is read as little-endian dwords, up to the 4 KiB of a full
+/// configuration space, and the rest of the space is zeroed. Returns `true`
+/// if a probe misbehaved by rejecting the device but leaving configuration
+/// space modified. Devices which were claimed are taken down straight away.
+///
+/// There is no unwinding in the kernel, thus a probe which panics takes the
+/// kernel down rather than being reported here, which a fuzzer watching the
+/// VM observes as a crash. A probe claiming the device accesses whatever its
+/// fuzzed BARs point at, thus this must only be run in a VM.
+pub unsafe fn fuzz_probe(data: &[u8]) -> bool {
+    let _lock = FUZZ_LOCK.lock();
+
+    // Build the configuration space out of the input
+    let mut config = [0u32; CONFIG_DWORDS];
+    for (dword, bytes) in config.iter_mut().zip(data.chunks(4)) {
+        let mut raw = [0u8; 4];
+        raw[..bytes.len()].copy_from_slice(bytes);
+        *dword = u32::from_le_bytes(raw);
+    }
+    FUZZ_CONFIG.load(&config);
+
+    if let Some((_, devices)) = probe_with(&FUZZ_CONFIG, FUZZ_ADDR) {
+        // Take the devices down like `remove_device` would
+        for device in devices {
+            device.unplug();
+            irq::free_device_vectors(&device);
+        }
+        return false;
+    }
+
+    // Everything must be as it was, the command register is restored by
+    // `probe_drivers`. The status register is not compared, writes of the
+    // command register clear it in the mock while the hardware ignores them.
+    let after = FUZZ_CONFIG.contents();
+    after[0] != config[0] || after[1] as u16 != config[1] as u16 ||
+        after[2..] != config[2..]
+}
+
+/// Attempt to find a driver for `device`. If `only` is `Some`, only the
+/// driver with that ID is probed.
+///
+/// Drivers which match the exact device are probed before the drivers which
+/// match the class code of the device.
+///
+/// Probes initialize the device when they claim it, thus we can't
+/// speculatively run the remaining probes to check for multiple matches, the
+/// first driver to claim the device wins.
+fn probe_drivers(device: &ProbedDevice, only: Option<u16>)
+        -> Option<(u16, Vec<Arc<dyn Device>>)> {
+    // Get the candidate drivers, exact matches first
+    // Copy the driver lists, such that they are not locked while probing
+    let exact = DRIVERS.lock().clone();
+    let class = CLASS_DRIVERS.lock().clone();
+
+    let drivers = exact.iter().filter(|x| x.matches(device))
+        .map(|x| (x.id, x.probe))
+        .chain(class.iter().filter(|x| x.matches(device))
+            .map(|x| (x.id, Probe::Single(x.probe))));
+
+    for (id, probe) in drivers {
+        // Skip drivers we were not asked to probe
+        if only.map(|only| only != id).unwrap_or(false) {
+            continue;
+        }
+
+        // Snapshot the command register, such that a probe which rejects the
+        // device does not leave it half enabled
+        let command = unsafe { device.command() };
+
+        let handlers = probe.run(device);
+        if handlers.is_empty() && unsafe { device.command() } != command {
+            unsafe { device.set_command(command); }
+        }
+
+        if !handlers.is_empty() {
+            if pci_debug() {
+                for handler in &handlers {
+                    print!("PCI device | {:#06x}:{:#06x} | claimed by {}\n",
+                           device.config.header.vendor_id,
+                           device.config.header.device_id,
+                           handler.name());
+                }
+            }
+
+            return Some((id, handlers));
+        }
+    }
+
+    None
+}
+
+/// Scan every device and function on `bus`, marking them as present in
+/// `pci_enum`, and recurse into the secondary bus range of every PCI-to-PCI
+/// bridge found on the bus
+///
+/// Buses which are already marked in `scanned` are skipped, this protects us
+/// against misconfigured bridges which have overlapping bus ranges.
+unsafe fn enumerate_bus(bus: u8, pci_enum: &mut [u64], scanned: &mut [bool]) {
+    // Don't scan buses multiple times
+    if scanned[bus as usize] {
+        return;
+    }
+    scanned[bus as usize] = true;
+
+    scan_bus(bus, |device, function, header_type| {
+        // Set the device present in the PCI enumeration table
+        let (word, bit) = bitmap_index(PciAddress { bus, device, function });
+        pci_enum[word] |= bit;
+
+        // Check if this is a bridge with buses behind it
+        let class = read_config_u32(bus, device, function, 0x8);
+        if has_downstream_bus(class, header_type) {
+            enumerate_bridge(bus, device, function, pci_enum, scanned);
+        } else if (header_type & 0x7f) != 0 && pci_debug() {
+            print!("PCI device | {:02x}:{:02x}.{} | header type {:#x} with \
+                    class {:02x}:{:02x}, not a bridge to recurse into\n",
+                   bus, device, function, header_type,
+                   (class >> 24) as u8, (class >> 16) as u8);
+        }
+    });
+}
+
+/// Validate the expansion ROM image at the start of `rom` for a device of
+/// `vendor_id`. Returns the size of the image in bytes and whether it's the
+/// last image of the ROM, `None` if it's not valid.
+///
+/// The device ID isn't checked, images may cover several devices through the
+/// device list of the PCI data structure.
+fn rom_image(rom: &[u8], vendor_id: u16) -> Option<(usize, bool)> {
+    let read_u16 = |offset: usize| {
+        Some(u16::from_le_bytes([*rom.get(offset)?, *rom.get(offset + 1)?]))
+    };
+
+    // Check the ROM signature
+    if read_u16(0)? != 0xaa55 {
+        return None;
+    }
+
+    // Find the PCI data structure and check that it's from our vendor
+    let pcir = read_u16(0x18)? as usize;
+    if rom.get(pcir..pcir.checked_add(4)?)? != b"PCIR" ||
+            read_u16(pcir + 4)? != vendor_id {
+        return None;
+    }
+
+    // The image length is in units of 512 bytes and must fit in the ROM
+    let size = read_u16(pcir + 0x10)? as usize * 512;
+    if size == 0 || size > rom.len() {
+        return None;
+    }
+
+    let last = (*rom.get(pcir + 0x15)? & 0x80) != 0;
+    Some((size, last))
+}
+
+/// Returns `true` if a function with the class code and revision register
+/// `class` and `header_type` is a bridge which decodes a range of buses
+/// behind it, which are described by its secondary and subordinate bus
+/// numbers
+///
+/// Host, ISA and other bridges are also of the bridge class, but have no
+/// PCI bus behind them and no bus numbers in their header. Only PCI-to-PCI
+/// bridges (including semi-transparent ones) with a type 1 header and
+/// CardBus bridges with a type 2 header are recursed into, both keep their
+/// bus numbers at offset `0x18`.
+fn has_downstream_bus(class: u32, header_type: u8) -> bool {
+    match ((class >> 24) as u8, (class >> 16) as u8, header_type & 0x7f) {
+        (0x06, 0x04, 1) | (0x06, 0x09, 1) | (0x06, 0x07, 2) => true,
+        _ => false,
+    }
+}
+
+/// Buses which are the secondary bus of a port with ARI forwarding enabled,
+/// indexed by bus number. These are marked by the hierarchy walk, thus scans
+/// which don't come from it only see ARI devices on buses it walked.
+static ARI_BUSES: [AtomicBool; PCI_MAX_BUS] =
+    [AtomicBool::new(false); PCI_MAX_BUS];
+
+/// Returns `true` if the PCIe port at `addr` forwards ARI routing IDs to its
+/// secondary bus, ie. it's a root or downstream port with ARI forwarding
+/// enabled in its device control 2 register
+unsafe fn ari_forwarding(addr: PciAddress) -> bool {
+    let port = ProbedDevice::from_access(&HARDWARE, addr);
+    let cap = if let Some(cap) = port.capability_offset(CAP_ID_PCIE) {
+        cap as u16
+    } else {
+        return false;
+    };
+
+    let port_type = (port.read_config_u32(cap) >> 20) & 0xf;
+    (port_type == 0x4 || port_type == 0x6) &&
+        (port.read_config_u32(cap + 0x28) & (1 << 5)) != 0
+}
+
+/// Returns `true` if `bus` is behind a port with ARI forwarding enabled and
+/// device 0 on it uses ARI, in which case the device is alone on the bus
+unsafe fn ari_bus(bus: u8) -> bool {
+    ARI_BUSES[bus as usize].load(Ordering::SeqCst) &&
+        ari_capability(PciAddress { bus, device: 0, function: 0 }).is_some()
+}
+
+/// Invoke `func` with the `(device, function, header type)` of every function
+/// present on `bus`
+unsafe fn scan_bus<F: FnMut(u8, u8, u8)>(bus: u8, mut func: F) {
+    // A device using ARI is alone on its bus and may have its functions
+    // spread over what would normally be the device numbers. This is only
+    // the case if the port above forwards ARI routing IDs, which root buses
+    // have no port to do.
+    if ari_bus(bus) {
+        scan_ari_device(bus, func);
+        return;
+    }
+
+    // For each possible device ID
+    for device in 0..PCI_MAX_DEV as u8 {
+        // For each possible function ID
+        for function in 0..PCI_MAX_FUNC as u8 {
+            // Read the device and vendor ID for this BDF combination
+            let did_vid = read_config_u32(bus, device, function, 0);
+            if !device_present(did_vid) {
+                // If function 0 is not present, the device is not present
+                if function == 0 {
+                    break;
+                }
+
+                continue;
+            }
+
+            // Get the header type of this function
+            let header = read_config_u32(bus, device, function, 0xc);
+            let header_type = (header >> 16) as u8;
+
+            // A gap in a multifunction device which responds to the ID read
+            // but not to anything else, don't decode it as a function
+            if function != 0 && header == PCI_INVALID {
+                if pci_debug() {
+                    print!("PCI device | {:02x}:{:02x}.{} | function reads as \
+                            all ones, skipping\n",
+                           bus, device, function);
+                }
+                continue;
+            }
+
+            func(device, function, header_type);
+
+            // Functions 1 through 7 are only implemented by multifunction
+            // devices. Single function devices may alias function 0 in the
+            // other functions, so we must not look at them.
+            if function == 0 && (header_type & 0x80) == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Returns `true` if the function at `addr` exists by the same rules as
+/// `scan_bus`, such that a single function device answering for its other
+/// functions isn't mistaken for a multifunction device
+unsafe fn function_exists(addr: PciAddress) -> bool {
+    let PciAddress { bus, device, function } = addr;
+    if !device_present(read_config_u32(bus, device, function, 0)) {
+        return false;
+    }
+
+    // Function 0 always exists if it responds, and ARI devices number their
+    // functions without going through the multifunction bit
+    if function == 0 || ari_bus(bus) {
+        return true;
+    }
+
+    // Otherwise function 0 must be there and be multifunction, and the
+    // function mustn't be a gap which only responds to the ID read
+    device_present(read_config_u32(bus, device, 0, 0)) &&
+        (read_config_u32(bus, device, 0, 0xc) >> 16) & 0x80 != 0 &&
+        read_config_u32(bus, device, function, 0xc) != PCI_INVALID
+}
+
+/// Get the offset of the ARI capability of the function at `addr`, if it is
+/// present and uses alternative routing-ID interpretation
+///
+/// The ARI capability is an extended capability, thus it can only be found
+/// when the extended configuration space is reachable via ECAM.
+unsafe fn ari_capability(addr: PciAddress) -> Option<u16> {
+    if !device_present(read_config_u32(addr.bus, addr.device,
+                                       addr.function, 0)) {
+        return None;
+    }
+
+    ProbedDevice::from_access(&HARDWARE, addr)
+        .find_extended_capability(EXT_CAP_ID_ARI)
+}
+
+/// Invoke `func` like `scan_bus` for every function of the ARI device on
+/// `bus`, by following the next function numbers of the ARI capabilities
+///
+/// With ARI the device number is part of an 8-bit function number, thus a
+/// function number `n` is reached as device `n >> 3` function `n & 7`.
+/// Function 0 must be present.
+unsafe fn scan_ari_device<F: FnMut(u8, u8, u8)>(bus: u8, mut func: F) {
+    let mut number = 0u8;
+
+    // A well-formed chain visits each of the 256 functions at most once
+    for _ in 0..PCI_MAX_DEV * PCI_MAX_FUNC {
+        let addr = PciAddress {
+            bus,
+            device:   number >> 3,
+            function: number & 7,
+        };
+
+        // Get the ARI capability, which every function of the device has
+        let ari = if let Some(ari) = ari_capability(addr) {
+            ari
+        } else {
+            break;
+        };
+
+        // Get the header type of this function
+        let header_type = (read_config_u32(bus, addr.device, addr.function,
+                                           0xc) >> 16) as u8;
+
+        func(addr.device, addr.function, header_type);
+
+        // Go to the next function, function 0 terminates the chain
+        number = (read_config_u32(bus, addr.device, addr.function,
+                                  ari + 4) >> 8) as u8;
+        if number == 0 {
+            break;
+        }
+    }
+}
+
+/// Recurse into the downstream bus range of the PCI-to-PCI bridge at
+/// `bus:device.function`
+unsafe fn enumerate_bridge(bus: u8, device: u8, function: u8,
+                           pci_enum: &mut [u64], scanned: &mut [bool]) {
+    // Get the secondary and subordinate bus numbers of the bridge
+    let buses       = read_config_u32(bus, device, function, 0x18);
+    let secondary   = (buses >> 8) as u8;
+    let subordinate = (buses >> 16) as u8;
+
+    // Skip bridges which have not been assigned a valid bus range. Downstream
+    // buses always have a larger number than the bus the bridge is on.
+    if secondary <= bus || subordinate < secondary {
+        return;
+    }
+
+    // ARI forwarding only applies to the bus directly below the bridge
+    ARI_BUSES[secondary as usize].store(
+        ari_forwarding(PciAddress { bus, device, function }), Ordering::SeqCst);
+
+    // Scan all the buses decoded by the bridge. Buses in the range which are
+    // behind nested bridges will be found by the recursion and skipped here.
+    for downstream in secondary..=subordinate {
+        enumerate_bus(downstream, pci_enum, scanned);
+    }
+}
+
+/// Information about an enumerated PCI function, see `snapshot`
+///
+/// The subsystem IDs are only meaningful for functions with a type 0 header.
+#[derive(Clone, Copy, Debug)]
+pub struct PciDeviceInfo {
+    /// Location of the function
+    pub addr: PciAddress,
+
+    /// Set if the header of the function was in the configuration space
+    /// cache. Otherwise the IDs, class and header type below are all zero.
+    pub cached: bool,
+
+    pub vendor_id:           u16,
+    pub device_id:           u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_device_id: u16,
+    pub class:               u8,
+    pub subclass:            u8,
+    pub prog_if:             u8,
+    pub header_type:         u8,
+
+    /// ID of the driver which claimed the function, `None` if no driver
+    /// claimed it or if it is not a device (eg. a bridge)
+    pub driver_id: Option<u16>,
+
+    /// Location of the PCI-to-PCI bridge whose secondary bus the function is
+    /// on, `None` for functions on a root bus
+    pub parent: Option<PciAddress>,
+}
+
+/// Iterate over the location of every PCI function found during enumeration
+///
+/// This is built from the enumeration persisted by `init` and does not touch
+/// the hardware, nothing is yielded if `init` has not run yet. The iterator
+/// works on a copy of the enumeration, thus functions found by a `rescan`
+/// while iterating are not yielded.
+pub fn present_devices() -> impl Iterator<Item = PciAddress> {
+    let pci_devices = unsafe {
+        core!().persist_store().pci_devices.lock()
+            .unwrap_or([0u64; BITMAP_WORDS])
+    };
+
+    (0..PciAddress::COUNT).map(PciAddress::from_index)
+        .filter(move |&addr| {
+            let (word, bit) = bitmap_index(addr);
+            (pci_devices[word] & bit) != 0
+        })
+}
+
+/// Returns `true` if a PCI function was found at `addr` during enumeration
+///
+/// This is answered from the enumeration persisted by `init` and does not
+/// touch the hardware. Returns `false` if `init` has not run yet.
+pub fn is_present(addr: PciAddress) -> bool {
+    let (word, bit) = bitmap_index(addr);
+    unsafe {
+        core!().persist_store().pci_devices.lock().as_ref()
+            .map(|x| (x[word] & bit) != 0)
+            .unwrap_or(false)
+    }
+}
+
+/// Find the first function with `vendor` and `device` IDs by scanning every
+/// bus, without allocating or relying on the enumeration
+///
+/// This is meant for finding a device very early during boot, before the
+/// allocator and `init`. Until `enumerate` has set up ECAM the legacy
+/// configuration ports are used. Functions are checked in bus, device and
+/// function order.
+pub unsafe fn find_first(vendor: u16, device: u16) -> Option<PciAddress> {
+    let ids = vendor as u32 | ((device as u32) << 16);
+
+    for bus in 0..PCI_MAX_BUS {
+        let bus = bus as u8;
+
+        let mut found = None;
+        scan_bus(bus, |device, function, _| {
+            if found.is_none() &&
+                    read_config_u32(bus, device, function, 0) == ids {
+                found = Some(PciAddress { bus, device, function });
+            }
+        });
+
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
+/// Get information about every PCI function found during enumeration
+///
+/// This is built only from the enumeration, configuration spaces and claims
+/// persisted by `init`, thus it's cheap to call and never touches the
+/// hardware. Functions which did not fit in the configuration space cache
+/// are returned with `cached` clear. Returns an empty list if `init` has not
+/// run yet.
+///
+/// The functions are in bus, device and function order, and `parent` links
+/// them to the bridge they are behind such that the tree can be rebuilt.
+pub fn snapshot() -> Vec<PciDeviceInfo> {
+    let mut ret = Vec::new();
+
+    // Secondary bus of each bridge found, along with the bridge
+    let mut bridges: Vec<(u8, PciAddress)> = Vec::new();
+
+    // Take a copy of the enumeration, such that its lock isn't held while
+    // we go through the other state
+    let pci_devices = unsafe { *core!().persist_store().pci_devices.lock() };
+    let pci_devices = if let Some(pci_devices) = pci_devices {
+        pci_devices
+    } else {
+        return ret;
+    };
+
+    {
+        let pci_configs = unsafe { core!().persist_store().pci_configs.lock() };
+        let pci_claims  = unsafe { core!().persist_store().pci_claims.lock() };
+
+        for (idx, &pci_map) in pci_devices.iter().enumerate() {
+            for bit in 0..64 {
+                if (pci_map & (1u64 << bit)) == 0 { continue; }
+
+                let addr = PciAddress::from_index((idx * 64) | bit);
+                let bdf  = addr.index() as u16;
+
+                // Find the driver which claimed the function
+                let driver_id = pci_claims.as_ref()
+                    .and_then(|x| x.find(bdf))
+                    .map(|x| x.driver_id)
+                    .filter(|&x| x != PCI_NO_DRIVER);
+
+                let mut info = PciDeviceInfo {
+                    addr,
+                    cached:              false,
+                    vendor_id:           0,
+                    device_id:           0,
+                    subsystem_vendor_id: 0,
+                    subsystem_device_id: 0,
+                    class:               0,
+                    subclass:            0,
+                    prog_if:             0,
+                    header_type:         0,
+                    driver_id,
+                    parent:              None,
+                };
+
+                // Fill in the header, if it's cached
+                let config = pci_configs.as_ref()
+                    .and_then(|x| x.find(bdf)).map(|x| x.config);
+                if let Some(config) = config {
+                    let device = PciDevice::from_raw(&config);
+
+                    // Remember which bus is behind a bridge, unless it was
+                    // not assigned a valid one, see `enumerate_bridge`
+                    let secondary = (config[6] >> 8) as u8;
+                    if has_downstream_bus(config[2],
+                                          device.header.header_type) &&
+                            secondary > addr.bus {
+                        bridges.push((secondary, addr));
+                    }
+
+                    info.cached              = true;
+                    info.vendor_id           = device.header.vendor_id;
+                    info.device_id           = device.header.device_id;
+                    info.subsystem_vendor_id = device.subsystem_vendor_id;
+                    info.subsystem_device_id = device.subsystem_device_id;
+                    info.class               = device.header.class;
+                    info.subclass            = device.header.subclass;
+                    info.prog_if             = device.header.prog_if;
+                    info.header_type         = device.header.header_type;
+                }
+
+                ret.push(info);
+            }
+        }
+    }
+
+    // Link the functions to the bridges leading to their bus
+    for info in ret.iter_mut() {
+        info.parent = bridges.iter()
+            .find(|&&(secondary, _)| secondary == info.addr.bus)
+            .map(|&(_, bridge)| bridge);
+    }
+
+    ret
+}
+
+/// Get information about every PCI device which no driver claimed, see
+/// `snapshot`. Bridges are not included as drivers are never bound to them,
+/// functions whose header isn't cached are included as we can't tell.
+pub fn unclaimed_devices() -> Vec<PciDeviceInfo> {
+    let mut devices = snapshot();
+
+    let registry = DEVICES.lock();
+    devices.retain(|x| (x.header_type & 0x7f) == 0 && x.driver_id.is_none() &&
+                   !registry.addrs.contains(&x.addr));
+    devices
+}
+
+/// Log every PCI device which no driver claimed, such that it's obvious when
+/// a device is not working because we have no driver for it
+fn log_unclaimed_devices() {
+    for device in unclaimed_devices() {
+        if !device.cached {
+            print!("PCI device | {:02x}:{:02x}.{} | header not cached | no \
+                    driver matched\n",
+                   device.addr.bus, device.addr.device, device.addr.function);
+            continue;
+        }
+
+        print!("PCI device | {:02x}:{:02x}.{} | {:04x}:{:04x} class \
+                {:02x}:{:02x}:{:02x} | no driver matched\n",
+               device.addr.bus, device.addr.device, device.addr.function,
+               device.vendor_id, device.device_id,
+               device.class, device.subclass, device.prog_if);
+    }
+}
+
+/// Set when a dump of the PCI enumeration was requested over the serial port,
+/// see `request_dump`
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Request the PCI enumeration to be dumped over the serial port by the next
+/// `service_dump_request`. This can be called from interrupt context.
+pub fn request_dump() {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Dump the PCI enumeration and the registered devices if it was requested
+/// with `request_dump`. This must not be called from interrupt context.
+///
+/// Requests are only answered where this is polled, ie. by the idle loop of
+/// `kmain` and between VM exits of `winboot`. It's a single atomic swap when
+/// nothing was requested, thus it's fine to poll on hot loops.
+pub fn service_dump_request() {
+    if DUMP_REQUESTED.swap(false, Ordering::SeqCst) {
+        dump_enumeration();
+        dump_devices();
+    }
+}
+
+/// Print every PCI function in the `snapshot`, with its IDs, class and the
+/// name of the driver which claimed it
+///
+/// This is read-only and may be called at any time after `init`, it's meant
+/// for inspecting the PCI map of a headless machine from the serial port.
+pub fn dump_enumeration() {
+    let devices = snapshot();
+
+    print!("PCI map | {} functions\n", devices.len());
+    for device in devices {
+        if device.cached {
+            print!("PCI map | {:02x}:{:02x}.{} | {:04x}:{:04x} {:04x}:{:04x} \
+                    class {:02x}:{:02x}:{:02x} | ",
+                   device.addr.bus, device.addr.device, device.addr.function,
+                   device.vendor_id, device.device_id,
+                   device.subsystem_vendor_id, device.subsystem_device_id,
+                   device.class, device.subclass, device.prog_if);
+        } else {
+            print!("PCI map | {:02x}:{:02x}.{} | header not cached | ",
+                   device.addr.bus, device.addr.device, device.addr.function);
+        }
+
+        // Name the driver by its device if it's still registered
+        let registry = DEVICES.lock();
+        let name = registry.addrs.iter().position(|&x| x == device.addr)
+            .map(|idx| registry.devices[idx].name());
+        match (name, device.driver_id) {
+            (Some(name), _)      => print!("{}\n", name),
+            (None, Some(driver)) => print!("driver {:#06x}\n", driver),
+            (None, None)         => print!("unclaimed\n"),
+        }
+    }
+}
+
+/// Print every registered device with its location, IDs and description,
+/// eg. to find out which functions a driver bound to
+///
+/// This doesn't allocate nor change the registry, it only reads the
+/// published device list and never applies deferred removals. Once the
+/// other cores are parked (eg. from a panic) it writes to the serial port
+/// bypassing the print locks, otherwise it takes them like `print!`. IDs are
+/// taken from the cached configuration headers and are only shown if those
+/// are available.
+pub fn dump_devices() {
+    if acpi::other_cores_parked() {
+        write_devices(&mut PurgeLog);
+    } else {
+        let _lock = core!().boot_args.print_lock.lock();
+        write_devices(&mut crate::print::SerialWriter);
+    }
+}
+
+/// Implementation of `dump_devices`, writing the listing to `out`
+fn write_devices(out: &mut dyn Write) {
+    let configs = unsafe { core!().persist_store().pci_configs.try_lock() };
+    let configs = configs.as_ref().and_then(|x| x.as_ref());
+
+    read_devices(|devices| {
+        let _ = write!(out, "PCI devices | {} registered\n", devices.len());
+
+        for device in devices {
+            let _ = write!(out, "PCI devices | ");
+            match device.bdf() {
+                Some(addr) => {
+                    let _ = write!(out, "{:02x}:{:02x}.{} | ",
+                                   addr.bus, addr.device, addr.function);
+
+                    let ids = configs
+                        .and_then(|x| x.find(addr.index() as u16))
+                        .map(|x| x.config[0]);
+                    if let Some(ids) = ids {
+                        let _ = write!(out, "{:04x}:{:04x} | ",
+                                       ids as u16, ids >> 16);
+                    }
+                }
+                None => {
+                    let _ = write!(out, "not PCI | ");
+                }
+            }
+
+            let _ = device.describe(out);
+            let _ = write!(out, "\n");
+        }
+    });
+}
+
+/// Invoke `func` with the list of all devices which have been handled by a
+/// driver
+///
+/// This does not take the device list lock, but devices can't be registered
+/// or removed for the duration of `func`. `func` must not register or remove
+/// devices.
+pub fn with_devices<F: FnOnce(&[Arc<dyn Device>])>(func: F) {
+    read_devices(func)
+}
+
+/// Find the first registered device whose driver is of type `T`
+///
+/// This does not take the device list lock, thus it's cheap enough to be
+/// called on hot paths.
+pub fn find_device<T: Device + 'static>() -> Option<Arc<T>> {
+    read_devices(|devices| {
+        devices.iter()
+            .find(|device| device.as_any().is::<T>())
+            .map(|device| device.clone())
+    }).and_then(|device| device.as_any_arc().downcast::<T>().ok())
+}
+
+/// Get every registered device whose driver is of type `T`, in registry
+/// order. Like `find_device` the returned devices stay alive for as long as
+/// they're held, even if they're removed from the registry.
+pub fn find_devices<T: Device + 'static>() -> Vec<Arc<T>> {
+    read_devices(|devices| {
+        devices.iter()
+            .filter(|device| device.as_any().is::<T>())
+            .cloned()
+            .collect::<Vec<_>>()
+    }).into_iter()
+        .filter_map(|device| device.as_any_arc().downcast::<T>().ok())
+        .collect()
+}
+
+/// Invoke `func` for every registered device whose driver is of type `T`
+///
+/// This does not take the device list lock, but devices can't be registered
+/// or removed for the duration of the iteration. `func` must not register or
+/// remove devices.
+pub fn for_each_as<T: Device + 'static, F: FnMut(&T)>(mut func: F) {
+    read_devices(|devices| {
+        for device in devices.iter() {
+            if let Some(device) = device.as_any().downcast_ref::<T>() {
+                func(device);
+            }
+        }
+    })
+}
+
+/// Exclusive ownership of a registered device, from `try_claim_exclusive`
+///
+/// Only one handle can exist for a device at a time, the claim is released
+/// when the handle is dropped. Holders of a handle are the only ones which
+/// should touch the registers and rings of the device.
+pub struct DeviceHandle {
+    /// Location of the device
+    addr: PciAddress,
+
+    /// The claimed device
+    device: Arc<dyn Device>,
+}
+
+impl DeviceHandle {
+    /// Location of the claimed device
+    pub fn addr(&self) -> PciAddress {
+        self.addr
+    }
+
+    /// Get a reference to the claimed device
+    pub fn device(&self) -> &Arc<dyn Device> {
+        &self.device
+    }
+}
+
+impl Deref for DeviceHandle {
+    type Target = dyn Device;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.device
+    }
+}
+
+impl Drop for DeviceHandle {
+    fn drop(&mut self) {
+        // Release the claim
+        let mut devices = DEVICES.lock();
+        if let Some(idx) = devices.exclusive.iter()
+                .position(|&x| x == self.addr) {
+            devices.exclusive.swap_remove(idx);
+        }
+    }
+}
+
+/// Claim exclusive use of the device at `addr`
+///
+/// Returns `None` if no driver handles a device at `addr` or if the device
+/// is already claimed. The claim outlives a removal of the device, thus a
+/// device which comes back at `addr` can't be claimed until the handle to
+/// the old one is dropped.
+pub fn try_claim_exclusive(addr: PciAddress) -> Option<DeviceHandle> {
+    let mut devices = DEVICES.lock();
+
+    // Check if someone else already owns the device
+    if devices.exclusive.contains(&addr) {
+        return None;
+    }
+
+    // Find the device
+    let device = devices.get(addr)?;
+
+    devices.exclusive.push(addr);
+    Some(DeviceHandle { addr, device })
+}
+
+/// Remove the device at `addr` from the system, eg. after it was hot-removed
+/// and its configuration space reads back as all ones
+///
+/// The device is dropped from the registry and told it was unplugged via
+/// `Device::unplug`, which stops its DMA and drops the references its
+/// subsystem holds. The device is finally dropped once the last reference to
+/// it goes away. The function is also cleared from the persisted
+/// enumeration, such that `rescan` will pick it up again if it comes back.
 ///
-/// This will drop the devices regardless of the current device lock, thus
-/// this is very unsafe and must only be done when we're doing a soft reboot
-/// and all other cores have been disabled.
-pub unsafe fn destroy_devices() {
-    let devices = &mut *DEVICES.shatter();
+/// Returns `false` if no driver had claimed a device at `addr`.
+pub unsafe fn remove_device(addr: PciAddress) -> bool {
+    // Take the devices out of the registry
+    let devices = DEVICES.lock().remove(addr);
+
+    // Forget about the function
+    if let Some(pci_devices) =
+            core!().persist_store().pci_devices.lock().as_mut() {
+        let (word, bit) = bitmap_index(addr);
+        pci_devices[word] &= !bit;
+    }
+
+    if devices.is_empty() {
+        return false;
+    }
+
+    let mut dropped = 0;
+    let mut outstanding = false;
     for device in devices {
-        device.purge();
+        // Stop the device and let go of the references handed out by the
+        // driver and of its interrupt vectors
+        device.unplug();
+        irq::free_device_vectors(&device);
+
+        // Anyone still holding on to the device will find it stopped, but
+        // the driver won't be dropped until they let go
+        if Arc::strong_count(&device) > 1 {
+            print!("PCI device | {:02x}:{:02x}.{} | {} removed with {} \
+                    outstanding references\n",
+                   addr.bus, addr.device, addr.function, device.name(),
+                   Arc::strong_count(&device) - 1);
+            outstanding = true;
+            continue;
+        }
+
+        // We hold the last reference, drop the driver
+        drop(device);
+        dropped += 1;
+    }
+
+    // Make sure the drivers cleaned up after themselves, once all of them
+    // are gone
+    #[cfg(debug_assertions)]
+    if !outstanding {
+        verify_dropped(addr, dropped);
+    }
+
+    // The counts are only checked in debug builds
+    let _ = (dropped, outstanding);
+
+    true
+}
+
+/// Outcome of `unregister_device`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unregister {
+    /// The device was purged and taken out of the registry
+    Removed,
+
+    /// The device can't be removed from this context, it will be removed by
+    /// `service_removals`
+    Deferred,
+
+    /// The device wasn't registered, eg. because it was already removed
+    NotRegistered,
+}
+
+/// Devices `unregister_device` was asked to remove from a context which
+/// can't remove them, see `service_removals`
+static PENDING_REMOVALS: LockCell<Vec<Arc<dyn Device>>, LockInterrupts> =
+    LockCell::new_no_preempt(Vec::new());
+
+/// Tear down the single registered `device` at runtime, eg. from a driver
+/// which hit a fatal error on its device, without touching any other device
+///
+/// The device is taken out of the registry under the `DEVICES` lock, which
+/// is released before the device is purged with `Device::purge` and has its
+/// interrupt vectors freed. The registry's reference is dropped only after
+/// the purge, thus if the caller holds the last other reference the `Drop`
+/// of the driver runs when the caller lets go of it, never before the purge.
+///
+/// The `DEVICES` lock can't be taken from interrupt context (eg. a
+/// `Device::handle_interrupt` noticing the error), and the list can't be
+/// republished from within a lock-free reader (eg. `with_devices` or
+/// `for_each_as`) as it would wait for ourselves. From those contexts the
+/// removal is queued instead and `Unregister::Deferred` is returned. Queued
+/// removals are applied by the next `service_removals`, which the idle loop
+/// of `kmain` polls.
+///
+/// The function stays in the persisted enumeration, such that a `rescan`
+/// doesn't bind a driver to the broken device again.
+pub unsafe fn unregister_device(device: &Arc<dyn Device>) -> Unregister {
+    if core!().in_interrupt() || reading_devices() {
+        PENDING_REMOVALS.lock().push(device.clone());
+        return Unregister::Deferred;
+    }
+
+    unregister_now(device)
+}
+
+/// Apply the removals `unregister_device` deferred. Returns the number of
+/// devices removed.
+///
+/// This runs the `purge` and `Drop` of other drivers, thus it must not be
+/// called from interrupt context nor while holding any lock a driver may
+/// need. Called from within a lock-free reader it leaves the queue alone.
+pub unsafe fn service_removals() -> usize {
+    if reading_devices() {
+        return 0;
+    }
+
+    let pending =
+        core::mem::replace(&mut *PENDING_REMOVALS.lock(), Vec::new());
+
+    pending.iter()
+        .filter(|device| unregister_now(device) == Unregister::Removed)
+        .count()
+}
+
+/// Implementation of `unregister_device`, from a context which may take the
+/// `DEVICES` lock and republish the device list
+unsafe fn unregister_now(device: &Arc<dyn Device>) -> Unregister {
+    // Take the device out of the registry. Bind the result first such that
+    // the lock is dropped before we call into the driver.
+    let removed = DEVICES.lock().remove_one(device);
+    let (addr, removed) = match removed {
+        Some(removed) => removed,
+        None          => return Unregister::NotRegistered,
+    };
+
+    // Stop the device and free its interrupt vectors
+    if let Err(err) = removed.purge() {
+        print!("PCI device | {:02x}:{:02x}.{} | {} failed to purge: {:?}\n",
+               addr.bus, addr.device, addr.function, removed.name(), err);
+    }
+    irq::free_device_vectors(&removed);
+
+    // Let go of the registry's reference, the caller's reference keeps the
+    // driver alive until they drop it
+    drop(removed);
+    Unregister::Removed
+}
+
+/// Devices whose drivers have been dropped, see `driver_dropped`
+#[cfg(debug_assertions)]
+static DROPPED_DRIVERS: LockCell<Vec<PciAddress>, LockInterrupts> =
+    LockCell::new(Vec::new());
+
+/// Record that the driver of the device at `addr` is being dropped and has
+/// stopped the device from performing DMA. Drivers should call this at the
+/// end of their `Drop`, such that debug builds can check it ran when the
+/// device is removed.
+pub fn driver_dropped(_addr: PciAddress) {
+    #[cfg(debug_assertions)]
+    DROPPED_DRIVERS.lock().push(_addr);
+}
+
+/// Check that the `count` dropped devices of the removed function at `addr`
+/// ran the `Drop` of their driver and did not leave any DMA memory allocated
+/// for the function
+#[cfg(debug_assertions)]
+fn verify_dropped(addr: PciAddress, count: usize) {
+    let reported = {
+        let mut dropped = DROPPED_DRIVERS.lock();
+        let mut reported = 0;
+        while reported < count {
+            let found = dropped.iter().position(|&x| x == addr);
+            if found.map(|idx| dropped.swap_remove(idx)).is_none() {
+                break;
+            }
+            reported += 1;
+        }
+        reported
+    };
+
+    if reported != count {
+        print!("PCI device | {:02x}:{:02x}.{} | {} drivers did not report \
+                stopping DMA when dropped\n",
+               addr.bus, addr.device, addr.function, count - reported);
+    }
+
+    let live = dma::live_regions(addr);
+    if live != 0 {
+        print!("PCI device | {:02x}:{:02x}.{} | {} DMA regions still live \
+                after its driver was dropped\n",
+               addr.bus, addr.device, addr.function, live);
+    }
+}
+
+/// Writer used to log purge failures. Other cores may have been halted while
+/// holding the print locks, thus we bypass them and write to the serial port
+/// directly.
+struct PurgeLog;
+
+impl core::fmt::Write for PurgeLog {
+    fn write_str(&mut self, st: &str) -> core::fmt::Result {
+        // Use the serial port lock if it's free, otherwise bypass it
+        let serial = &core!().boot_args.serial;
+        if let Some(mut serial) = serial.try_lock() {
+            if let Some(serial) = serial.as_mut() {
+                serial.write(st.as_bytes());
+            }
+        } else if let Some(serial) = unsafe { &mut *serial.shatter() } {
+            serial.write(st.as_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+/// Time budget for a single `Device::purge` during `destroy_devices`, in
+/// microseconds
+const PURGE_TIMEOUT: u64 = 500_000;
+
+/// TSC value after which the purge being run by `destroy_devices` is
+/// overdue, zero if no purge is running
+static PURGE_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Returns `true` if the `Device::purge` being run by `destroy_devices` has
+/// exceeded its time budget, and should give up on the device
+pub fn purge_overdue() -> bool {
+    let deadline = PURGE_DEADLINE.load(Ordering::SeqCst);
+    deadline != 0 && cpu::rdtsc() >= deadline
+}
+
+/// Shut down and drop all devices in the `DEVICES` list in an orderly
+/// fashion, eg. for a shutdown where all cores cooperate. Use
+/// `destroy_devices` from hostile contexts such as a panic or an NMI.
+///
+/// The registry is emptied, then the devices are stopped with
+/// `Device::unplug` (which suspends them and lets go of the references their
+/// subsystems hold) in order of their `Device::purge_priority`, have their
+/// interrupt vectors freed, and are dropped. Devices which are still
+/// referenced elsewhere are logged and dropped once the last reference goes
+/// away, the number of such devices is returned.
+pub unsafe fn shutdown_devices() -> usize {
+    // Take every device out of the registry, such that nobody can find them
+    // anymore
+    let mut devices: Vec<(PciAddress, Arc<dyn Device>)> = {
+        let mut registry = DEVICES.lock();
+        let addrs = core::mem::replace(&mut registry.addrs, Vec::new());
+        let devices = core::mem::replace(&mut registry.devices, Vec::new());
+        registry.publish();
+        addrs.into_iter().zip(devices).collect()
+    };
+
+    // Shut down devices in purge order, keeping the registration order for
+    // devices with the same priority
+    devices.sort_by_key(|(_, device)| device.purge_priority());
+
+    let mut outstanding = 0;
+    for (addr, device) in devices {
+        device.unplug();
+        irq::free_device_vectors(&device);
+
+        if Arc::strong_count(&device) > 1 {
+            print!("PCI device | {:02x}:{:02x}.{} | {} shut down with {} \
+                    outstanding references\n",
+                   addr.bus, addr.device, addr.function, device.name(),
+                   Arc::strong_count(&device) - 1);
+            outstanding += 1;
+        }
+    }
+
+    outstanding
+}
+
+/// Drop all devices in the `DEVICES` list, causing the devices to have their
+/// `Drop` handlers invoked
+///
+/// The device lock is used if it is free, otherwise this will drop the
+/// devices regardless of the current device lock, thus this is very unsafe
+/// and must only be done when we're doing a soft reboot and all other cores
+/// have been disabled. Orderly shutdowns should use `shutdown_devices`.
+///
+/// Devices are purged in order of their `Device::purge_priority`. Every
+/// device is purged, even if we fail to purge some of them. Failures are
+/// logged to the serial port and the number of devices which could not be
+/// purged is returned.
+///
+/// Each purge gets `PURGE_TIMEOUT` microseconds, which purges poll through
+/// `purge_overdue`. Devices which took longer are logged and counted as
+/// failures, and we move on to the next device. The deadline is only checked
+/// once the purge returns, thus a purge which never polls `purge_overdue`
+/// and hangs can't be cut off and hangs the soft reboot.
+pub unsafe fn destroy_devices() -> usize {
+    let mut failures = 0;
+
+    // Take the device list gracefully if we can. The lock may be held if we
+    // were interrupted by the soft reboot while holding it, in which case
+    // we have no choice but to bypass it.
+    let guard = DEVICES.try_lock();
+    let devices = match &guard {
+        Some(guard) => &guard.devices,
+        None        => &(*DEVICES.shatter()).devices,
+    };
+
+    // Purge the devices one priority level at a time. We can't allocate here
+    // and an unstable in-place sort would make the order of devices with the
+    // same priority arbitrary, so we just make a pass per priority.
+    for priority in 0..=u8::MAX {
+        for device in devices {
+            if device.purge_priority() != priority {
+                continue;
+            }
+
+            // Arm the watchdog for this device
+            PURGE_DEADLINE.store(crate::time::future(PURGE_TIMEOUT),
+                                 Ordering::SeqCst);
+
+            let result = device.purge();
+            let overdue = purge_overdue();
+            PURGE_DEADLINE.store(0, Ordering::SeqCst);
+
+            if let Err(err) = result {
+                let _ = write!(PurgeLog, "Failed to purge device {}: {:?}\n",
+                               device.name(), err);
+                failures += 1;
+            } else if overdue {
+                let _ = write!(PurgeLog, "Purge of device {} exceeded its \
+                                          deadline\n", device.name());
+                failures += 1;
+            }
+        }
+    }
+
+    failures
+}
+
+/// Error bits of the status register: master data parity error, signaled
+/// target abort, received target abort, received master abort, signaled
+/// system error, and detected parity error
+const STATUS_ERRORS: u16 = (1 << 8) | (0x1f << 11);
+
+/// Exercise `Device::purge` of every registered device from a sane context,
+/// such that soft reboot teardown can be validated before relying on it from
+/// a panic or NMI
+///
+/// Each device is purged in purge priority order, and afterwards its
+/// configuration space is checked to make sure the device is still present
+/// with the same IDs and did not log any errors in its status register.
+/// Problems are logged and the number of devices which failed is returned.
+///
+/// The devices are left purged and are unusable until the next soft reboot.
+/// This is only available in debug builds and must only be run when nothing
+/// else is using the devices.
+#[cfg(debug_assertions)]
+pub unsafe fn test_purge_all() -> usize {
+    let mut failures = 0;
+
+    let registry = DEVICES.lock();
+    for priority in 0..=u8::MAX {
+        let devices = registry.devices.iter().zip(&registry.addrs)
+            .filter(|(device, _)| device.purge_priority() == priority);
+
+        for (device, &addr) in devices {
+            let probed = ProbedDevice::from_access(&HARDWARE, addr);
+            let ids    = probed.read_config_u32(0x00);
+
+            // Clear any stale errors, such that we only see what the purge
+            // caused. The error bits are write-1-to-clear.
+            probed.write_config_u32(0x04, probed.command() as u32 |
+                ((STATUS_ERRORS as u32) << 16));
+
+            let result = device.purge();
+
+            // Check the state the device was left in
+            let after  = probed.read_config_u32(0x04);
+            let errors = (after >> 16) as u16 & STATUS_ERRORS;
+            let ok = if let Err(err) = result {
+                print!("Purge test | {} | purge failed: {:?}\n",
+                       device.name(), err);
+                false
+            } else if probed.read_config_u32(0x00) != ids {
+                print!("Purge test | {} | device gone or changed identity\n",
+                       device.name());
+                false
+            } else if errors != 0 {
+                print!("Purge test | {} | status errors {:#06x}\n",
+                       device.name(), errors);
+                false
+            } else {
+                true
+            };
+
+            if !ok {
+                failures += 1;
+            }
+        }
     }
+
+    failures
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn class_names() {
+        let table = [
+            // Exact matches
+            ((0x02, 0x00), "Ethernet controller"),
+            ((0x06, 0x04), "PCI bridge"),
+
+            // Known class, unknown subclass
+            ((0x06, 0x80), "Bridge"),
+            ((0x01, 0x80), "Mass storage controller"),
+
+            // Unknown class
+            ((0xfe, 0x00), "Unknown device"),
+        ];
+
+        for &((class, subclass), name) in table.iter() {
+            assert_eq!(class_name(class, subclass), name,
+                "Wrong name for class {:02x}:{:02x}", class, subclass);
+        }
+    }
+}
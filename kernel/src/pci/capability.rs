@@ -0,0 +1,1017 @@
+//! PCI capability list parsing
+
+use page_table::{PhysAddr, VirtAddr};
+
+use crate::mm;
+use super::{PciAddress, PciDevice, Bar, BarType};
+use super::config::ConfigAccess;
+
+/// Capability ID for PCI power management
+pub const CAP_ID_PM: u8 = 0x01;
+
+/// Capability ID for Message Signaled Interrupts
+pub const CAP_ID_MSI: u8 = 0x05;
+
+/// Capability ID for vendor-specific capabilities
+pub const CAP_ID_VENDOR: u8 = 0x09;
+
+/// Capability ID for the PCI Express capability
+pub const CAP_ID_PCIE: u8 = 0x10;
+
+/// Capability ID for MSI-X
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+/// Capability ID for enhanced allocation
+pub const CAP_ID_EA: u8 = 0x14;
+
+/// Extended capability ID for advanced error reporting
+pub const EXT_CAP_ID_AER: u16 = 0x01;
+
+/// Extended capability ID for alternative routing-ID interpretation
+pub const EXT_CAP_ID_ARI: u16 = 0x0e;
+
+/// Extended capability ID for single root I/O virtualization
+pub const EXT_CAP_ID_SRIOV: u16 = 0x10;
+
+/// Maximum number of capabilities which can fit in the 192 bytes of
+/// configuration space following the standard header. Used to bound walks of
+/// the capability list in the case the list contains a loop.
+const MAX_CAPABILITIES: usize = (256 - 0x40) / 4;
+
+/// Maximum number of extended capabilities which can fit in the extended
+/// configuration space
+const MAX_EXT_CAPABILITIES: usize = (4096 - 0x100) / 4;
+
+/// Log that the capability list of the device at `addr` points to `offset`,
+/// which can't hold a capability. The rest of the list is ignored.
+fn malformed_list(addr: PciAddress, offset: u16) {
+    print!("PCI device | {:02x}:{:02x}.{} | malformed capability pointer \
+            {:#x}, ignoring the rest of the list\n",
+           addr.bus, addr.device, addr.function, offset);
+}
+
+/// A parsed entry of the capability list of a device, see
+/// `ProbedDevice::parsed_capabilities`
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    /// Power management capability
+    PowerManagement(PmCapability),
+
+    /// Message signaled interrupts capability
+    Msi(MsiCapability),
+
+    /// MSI-X capability
+    MsiX(MsiXCapability),
+
+    /// Enhanced allocation capability
+    EnhancedAllocation(EaCapability),
+
+    /// A capability which we don't parse
+    Other {
+        /// Capability ID
+        id: u8,
+
+        /// Offset of the capability in configuration space
+        offset: u8,
+    },
+}
+
+/// An iterator over the capability linked list of a PCI device. Yields
+/// `(capability ID, configuration space offset)` pairs.
+pub struct CapabilityIter {
+    /// Configuration space accessor for the device
+    access: &'static dyn ConfigAccess,
+
+    /// Location of the device whose capabilities we're walking
+    addr: PciAddress,
+
+    /// Offset of the next capability in configuration space, zero if there
+    /// are no more capabilities
+    next: u8,
+
+    /// Number of capabilities we will still walk before giving up
+    remaining: usize,
+
+    /// Bitmap of the dwords after the standard header which we already
+    /// visited, to detect loops in the list
+    visited: u64,
+}
+
+impl CapabilityIter {
+    /// Create a new iterator over the capability list of the device at `addr`
+    /// starting at configuration space offset `start`
+    pub(super) fn new(access: &'static dyn ConfigAccess, addr: PciAddress,
+                      start: u8) -> Self {
+        CapabilityIter {
+            access,
+            addr,
+            next:      start,
+            remaining: MAX_CAPABILITIES,
+            visited:   0,
+        }
+    }
+}
+
+impl Iterator for CapabilityIter {
+    type Item = (u8, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The low 2 bits of capability pointers are reserved
+        let offset = self.next & !3;
+
+        // Check if we're at the end of the list, or if we've gone on for
+        // longer than possible in a well-formed list
+        if offset == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        // Capabilities can't live in the standard header, and a capability
+        // we've seen before means the list loops
+        let bit = offset.checked_sub(0x40).map(|x| 1u64 << (x / 4));
+        if bit.map(|bit| (self.visited & bit) != 0).unwrap_or(true) {
+            malformed_list(self.addr, offset as u16);
+            self.remaining = 0;
+            return None;
+        }
+        self.visited |= bit.unwrap();
+
+        // Read the capability ID and the pointer to the next capability
+        let header = unsafe {
+            self.access.read(self.addr, offset as u16)
+        };
+        self.next = (header >> 8) as u8;
+
+        Some((header as u8, offset))
+    }
+}
+
+/// An iterator over the extended capability linked list of a PCIe device,
+/// which lives in the extended configuration space starting at `0x100`.
+/// Yields `(extended capability ID, configuration space offset)` pairs.
+pub struct ExtCapabilityIter {
+    /// Configuration space accessor for the device
+    access: &'static dyn ConfigAccess,
+
+    /// Location of the device whose capabilities we're walking
+    addr: PciAddress,
+
+    /// Offset of the next capability in configuration space, zero if there
+    /// are no more capabilities
+    next: u16,
+
+    /// Number of capabilities we will still walk before giving up
+    remaining: usize,
+
+    /// Bitmap of the dwords of the extended configuration space which we
+    /// already visited, to detect loops in the list
+    visited: [u64; MAX_EXT_CAPABILITIES / 64],
+}
+
+impl ExtCapabilityIter {
+    /// Create a new iterator over the extended capability list of the device
+    /// at `addr`. If `start` is zero the list is empty.
+    pub(super) fn new(access: &'static dyn ConfigAccess, addr: PciAddress,
+                      start: u16) -> Self {
+        ExtCapabilityIter {
+            access,
+            addr,
+            next:      start,
+            remaining: MAX_EXT_CAPABILITIES,
+            visited:   [0; MAX_EXT_CAPABILITIES / 64],
+        }
+    }
+}
+
+impl Iterator for ExtCapabilityIter {
+    type Item = (u16, u16);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The low 2 bits of capability pointers are reserved
+        let offset = self.next & !3;
+
+        // Check if we're at the end of the list, or if we've gone on for
+        // longer than possible in a well-formed list
+        if offset == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        // Extended capabilities can only live in the extended configuration
+        // space, and a capability we've seen before means the list loops
+        let dword = (offset.wrapping_sub(0x100) / 4) as usize;
+        if offset < 0x100 || offset >= 4096 ||
+                (self.visited[dword / 64] & (1 << (dword % 64))) != 0 {
+            malformed_list(self.addr, offset);
+            self.remaining = 0;
+            return None;
+        }
+        self.visited[dword / 64] |= 1 << (dword % 64);
+
+        // Read the capability ID, version, and the pointer to the next
+        // capability. Devices without extended capabilities have a zero
+        // header at `0x100`, and non-PCIe devices read back all ones.
+        let header = unsafe { self.access.read(self.addr, offset) };
+        if header == 0 || header == super::PCI_INVALID {
+            return None;
+        }
+        self.next = (header >> 20) as u16;
+
+        Some((header as u16, offset))
+    }
+}
+
+/// Device power states which can be selected through the power management
+/// capability
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerState {
+    /// Fully on
+    D0 = 0,
+
+    /// Light sleep, optional
+    D1 = 1,
+
+    /// Deeper sleep, optional
+    D2 = 2,
+
+    /// Off, but still responding to configuration space accesses
+    D3Hot = 3,
+}
+
+/// The power management capability of a device
+#[derive(Clone, Copy, Debug)]
+pub struct PmCapability {
+    /// Configuration space accessor for the device
+    access: &'static dyn ConfigAccess,
+
+    /// Location of the device this capability belongs to
+    addr: PciAddress,
+
+    /// Offset of the capability in configuration space
+    offset: u8,
+
+    /// Power management capabilities register
+    pmc: u16,
+}
+
+impl PmCapability {
+    /// Parse the power management capability at `offset` in the
+    /// configuration space of the device at `addr`
+    pub(super) unsafe fn new(access: &'static dyn ConfigAccess,
+                             addr: PciAddress, offset: u8) -> Self {
+        let pmc = (access.read(addr, offset as u16) >> 16) as u16;
+        PmCapability { access, addr, offset, pmc }
+    }
+
+    /// Read the power management control/status register
+    unsafe fn pmcsr(&self) -> u32 {
+        self.access.read(self.addr, (self.offset + 4) as u16)
+    }
+
+    /// Returns `true` if the device supports `state`
+    pub fn supports(&self, state: PowerState) -> bool {
+        match state {
+            PowerState::D0 | PowerState::D3Hot => true,
+            PowerState::D1 => (self.pmc & (1 << 9))  != 0,
+            PowerState::D2 => (self.pmc & (1 << 10)) != 0,
+        }
+    }
+
+    /// Get the current power state of the device
+    pub unsafe fn power_state(&self) -> PowerState {
+        match self.pmcsr() & 3 {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Transition the device to `state`. Returns `false` if the device does
+    /// not support `state`.
+    ///
+    /// The device must not be accessed for 10 milliseconds after entering or
+    /// leaving D3hot, this waits out that delay before returning.
+    pub unsafe fn set_power_state(&self, state: PowerState) -> bool {
+        if !self.supports(state) {
+            return false;
+        }
+
+        // Nothing to do if we're already in this state
+        let old = self.power_state();
+        if old == state {
+            return true;
+        }
+
+        // Update the state, writing zero to the write-one-to-clear PME status
+        // bit so we don't clear it
+        let pmcsr = (self.pmcsr() & !((1 << 15) | 3)) | state as u32;
+        self.access.write(self.addr, (self.offset + 4) as u16, pmcsr);
+
+        // Wait for the transition to complete
+        if old == PowerState::D3Hot || state == PowerState::D3Hot {
+            crate::time::sleep(10_000);
+        }
+
+        true
+    }
+
+    /// Get the power the device reports consuming in `state` through the
+    /// optional data register, in milliwatts. Returns `None` if the device
+    /// doesn't report it.
+    pub unsafe fn power_consumed(&self, state: PowerState) -> Option<u32> {
+        // Select the power consumed in `state`, without clearing the PME
+        // status or changing the power state
+        let pmcsr = self.pmcsr() & !((1 << 15) | (0xf << 9));
+        self.access.write(self.addr, (self.offset + 4) as u16,
+                          pmcsr | ((state as u32) << 9));
+
+        // Read back the value, the scale decides how many milliwatts a unit
+        // of the data register is. A scale of zero means the register isn't
+        // implemented.
+        let pmcsr = self.pmcsr();
+        let data  = pmcsr >> 24;
+        match (pmcsr >> 13) & 3 {
+            1 => Some(data * 100),
+            2 => Some(data * 10),
+            3 => Some(data),
+            _ => None,
+        }
+    }
+}
+
+/// The Message Signaled Interrupts (MSI) capability of a device
+#[derive(Clone, Copy, Debug)]
+pub struct MsiCapability {
+    /// Configuration space accessor for the device
+    access: &'static dyn ConfigAccess,
+
+    /// Location of the device this capability belongs to
+    addr: PciAddress,
+
+    /// Offset of the capability in configuration space
+    offset: u8,
+
+    /// Message control register at the time the capability was parsed
+    control: u16,
+}
+
+impl MsiCapability {
+    /// Parse the MSI capability at `offset` in the configuration space of the
+    /// device at `addr`
+    pub(super) unsafe fn new(access: &'static dyn ConfigAccess,
+                             addr: PciAddress, offset: u8) -> Self {
+        let mut ret = MsiCapability { access, addr, offset, control: 0 };
+        ret.control = (ret.read(0) >> 16) as u16;
+        ret
+    }
+
+    /// Read the 32-bit register at `offset` bytes into the capability
+    unsafe fn read(&self, offset: u8) -> u32 {
+        self.access.read(self.addr, (self.offset + offset) as u16)
+    }
+
+    /// Write the 32-bit register at `offset` bytes into the capability
+    unsafe fn write(&self, offset: u8, value: u32) {
+        self.access.write(self.addr, (self.offset + offset) as u16, value)
+    }
+
+    /// Offset of the message data register in the capability, which depends
+    /// on whether the capability has a 64-bit message address
+    fn data_offset(&self) -> u8 {
+        if self.is_64bit() { 0xc } else { 0x8 }
+    }
+
+    /// Get the raw message control register
+    pub fn control(&self) -> u16 {
+        self.control
+    }
+
+    /// Returns `true` if the device supports 64-bit message addresses
+    pub fn is_64bit(&self) -> bool {
+        (self.control & (1 << 7)) != 0
+    }
+
+    /// Returns `true` if the device supports masking individual vectors
+    pub fn per_vector_masking(&self) -> bool {
+        (self.control & (1 << 8)) != 0
+    }
+
+    /// Number of vectors the device would like to have allocated. This is
+    /// always a power of two from 1 to 32.
+    pub fn multiple_message_capable(&self) -> u8 {
+        1 << ((self.control >> 1) & 7)
+    }
+
+    /// Get the message address and data the device writes when it signals
+    /// an interrupt
+    pub unsafe fn message(&self) -> (u64, u16) {
+        let mut address = self.read(0x4) as u64;
+        if self.is_64bit() {
+            address |= (self.read(0x8) as u64) << 32;
+        }
+
+        (address, self.read(self.data_offset()) as u16)
+    }
+
+    /// Program the message address and data the device will write when it
+    /// signals an interrupt
+    pub unsafe fn set_message(&self, address: u64, data: u16) {
+        assert!(self.is_64bit() || (address >> 32) == 0,
+            "64-bit MSI address on a device with 32-bit MSI");
+
+        // Program the address
+        self.write(0x4, address as u32);
+        if self.is_64bit() {
+            self.write(0x8, (address >> 32) as u32);
+        }
+
+        // Program the data, preserving the upper 16 bits of the register
+        let offset = self.data_offset();
+        self.write(offset, (self.read(offset) & !0xffff) | data as u32);
+    }
+
+    /// Enable or disable MSI for the device
+    pub unsafe fn set_enable(&mut self, enable: bool) {
+        if enable {
+            self.control |= 1 << 0;
+        } else {
+            self.control &= !(1 << 0);
+        }
+
+        // The low 16 bits of the register are read-only
+        self.write(0, (self.control as u32) << 16);
+    }
+}
+
+/// The MSI-X capability of a device
+#[derive(Clone, Copy, Debug)]
+pub struct MsiXCapability {
+    /// Configuration space accessor for the device
+    access: &'static dyn ConfigAccess,
+
+    /// Location of the device this capability belongs to
+    addr: PciAddress,
+
+    /// Offset of the capability in configuration space
+    offset: u8,
+
+    /// Message control register at the time the capability was parsed
+    control: u16,
+
+    /// Raw table offset and BIR register
+    table: u32,
+
+    /// Raw pending bit array offset and BIR register
+    pba: u32,
+}
+
+impl MsiXCapability {
+    /// Parse the MSI-X capability at `offset` in the configuration space of
+    /// the device at `addr`
+    pub(super) unsafe fn new(access: &'static dyn ConfigAccess,
+                             addr: PciAddress, offset: u8) -> Self {
+        let read = |x: u8| access.read(addr, (offset + x) as u16);
+
+        MsiXCapability {
+            access,
+            addr,
+            offset,
+            control: (read(0x0) >> 16) as u16,
+            table:   read(0x4),
+            pba:     read(0x8),
+        }
+    }
+
+    /// Get the raw message control register
+    pub fn control(&self) -> u16 {
+        self.control
+    }
+
+    /// Number of entries in the MSI-X table
+    pub fn table_size(&self) -> u16 {
+        (self.control & 0x7ff) + 1
+    }
+
+    /// Index of the BAR which holds the MSI-X table
+    pub fn table_bir(&self) -> usize {
+        (self.table & 7) as usize
+    }
+
+    /// Offset of the MSI-X table from the start of its BAR
+    pub fn table_offset(&self) -> u64 {
+        (self.table & !7) as u64
+    }
+
+    /// Index of the BAR which holds the pending bit array
+    pub fn pba_bir(&self) -> usize {
+        (self.pba & 7) as usize
+    }
+
+    /// Offset of the pending bit array from the start of its BAR
+    pub fn pba_offset(&self) -> u64 {
+        (self.pba & !7) as u64
+    }
+
+    /// Update the message control register with `bit` set to `value`
+    unsafe fn set_control_bit(&mut self, bit: u16, value: bool) {
+        if value {
+            self.control |= 1 << bit;
+        } else {
+            self.control &= !(1 << bit);
+        }
+
+        // The low 16 bits of the register are read-only
+        self.access.write(self.addr, self.offset as u16,
+                          (self.control as u32) << 16);
+    }
+
+    /// Enable or disable MSI-X for the device
+    pub unsafe fn set_enable(&mut self, enable: bool) {
+        self.set_control_bit(15, enable);
+    }
+
+    /// Mask or unmask all vectors of the device, regardless of their
+    /// individual mask bits
+    pub unsafe fn set_function_mask(&mut self, mask: bool) {
+        self.set_control_bit(14, mask);
+    }
+
+    /// Map in the MSI-X table of the device. `device` must be the
+    /// configuration space of the device this capability belongs to.
+    ///
+    /// Returns `None` if the BAR holding the table is not a memory BAR.
+    pub unsafe fn map_table(&self, device: &PciDevice) -> Option<MsiXTable> {
+        // Get the physical address of the table
+        let base = match device.bar(self.table_bir())? {
+            Bar::Memory { addr, .. } => addr,
+            Bar::Io { .. } => return None,
+        };
+        let paddr = PhysAddr(base.checked_add(self.table_offset())?);
+
+        // Map in the table as uncacheable memory
+        let entries = self.table_size();
+        let vaddr = mm::map_mmio(paddr,
+            entries as u64 * MsiXTable::ENTRY_SIZE as u64);
+
+        Some(MsiXTable { vaddr, entries })
+    }
+}
+
+/// A mapping of the MSI-X table of a device
+pub struct MsiXTable {
+    /// Virtual address of the first entry of the table
+    vaddr: VirtAddr,
+
+    /// Number of entries in the table
+    entries: u16,
+}
+
+impl MsiXTable {
+    /// Size of an entry in the MSI-X table, in bytes
+    const ENTRY_SIZE: usize = 16;
+
+    /// Number of entries in the table
+    pub fn len(&self) -> usize {
+        self.entries as usize
+    }
+
+    /// Get a pointer to the 32-bit word `word` of table entry `vector`
+    fn register(&self, vector: usize, word: usize) -> *mut u32 {
+        assert!(vector < self.len(), "MSI-X vector out of bounds");
+        (self.vaddr.0 as usize + vector * Self::ENTRY_SIZE + word * 4)
+            as *mut u32
+    }
+
+    /// Read the little-endian 32-bit word `word` of table entry `vector`
+    unsafe fn read(&self, vector: usize, word: usize) -> u32 {
+        u32::from_le(core::ptr::read_volatile(self.register(vector, word)))
+    }
+
+    /// Write `value` to the little-endian 32-bit word `word` of table entry
+    /// `vector`
+    unsafe fn write(&mut self, vector: usize, word: usize, value: u32) {
+        core::ptr::write_volatile(self.register(vector, word), value.to_le());
+    }
+
+    /// Get the message address and data `vector` writes when it signals an
+    /// interrupt
+    pub unsafe fn vector(&self, vector: usize) -> (u64, u32) {
+        let low  = self.read(vector, 0);
+        let high = self.read(vector, 1);
+        let data = self.read(vector, 2);
+        (((high as u64) << 32) | low as u64, data)
+    }
+
+    /// Program the message address and data `vector` will write when it
+    /// signals an interrupt
+    ///
+    /// The vector should be masked while it is being reprogrammed.
+    pub unsafe fn set_vector(&mut self, vector: usize, address: u64,
+                             data: u32) {
+        self.write(vector, 0, address as u32);
+        self.write(vector, 1, (address >> 32) as u32);
+        self.write(vector, 2, data);
+    }
+
+    /// Returns `true` if `vector` is masked
+    pub unsafe fn is_masked(&self, vector: usize) -> bool {
+        (self.read(vector, 3) & 1) != 0
+    }
+
+    /// Mask or unmask `vector`
+    pub unsafe fn set_masked(&mut self, vector: usize, mask: bool) {
+        let ctrl = self.read(vector, 3);
+        self.write(vector, 3, if mask { ctrl | 1 } else { ctrl & !1 });
+    }
+}
+
+/// The enhanced allocation (EA) capability of a device, which describes
+/// resources at fixed addresses in place of BARs
+#[derive(Clone, Copy, Debug)]
+pub struct EaCapability {
+    /// Configuration space accessor for the device
+    access: &'static dyn ConfigAccess,
+
+    /// Location of the device this capability belongs to
+    addr: PciAddress,
+
+    /// Offset of the first entry in configuration space
+    first: u16,
+
+    /// Number of entries in the capability
+    entries: u8,
+}
+
+/// A resource described by an entry of the enhanced allocation capability
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EaEntry {
+    /// BAR equivalent indicator. 0 to 5 are the BAR the entry stands in for,
+    /// 8 is the expansion ROM and 9 to 14 are the SR-IOV VF BARs.
+    pub bei: u8,
+
+    /// Type of the resource, eg. 0 for memory and 2 for I/O
+    pub properties: u8,
+
+    /// If set, the device decodes the resource
+    pub enabled: bool,
+
+    /// Base address of the resource
+    pub base: u64,
+
+    /// Size of the resource, in bytes
+    pub size: u64,
+}
+
+impl EaEntry {
+    /// Get the resource of the function described by this entry as the BAR
+    /// it stands in for. Returns `None` for resources of other types, eg.
+    /// the windows of a bridge.
+    pub fn bar(&self) -> Option<Bar> {
+        let ty = if self.base > !0u32 as u64 {
+            BarType::Bits64
+        } else {
+            BarType::Bits32
+        };
+
+        match self.properties {
+            0x00 | 0x01 => Some(Bar::Memory {
+                addr:         self.base,
+                prefetchable: self.properties == 0x01,
+                ty,
+            }),
+            0x02 if self.base.checked_add(self.size)? <= 0x10000 => {
+                Some(Bar::Io { port: self.base as u16 })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl EaCapability {
+    /// Parse the enhanced allocation capability at `offset` in the
+    /// configuration space of the device at `addr`
+    pub(super) unsafe fn new(access: &'static dyn ConfigAccess,
+                             addr: PciAddress, offset: u8) -> Self {
+        let header = access.read(addr, offset as u16);
+
+        // Bridges have an extra dword with their fixed bus numbers before
+        // the entries
+        let header_type = (access.read(addr, 0x0c) >> 16) & 0x7f;
+        let first = offset as u16 + if header_type == 1 { 8 } else { 4 };
+
+        EaCapability {
+            access,
+            addr,
+            first,
+            entries: ((header >> 16) & 0x3f) as u8,
+        }
+    }
+
+    /// Read the 32-bit register at configuration space offset `offset`
+    unsafe fn read(&self, offset: u16) -> u32 {
+        self.access.read(self.addr, offset)
+    }
+
+    /// Decode all the entries of the capability. Decoding stops at the first
+    /// entry which is too small for the addresses it claims to hold or runs
+    /// past the end of configuration space.
+    pub unsafe fn entries(&self) -> impl Iterator<Item = EaEntry> + '_ {
+        (0..self.entries).scan(self.first, move |offset, _| {
+            let entry = self.entry(*offset)?;
+            *offset += ((self.read(*offset) & 7) as u16 + 1) * 4;
+            Some(entry)
+        })
+    }
+
+    /// Decode the entry at configuration space offset `offset`
+    unsafe fn entry(&self, offset: u16) -> Option<EaEntry> {
+        // The entry size is the number of dwords following the header
+        let header = self.read(offset);
+        let dwords = (header & 7) as u16 + 1;
+        if offset + dwords * 4 > 0x100 || dwords < 3 {
+            return None;
+        }
+
+        // The primary properties of 0xff mean the secondary ones apply
+        let properties = match (header >> 8) as u8 {
+            0xff => (header >> 16) as u8,
+            x    => x,
+        };
+
+        // Bit 1 of the base and max offset fields mean the upper 32 bits
+        // follow
+        let base_lo = self.read(offset + 4);
+        let max_lo  = self.read(offset + 8);
+        let needed  = 3 + ((base_lo >> 1) & 1) + ((max_lo >> 1) & 1);
+        if (dwords as u32) < needed {
+            return None;
+        }
+
+        let mut next = offset + 12;
+        let mut base = (base_lo & !3) as u64;
+        if (base_lo & 2) != 0 {
+            base |= (self.read(next) as u64) << 32;
+            next += 4;
+        }
+
+        // The low 2 bits of the max offset are always set
+        let mut max_offset = (max_lo | 3) as u64;
+        if (max_lo & 2) != 0 {
+            max_offset |= (self.read(next) as u64) << 32;
+        }
+
+        Some(EaEntry {
+            bei:     ((header >> 4) & 0xf) as u8,
+            properties,
+            enabled: (header >> 31) != 0,
+            base,
+            size:    max_offset.checked_add(1)?,
+        })
+    }
+}
+
+/// The single root I/O virtualization (SR-IOV) extended capability of a
+/// physical function
+#[derive(Clone, Copy, Debug)]
+pub struct SriovCapability {
+    /// Configuration space accessor for the device
+    access: &'static dyn ConfigAccess,
+
+    /// Location of the physical function this capability belongs to
+    addr: PciAddress,
+
+    /// Offset of the capability in configuration space
+    offset: u16,
+}
+
+/// VF enable bit in the SR-IOV control register
+const SRIOV_VF_ENABLE: u32 = 1 << 0;
+
+/// VF memory space enable bit in the SR-IOV control register
+const SRIOV_VF_MSE: u32 = 1 << 3;
+
+/// Offset of the first VF BAR in the SR-IOV capability
+const SRIOV_VF_BAR0: u16 = 0x24;
+
+impl SriovCapability {
+    /// Parse the SR-IOV capability at `offset` in the configuration space of
+    /// the physical function at `addr`
+    pub(super) fn new(access: &'static dyn ConfigAccess, addr: PciAddress,
+                      offset: u16) -> Self {
+        SriovCapability { access, addr, offset }
+    }
+
+    /// Read the 32-bit register at `offset` bytes into the capability
+    unsafe fn read(&self, offset: u16) -> u32 {
+        self.access.read(self.addr, self.offset + offset)
+    }
+
+    /// Write the 32-bit register at `offset` bytes into the capability
+    unsafe fn write(&self, offset: u16, value: u32) {
+        self.access.write(self.addr, self.offset + offset, value)
+    }
+
+    /// Write the SR-IOV control register. The status register shares the
+    /// dword and is written with zeros such that its write-1-to-clear bit is
+    /// not cleared.
+    unsafe fn set_control(&self, control: u32) {
+        self.write(0x08, control & 0xffff);
+    }
+
+    /// Returns `true` if the virtual functions are enabled
+    pub unsafe fn enabled(&self) -> bool {
+        (self.read(0x08) & SRIOV_VF_ENABLE) != 0
+    }
+
+    /// Maximum number of virtual functions the device supports
+    pub unsafe fn total_vfs(&self) -> u16 {
+        (self.read(0x0c) >> 16) as u16
+    }
+
+    /// Number of virtual functions currently configured
+    pub unsafe fn num_vfs(&self) -> u16 {
+        self.read(0x10) as u16
+    }
+
+    /// Routing ID offset of the first virtual function from the physical
+    /// function. This depends on the number of VFs configured.
+    pub unsafe fn first_vf_offset(&self) -> u16 {
+        self.read(0x14) as u16
+    }
+
+    /// Routing ID distance between consecutive virtual functions. This
+    /// depends on the number of VFs configured.
+    pub unsafe fn vf_stride(&self) -> u16 {
+        (self.read(0x14) >> 16) as u16
+    }
+
+    /// Device ID reported by the virtual functions
+    pub unsafe fn vf_device_id(&self) -> u16 {
+        (self.read(0x18) >> 16) as u16
+    }
+
+    /// Get the location of virtual function `vf`, `None` if `vf` is not
+    /// configured or would be placed past the last bus
+    pub unsafe fn vf_address(&self, vf: u16) -> Option<PciAddress> {
+        if vf >= self.num_vfs() {
+            return None;
+        }
+
+        // Compute the routing ID of the VF relative to the PF, routing IDs
+        // are laid out like the indices of the presence bitmap
+        let rid = self.addr.index() + self.first_vf_offset() as usize +
+            vf as usize * self.vf_stride() as usize;
+        if rid >= PciAddress::COUNT {
+            return None;
+        }
+
+        Some(PciAddress::from_index(rid))
+    }
+
+    /// Get the raw VF BAR registers. Each VF BAR describes the base of an
+    /// array of equally sized regions, one for each virtual function.
+    pub unsafe fn vf_bars(&self) -> [u32; 6] {
+        let mut ret = [0u32; 6];
+        for (ii, bar) in ret.iter_mut().enumerate() {
+            *bar = self.read(SRIOV_VF_BAR0 + ii as u16 * 4);
+        }
+        ret
+    }
+
+    /// Determine the size (in bytes) of the region each virtual function gets
+    /// from each VF BAR, in the same way as `ProbedDevice::bar_size`. The high
+    /// halves of 64-bit VF BARs report a size of zero.
+    ///
+    /// VF memory decoding is disabled while the BARs are being sized.
+    pub unsafe fn vf_bar_sizes(&self) -> [u64; 6] {
+        let mut ret = [0u64; 6];
+
+        // Save the control register and disable VF memory decoding
+        let control = self.read(0x08) & 0xffff;
+        self.set_control(control & !SRIOV_VF_MSE);
+
+        let mut ii = 0;
+        while ii < 6 {
+            let offset = SRIOV_VF_BAR0 + ii as u16 * 4;
+            let orig   = self.read(offset);
+
+            // VF BARs are always memory BARs, bit 2 indicates a 64-bit BAR
+            if (orig & (1 << 2)) != 0 && ii < 5 {
+                let orig_hi = self.read(offset + 4);
+                self.write(offset,     !0);
+                self.write(offset + 4, !0);
+                let mask = ((self.read(offset + 4) as u64) << 32) |
+                    (self.read(offset) & 0xffff_fff0) as u64;
+                self.write(offset,     orig);
+                self.write(offset + 4, orig_hi);
+
+                ret[ii] = if mask == 0 { 0 } else { (!mask).wrapping_add(1) };
+                ii += 2;
+            } else {
+                self.write(offset, !0);
+                let mask = self.read(offset) & 0xffff_fff0;
+                self.write(offset, orig);
+
+                ret[ii] = if mask == 0 { 0 } else { (!mask) as u64 + 1 };
+                ii += 1;
+            }
+        }
+
+        // Restore the control register
+        self.set_control(control);
+
+        ret
+    }
+
+    /// Configure `num_vfs` virtual functions and enable them, along with their
+    /// memory decoding. If the VFs are already enabled they are left alone.
+    ///
+    /// This waits the 100 milliseconds required before the VFs can be
+    /// accessed.
+    pub unsafe fn enable(&self, num_vfs: u16) {
+        if self.enabled() {
+            return;
+        }
+
+        assert!(num_vfs <= self.total_vfs(), "Too many VFs requested");
+
+        // Set the number of VFs, the upper 16 bits are read-only
+        self.write(0x10, num_vfs as u32);
+
+        // Enable the VFs and their memory decoding
+        let control = self.read(0x08) & 0xffff;
+        self.set_control(control | SRIOV_VF_ENABLE | SRIOV_VF_MSE);
+
+        // Wait for the VFs to become ready
+        crate::time::sleep(100_000);
+    }
+}
+
+/// Snapshot of the error registers of the advanced error reporting (AER)
+/// extended capability
+#[derive(Clone, Copy, Debug)]
+pub struct AerStatus {
+    /// Uncorrectable errors which were detected, one bit per error type
+    pub uncorrectable_status: u32,
+
+    /// Uncorrectable errors which are not reported
+    pub uncorrectable_mask: u32,
+
+    /// Uncorrectable errors which are reported as fatal rather than as
+    /// non-fatal
+    pub uncorrectable_severity: u32,
+
+    /// Correctable errors which were detected, eg. receiver errors and bad
+    /// TLPs or DLLPs on the link
+    pub correctable_status: u32,
+
+    /// Correctable errors which are not reported
+    pub correctable_mask: u32,
+
+    /// Bit index in `uncorrectable_status` of the first uncorrectable error
+    /// which was logged
+    pub first_error: u8,
+
+    /// Header of the TLP which caused the first uncorrectable error
+    pub header_log: [u32; 4],
+}
+
+impl AerStatus {
+    /// Read the AER capability at `offset` in the configuration space of the
+    /// device at `addr`
+    pub(super) unsafe fn read(access: &'static dyn ConfigAccess,
+                              addr: PciAddress, offset: u16) -> Self {
+        let read = |reg: u16| access.read(addr, offset + reg);
+
+        AerStatus {
+            uncorrectable_status:   read(0x04),
+            uncorrectable_mask:     read(0x08),
+            uncorrectable_severity: read(0x0c),
+            correctable_status:     read(0x10),
+            correctable_mask:       read(0x14),
+            first_error:            (read(0x18) & 0x1f) as u8,
+            header_log: [read(0x1c), read(0x20), read(0x24), read(0x28)],
+        }
+    }
+
+    /// Returns `true` if any error, correctable or not, has been logged
+    pub fn has_errors(&self) -> bool {
+        self.uncorrectable_status != 0 || self.correctable_status != 0
+    }
+
+    /// Get the logged uncorrectable errors which are reported as fatal
+    pub fn fatal_errors(&self) -> u32 {
+        self.uncorrectable_status & self.uncorrectable_severity &
+            !self.uncorrectable_mask
+    }
+
+    /// Clear the logged error bits of the AER capability at `offset`. The
+    /// status registers are write-1-to-clear.
+    pub(super) unsafe fn clear(access: &'static dyn ConfigAccess,
+                               addr: PciAddress, offset: u16) {
+        access.write(addr, offset + 0x04, !0);
+        access.write(addr, offset + 0x10, !0);
+    }
+}
@@ -0,0 +1,281 @@
+//! Abstraction over how PCI configuration space is accessed
+//!
+//! Everything which decodes configuration space (BAR sizing, capability
+//! walking, header parsing) goes through `ConfigAccess`, such that it can be
+//! pointed at a synthetic configuration space rather than real hardware.
+
+use lockcell::LockCell;
+
+use crate::core_locals::LockInterrupts;
+use super::{PciAddress, ecam, read_config_u32, write_config_u32};
+
+/// Access to the configuration space of PCI functions. Offsets are in bytes
+/// and must be 4-byte aligned.
+pub trait ConfigAccess: Sync {
+    /// Read the 32-bit register at `offset` for the function at `addr`
+    unsafe fn read(&self, addr: PciAddress, offset: u16) -> u32;
+
+    /// Write `value` to the 32-bit register at `offset` for the function at
+    /// `addr`
+    unsafe fn write(&self, addr: PciAddress, offset: u16, value: u32);
+
+    /// Returns `true` if the extended configuration space (offsets `0x100`
+    /// and above) of the function at `addr` can be accessed
+    fn has_extended(&self, addr: PciAddress) -> bool;
+}
+
+impl core::fmt::Debug for dyn ConfigAccess {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("ConfigAccess")
+    }
+}
+
+/// Configuration space access to the real hardware, via ECAM or the legacy
+/// `0xcf8`/`0xcfc` ports
+pub struct HardwareConfig;
+
+/// The one and only hardware configuration space accessor
+pub static HARDWARE: HardwareConfig = HardwareConfig;
+
+impl ConfigAccess for HardwareConfig {
+    unsafe fn read(&self, addr: PciAddress, offset: u16) -> u32 {
+        read_config_u32(addr.bus, addr.device, addr.function, offset)
+    }
+
+    unsafe fn write(&self, addr: PciAddress, offset: u16, value: u32) {
+        write_config_u32(addr.bus, addr.device, addr.function, offset, value)
+    }
+
+    fn has_extended(&self, addr: PciAddress) -> bool {
+        ecam::available(addr.bus)
+    }
+}
+
+/// Number of dwords in the configuration space of a function, including the
+/// extended configuration space
+pub const CONFIG_DWORDS: usize = 4096 / 4;
+
+/// A synthetic configuration space for a single function
+///
+/// Bits which are not set in the write mask of a register are read-only, this
+/// is what lets BARs be sized against the mock. Accesses to any other function
+/// behave as if nothing is there, reads return all ones and writes are
+/// dropped.
+pub struct MockConfig {
+    /// Location of the function being emulated
+    addr: PciAddress,
+
+    /// Contents of the configuration space
+    space: LockCell<[u32; CONFIG_DWORDS], LockInterrupts>,
+
+    /// Writable bits of each register
+    write_mask: [u32; CONFIG_DWORDS],
+}
+
+impl MockConfig {
+    /// Create a mock function at `addr` whose configuration space starts with
+    /// `config`, with the rest of the space zeroed. Everything is writable
+    /// until restricted with `set_write_mask`.
+    pub fn new(addr: PciAddress, config: &[u32]) -> Self {
+        assert!(config.len() <= CONFIG_DWORDS,
+            "Mock configuration space too large");
+
+        let mut space = [0u32; CONFIG_DWORDS];
+        space[..config.len()].copy_from_slice(config);
+
+        MockConfig {
+            addr,
+            space:      LockCell::new(space),
+            write_mask: [!0u32; CONFIG_DWORDS],
+        }
+    }
+
+    /// Create a mock function at `addr` with a zeroed configuration space,
+    /// everything is writable. This can be used for a `static` mock which is
+    /// filled with `load`.
+    pub const fn empty(addr: PciAddress) -> Self {
+        MockConfig {
+            addr,
+            space:      LockCell::new([0u32; CONFIG_DWORDS]),
+            write_mask: [!0u32; CONFIG_DWORDS],
+        }
+    }
+
+    /// Replace the configuration space with `config`, with the rest of the
+    /// space zeroed. Write masks are left as they are.
+    pub fn load(&self, config: &[u32]) {
+        assert!(config.len() <= CONFIG_DWORDS,
+            "Mock configuration space too large");
+
+        let mut space = self.space.lock();
+        space[..config.len()].copy_from_slice(config);
+        space[config.len()..].iter_mut().for_each(|x| *x = 0);
+    }
+
+    /// Get a copy of the current contents of the configuration space
+    pub fn contents(&self) -> [u32; CONFIG_DWORDS] {
+        *self.space.lock()
+    }
+
+    /// Restrict which bits of the register at `offset` can be written
+    pub fn set_write_mask(&mut self, offset: u16, mask: u32) {
+        self.write_mask[Self::index(offset)] = mask;
+    }
+
+    /// Get the dword index for the register at `offset`
+    fn index(offset: u16) -> usize {
+        assert!((offset & 3) == 0 && (offset as usize) < CONFIG_DWORDS * 4,
+            "Invalid PCI config space offset");
+        offset as usize / 4
+    }
+}
+
+impl ConfigAccess for MockConfig {
+    unsafe fn read(&self, addr: PciAddress, offset: u16) -> u32 {
+        if addr != self.addr {
+            return !0;
+        }
+
+        self.space.lock()[Self::index(offset)]
+    }
+
+    unsafe fn write(&self, addr: PciAddress, offset: u16, value: u32) {
+        if addr != self.addr {
+            return;
+        }
+
+        let idx  = Self::index(offset);
+        let mask = self.write_mask[idx];
+        let mut space = self.space.lock();
+        space[idx] = (space[idx] & !mask) | (value & mask);
+    }
+
+    fn has_extended(&self, _addr: PciAddress) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::pci::ProbedDevice;
+
+    /// Location of the mocked function
+    const ADDR: PciAddress = PciAddress { bus: 0, device: 3, function: 0 };
+
+    /// Status register bit, the capability pointer is valid
+    const STATUS_CAP_LIST: u32 = 1 << 20;
+
+    /// Create a header of a network controller with `bars` in BAR 0 onwards
+    fn header(status: u32, bars: &[u32]) -> [u32; 16] {
+        let mut config = [0u32; 16];
+        config[0] = 0x1234_8086;
+        config[1] = status;
+        config[2] = 0x0200_0000;
+        config[4..4 + bars.len()].copy_from_slice(bars);
+        config
+    }
+
+    /// Leak `mock` such that a `ProbedDevice` can be created on it
+    fn probe(mock: MockConfig) -> ProbedDevice {
+        let mock: &'static MockConfig = Box::leak(Box::new(mock));
+        unsafe { ProbedDevice::from_access(mock, ADDR) }
+    }
+
+    #[test]
+    fn bar_sizing_32bit() {
+        let mut mock = MockConfig::new(ADDR, &header(0, &[0xfebf_0000]));
+        mock.set_write_mask(0x10, 0xffff_0000);
+
+        let device = probe(mock);
+        assert_eq!(unsafe { device.bar_size(0) }, 0x1_0000);
+
+        // The BAR must be left as it was
+        assert_eq!(unsafe { device.read_config_u32(0x10) }, 0xfebf_0000);
+    }
+
+    #[test]
+    fn bar_sizing_64bit() {
+        let mut mock = MockConfig::new(ADDR,
+            &header(0, &[0xe000_000c, 0x0000_0001]));
+        mock.set_write_mask(0x10, 0xfe00_0000);
+        mock.set_write_mask(0x14, !0);
+
+        let device = probe(mock);
+        assert_eq!(unsafe { device.bar_size(0) }, 0x200_0000);
+
+        // Both halves must be left as they were
+        assert_eq!(unsafe { device.read_config_u32(0x10) }, 0xe000_000c);
+        assert_eq!(unsafe { device.read_config_u32(0x14) }, 0x0000_0001);
+    }
+
+    #[test]
+    fn capability_walk() {
+        let mut config = [0u32; 0x60 / 4];
+        config[..16].copy_from_slice(&header(STATUS_CAP_LIST, &[]));
+        config[0x34 / 4] = 0x40;
+        config[0x40 / 4] = 0x0000_5001;
+        config[0x50 / 4] = 0x0000_0005;
+
+        let device = probe(MockConfig::new(ADDR, &config));
+        let caps: Vec<_> = unsafe { device.capabilities() }.collect();
+        assert_eq!(caps, [(0x01, 0x40), (0x05, 0x50)]);
+    }
+
+    #[test]
+    fn capability_loop() {
+        let mut config = [0u32; 0x60 / 4];
+        config[..16].copy_from_slice(&header(STATUS_CAP_LIST, &[]));
+        config[0x34 / 4] = 0x40;
+        config[0x40 / 4] = 0x0000_5001;
+        config[0x50 / 4] = 0x0000_4005;
+
+        // The walk stops once it gets back to the first capability
+        let device = probe(MockConfig::new(ADDR, &config));
+        let caps: Vec<_> = unsafe { device.capabilities() }.collect();
+        assert_eq!(caps, [(0x01, 0x40), (0x05, 0x50)]);
+    }
+
+    #[test]
+    fn capability_in_header() {
+        let mut config = [0u32; 0x60 / 4];
+        config[..16].copy_from_slice(&header(STATUS_CAP_LIST, &[]));
+        config[0x34 / 4] = 0x10;
+
+        // Capabilities can't live in the standard header
+        let device = probe(MockConfig::new(ADDR, &config));
+        assert_eq!(unsafe { device.capabilities() }.count(), 0);
+    }
+
+    #[test]
+    fn extended_capability_bounds() {
+        let mut config = [0u32; CONFIG_DWORDS];
+        config[..16].copy_from_slice(&header(0, &[]));
+
+        // A capability in the last dword of the extended configuration space
+        // is fine
+        config[0x100 / 4] = (0xffc << 20) | (1 << 16) | 0x0001;
+        config[0xffc / 4] = (0x000 << 20) | (1 << 16) | 0x000e;
+        let device = probe(MockConfig::new(ADDR, &config));
+        let caps: Vec<_> = unsafe { device.extended_capabilities() }
+            .collect();
+        assert_eq!(caps, [(0x0001, 0x100), (0x000e, 0xffc)]);
+
+        // A pointer back into the legacy configuration space ends the walk
+        config[0xffc / 4] = (0x0f0 << 20) | (1 << 16) | 0x000e;
+        let device = probe(MockConfig::new(ADDR, &config));
+        let caps: Vec<_> = unsafe { device.extended_capabilities() }
+            .collect();
+        assert_eq!(caps, [(0x0001, 0x100), (0x000e, 0xffc)]);
+
+        // As does a capability pointing to itself
+        config[0x100 / 4] = (0x100 << 20) | (1 << 16) | 0x0001;
+        let device = probe(MockConfig::new(ADDR, &config));
+        let caps: Vec<_> = unsafe { device.extended_capabilities() }
+            .collect();
+        assert_eq!(caps, [(0x0001, 0x100)]);
+    }
+}
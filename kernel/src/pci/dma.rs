@@ -0,0 +1,281 @@
+//! Physically contiguous memory which devices can access with DMA
+
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::Ordering;
+#[cfg(debug_assertions)]
+use alloc::vec::Vec;
+
+use rangeset::{Range, RangeSet};
+#[cfg(debug_assertions)]
+use lockcell::LockCell;
+use page_table::PhysAddr;
+use boot_args::{KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE};
+
+use crate::mm::{self, GLOBAL_ALLOCATOR};
+use crate::pci::PciAddress;
+#[cfg(debug_assertions)]
+use crate::core_locals::LockInterrupts;
+
+/// Number of live DMA regions per device, used to catch drivers which leak
+/// DMA memory when their device goes away
+#[cfg(debug_assertions)]
+static LIVE_REGIONS: LockCell<Vec<(PciAddress, usize)>, LockInterrupts> =
+    LockCell::new_no_preempt(Vec::new());
+
+/// Get the number of DMA regions allocated for the device at `addr` which
+/// have not been freed yet
+#[cfg(debug_assertions)]
+pub fn live_regions(addr: PciAddress) -> usize {
+    LIVE_REGIONS.lock().iter().find(|(owner, _)| *owner == addr)
+        .map(|&(_, count)| count).unwrap_or(0)
+}
+
+/// Account for a DMA region of the device at `addr` being allocated
+/// (`delta` of 1) or freed (`delta` of -1)
+#[cfg(debug_assertions)]
+fn track_region(addr: PciAddress, delta: isize) {
+    let mut live = LIVE_REGIONS.lock();
+    if let Some(idx) = live.iter().position(|(owner, _)| *owner == addr) {
+        live[idx].1 = (live[idx].1 as isize + delta) as usize;
+        if live[idx].1 == 0 {
+            live.swap_remove(idx);
+        }
+    } else {
+        assert!(delta > 0, "Freed an untracked DMA region");
+        live.push((addr, delta as usize));
+    }
+}
+
+/// Physically contiguous memory holding a `T`, visible to a device at
+/// `phys_addr()` and to the kernel through the linear physical window.
+///
+/// Unlike `PhysContig` this is not limited to a single page, and can hold a
+/// slice whose length is picked at runtime with `new_slice`. Drivers store
+/// these in their device structure so the memory lives exactly as long as
+/// the device's `Arc`, and is only returned to the kernel once the device
+/// can no longer be told to access it.
+///
+/// Dropping a region never touches the device, it only gives the memory
+/// back. It's up to the driver to stop the device from accessing the memory
+/// first. Soft reboots don't drop devices, they are only purged, thus DMA
+/// which is still running during a purge never lands in memory which was
+/// handed out again.
+pub struct DmaRegion<T: ?Sized> {
+    /// Device this region was allocated for
+    owner: PciAddress,
+
+    /// Physical address of the allocation
+    paddr: PhysAddr,
+
+    /// Size of the allocation, in bytes. Always a multiple of 4 KiB.
+    size: u64,
+
+    /// Pointer to the contents in the linear physical window
+    ptr: NonNull<T>,
+
+    /// Mark that this "holds" a `T`
+    _phantom: PhantomData<T>,
+}
+
+// The region owns its contents like a `Box` does
+unsafe impl<T: ?Sized + Send> Send for DmaRegion<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for DmaRegion<T> {}
+
+/// Allocate physically contiguous memory of `bytes` bytes aligned to `align`
+/// bytes for the device at `_owner`, which lies entirely within the
+/// addresses covered by `dma_mask`. `align` must be a power of two of at
+/// least 4 KiB. Returns the address and rounded up size of the allocation.
+fn alloc(_owner: PciAddress, dma_mask: u64, bytes: usize, align: u64)
+        -> Option<(PhysAddr, u64)> {
+    assert!(bytes > 0, "Cannot use ZST for DmaRegion");
+    assert!(align >= 4096 && align.is_power_of_two(),
+        "Invalid DmaRegion alignment");
+
+    // Round the allocation up to a whole number of pages
+    let size = (bytes as u64).checked_add(0xfff)? & !0xfff;
+
+    // Memory must be reachable by both the device and the kernel
+    let limit = core::cmp::min(dma_mask.saturating_add(1),
+                               KERNEL_PHYS_WINDOW_SIZE);
+
+    let paddr = unsafe {
+        // Get access to physical memory
+        let mut phys_mem = core!().boot_args.free_memory_ref().lock();
+        let phys_mem = phys_mem.as_mut()?;
+
+        // Allocate the memory, preferring our NUMA node
+        let alc = phys_mem.allocate_prefer(size, align,
+                                           mm::memory_range())? as u64;
+
+        // Make sure the allocation is reachable, otherwise give it back
+        if alc + size > limit {
+            phys_mem.insert(Range { start: alc, end: alc + size - 1 });
+            None
+        } else {
+            // Update stats
+            GLOBAL_ALLOCATOR.free_physical
+                .store(phys_mem.sum().unwrap(), Ordering::Relaxed);
+
+            Some(PhysAddr(alc))
+        }
+    };
+
+    // If the memory near us was out of reach of the device, look for
+    // memory below the limit anywhere
+    let paddr = paddr.or_else(|| mm::alloc_phys_below(size, align, limit))?;
+
+    #[cfg(debug_assertions)]
+    track_region(_owner, 1);
+
+    Some((paddr, size))
+}
+
+/// Return the `size` bytes at `paddr` allocated by `alloc` to `phys_mem`
+///
+/// This only needs the memory pool, not the device the memory was allocated
+/// for, thus freeing works the same whether or not the device was stopped.
+fn give_back(phys_mem: &mut RangeSet, paddr: PhysAddr, size: u64) {
+    phys_mem.insert(Range { start: paddr.0, end: paddr.0 + size - 1 });
+
+    // Update stats
+    GLOBAL_ALLOCATOR.free_physical
+        .store(phys_mem.sum().unwrap(), Ordering::Relaxed);
+}
+
+impl<T> DmaRegion<T> {
+    /// Allocate physically contiguous, page aligned memory for the device at
+    /// `owner` large enough to hold `val` and move `val` into it. The memory
+    /// lies entirely within the addresses covered by `dma_mask`. Returns
+    /// `None` if there is no physical memory left to satisfy the allocation.
+    pub(super) fn new(owner: PciAddress, dma_mask: u64, val: T)
+            -> Option<Self> {
+        let (paddr, size) = alloc(owner, dma_mask, size_of::<T>(), 4096)?;
+        let ptr = (KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut T;
+
+        // Initialize the memory to `val`
+        unsafe { core::ptr::write(ptr, val); }
+
+        Some(DmaRegion {
+            owner,
+            paddr,
+            size,
+            ptr:      NonNull::new(ptr).unwrap(),
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<T: Copy> DmaRegion<[T]> {
+    /// Allocate memory for the device at `owner` like `new`, holding `len`
+    /// copies of `val`
+    pub(super) fn new_slice(owner: PciAddress, dma_mask: u64, val: T,
+                            len: usize) -> Option<Self> {
+        Self::new_slice_aligned(owner, dma_mask, val, len, 4096)
+    }
+
+    /// Same as `new_slice`, but the memory is aligned to `align` bytes,
+    /// which must be a power of two of at least 4 KiB
+    pub(super) fn new_slice_aligned(owner: PciAddress, dma_mask: u64, val: T,
+                                    len: usize, align: u64) -> Option<Self> {
+        let bytes = size_of::<T>().checked_mul(len)?;
+        let (paddr, size) = alloc(owner, dma_mask, bytes, align)?;
+        let ptr = (KERNEL_PHYS_WINDOW_BASE + paddr.0) as *mut T;
+
+        // Initialize every element to `val`
+        for ii in 0..len {
+            unsafe { core::ptr::write(ptr.add(ii), val); }
+        }
+
+        Some(DmaRegion {
+            owner,
+            paddr,
+            size,
+            ptr:      NonNull::new(
+                core::ptr::slice_from_raw_parts_mut(ptr, len)).unwrap(),
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<T: ?Sized> DmaRegion<T> {
+    /// Get the physical address of the allocation
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    /// Get the kernel virtual address of the allocation
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
+
+    /// Location of the device this region was allocated for
+    pub fn owner(&self) -> PciAddress {
+        self.owner
+    }
+
+    /// Size of the allocation, in bytes
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<T: ?Sized> Drop for DmaRegion<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // Drop the contents
+            core::ptr::drop_in_place(self.ptr.as_ptr());
+
+            // Give the memory back to the physical memory pool
+            let mut phys_mem = core!().boot_args.free_memory_ref().lock();
+            give_back(phys_mem.as_mut().unwrap(), self.paddr, self.size);
+        }
+
+        #[cfg(debug_assertions)]
+        track_region(self.owner, -1);
+    }
+}
+
+impl<T: ?Sized> Deref for DmaRegion<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for DmaRegion<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::config::MockConfig;
+
+    /// Location of the mocked function
+    const ADDR: PciAddress = PciAddress { bus: 0, device: 3, function: 0 };
+
+    #[test]
+    fn free_while_bus_mastering() {
+        // A function which still has memory space and bus mastering enabled
+        let mock = MockConfig::new(ADDR, &[0x1234_8086, 0x0000_0006]);
+        let before = mock.contents();
+
+        // Take a 16 KiB ring out of 64 KiB of memory
+        let mut phys_mem = RangeSet::new();
+        phys_mem.insert(Range { start: 0x10_0000, end: 0x10_ffff });
+        let ring = phys_mem.allocate(0x4000, 0x4000).unwrap() as u64;
+        assert_eq!(ring % 0x4000, 0);
+        assert_eq!(phys_mem.sum(), Some(0xc000));
+
+        // Freeing gives all of the memory back without stopping the device
+        give_back(&mut phys_mem, PhysAddr(ring), 0x4000);
+        assert_eq!(phys_mem.sum(), Some(0x1_0000));
+        assert!(mock.contents()[..] == before[..]);
+    }
+}
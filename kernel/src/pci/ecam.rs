@@ -0,0 +1,149 @@
+//! PCIe enhanced configuration access mechanism (ECAM)
+//!
+//! This gives memory-mapped access to the full 4 KiB configuration space of
+//! every PCI function, including the PCIe extended capabilities past the
+//! first 256 bytes which cannot be reached via the legacy `0xcf8`/`0xcfc` I/O
+//! ports.
+
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use lockcell::LockCell;
+use page_table::{PhysAddr, VirtAddr};
+
+use crate::acpi;
+use crate::mm;
+use crate::core_locals::LockInterrupts;
+use crate::pci::PciInitError;
+
+/// Number of bytes of configuration space for each function
+pub const CONFIG_SIZE: u64 = 4096;
+
+/// Number of bytes of configuration space for a whole bus
+const BUS_CONFIG_SIZE: u64 = 32 * 8 * CONFIG_SIZE;
+
+/// Physical address of the ECAM region for bus 0 of segment 0. If this is
+/// zero, ECAM is not available on this system.
+static ECAM_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// First bus decoded by the ECAM region
+static START_BUS: AtomicU8 = AtomicU8::new(0);
+
+/// Last bus (inclusive) decoded by the ECAM region
+static END_BUS: AtomicU8 = AtomicU8::new(0);
+
+/// Virtual addresses of the uncacheable mappings of the configuration space
+/// for each bus. Buses are mapped in lazily on first access, a value of zero
+/// indicates the bus has not been mapped yet.
+static BUS_MAPPINGS: [AtomicU64; 256] = [AtomicU64::new(0); 256];
+
+/// Lock held while creating a new mapping in `BUS_MAPPINGS`
+static MAPPING_LOCK: LockCell<(), LockInterrupts> = LockCell::new(());
+
+/// Locate the ECAM region via the ACPI MCFG table
+///
+/// We only support segment group 0, as that's the only segment which can be
+/// reached by the legacy configuration mechanism as well. It's not an error
+/// for there to be no region, but it is if the firmware described one we
+/// can't use.
+pub unsafe fn init() -> Result<(), PciInitError> {
+    // Find the segment group 0 region
+    let region = acpi::parse_mcfg().into_iter().find(|x| x.segment == 0);
+
+    if let Some(region) = region {
+        if region.start_bus > region.end_bus {
+            return Err(PciInitError::InvalidMcfg(
+                "Invalid bus range for MCFG entry"));
+        }
+        if (region.base.0 & 0xfff) != 0 {
+            return Err(PciInitError::InvalidMcfg(
+                "Non-4 KiB aligned ECAM region"));
+        }
+
+        // Save the bus range and then publish the base address
+        START_BUS.store(region.start_bus, Ordering::SeqCst);
+        END_BUS.store(region.end_bus, Ordering::SeqCst);
+        ECAM_BASE.store(region.base.0, Ordering::SeqCst);
+    }
+
+    Ok(())
+}
+
+/// Get the physical base address and the first and last bus of the ECAM
+/// region, `None` if ECAM is not available
+pub fn region() -> Option<(PhysAddr, u8, u8)> {
+    match ECAM_BASE.load(Ordering::SeqCst) {
+        0    => None,
+        base => Some((PhysAddr(base), START_BUS.load(Ordering::SeqCst),
+                      END_BUS.load(Ordering::SeqCst))),
+    }
+}
+
+/// Returns `true` if `bus` can be accessed via ECAM
+pub fn available(bus: u8) -> bool {
+    ECAM_BASE.load(Ordering::SeqCst) != 0 &&
+        bus >= START_BUS.load(Ordering::SeqCst) &&
+        bus <= END_BUS.load(Ordering::SeqCst)
+}
+
+/// Get the virtual address of the configuration space of `bus`, mapping it
+/// in if it has not been accessed yet
+unsafe fn bus_vaddr(bus: u8) -> VirtAddr {
+    assert!(available(bus), "Bus not accessible via ECAM");
+
+    // Fast path, the bus is already mapped in
+    let vaddr = BUS_MAPPINGS[bus as usize].load(Ordering::SeqCst);
+    if vaddr != 0 {
+        return VirtAddr(vaddr);
+    }
+
+    // Serialize mappings such that a bus is only ever mapped once
+    let _lock = MAPPING_LOCK.lock();
+
+    // Someone else may have mapped the bus while we were waiting on the lock
+    let vaddr = BUS_MAPPINGS[bus as usize].load(Ordering::SeqCst);
+    if vaddr != 0 {
+        return VirtAddr(vaddr);
+    }
+
+    // Compute the physical address of the configuration space for this bus
+    let paddr = PhysAddr(ECAM_BASE.load(Ordering::SeqCst) +
+                         bus as u64 * BUS_CONFIG_SIZE);
+
+    // Map in the configuration space as uncacheable memory
+    let vaddr = mm::map_mmio(paddr, BUS_CONFIG_SIZE);
+
+    // Publish the mapping
+    BUS_MAPPINGS[bus as usize].store(vaddr.0, Ordering::SeqCst);
+
+    vaddr
+}
+
+/// Get a pointer to the 32-bit configuration space register at `offset` for
+/// the PCI device at `bus:device.function`
+unsafe fn register(bus: u8, device: u8, function: u8,
+                   offset: u16) -> *mut u32 {
+    assert!((offset & 3) == 0 && (offset as u64) < CONFIG_SIZE,
+        "Invalid PCI config space offset");
+    assert!(device < 32 && function < 8, "Invalid PCI device or function");
+
+    let vaddr = bus_vaddr(bus).0 +
+        ((device as u64) << 15) + ((function as u64) << 12) + offset as u64;
+    vaddr as *mut u32
+}
+
+/// Read the 32-bit configuration space register at `offset` for the PCI
+/// device at `bus:device.function`
+pub unsafe fn read_u32(bus: u8, device: u8, function: u8,
+                       offset: u16) -> u32 {
+    // Configuration space is little-endian
+    u32::from_le(core::ptr::read_volatile(
+        register(bus, device, function, offset)))
+}
+
+/// Write `value` to the 32-bit configuration space register at `offset` for
+/// the PCI device at `bus:device.function`
+pub unsafe fn write_u32(bus: u8, device: u8, function: u8,
+                        offset: u16, value: u32) {
+    core::ptr::write_volatile(register(bus, device, function, offset),
+                              value.to_le());
+}
@@ -0,0 +1,252 @@
+//! Legacy INTx interrupts, routed through the I/O APICs
+//!
+//! Functions which don't use MSI signal their interrupt pin, which the
+//! platform wires to an input of an I/O APIC. Lines are often shared between
+//! devices, thus every device registered on a line has its interrupt handler
+//! invoked whenever the line fires.
+//!
+//! The input is derived from the interrupt line firmware wrote into
+//! configuration space, with the interrupt source overrides of the MADT
+//! applied. Platforms which route PCI interrupts elsewhere in APIC mode only
+//! describe that in the ACPI `_PRT`, which we can't evaluate.
+
+use core::ptr::{read_volatile, write_volatile};
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+
+use lockcell::LockCell;
+use page_table::VirtAddr;
+
+use crate::acpi::{self, InterruptOverride};
+use crate::mm;
+use crate::core_locals::LockInterrupts;
+use super::{irq, Device, PurgeError, ProbedDevice};
+
+/// Reasons a legacy interrupt handler could not be registered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyIrqError {
+    /// The device doesn't use an interrupt pin, or firmware didn't route it
+    NotRouted,
+
+    /// No I/O APIC handles the global system interrupt of the line
+    NoIoApic,
+
+    /// We ran out of interrupt vectors
+    NoVectors,
+
+    /// The APIC ID of the core can't be targeted by the I/O APIC
+    UnreachableCore,
+}
+
+/// An I/O APIC mapped into memory
+struct IoApic {
+    /// Virtual address of the index register, the data register is 16 bytes
+    /// after it
+    vaddr: VirtAddr,
+
+    /// Global system interrupt of the first input
+    gsi_base: u32,
+
+    /// Number of inputs
+    inputs: u32,
+}
+
+impl IoApic {
+    /// Read the register at `index`. `IOAPIC_LOCK` must be held.
+    unsafe fn read(&self, index: u32) -> u32 {
+        write_volatile(self.vaddr.0 as *mut u32, index);
+        read_volatile((self.vaddr.0 + 0x10) as *const u32)
+    }
+
+    /// Write `value` to the register at `index`. `IOAPIC_LOCK` must be held.
+    unsafe fn write(&self, index: u32, value: u32) {
+        write_volatile(self.vaddr.0 as *mut u32, index);
+        write_volatile((self.vaddr.0 + 0x10) as *mut u32, value);
+    }
+
+    /// Mask or unmask `input`
+    unsafe fn set_masked(&self, input: u32, masked: bool) {
+        let _lock = IOAPIC_LOCK.lock();
+        let low = self.read(0x10 + input * 2);
+        let low = if masked { low | (1 << 16) } else { low & !(1 << 16) };
+        self.write(0x10 + input * 2, low);
+    }
+}
+
+/// Interrupt routing of the system, set up on first use
+struct Routing {
+    /// The I/O APICs, mapped in
+    ioapics: Vec<Arc<IoApic>>,
+
+    /// Interrupt source overrides for the ISA IRQs
+    overrides: Vec<InterruptOverride>,
+
+    /// Lines which have handlers registered on them, or had at some point
+    lines: Vec<Arc<SharedLine>>,
+}
+
+/// Interrupt routing, `None` until the first handler is registered
+static ROUTING: LockCell<Option<Routing>, LockInterrupts> =
+    LockCell::new_no_preempt(None);
+
+/// Lock held while using the index and data registers of an I/O APIC
+static IOAPIC_LOCK: LockCell<(), LockInterrupts> =
+    LockCell::new_no_preempt(());
+
+/// An I/O APIC input with the devices sharing it, which owns the interrupt
+/// vector the input is programmed with
+struct SharedLine {
+    /// Global system interrupt of the line
+    gsi: u32,
+
+    /// I/O APIC the line is an input of
+    ioapic: Arc<IoApic>,
+
+    /// Input of `ioapic` the line is connected to
+    input: u32,
+
+    /// Devices sharing the line. This is taken in interrupt context, thus
+    /// handlers must not register or free handlers themselves.
+    handlers: LockCell<Vec<Arc<dyn Device>>, LockInterrupts>,
+}
+
+impl Device for SharedLine {
+    fn name(&self) -> &str {
+        "Legacy interrupt line"
+    }
+
+    unsafe fn purge(&self) -> Result<(), PurgeError> {
+        self.ioapic.set_masked(self.input, true);
+        Ok(())
+    }
+
+    unsafe fn handle_interrupt(&self, vector: u8) -> bool {
+        // Several devices may be asserting the line, give all of them a
+        // chance to deassert it
+        let mut handled = false;
+        for handler in self.handlers.lock().iter() {
+            handled |= handler.handle_interrupt(vector);
+        }
+
+        // Nobody wanted it. The line is level triggered and would fire again
+        // right away, so turn it off rather than storm.
+        if !handled {
+            print!("Legacy interrupt | GSI {} | unhandled, masking\n",
+                   self.gsi);
+            self.ioapic.set_masked(self.input, true);
+        }
+
+        true
+    }
+}
+
+/// Map in the I/O APICs and set up the interrupt routing
+unsafe fn init_routing() -> Routing {
+    let (entries, overrides) = acpi::parse_ioapics();
+
+    let ioapics = entries.iter().map(|entry| {
+        let mut ioapic = IoApic {
+            vaddr:    mm::map_mmio(entry.addr, 0x20),
+            gsi_base: entry.gsi_base,
+            inputs:   0,
+        };
+
+        // The version register holds the index of the last input
+        let _lock = IOAPIC_LOCK.lock();
+        ioapic.inputs = ((ioapic.read(0x01) >> 16) & 0xff) + 1;
+        Arc::new(ioapic)
+    }).collect();
+
+    Routing { ioapics, overrides, lines: Vec::new() }
+}
+
+/// Deliver the legacy interrupt of `device` to the current core, invoking
+/// `handler.handle_interrupt` whenever the line of the device fires. The
+/// device must deassert its interrupt when it's handled, and must have its
+/// INTx enabled (see `ProbedDevice::set_intx_mask`) to signal it.
+///
+/// Returns the global system interrupt the device was hooked to. The handler
+/// stays registered until `irq::free_device_vectors` is invoked on it, which
+/// happens when the device is removed.
+pub unsafe fn register_handler(device: &ProbedDevice,
+                               handler: Arc<dyn Device>)
+        -> Result<u32, LegacyIrqError> {
+    let line = device.legacy_interrupt().and_then(|x| x.line)
+        .ok_or(LegacyIrqError::NotRouted)?;
+
+    let mut routing = ROUTING.lock();
+    if routing.is_none() {
+        *routing = Some(init_routing());
+    }
+    let routing = routing.as_mut().unwrap();
+
+    // Apply the override of the line, PCI interrupts are level triggered
+    // and active low unless told otherwise
+    let (gsi, flags) = routing.overrides.iter()
+        .find(|x| x.source == line)
+        .map(|x| (x.gsi, x.flags))
+        .unwrap_or((line as u32, 0));
+    let active_low = (flags & 3) != 1;
+    let level      = ((flags >> 2) & 3) != 1;
+
+    // Share the line if someone already set it up
+    if let Some(shared) = routing.lines.iter().find(|x| x.gsi == gsi) {
+        shared.handlers.lock().push(handler);
+        shared.ioapic.set_masked(shared.input, false);
+        return Ok(gsi);
+    }
+
+    // Find the I/O APIC the line is an input of
+    let ioapic = routing.ioapics.iter()
+        .find(|x| gsi >= x.gsi_base && gsi - x.gsi_base < x.inputs)
+        .ok_or(LegacyIrqError::NoIoApic)?.clone();
+    let input = gsi - ioapic.gsi_base;
+
+    // The redirection entry only has room for an 8-bit APIC ID
+    let apic_id = core!().apic_id().unwrap();
+    if apic_id > 0xff {
+        return Err(LegacyIrqError::UnreachableCore);
+    }
+
+    let shared = Arc::new(SharedLine {
+        gsi,
+        ioapic: ioapic.clone(),
+        input,
+        handlers: LockCell::new_no_preempt(Vec::new()),
+    });
+    shared.handlers.lock().push(handler);
+
+    let vector = irq::allocate_vector(shared.clone())
+        .ok_or(LegacyIrqError::NoVectors)?;
+
+    // Program the redirection entry as a fixed interrupt to us, physical
+    // destination mode
+    {
+        let _lock = IOAPIC_LOCK.lock();
+        ioapic.write(0x11 + input * 2, apic_id << 24);
+        ioapic.write(0x10 + input * 2, vector as u32 |
+                     if active_low { 1 << 13 } else { 0 } |
+                     if level { 1 << 15 } else { 0 });
+    }
+
+    routing.lines.push(shared);
+    Ok(gsi)
+}
+
+/// Drop the legacy interrupt handlers of `device`, masking lines which no
+/// one is left on
+pub(super) fn free_device_handlers(device: &Arc<dyn Device>) {
+    let device = &**device as *const dyn Device as *const u8;
+
+    let routing = ROUTING.lock();
+    let lines = routing.as_ref().map(|x| &x.lines[..]).unwrap_or(&[]);
+    for line in lines {
+        let mut handlers = line.handlers.lock();
+        let count = handlers.len();
+        handlers.retain(|x| &**x as *const dyn Device as *const u8 != device);
+
+        if handlers.is_empty() && count != 0 {
+            unsafe { line.ioapic.set_masked(line.input, true); }
+        }
+    }
+}
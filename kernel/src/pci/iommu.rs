@@ -0,0 +1,62 @@
+//! Detection of DMA remapping hardware (Intel VT-d)
+//!
+//! Our drivers hand physical addresses straight to devices for DMA, which is
+//! only correct if those addresses are not translated by an IOMMU. We do not
+//! program IOMMUs, so instead we detect if firmware left translation enabled
+//! and refuse to let devices master the bus in that case.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use page_table::PhysAddr;
+
+use crate::acpi;
+use crate::mm;
+
+/// Offset of the global status register of a remapping unit
+const GSTS: usize = 0x1c;
+
+/// Translation enable status bit in the global status register
+const GSTS_TES: u32 = 1 << 31;
+
+/// Set if any DMA remapping unit on the system has translation enabled
+static TRANSLATION_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set once `init` checked the remapping units
+static DETECTED: AtomicBool = AtomicBool::new(false);
+
+/// Locate the DMA remapping units via the ACPI DMAR table and check whether
+/// any of them are translating DMA
+///
+/// We don't parse the device scopes of the units, if any unit is translating
+/// we assume every device is behind it.
+///
+/// The units are only checked by the first call, later calls (eg. from each
+/// `rescan`) return right away rather than mapping the registers again.
+pub unsafe fn init() {
+    if DETECTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut enabled = false;
+
+    for unit in acpi::parse_dmar() {
+        // Map in the register set and read the global status
+        let regs = mm::map_mmio(PhysAddr(unit.base.0), 4096);
+        let status = u32::from_le(core::ptr::read_volatile(
+            (regs.0 as usize + GSTS) as *const u32));
+
+        if (status & GSTS_TES) != 0 {
+            print!("DMA remapping unit at {:#x} has translation enabled, \
+                    PCI bus mastering will be refused\n", unit.base.0);
+            enabled = true;
+        }
+    }
+
+    TRANSLATION_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns `true` if device DMA may be translated by an IOMMU, in which case
+/// drivers must not use physical addresses for DMA
+pub fn translation_enabled() -> bool {
+    TRANSLATION_ENABLED.load(Ordering::SeqCst)
+}
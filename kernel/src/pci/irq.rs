@@ -0,0 +1,216 @@
+//! Routing of device interrupt vectors back to the devices which own them
+//!
+//! Drivers allocate a vector for their device here, program it into the MSI
+//! or MSI-X capability of the device with the message from `msi_message`, and
+//! will then have `Device::handle_interrupt` invoked whenever it fires.
+//!
+//! Every vector a device signals must come from here, such that no two
+//! devices are ever programmed with the same vector. `lease_vector` hands out
+//! vectors which are freed again when dropped, eg. when setting up MSI fails
+//! part way through.
+
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering, spin_loop_hint};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use lockcell::LockCell;
+
+use crate::core_locals::LockInterrupts;
+use crate::interrupts;
+use super::Device;
+
+/// First interrupt vector handed out to devices
+const FIRST_VECTOR: u8 = 0x40;
+
+/// Last interrupt vector (inclusive) handed out to devices. Vectors above
+/// this are used by the APIC timer and the spurious interrupt vector.
+const LAST_VECTOR: u8 = 0xdf;
+
+/// Number of vectors which can be handed out to devices
+const NUM_VECTORS: usize = (LAST_VECTOR - FIRST_VECTOR) as usize + 1;
+
+/// Device which owns each vector from `FIRST_VECTOR` on, null if the vector
+/// is free. Owners are leaked `Box`es, freed by `release` once no reader can
+/// be using them.
+///
+/// This is read from interrupt context without taking any lock, see `owner`,
+/// such that an interrupt never waits for a core which is managing vectors.
+static OWNERS: [AtomicPtr<Arc<dyn Device>>; NUM_VECTORS] =
+    [AtomicPtr::new(core::ptr::null_mut()); NUM_VECTORS];
+
+/// Number of readers currently looking at each entry of `OWNERS`
+static READERS: [AtomicU32; NUM_VECTORS] = [AtomicU32::new(0); NUM_VECTORS];
+
+/// Lock held while changing `OWNERS`, such that allocations and frees don't
+/// race with each other. Readers never take this.
+static OWNERS_LOCK: LockCell<(), LockInterrupts> =
+    LockCell::new_no_preempt(());
+
+/// Get the index of `vector` into `OWNERS`, if it is a device vector
+fn slot(vector: u8) -> Option<usize> {
+    if vector < FIRST_VECTOR || vector > LAST_VECTOR {
+        None
+    } else {
+        Some((vector - FIRST_VECTOR) as usize)
+    }
+}
+
+/// Get the device which owns `vector`, without taking any lock
+///
+/// This only ever waits for the reference count of the owner to be bumped,
+/// thus it is bounded and safe from interrupt context.
+fn owner(vector: u8) -> Option<Arc<dyn Device>> {
+    let slot = slot(vector)?;
+
+    // Announce ourselves before looking at the owner, such that `release`
+    // waits for us if it took the owner away after we loaded it
+    READERS[slot].fetch_add(1, Ordering::SeqCst);
+    let owner = OWNERS[slot].load(Ordering::SeqCst);
+    let ret = if owner.is_null() {
+        None
+    } else {
+        Some(unsafe { (*owner).clone() })
+    };
+    READERS[slot].fetch_sub(1, Ordering::SeqCst);
+
+    ret
+}
+
+/// Take the owner away from `slot` of `OWNERS` and drop our reference to it
+/// once no reader can be using it. `OWNERS_LOCK` must be held.
+fn release(slot: usize) {
+    let owner = OWNERS[slot].swap(core::ptr::null_mut(), Ordering::SeqCst);
+    assert!(!owner.is_null(), "Freed an unallocated interrupt vector");
+
+    // Readers only hold on to the owner long enough to clone it
+    while READERS[slot].load(Ordering::SeqCst) != 0 {
+        spin_loop_hint();
+    }
+
+    unsafe { drop(Box::from_raw(owner)); }
+    interrupts::set_eoi_required(FIRST_VECTOR + slot as u8, false);
+}
+
+/// Allocate an interrupt vector for `device`. Returns `None` if we ran out of
+/// vectors.
+pub fn allocate_vector(device: Arc<dyn Device>) -> Option<u8> {
+    let _lock = OWNERS_LOCK.lock();
+
+    // Find the first free vector
+    let slot = OWNERS.iter()
+        .position(|x| x.load(Ordering::SeqCst).is_null())?;
+    let vector = FIRST_VECTOR + slot as u8;
+
+    // Device interrupts come from the APIC and must be EOIed
+    interrupts::set_eoi_required(vector, true);
+
+    OWNERS[slot].store(Box::into_raw(Box::new(device)), Ordering::SeqCst);
+    Some(vector)
+}
+
+/// An interrupt vector allocated with `lease_vector`, which is freed when
+/// this is dropped
+///
+/// The vector stays allocated to the device until it's dropped or the device
+/// has all of its vectors freed when it's removed, whichever comes first.
+/// Dropping the lease after that is harmless, even if the vector was handed
+/// to another device in the meantime.
+pub struct VectorLease {
+    /// The allocated vector
+    vector: u8,
+
+    /// Device the vector was allocated for, only used for its address
+    device: *const u8,
+}
+
+// The device pointer is only compared against, never dereferenced
+unsafe impl Send for VectorLease {}
+unsafe impl Sync for VectorLease {}
+
+impl VectorLease {
+    /// Get the interrupt vector, to program into the device
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// Keep the vector allocated for as long as the device is registered,
+    /// without holding on to the lease
+    pub fn into_vector(self) -> u8 {
+        let vector = self.vector;
+        core::mem::forget(self);
+        vector
+    }
+}
+
+impl Drop for VectorLease {
+    fn drop(&mut self) {
+        let _lock = OWNERS_LOCK.lock();
+
+        // Only free the vector if it still belongs to our device
+        let slot  = slot(self.vector).unwrap();
+        let owner = OWNERS[slot].load(Ordering::SeqCst);
+        if !owner.is_null() && device_addr(unsafe { &*owner }) == self.device {
+            release(slot);
+        }
+    }
+}
+
+/// Get the address of `device`, for telling devices apart
+fn device_addr(device: &Arc<dyn Device>) -> *const u8 {
+    &**device as *const dyn Device as *const u8
+}
+
+/// Allocate an interrupt vector for `device` like `allocate_vector`, which
+/// is freed when the returned lease is dropped
+pub fn lease_vector(device: Arc<dyn Device>) -> Option<VectorLease> {
+    let addr = device_addr(&device);
+    allocate_vector(device).map(|vector| {
+        VectorLease { vector, device: addr }
+    })
+}
+
+/// Release `vector` which was allocated with `allocate_vector`. The device
+/// must no longer be signalling the vector.
+pub fn free_vector(vector: u8) {
+    let _lock = OWNERS_LOCK.lock();
+    release(slot(vector).expect("Freed an unallocated interrupt vector"));
+}
+
+/// Release all vectors owned by `device`, which must no longer be signalling
+/// them, along with its legacy interrupt handlers
+pub(super) fn free_device_vectors(device: &Arc<dyn Device>) {
+    super::intx::free_device_handlers(device);
+
+    let device = device_addr(device);
+
+    let _lock = OWNERS_LOCK.lock();
+    for slot in 0..NUM_VECTORS {
+        // Owners only change with the lock held, thus we can look at them
+        let owner = OWNERS[slot].load(Ordering::SeqCst);
+        if !owner.is_null() && device_addr(unsafe { &*owner }) == device {
+            release(slot);
+        }
+    }
+}
+
+/// Get the MSI message address which delivers interrupts to the APIC with
+/// `apic_id`
+pub fn msi_address(apic_id: u32) -> u64 {
+    assert!(apic_id <= 0xff, "APIC ID not addressable by MSI");
+    0xfee0_0000 | ((apic_id as u64) << 12)
+}
+
+/// Get the MSI message address and data which deliver `vector` to the APIC
+/// with `apic_id`, as fixed, edge-triggered interrupts
+pub fn msi_message(vector: u8, apic_id: u32) -> (u64, u16) {
+    (msi_address(apic_id), vector as u16)
+}
+
+/// Invoke the interrupt handler of the device which owns `vector`. Returns
+/// `true` if the device handled the interrupt.
+pub unsafe fn dispatch(vector: u8) -> bool {
+    // Get the owner, the device may manage its vectors while it handles the
+    // interrupt
+    owner(vector).map(|device| device.handle_interrupt(vector))
+        .unwrap_or(false)
+}
@@ -0,0 +1,83 @@
+//! Bounds-checked access to the memory-mapped registers of PCI devices
+
+use core::mem::size_of;
+
+use page_table::{PhysAddr, VirtAddr};
+
+use crate::mm::{self, MmioError};
+use crate::pci::Bar;
+
+/// Reasons a BAR could not be mapped in as a `RegisterBlock`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterBlockError {
+    /// The BAR is an I/O BAR
+    NotMemory,
+
+    /// The memory range of the BAR could not be mapped in
+    Mmio(MmioError),
+}
+
+impl From<MmioError> for RegisterBlockError {
+    fn from(val: MmioError) -> Self {
+        RegisterBlockError::Mmio(val)
+    }
+}
+
+/// A memory BAR mapped into uncacheable virtual memory, accessed as 32-bit
+/// registers at byte offsets into the BAR
+pub struct RegisterBlock {
+    /// Virtual address of the start of the BAR
+    vaddr: VirtAddr,
+
+    /// Size of the BAR, in bytes
+    size: usize,
+}
+
+impl RegisterBlock {
+    /// Map in `size` bytes of the memory BAR `bar`. Fails if `bar` is not a
+    /// memory BAR, or if its range is empty or is not device memory the CPU
+    /// can reach, see `mm::try_map_mmio`.
+    pub unsafe fn new(bar: Bar, size: u64)
+            -> Result<Self, RegisterBlockError> {
+        // Get the physical address of the BAR
+        let addr = match bar {
+            Bar::Memory { addr, .. } => addr,
+            Bar::Io { .. } => return Err(RegisterBlockError::NotMemory),
+        };
+
+        // Map in the BAR as uncacheable memory
+        let vaddr = mm::try_map_mmio(PhysAddr(addr), size)?;
+
+        Ok(RegisterBlock {
+            vaddr,
+            size: size as usize,
+        })
+    }
+
+    /// Size of the register block, in bytes
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Get a pointer to the 32-bit register at byte offset `offset`
+    fn register(&self, offset: usize) -> *mut u32 {
+        assert!((offset & (size_of::<u32>() - 1)) == 0,
+            "Unaligned MMIO register offset");
+        assert!(offset.checked_add(size_of::<u32>())
+                .map(|end| end <= self.size).unwrap_or(false),
+            "MMIO register offset out of bounds");
+
+        (self.vaddr.0 as usize + offset) as *mut u32
+    }
+
+    /// Read the little-endian 32-bit register at byte offset `offset`
+    pub unsafe fn read32(&self, offset: usize) -> u32 {
+        u32::from_le(core::ptr::read_volatile(self.register(offset)))
+    }
+
+    /// Write `val` to the little-endian 32-bit register at byte offset
+    /// `offset`
+    pub unsafe fn write32(&self, offset: usize, val: u32) {
+        core::ptr::write_volatile(self.register(offset), val.to_le());
+    }
+}
@@ -0,0 +1,79 @@
+//! Bounds-checked access to the I/O port registers of PCI devices
+
+use core::mem::size_of;
+
+use crate::pci::Bar;
+
+/// An I/O BAR, accessed as 8, 16, or 32-bit registers at byte offsets into
+/// the BAR
+pub struct IoPort {
+    /// First I/O port decoded by the BAR
+    base: u16,
+
+    /// Size of the BAR, in bytes
+    size: u16,
+}
+
+impl IoPort {
+    /// Access `size` bytes of the I/O BAR `bar`. Returns `None` if `bar` is
+    /// not an I/O BAR, if `size` is zero, or if the BAR would extend past the
+    /// end of the I/O port space.
+    pub fn new(bar: Bar, size: u64) -> Option<Self> {
+        // Get the base port of the BAR
+        let base = match bar {
+            Bar::Io { port } => port,
+            Bar::Memory { .. } => return None,
+        };
+
+        if size == 0 || base as u64 + size > 0x10000 {
+            return None;
+        }
+
+        Some(IoPort { base, size: size as u16 })
+    }
+
+    /// Size of the I/O region, in bytes
+    pub fn len(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Get the I/O port of the `width`-byte register at byte offset `offset`
+    fn port(&self, offset: u16, width: usize) -> u16 {
+        assert!((offset as usize & (width - 1)) == 0,
+            "Unaligned I/O register offset");
+        assert!(offset as usize + width <= self.size as usize,
+            "I/O register offset out of bounds");
+
+        self.base + offset
+    }
+
+    /// Read the 8-bit register at byte offset `offset`
+    pub unsafe fn read8(&self, offset: u16) -> u8 {
+        cpu::in8(self.port(offset, size_of::<u8>()))
+    }
+
+    /// Read the 16-bit register at byte offset `offset`
+    pub unsafe fn read16(&self, offset: u16) -> u16 {
+        cpu::in16(self.port(offset, size_of::<u16>()))
+    }
+
+    /// Read the 32-bit register at byte offset `offset`
+    pub unsafe fn read32(&self, offset: u16) -> u32 {
+        cpu::in32(self.port(offset, size_of::<u32>()))
+    }
+
+    /// Write `val` to the 8-bit register at byte offset `offset`
+    pub unsafe fn write8(&self, offset: u16, val: u8) {
+        cpu::out8(self.port(offset, size_of::<u8>()), val);
+    }
+
+    /// Write `val` to the 16-bit register at byte offset `offset`
+    pub unsafe fn write16(&self, offset: u16, val: u16) {
+        cpu::out16(self.port(offset, size_of::<u16>()), val);
+    }
+
+    /// Write `val` to the 32-bit register at byte offset `offset`
+    pub unsafe fn write32(&self, offset: u16, val: u32) {
+        cpu::out32(self.port(offset, size_of::<u32>()), val);
+    }
+}
@@ -0,0 +1,43 @@
+//! Per-device workarounds applied before any driver probes a device
+
+use core::ops::RangeInclusive;
+
+use super::ProbedDevice;
+
+/// A workaround for a specific device
+struct Quirk {
+    /// Vendor ID of the device this quirk applies to
+    vendor_id: u16,
+
+    /// Device ID of the device this quirk applies to
+    device_id: u16,
+
+    /// Revisions of the device this quirk applies to, `None` for all of them
+    revisions: Option<RangeInclusive<u8>>,
+
+    /// Routine applying the workaround. It may reconfigure the device
+    /// through `ProbedDevice::access` and patch the decoded configuration
+    /// drivers will see, eg. to hide a broken capability, in which case it
+    /// must call `ProbedDevice::cache_capabilities`.
+    apply: unsafe fn(&mut ProbedDevice),
+}
+
+/// All known device quirks, every matching entry is applied in order
+static QUIRKS: &[Quirk] = &[];
+
+/// Apply all quirks matching `device`, right after it was decoded and before
+/// any driver probes it
+pub(super) unsafe fn apply(device: &mut ProbedDevice) {
+    for quirk in QUIRKS.iter() {
+        let matches = match &quirk.revisions {
+            Some(revisions) => device.config.revision_matches(
+                quirk.vendor_id, quirk.device_id, revisions.clone()),
+            None => device.config.matches(
+                quirk.vendor_id, quirk.device_id, None, None),
+        };
+
+        if matches {
+            (quirk.apply)(device);
+        }
+    }
+}
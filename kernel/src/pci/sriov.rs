@@ -0,0 +1,176 @@
+//! Enumeration of SR-IOV virtual functions
+//!
+//! Virtual functions have a configuration space of their own, but their
+//! vendor and device IDs read back as all ones and their BARs are hardwired to
+//! zero. Instead, the IDs and BARs are described by the SR-IOV capability of
+//! the physical function. We present each virtual function to the drivers as
+//! a normal device by emulating those registers in a `ConfigAccess`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use alloc::boxed::Box;
+
+use lockcell::LockCell;
+
+use crate::core_locals::LockInterrupts;
+use super::{PciAddress, ProbedDevice, ProbeState, claim_device, pci_debug};
+use super::config::{ConfigAccess, HARDWARE};
+use super::quirks;
+
+/// Offset of the first BAR in the configuration space header
+const BAR0: u16 = 0x10;
+
+/// Configuration space of a virtual function, with the registers which are
+/// described by the physical function emulated
+struct VfConfig {
+    /// Vendor and device ID register reported for the VF
+    id: u32,
+
+    /// BAR registers of the VF
+    bars: [u32; 6],
+
+    /// Values read back from the BAR registers while all ones are written to
+    /// them, this is what is used to size BARs
+    masks: [u32; 6],
+
+    /// Bitmap of BARs which currently have all ones written to them
+    sizing: AtomicU8,
+}
+
+impl VfConfig {
+    /// Create the emulated registers for virtual function number `vf`, given
+    /// the raw VF BAR registers and the per-VF sizes of the VF BARs of the
+    /// physical function
+    fn new(id: u32, vf: u16, vf_bars: &[u32; 6], sizes: &[u64; 6]) -> Self {
+        let mut bars  = [0u32; 6];
+        let mut masks = [0u32; 6];
+
+        let mut ii = 0;
+        while ii < 6 {
+            let raw   = vf_bars[ii];
+            let flags = raw & 0xf;
+            let is_64 = (raw & (1 << 2)) != 0 && ii < 5;
+
+            // Get the base of the VF BAR array
+            let base = if is_64 {
+                ((vf_bars[ii + 1] as u64) << 32) | (raw & !0xf) as u64
+            } else {
+                (raw & !0xf) as u64
+            };
+
+            // Each VF gets the next `size` bytes of the array
+            let size = sizes[ii];
+            if size != 0 {
+                let addr = base + vf as u64 * size;
+                let mask = !(size - 1);
+
+                bars[ii]  = (addr as u32 & !0xf) | flags;
+                masks[ii] = (mask as u32 & !0xf) | flags;
+                if is_64 {
+                    bars[ii + 1]  = (addr >> 32) as u32;
+                    masks[ii + 1] = (mask >> 32) as u32;
+                }
+            }
+
+            ii += if is_64 { 2 } else { 1 };
+        }
+
+        VfConfig { id, bars, masks, sizing: AtomicU8::new(0) }
+    }
+
+    /// Get the BAR index of the register at `offset`, if it is a BAR
+    fn bar_index(offset: u16) -> Option<usize> {
+        if offset >= BAR0 && offset < BAR0 + 6 * 4 {
+            Some(((offset - BAR0) / 4) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl ConfigAccess for VfConfig {
+    unsafe fn read(&self, addr: PciAddress, offset: u16) -> u32 {
+        if offset == 0 {
+            return self.id;
+        }
+
+        if let Some(bar) = Self::bar_index(offset) {
+            let sizing = (self.sizing.load(Ordering::SeqCst) & (1 << bar)) != 0;
+            return if sizing { self.masks[bar] } else { self.bars[bar] };
+        }
+
+        HARDWARE.read(addr, offset)
+    }
+
+    unsafe fn write(&self, addr: PciAddress, offset: u16, value: u32) {
+        // The BARs are read-only in hardware, we only track whether they are
+        // being sized
+        if let Some(bar) = Self::bar_index(offset) {
+            if value == !0 {
+                self.sizing.fetch_or(1 << bar, Ordering::SeqCst);
+            } else {
+                self.sizing.fetch_and(!(1 << bar), Ordering::SeqCst);
+            }
+            return;
+        }
+
+        HARDWARE.write(addr, offset, value)
+    }
+
+    fn has_extended(&self, addr: PciAddress) -> bool {
+        HARDWARE.has_extended(addr)
+    }
+}
+
+/// Enable all of the virtual functions of the physical function `pf` and
+/// bind drivers to them
+///
+/// We don't assign resources ourselves, thus this does nothing if firmware
+/// did not assign the VF BARs.
+pub(super) unsafe fn init_virtual_functions(pf: &ProbedDevice,
+        state: &LockCell<Option<ProbeState>, LockInterrupts>) {
+    // Check if this is an SR-IOV capable physical function
+    let sriov = if let Some(sriov) = pf.sriov() { sriov } else { return; };
+    if sriov.total_vfs() == 0 {
+        return;
+    }
+
+    // Make sure firmware gave the VFs some memory
+    let vf_bars = sriov.vf_bars();
+    if vf_bars.iter().all(|&bar| (bar & !0xf) == 0) {
+        if pci_debug() {
+            print!("PCI device | {:02x}:{:02x}.{} | VF BARs not assigned, \
+                    not enabling SR-IOV\n",
+                   pf.addr.bus, pf.addr.device, pf.addr.function);
+        }
+        return;
+    }
+
+    // Size the VF BARs and bring up the VFs
+    let sizes = sriov.vf_bar_sizes();
+    sriov.enable(sriov.total_vfs());
+
+    // Vendor and device ID register the VFs will report
+    let id = ((sriov.vf_device_id() as u32) << 16) |
+        pf.config.header.vendor_id as u32;
+
+    for vf in 0..sriov.num_vfs() {
+        let addr = if let Some(addr) = sriov.vf_address(vf) {
+            addr
+        } else {
+            break;
+        };
+
+        // The VF lives for the rest of this boot
+        let access: &'static VfConfig =
+            Box::leak(Box::new(VfConfig::new(id, vf, &vf_bars, &sizes)));
+        let mut device = ProbedDevice::from_access(access, addr);
+        quirks::apply(&mut device);
+
+        // Look up what the previous boot did with this VF
+        let bdf = addr.index() as u16;
+        let previous = state.lock().as_ref().unwrap().previous_claims
+            .as_ref().and_then(|x| x.find(bdf)).copied();
+
+        claim_device(&device, previous, state);
+    }
+}
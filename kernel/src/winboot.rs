@@ -113,6 +113,10 @@ pub fn main() {
     worker.write_phys_from(PhysAddr(0x7c00), &disk[..512]).unwrap();
 
     'vm_loop: loop {
+        // The other cores are halted and we never go idle while the VM runs,
+        // so answer PCI dump requests from the serial port here
+        crate::pci::service_dump_request();
+
         let (vmexit, _) = worker.vm_mut().run();
 
         match vmexit {
@@ -37,6 +37,151 @@ pub const KERNEL_STACK_PAD: u64 = 32 * 1024;
 /// Size of the kernel physical window (in bytes)
 pub const KERNEL_PHYS_WINDOW_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
 
+/// Maximum number of PCI functions whose driver bindings can be persisted
+/// across a soft reboot
+pub const MAX_PCI_CLAIMS: usize = 256;
+
+/// Driver ID used in a `PciClaim` for a device which no driver claimed
+pub const PCI_NO_DRIVER: u16 = 0;
+
+/// The driver binding for a PCI function from a previous boot
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct PciClaim {
+    /// Location of the function, indexed by B:D:F the same way as the
+    /// `pci_devices` bitmap
+    pub bdf: u16,
+
+    /// Vendor ID of the function when it was probed
+    pub vendor_id: u16,
+
+    /// Device ID of the function when it was probed
+    pub device_id: u16,
+
+    /// ID of the driver which claimed the function, `PCI_NO_DRIVER` if the
+    /// function was not claimed
+    pub driver_id: u16,
+}
+
+/// Driver bindings for PCI functions from a previous boot
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct PciClaims {
+    /// Signature of the set of drivers which were available when these
+    /// claims were made. The claims are only valid for the same drivers.
+    pub drivers: u64,
+
+    /// Number of valid entries in `claims`
+    pub len: u64,
+
+    /// Driver bindings, in enumeration order
+    pub claims: [PciClaim; MAX_PCI_CLAIMS],
+}
+
+impl PciClaims {
+    /// Create a new empty set of claims for the drivers with `drivers` as a
+    /// signature
+    pub const fn new(drivers: u64) -> Self {
+        PciClaims {
+            drivers,
+            len:    0,
+            claims: [PciClaim {
+                bdf:       0,
+                vendor_id: 0,
+                device_id: 0,
+                driver_id: PCI_NO_DRIVER,
+            }; MAX_PCI_CLAIMS],
+        }
+    }
+
+    /// Get the valid claims
+    pub fn claims(&self) -> &[PciClaim] {
+        &self.claims[..self.len as usize]
+    }
+
+    /// Find the claim for the function at `bdf`
+    pub fn find(&self, bdf: u16) -> Option<&PciClaim> {
+        self.claims().iter().find(|x| x.bdf == bdf)
+    }
+
+    /// Record a claim. Returns `false` if there was no room left to save the
+    /// claim.
+    pub fn push(&mut self, claim: PciClaim) -> bool {
+        if self.len as usize >= MAX_PCI_CLAIMS {
+            return false;
+        }
+
+        self.claims[self.len as usize] = claim;
+        self.len += 1;
+        true
+    }
+}
+
+/// Maximum number of PCI functions whose configuration space can be cached
+/// across a soft reboot
+pub const MAX_PCI_CONFIGS: usize = 256;
+
+/// Number of dwords of configuration space cached for each PCI function
+pub const PCI_CONFIG_DWORDS: usize = 16;
+
+/// The configuration space header of a PCI function from a previous boot
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct PciConfig {
+    /// Location of the function, indexed by B:D:F the same way as the
+    /// `pci_devices` bitmap
+    pub bdf: u16,
+
+    /// Raw configuration space, starting at offset 0
+    pub config: [u32; PCI_CONFIG_DWORDS],
+}
+
+/// Configuration space headers of PCI functions from a previous boot
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct PciConfigs {
+    /// Number of valid entries in `configs`
+    pub len: u64,
+
+    /// Configuration spaces, in enumeration order
+    pub configs: [PciConfig; MAX_PCI_CONFIGS],
+}
+
+impl PciConfigs {
+    /// Create a new empty configuration space cache
+    pub const fn new() -> Self {
+        PciConfigs {
+            len:     0,
+            configs: [PciConfig {
+                bdf:    0,
+                config: [0; PCI_CONFIG_DWORDS],
+            }; MAX_PCI_CONFIGS],
+        }
+    }
+
+    /// Get the valid configuration spaces
+    pub fn configs(&self) -> &[PciConfig] {
+        &self.configs[..self.len as usize]
+    }
+
+    /// Find the configuration space of the function at `bdf`
+    pub fn find(&self, bdf: u16) -> Option<&PciConfig> {
+        self.configs().iter().find(|x| x.bdf == bdf)
+    }
+
+    /// Record a configuration space. Returns `false` if there was no room
+    /// left to save it.
+    pub fn push(&mut self, config: PciConfig) -> bool {
+        if self.len as usize >= MAX_PCI_CONFIGS {
+            return false;
+        }
+
+        self.configs[self.len as usize] = config;
+        self.len += 1;
+        true
+    }
+}
+
 /// Memory that can persist a soft reboot. Similar to `BootArgs` this structure
 /// must not change shape between 32-bit and 64-bit versions. No using
 /// references, pointers, `usize`s, etc.
@@ -46,6 +191,13 @@ pub struct PersistStore<I: InterruptState> {
     /// indexed by B:D:A
     pub pci_devices: LockCell<Option<[u64; 256 * 32 * 8 / 64]>, I>,
 
+    /// Which drivers claimed which PCI functions during the previous boot
+    pub pci_claims: LockCell<Option<PciClaims>, I>,
+
+    /// Configuration space headers of the PCI functions read during the
+    /// previous boot
+    pub pci_configs: LockCell<Option<PciConfigs>, I>,
+
     /// Tick rate of the RDTSC in MHz
     pub rdtsc_freq: LockCell<Option<u64>, I>,
 }
@@ -55,6 +207,8 @@ impl<I: InterruptState> PersistStore<I> {
     pub const fn new() -> Self {
         PersistStore {
             pci_devices: LockCell::new(None),
+            pci_claims:  LockCell::new(None),
+            pci_configs: LockCell::new(None),
             rdtsc_freq:  LockCell::new(None),
         }
     }
@@ -23,6 +23,20 @@ pub unsafe fn in8(addr: u16) -> u8 {
     val
 }
 
+/// Output a 16-bit `val` to I/O port `addr`
+#[inline]
+pub unsafe fn out16(addr: u16, val: u16) {
+    llvm_asm!("out dx, ax" :: "{dx}"(addr), "{ax}"(val) :: "volatile", "intel");
+}
+
+/// Read a 16-bit value from I/O port `addr`
+#[inline]
+pub unsafe fn in16(addr: u16) -> u16 {
+    let val: u16;
+    llvm_asm!("in ax, dx" : "={ax}"(val) : "{dx}"(addr) :: "volatile", "intel");
+    val
+}
+
 /// Output a 32-bit `val` to I/O port `addr`
 #[inline]
 pub unsafe fn out32(addr: u16, val: u32) {
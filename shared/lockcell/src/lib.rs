@@ -38,6 +38,16 @@ pub trait InterruptState {
     /// unique to the core, and cannot be `!0`
     fn core_id() -> u32;
 
+    /// Returns `true` if every core other than the running core has been
+    /// parked and thus can no longer access any locks. This is used to sanity
+    /// check `LockCell::shatter` in debug builds.
+    ///
+    /// Implementations which do not track the state of other cores should
+    /// return `true`.
+    fn other_cores_parked() -> bool {
+        true
+    }
+
     /// A lock which does not allow interrupting was taken, and thus interrupts
     /// must be disabled. It's up to the callee to handle the nesting of the
     /// interrupt status. Eg. using a refcount of number of interrupt disable
@@ -207,9 +217,63 @@ impl<T: ?Sized, I: InterruptState> LockCell<T, I> {
         }
     }
     
+    /// Attempt to get exclusive access to the value guarded by the lock
+    /// without waiting. Returns `None` if the lock is currently held, this
+    /// includes the case where the running core is the one holding it.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<LockCellGuard<T, I>> {
+        assert!(self.disables_interrupts || !I::in_interrupt(),
+            "Attempted to take a non-preemptable lock in an interrupt");
+
+        // Disable interrupts if needed
+        if self.disables_interrupts {
+            I::enter_lock();
+        }
+
+        // Only take a ticket if it would be the one currently being served,
+        // otherwise we'd have to wait for our turn
+        let release = self.release.load(Ordering::SeqCst);
+        if self.ticket.compare_exchange(release, release.wrapping_add(1),
+                Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            // Lock is contended, re-enable interrupts if needed
+            if self.disables_interrupts {
+                I::exit_lock();
+            }
+
+            return None;
+        }
+
+        // Note that this core owns the lock
+        self.owner.store(I::core_id(), Ordering::SeqCst);
+        self.owner_location.store(Location::caller() as *const _ as u64,
+            Ordering::SeqCst);
+
+        // At this point we have exclusive access
+        Some(LockCellGuard {
+            cell: self,
+        })
+    }
+    
     /// Return a raw pointer to the internal locked value, regardless of the
     /// lock state. This bypasses the lock.
+    ///
+    /// This must only be used once all other cores have been parked, which
+    /// is checked in debug builds. Use `shatter_local` for values which are
+    /// only ever accessed by the running core.
+    #[track_caller]
     pub unsafe fn shatter(&self) -> *mut T {
+        debug_assert!(I::other_cores_parked(),
+            "Attempted to shatter a lock while other cores are running");
+        self.val.get()
+    }
+
+    /// Return a raw pointer to the internal locked value, regardless of the
+    /// lock state. This bypasses the lock.
+    ///
+    /// Unlike `shatter` this may be used while other cores are running, and
+    /// thus must only be used on values which are local to the running core,
+    /// such that only an interrupted holder on this core could hold the lock.
+    pub unsafe fn shatter_local(&self) -> *mut T {
         self.val.get()
     }
 }